@@ -1,21 +1,19 @@
-(fset 'fib
-      '(lambda (a)
-	 (if (eq a 1)
-	     1
-	   (if (eq a 2)
-	       2
-	     (+ (fib (- a 1)) (fib (- a 2)))))))
+;;; demo.el --- A few things to try  -*- lexical-binding: t -*-
 
-(fset 'fibs
-      '(lambda (a)
-	 (if (eq a 0)
-	     nil
-	   (progn
-	     (print (fib a))
-	     (fibs (- a 1))))))
+(defun fib (a)
+  (if (eq a 1)
+      1
+    (if (eq a 2)
+	2
+      (+ (fib (- a 1)) (fib (- a 2))))))
 
-(fset 'hello-world
-      '(lambda () (print "Hello, World!")))
+(defun fibs (a)
+  (while (not (eq a 0))
+    (print (fib a))
+    (setq a (- a 1))))
+
+(defun hello-world ()
+  (print "Hello, World!"))
 
 (hello-world)
 (print "fibs: ")
@@ -1,3 +1,4 @@
-(fset 'backward-char '(lambda () (forward-char -1)))
+(defun backward-char ()
+  (forward-char -1))
 
 (load bindings)
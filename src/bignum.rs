@@ -0,0 +1,544 @@
+// Copyright (C) 2017 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Arbitrary precision integers
+//!
+//! Integers which do not fit in a fixnum are kept as a sign and a magnitude
+//! of 32 bit digits, least significant first. Only what the arithmetic
+//! builtins need is implemented and the algorithms are the simple ones from
+//! school, so very large numbers will be slow.
+
+use std::cmp::{self, Ordering};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    neg: bool,
+    /// The digits, least significant first, without any leading zeros
+    mag: Vec<u32>,
+}
+
+/// Remove leading zero digits
+fn trim(mut mag: Vec<u32>) -> Vec<u32> {
+    while mag.last() == Some(&0) {
+        mag.pop();
+    }
+    mag
+}
+
+fn mag_cmp(a: &[u32], b: &[u32]) -> Ordering {
+    if a.len() != b.len() {
+        return a.len().cmp(&b.len());
+    }
+
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x.cmp(y);
+        }
+    }
+    Ordering::Equal
+}
+
+fn mag_add(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (a, b) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut sum = Vec::with_capacity(a.len() + 1);
+    let mut carry = 0u64;
+
+    for (i, &x) in a.iter().enumerate() {
+        let s = x as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        sum.push(s as u32);
+        carry = s >> 32;
+    }
+    if carry > 0 {
+        sum.push(carry as u32);
+    }
+    sum
+}
+
+/// a - b where a >= b
+fn mag_sub(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut diff = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+
+    for (i, &x) in a.iter().enumerate() {
+        let mut d = x as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if d < 0 {
+            d += 1 << 32;
+            borrow = 1;
+        }
+        diff.push(d as u32);
+    }
+    trim(diff)
+}
+
+fn mag_mul(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut prod = vec![0u32; a.len() + b.len()];
+
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &y) in b.iter().enumerate() {
+            let p = x as u64 * y as u64 + prod[i + j] as u64 + carry;
+            prod[i + j] = p as u32;
+            carry = p >> 32;
+        }
+        prod[i + b.len()] = carry as u32;
+    }
+    trim(prod)
+}
+
+/// a * m + c
+fn mag_mul_small(a: &[u32], m: u32, c: u32) -> Vec<u32> {
+    let mut prod = Vec::with_capacity(a.len() + 1);
+    let mut carry = c as u64;
+
+    for &x in a.iter() {
+        let p = x as u64 * m as u64 + carry;
+        prod.push(p as u32);
+        carry = p >> 32;
+    }
+    if carry > 0 {
+        prod.push(carry as u32);
+    }
+    trim(prod)
+}
+
+fn mag_divrem_small(a: &[u32], d: u32) -> (Vec<u32>, u32) {
+    let mut quot = vec![0u32; a.len()];
+    let mut rem = 0u64;
+
+    for i in (0..a.len()).rev() {
+        let cur = (rem << 32) | a[i] as u64;
+        quot[i] = (cur / d as u64) as u32;
+        rem = cur % d as u64;
+    }
+    (trim(quot), rem as u32)
+}
+
+fn mag_shl(a: &[u32], n: usize) -> Vec<u32> {
+    if a.is_empty() {
+        return Vec::new();
+    }
+
+    let (digits, bits) = (n / 32, n % 32);
+    let mut res = vec![0u32; digits];
+    let mut carry = 0u32;
+
+    for &x in a.iter() {
+        if bits == 0 {
+            res.push(x);
+        } else {
+            res.push((x << bits) | carry);
+            carry = x >> (32 - bits);
+        }
+    }
+    if carry > 0 {
+        res.push(carry);
+    }
+    res
+}
+
+fn mag_shr(a: &[u32], n: usize) -> Vec<u32> {
+    let (digits, bits) = (n / 32, n % 32);
+    if digits >= a.len() {
+        return Vec::new();
+    }
+
+    let a = &a[digits..];
+    let mut res = Vec::with_capacity(a.len());
+    for i in 0..a.len() {
+        if bits == 0 {
+            res.push(a[i]);
+        } else {
+            let high = a.get(i + 1).map_or(0, |&h| h << (32 - bits));
+            res.push((a[i] >> bits) | high);
+        }
+    }
+    trim(res)
+}
+
+fn mag_bit(a: &[u32], i: usize) -> bool {
+    a.get(i / 32).is_some_and(|&d| d & (1 << (i % 32)) != 0)
+}
+
+/// Long division one bit at a time
+fn mag_divrem(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if b.len() == 1 {
+        let (q, r) = mag_divrem_small(a, b[0]);
+        return (q, trim(vec![r]));
+    }
+    if mag_cmp(a, b) == Ordering::Less {
+        return (Vec::new(), a.to_vec());
+    }
+
+    let mut quot = vec![0u32; a.len()];
+    let mut rem: Vec<u32> = Vec::new();
+    for i in (0..a.len() * 32).rev() {
+        rem = mag_shl(&rem, 1);
+        if mag_bit(a, i) {
+            if rem.is_empty() {
+                rem.push(1);
+            } else {
+                rem[0] |= 1;
+            }
+        }
+        if mag_cmp(&rem, b) != Ordering::Less {
+            rem = mag_sub(&rem, b);
+            quot[i / 32] |= 1 << (i % 32);
+        }
+    }
+    (trim(quot), rem)
+}
+
+impl BigInt {
+    fn new(neg: bool, mag: Vec<u32>) -> BigInt {
+        let mag = trim(mag);
+        BigInt {
+            neg: neg && !mag.is_empty(),
+            mag,
+        }
+    }
+
+    pub fn from_i64(i: i64) -> BigInt {
+        let m = if i < 0 { (!(i as u64)).wrapping_add(1) } else { i as u64 };
+        BigInt::new(i < 0, vec![m as u32, (m >> 32) as u32])
+    }
+
+    /// The integer part of a float, None if it is infinite or NaN
+    pub fn from_f64(f: f64) -> Option<BigInt> {
+        if !f.is_finite() {
+            return None;
+        }
+
+        let f = f.trunc();
+        if f.abs() < 9.2e18 {
+            return Some(BigInt::from_i64(f as i64));
+        }
+
+        // Large floats are integers, the mantissa shifted by the exponent
+        let bits = f.to_bits();
+        let exp = ((bits >> 52) & 0x7ff) as usize - 1075;
+        let mant = (bits & ((1 << 52) - 1)) | (1 << 52);
+        let mag = mag_shl(&[mant as u32, (mant >> 32) as u32], exp);
+        Some(BigInt::new(f < 0.0, mag))
+    }
+
+    /// Parse digits in radix, with an optional sign
+    pub fn parse(s: &str, radix: u32) -> Option<BigInt> {
+        let (neg, digits) = match s.chars().next() {
+            Some('-') => (true, &s[1..]),
+            Some('+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        if digits.is_empty() {
+            return None;
+        }
+
+        let mut mag = Vec::new();
+        for c in digits.chars() {
+            mag = mag_mul_small(&mag, radix, c.to_digit(radix)?);
+        }
+        Some(BigInt::new(neg, mag))
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.mag.len() > 2 {
+            return None;
+        }
+
+        let m = self.mag.iter().rev().fold(0u64, |m, &d| (m << 32) | d as u64);
+        if self.neg {
+            if m <= 1 << 63 {
+                Some((!m).wrapping_add(1) as i64)
+            } else {
+                None
+            }
+        } else if m < 1 << 63 {
+            Some(m as i64)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let m = self.mag.iter().rev().fold(0.0, |m, &d| m * 4294967296.0 + d as f64);
+        if self.neg { -m } else { m }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mag.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.neg
+    }
+
+    /// The number of bits in the magnitude
+    pub fn bits(&self) -> usize {
+        match self.mag.last() {
+            Some(&top) => self.mag.len() * 32 - top.leading_zeros() as usize,
+            None => 0,
+        }
+    }
+
+    pub fn is_even(&self) -> bool {
+        self.mag.first().is_none_or(|&d| d & 1 == 0)
+    }
+
+    pub fn neg(&self) -> BigInt {
+        BigInt::new(!self.neg, self.mag.clone())
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt::new(false, self.mag.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.neg == other.neg {
+            return BigInt::new(self.neg, mag_add(&self.mag, &other.mag));
+        }
+
+        match mag_cmp(&self.mag, &other.mag) {
+            Ordering::Less => BigInt::new(other.neg, mag_sub(&other.mag, &self.mag)),
+            _ => BigInt::new(self.neg, mag_sub(&self.mag, &other.mag)),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        BigInt::new(self.neg != other.neg, mag_mul(&self.mag, &other.mag))
+    }
+
+    /// The quotient rounded towards zero and the remainder, which has the
+    /// sign of self; None if other is zero
+    pub fn div_rem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+
+        let (q, r) = mag_divrem(&self.mag, &other.mag);
+        Some((BigInt::new(self.neg != other.neg, q), BigInt::new(self.neg, r)))
+    }
+
+    pub fn pow(&self, mut exp: u64) -> BigInt {
+        let mut base = self.clone();
+        let mut res = BigInt::from_i64(1);
+
+        while exp > 0 {
+            if exp & 1 == 1 {
+                res = res.mul(&base);
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = base.mul(&base);
+            }
+        }
+        res
+    }
+
+    /// Multiply by 2^n
+    pub fn shl(&self, n: usize) -> BigInt {
+        BigInt::new(self.neg, mag_shl(&self.mag, n))
+    }
+
+    /// Divide by 2^n, rounding towards negative infinity
+    pub fn shr(&self, n: usize) -> BigInt {
+        let shifted = BigInt::new(self.neg, mag_shr(&self.mag, n));
+
+        // Rounding the magnitude down rounds negative numbers up
+        let lost = (0..cmp::min(n, self.mag.len() * 32)).any( |i| mag_bit(&self.mag, i) );
+        if self.neg && lost {
+            shifted.sub(&BigInt::from_i64(1))
+        } else {
+            shifted
+        }
+    }
+
+    /// The two's complement digits, sign extended to len digits
+    fn twos(&self, len: usize) -> Vec<u32> {
+        let mut digits = self.mag.clone();
+        digits.resize(len, 0);
+
+        if self.neg {
+            let mut carry = true;
+            for d in digits.iter_mut() {
+                *d = !*d;
+                if carry {
+                    let (s, overflow) = d.overflowing_add(1);
+                    *d = s;
+                    carry = overflow;
+                }
+            }
+        }
+        digits
+    }
+
+    fn from_twos(mut digits: Vec<u32>) -> BigInt {
+        let neg = digits.last().is_some_and(|&d| d & (1 << 31) != 0);
+
+        if neg {
+            let mut carry = true;
+            for d in digits.iter_mut() {
+                *d = !*d;
+                if carry {
+                    let (s, overflow) = d.overflowing_add(1);
+                    *d = s;
+                    carry = overflow;
+                }
+            }
+        }
+        BigInt::new(neg, digits)
+    }
+
+    fn bitwise<F: Fn(u32, u32) -> u32>(&self, other: &BigInt, f: F) -> BigInt {
+        let len = cmp::max(self.mag.len(), other.mag.len()) + 1;
+        let digits = self.twos(len).iter().zip(other.twos(len).iter())
+            .map( |(&a, &b)| f(a, b) )
+            .collect();
+        BigInt::from_twos(digits)
+    }
+
+    pub fn and(&self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a & b)
+    }
+
+    pub fn or(&self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a | b)
+    }
+
+    pub fn xor(&self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a ^ b)
+    }
+
+    /// The bitwise complement, -self - 1
+    pub fn not(&self) -> BigInt {
+        self.neg().sub(&BigInt::from_i64(1))
+    }
+
+    pub fn to_string_radix(&self, radix: u32) -> String {
+        if self.is_zero() {
+            return "0".to_owned();
+        }
+
+        // Divide by the largest power of radix which fits in a digit
+        let (mut chunk, mut width) = (radix, 1);
+        while (chunk as u64) * (radix as u64) <= u32::MAX as u64 {
+            chunk *= radix;
+            width += 1;
+        }
+
+        let mut parts = Vec::new();
+        let mut mag = self.mag.clone();
+        while !mag.is_empty() {
+            let (q, r) = mag_divrem_small(&mag, chunk);
+            parts.push(r);
+            mag = q;
+        }
+
+        let mut s = String::new();
+        if self.neg {
+            s.push('-');
+        }
+        for (i, part) in parts.iter().rev().enumerate() {
+            let mut digits = Vec::new();
+            let mut part = *part;
+            while part > 0 {
+                digits.push(::std::char::from_digit(part % radix, radix).unwrap());
+                part /= radix;
+            }
+            if i > 0 {
+                for _ in digits.len()..width {
+                    s.push('0');
+                }
+            }
+            s.extend(digits.iter().rev());
+        }
+        s
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.neg, other.neg) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => mag_cmp(&self.mag, &other.mag),
+            (true, true) => mag_cmp(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_radix(10))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(s: &str) -> BigInt {
+        BigInt::parse(s, 10).unwrap()
+    }
+
+    #[test]
+    fn arithmetic() {
+        let a = big("123456789012345678901234567890");
+        let b = big("-987654321098765432");
+
+        assert_eq!(a.add(&b).to_string(), "123456789011358024580135802458");
+        assert_eq!(a.sub(&b).to_string(), "123456789013333333222333333322");
+        assert_eq!(a.mul(&b).to_string(), "-121932631137021795212620027521140070120989178480");
+        let (q, r) = a.div_rem(&b).unwrap();
+        assert_eq!((q.to_string(), r.to_string()),
+                   ("-124999998860".to_owned(), "925925953827160370".to_owned()));
+        assert_eq!(q.mul(&b).add(&r), a);
+        assert!(a.div_rem(&big("0")).is_none());
+        assert_eq!(big("2").pow(100).to_string(), "1267650600228229401496703205376");
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(BigInt::from_i64(i64::MIN).to_i64(), Some(i64::MIN));
+        assert_eq!(big("9223372036854775808").to_i64(), None);
+        assert_eq!(big("-9223372036854775809").to_i64(), None);
+        assert_eq!(BigInt::from_f64(1e20).unwrap().to_string(), "100000000000000000000");
+        assert_eq!(big("-100000000000000000000").to_f64(), -1e20);
+        assert_eq!(big("-255").to_string_radix(16), "-ff");
+        assert_eq!(BigInt::parse("ff", 10), None);
+    }
+
+    #[test]
+    fn bits() {
+        let a = big("-36893488147419103232");
+        assert_eq!(a.shr(3).to_string(), "-4611686018427387904");
+        assert_eq!(big("-5").shr(1).to_string(), "-3");
+        assert_eq!(big("5").shl(64).to_string(), "92233720368547758080");
+        assert_eq!(a.and(&big("-1")), a);
+        assert_eq!(a.or(&big("1")).to_string(), "-36893488147419103231");
+        assert_eq!(big("-6").xor(&big("3")).to_string(), "-7");
+        assert_eq!(a.not().to_string(), "36893488147419103231");
+    }
+}
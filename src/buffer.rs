@@ -7,6 +7,7 @@ use std::sync::{Arc, RwLock};
 use std::str;
 
 use rselisp::LispForm;
+use rselisp::error::LispError;
 
 use editor::*;
 
@@ -123,7 +124,7 @@ impl Buffer {
         self.gap_len -= text.len();
     }
 
-    pub fn chars(&self) -> BufferIter {
+    pub fn chars(&self) -> BufferIter<'_> {
         self.gap_buf.chars()
             .take(self.gap_indx)
             .chain(self.gap_buf.chars().skip(self.gap_indx + self.gap_len))
@@ -240,9 +241,21 @@ impl LispForm for Buffer {
         "buffer"
     }
 
-    fn as_any(&mut self) -> &mut Any {
+    fn as_any(&mut self) -> &mut dyn Any {
         self
     }
+
+    fn contents(&self) -> Result<String, LispError> {
+        Ok(self.chars().collect())
+    }
+
+    /// The buffer does not have a point of its own, so printed text is
+    /// appended. Print to a cursor to insert at it instead.
+    fn insert_text(&mut self, text: &str) -> Result<(), LispError> {
+        let end = self.len();
+        self.insert(end, text);
+        Ok(())
+    }
 }
 
 impl fmt::Debug for Buffer {
@@ -1,5 +1,6 @@
 use super::*;
 use lambda::{EvalOption, Func};
+use error::LispError;
 use std::fmt;
 
 /// Define a function which can be called from Lisp
@@ -14,9 +15,22 @@ use std::fmt;
 /// fn lambda(args: Sexp, docstr: &str, interactive: ???, body: Iter<LispObj>) -> UserFunc {
 ///   ...
 /// }
+///
+/// The minimum and maximum number of arguments follow the evaluation option,
+/// with `many` when there is no maximum, as in Emacs' `DEFUN`. Calls outside
+/// this range signal `wrong-number-of-arguments` before the body is run.
 #[macro_export]
 macro_rules! def_builtin {
-    ($name:expr, $rname:ident, $evaled:ident, $lsp:ident, $args:ident; $fn_body:block ) => (
+    ($name:expr, $rname:ident, $evaled:ident, $min:tt, many, $lsp:ident, $args:ident;
+     $fn_body:block ) => (
+        def_builtin! { @def $name, $rname, $evaled, $min, None, $lsp, $args; $fn_body }
+    );
+    ($name:expr, $rname:ident, $evaled:ident, $min:tt, $max:tt, $lsp:ident, $args:ident;
+     $fn_body:block ) => (
+        def_builtin! { @def $name, $rname, $evaled, $min, Some($max), $lsp, $args; $fn_body }
+    );
+    (@def $name:expr, $rname:ident, $evaled:ident, $min:expr, $max:expr, $lsp:ident,
+     $args:ident; $fn_body:block ) => (
         #[derive(Clone)]
         pub struct $rname {
             name: Atom,
@@ -46,7 +60,16 @@ macro_rules! def_builtin {
                 self.name
             }
 
-            fn call(&self, $lsp: &mut Lsp, $args: &mut Iter<LispObj>) -> Result<LispObj, String> {
+            fn arity(&self) -> (usize, Option<usize>) {
+                ($min, $max)
+            }
+
+            #[allow(unused_comparisons)]
+            fn call(&self, $lsp: &mut Lsp, $args: &mut Iter<LispObj>) -> Result<LispObj, LispError> {
+                let count = $args.len();
+                if count < $min || $max.map_or(false, |max: usize| count > max) {
+                    return Err(LispError::wrong_number_of_args(LispObj::Atm(self.name), count));
+                }
                 $fn_body
             }
         }
@@ -70,180 +93,678 @@ macro_rules! reg_funcs {
     )+ }
 }
 
-def_builtin! { "-", MinusBuiltin, Evaluated, _lsp, args; {
-    let mut res = 0;
+def_builtin! { "quote", QuoteBuiltin, Unevaluated, 1, 1, lsp, args; {
+    one_arg(lsp, "quote", args).cloned()
+}}
 
-    if let Some(arg) = args.next() {
-        match arg {
-            &LispObj::Int(i) => res = i,
-            _ => return Err(format!("Expected int, but found {:?}", arg)),
+/// If obj is `(MARKER FORM)` then return FORM
+fn unquoted(obj: &LispObj, marker: Atom) -> Option<LispObj> {
+    if let LispObj::Cons(cons) = obj {
+        if cons.car() == LispObj::Atm(marker) {
+            if let LispObj::Cons(arg) = cons.cdr() {
+                if arg.cdr().is_nil() {
+                    return Some(arg.car());
+                }
+            }
         }
     }
+    None
+}
+
+/// Quote a constant part of a backquote template, unless it evaluates to itself
+fn bq_const(tmpl: &LispObj) -> LispObj {
+    match tmpl {
+        &LispObj::Atm(symbols::NIL) | &LispObj::Atm(symbols::T) => tmpl.clone(),
+        &LispObj::Atm(_) | &LispObj::Sym(_) | &LispObj::Cons(_) | &LispObj::Vector(_) =>
+            LispObj::list_from(&[LispObj::Atm(symbols::QUOTE), tmpl.clone()]),
+        obj => obj.clone(),
+    }
+}
 
-    if let Some(arg) = args.next() {
-        match arg {
-            &LispObj::Int(i) => res -= i,
-            _ => return Err(format!("Expected int, but found {:?}", arg)),
-        }
-    } else {
-        res = -res;
+/// Whether anything in a backquote template is evaluated
+fn bq_has_unquote(tmpl: &LispObj, depth: usize) -> bool {
+    let comma = unquoted(tmpl, symbols::COMMA).or_else( || unquoted(tmpl, symbols::COMMA_AT) );
+    if let Some(form) = comma {
+        return depth == 0 || bq_has_unquote(&form, depth - 1);
+    }
+    if let Some(form) = unquoted(tmpl, symbols::BACKQUOTE) {
+        return bq_has_unquote(&form, depth + 1);
     }
 
-    while let Some(arg) = args.next() {
-        match arg {
-            &LispObj::Int(i) => res -= i,
-            _ => return Err(format!("Expected int, but found {:?}", arg)),
+    match tmpl {
+        LispObj::Cons(cons) => bq_has_unquote(&cons.car(), depth) ||
+            bq_has_unquote(&cons.cdr(), depth),
+        LispObj::Vector(vec) => vec.borrow().iter().any( |item| bq_has_unquote(item, depth) ),
+        _ => false,
+    }
+}
+
+fn bq_call(fun: &str, lsp: &mut Lsp, args: &[LispObj]) -> LispObj {
+    LispObj::list_with_tail(&[LispObj::Atm(lsp.atomize(fun))], LispObj::list_from(args))
+}
+
+/// Expand a backquote template into a form which builds it
+///
+/// Unquoted forms are spliced into the expansion when depth is zero; each
+/// nested backquote adds a level which must be removed by a comma before
+/// anything is evaluated. Parts of the template without any commas are quoted.
+fn bq_expand(lsp: &mut Lsp, tmpl: &LispObj, depth: usize) -> Result<LispObj, LispError> {
+    if !bq_has_unquote(tmpl, depth) {
+        return Ok(bq_const(tmpl));
+    }
+
+    if let Some(form) = unquoted(tmpl, symbols::COMMA) {
+        return if depth == 0 {
+            Ok(form)
+        } else {
+            bq_marked(lsp, symbols::COMMA, &form, depth - 1)
         };
     }
 
-    Ok(LispObj::Int(res))
-}}
+    if let Some(form) = unquoted(tmpl, symbols::COMMA_AT) {
+        return if depth == 0 {
+            Err(LispError::error(",@ after `"))
+        } else {
+            bq_marked(lsp, symbols::COMMA_AT, &form, depth - 1)
+        };
+    }
 
-def_builtin! { "+", PlusBuiltin, Evaluated, _lsp, args; {
-    let mut res = 0;
+    if let Some(form) = unquoted(tmpl, symbols::BACKQUOTE) {
+        return bq_marked(lsp, symbols::BACKQUOTE, &form, depth + 1);
+    }
 
-    while let Some(arg) = args.next() {
-        match arg {
-            &LispObj::Int(i) => res += i,
-            _ => return Err(format!("Expected int, but found {:?}", arg)),
+    match tmpl {
+        &LispObj::Cons(_) => {
+            let (items, tail) = bq_items(lsp, tmpl, depth)?;
+
+            // Runs of unspliced items are collected into calls to list, then
+            // everything is appended to the tail
+            let mut parts = Vec::new();
+            let mut run = Vec::new();
+            for (spliced, item) in items {
+                if spliced {
+                    if !run.is_empty() {
+                        parts.push(bq_call("list", lsp, &run));
+                        run.clear();
+                    }
+                    parts.push(item);
+                } else {
+                    run.push(item);
+                }
+            }
+
+            if parts.is_empty() && tail.is_nil() {
+                return Ok(bq_call("list", lsp, &run));
+            }
+            if !run.is_empty() {
+                parts.push(bq_call("list", lsp, &run));
+            }
+            if !tail.is_nil() {
+                parts.push(tail);
+            }
+            Ok(if parts.len() == 1 { parts.pop().unwrap() } else { bq_call("append", lsp, &parts) })
+        },
+        LispObj::Vector(vec) => {
+            let list = LispObj::list_from(&vec.borrow());
+            let form = bq_expand(lsp, &list, depth)?;
+            Ok(bq_call("vconcat", lsp, &[form]))
+        },
+        obj => Ok(bq_const(obj)),
+    }
+}
+
+/// Expand `(MARKER FORM)`, where the marker is kept as part of the template
+fn bq_marked(lsp: &mut Lsp, marker: Atom, form: &LispObj, depth: usize)
+             -> Result<LispObj, LispError> {
+    let form = bq_expand(lsp, form, depth)?;
+    Ok(bq_call("list", lsp, &[bq_const(&LispObj::Atm(marker)), form]))
+}
+
+/// Expand the items of a list template, marking those which are spliced, and
+/// the tail
+fn bq_items(lsp: &mut Lsp, tmpl: &LispObj, depth: usize)
+            -> Result<(Vec<(bool, LispObj)>, LispObj), LispError> {
+    let mut items = Vec::new();
+    let mut next = tmpl.clone();
+
+    loop {
+        next = match next {
+            LispObj::Cons(ref cons) => {
+                // A dotted tail such as `(a . ,b)` is read as (a \, b)
+                if unquoted(&next, symbols::COMMA).is_some() ||
+                    unquoted(&next, symbols::COMMA_AT).is_some() ||
+                    unquoted(&next, symbols::BACKQUOTE).is_some()
+                {
+                    let tail = bq_expand(lsp, &next, depth)?;
+                    return Ok((items, tail));
+                }
+
+                let item = cons.car();
+                match unquoted(&item, symbols::COMMA_AT) {
+                    Some(form) if depth == 0 => items.push((true, form)),
+                    _ => items.push((false, bq_expand(lsp, &item, depth)?)),
+                }
+                cons.cdr()
+            },
+            tail => return Ok((items, bq_const(&tail))),
         };
     }
+}
 
-    Ok(LispObj::Int(res))
+// A macro, see Lsp::new
+def_builtin! { "`", BackquoteBuiltin, Evaluated, 1, 1, lsp, args; {
+    let tmpl = one_arg(lsp, "`", args)?;
+    bq_expand(lsp, tmpl, 0)
 }}
 
-def_builtin! { "quote", QuoteBuiltin, Unevaluated, _lsp, args; {
-    let argt = take2!(args);
-    match argt {
+def_builtin! { "function", FunctionBuiltin, Unevaluated, 1, 1, lsp, args; {
+    match take2!(args) {
+        (Some(LispObj::Cons(fun)), None) if fun.car() == LispObj::Atm(symbols::LAMBDA) =>
+            Ok(lsp.closure(fun.cdr())),
         (Some(arg), None) => Ok(arg.clone()),
-        _ => Err(format!("Wrong number of arguments; quote only accepts one")),
+        _ => Err(LispError::error("Wrong number of arguments; function only accepts one")),
     }
 }}
 
-def_builtin! { "interactive", InteractiveBuiltin, Unevaluated, _lsp, _args; {
+def_builtin! { "lambda", LambdaBuiltin, Unevaluated, 0, many, lsp, args; {
+    Ok(lsp.closure(LispObj::list_from(args.as_slice())))
+}}
+
+def_builtin! { "interactive", InteractiveBuiltin, Unevaluated, 0, many, _lsp, _args; {
     Ok(LispObj::nil())
 }}
 
-def_builtin! { "print", PrintBuiltin, Evaluated, lsp, args; {
-    let mut s = String::new();
-    lsp.print_itr(&mut s, args.peekable());
-    println!("{}", &s);
-    Ok(LispObj::Str(s))
+def_builtin! { "backtrace", BacktraceBuiltin, Evaluated, 0, 0, lsp, _args; {
+    let text = lsp.format_backtrace(&lsp.backtrace());
+    lsp.print_to(None, &text)?;
+    Ok(LispObj::nil())
 }}
 
-def_builtin! { "exit", ExitBuiltin, Unevaluated, _lsp, _args; {
-    Ok(LispObj::Atm(symbols::EXIT))
+def_builtin! { "debug", DebugBuiltin, Evaluated, 0, many, lsp, _args; {
+    match lsp.debug(debug::DebugReason::Explicit) {
+        debug::DebugAction::Quit => Err(LispError::quit()),
+        debug::DebugAction::Continue => Ok(LispObj::nil()),
+    }
 }}
 
-def_builtin! { "progn", PrognBuiltin, Evaluated, _lsp, args; {
-    if let Some(val) = args.last() {
-        Ok(val.clone())
-    } else {
-        Err(format!("progn requires one or more arguments"))
+def_builtin! { "debug-on-entry", DebugOnEntryBuiltin, Evaluated, 1, 1, lsp, args; {
+    match args.next() {
+        Some(fun) => {
+            lsp.debug_on_entry(symbol_name(fun)?);
+            Ok(fun.clone())
+        },
+        None => Err(LispError::wrong_number_of_args(LispObj::Atm(lsp.atomize("debug-on-entry")), 0)),
     }
 }}
 
-def_builtin! { "if", IfBuiltin, Unevaluated, lsp, args; {
+def_builtin! { "cancel-debug-on-entry", CancelDebugOnEntryBuiltin, Evaluated, 0, 1, lsp, args; {
+    match args.next() {
+        Some(fun) if !fun.is_nil() => {
+            lsp.cancel_debug_on_entry(Some(symbol_name(fun)?));
+            Ok(fun.clone())
+        },
+        _ => {
+            lsp.cancel_debug_on_entry(None);
+            Ok(LispObj::nil())
+        },
+    }
+}}
+
+def_builtin! { "exit", ExitBuiltin, Unevaluated, 0, 0, _lsp, _args; {
+    Ok(LispObj::Atm(symbols::EXIT))
+}}
+
+def_builtin! { "progn", PrognBuiltin, Unevaluated, 0, many, lsp, args; {
+    lsp.eval_progn(args)
+}}
+
+def_builtin! { "if", IfBuiltin, Unevaluated, 2, many, lsp, args; {
     if let Some(cond) = args.next() {
         let then = match args.next() {
             Some(then) => then,
-            None => return Err(format!("if requires a THEN argument")),
+            None => return Err(LispError::error("if requires a THEN argument")),
         };
 
-        if !lsp.eval_inner(cond)?.is_nil() {
-                lsp.eval_inner(then)
+        if !lsp.eval(cond)?.is_nil() {
+                lsp.eval(then)
         } else {
             let mut ret = if let Some(lelse) = args.next() {
-                lsp.eval_inner(lelse)?
+                lsp.eval(lelse)?
             } else {
                 LispObj::nil()
             };
 
-            while let Some(lelse) = args.next() {
-                ret = lsp.eval_inner(lelse)?;
+            for lelse in args.by_ref() {
+                ret = lsp.eval(lelse)?;
             }
 
             Ok(ret)
         }
     } else {
-        Err(format!("if requires a COND argument"))
+        Err(LispError::error("if requires a COND argument"))
     }
 }}
 
-def_builtin! { "eq", EqBuiltin, Evaluated, _lsp, args; {
-    if let (Some(left), Some(right)) = take2!(args) {
-        if left == right {
-            Ok(LispObj::t())
-        } else {
-            Ok(LispObj::nil())
+/// Parse a binding from a let varlist; `VAR`, `(VAR)` or `(VAR VALUE-FORM)`
+///
+/// VAR is an atom or an uninterned symbol.
+fn let_binding(binding: &LispObj) -> Result<(LispObj, Option<LispObj>), LispError> {
+    match binding {
+        &LispObj::Atm(_) | &LispObj::Sym(_) => Ok((binding.clone(), None)),
+        LispObj::Cons(cons) => {
+            let name = match cons.car() {
+                name @ LispObj::Atm(_) | name @ LispObj::Sym(_) => name,
+                ref obj => return Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+            };
+            let mut rest = cons.cdr().list_to_vec()?;
+
+            if rest.len() > 1 {
+                Err(LispError::error("`let' bindings can have only one value-form"))
+            } else {
+                Ok((name, rest.pop()))
+            }
+        },
+        obj => Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+    }
+}
+
+fn eval_let_binding(lsp: &mut Lsp, binding: &LispObj) -> Result<(LispObj, LispObj), LispError> {
+    let (name, form) = let_binding(binding)?;
+    if let LispObj::Atm(name) = name {
+        if lsp.is_constant(name) {
+            return Err(LispError::setting_constant(name));
         }
+    }
+    let val = match form {
+        Some(form) => lsp.eval(&form)?,
+        None => LispObj::nil(),
+    };
+    Ok((name, val))
+}
+
+/// The values of uninterned symbols which let has bound dynamically
+///
+/// These symbols are in no namespace, so the new value is set in the symbol
+/// and the old one is put back by restore, whether or not the body succeeds.
+struct SavedValues(Vec<(Symbol, Option<LispObj>)>);
+
+impl SavedValues {
+    fn bind(&mut self, sym: &Symbol, val: LispObj) {
+        self.0.push((sym.clone(), sym.get_val()));
+        sym.set_val(val);
+    }
+
+    fn restore(self) {
+        for (sym, val) in self.0.into_iter().rev() {
+            match val {
+                Some(val) => sym.set_val(val),
+                None => sym.unset_val(),
+            }
+        }
+    }
+}
+
+/// Bind a variable in the environment env, or dynamically
+fn let_bind(lsp: &Lsp, name: LispObj, val: LispObj, env: &mut LispObj, ns: &mut Namespace,
+            saved: &mut SavedValues) {
+    let lexical = match name {
+        LispObj::Atm(name) => lsp.binds_lexically(env, name),
+        LispObj::Sym(ref sym) => !env.is_nil() && !sym.is_special(),
+        _ => unreachable!("let_binding only returns symbols"),
+    };
+
+    if lexical {
+        let outer = mem::replace(env, LispObj::nil());
+        *env = LispObj::cons(LispObj::cons(name, val), outer);
     } else {
-        Err(format!("eq requires two arguments"))
+        match name {
+            LispObj::Atm(name) => ns.intern(Symbol::with_val(name, val)),
+            LispObj::Sym(ref sym) => saved.bind(sym, val),
+            _ => unreachable!("let_binding only returns symbols"),
+        }
+    }
+}
+
+/// Bind each variable in turn, in the innermost scope
+fn let_star_bind(lsp: &mut Lsp, varlist: &LispObj, saved: &mut SavedValues)
+                 -> Result<(), LispError> {
+    for binding in varlist.list_to_vec()? {
+        let (name, val) = eval_let_binding(lsp, &binding)?;
+
+        let mut env = mem::replace(&mut lsp.lexenv, LispObj::nil());
+        let mut ns = lsp.locals.pop().unwrap_or_default();
+        let_bind(lsp, name, val, &mut env, &mut ns, saved);
+        lsp.locals.push(ns);
+        lsp.lexenv = env;
+    }
+    Ok(())
+}
+
+def_builtin! { "let", LetBuiltin, Unevaluated, 1, many, lsp, args; {
+    let varlist = match args.next() {
+        Some(varlist) => varlist,
+        None => return Err(LispError::wrong_number_of_args(LispObj::Atm(lsp.atomize("let")), 0)),
+    };
+
+    // All of the values are evaluated before any of the variables are bound
+    let mut vals = Vec::new();
+    for binding in varlist.list_to_vec()? {
+        vals.push(eval_let_binding(lsp, &binding)?);
+    }
+
+    let mut ns = Namespace::new();
+    let mut env = lsp.lexenv.clone();
+    let mut saved = SavedValues(Vec::new());
+    for (name, val) in vals {
+        let_bind(lsp, name, val, &mut env, &mut ns, &mut saved);
+    }
+
+    let ret = lsp.with_scope(ns, env, |lsp| lsp.eval_progn(args));
+    saved.restore();
+    ret
+}}
+
+def_builtin! { "let*", LetStarBuiltin, Unevaluated, 1, many, lsp, args; {
+    let varlist = match args.next() {
+        Some(varlist) => varlist,
+        None => return Err(LispError::wrong_number_of_args(LispObj::Atm(lsp.atomize("let*")), 0)),
+    };
+
+    let env = lsp.lexenv.clone();
+    let mut saved = SavedValues(Vec::new());
+    let ret = lsp.with_scope(Namespace::new(), env, |lsp| {
+        let_star_bind(lsp, varlist, &mut saved).and_then( |_| lsp.eval_progn(args) )
+    });
+    saved.restore();
+    ret
+}}
+
+def_builtin! { "setq", SetqBuiltin, Unevaluated, 0, many, lsp, args; {
+    let pairs = args.as_slice();
+    let mut ret = LispObj::nil();
+
+    if !pairs.len().is_multiple_of(2) {
+        return Err(LispError::wrong_number_of_args(LispObj::Atm(lsp.atomize("setq")),
+                                                   pairs.len()));
+    }
+
+    for pair in pairs.chunks(2) {
+        ret = lsp.eval(&pair[1])?;
+
+        match pair[0] {
+            LispObj::Atm(name) => match lsp.lexical_binding(name) {
+                Some(binding) => binding.set_cdr(ret.clone()),
+                None => lsp.set_var(name, ret.clone())?,
+            },
+            LispObj::Sym(ref sym) => match lsp.lexical_symbol_binding(sym) {
+                Some(binding) => binding.set_cdr(ret.clone()),
+                None => sym.set_val(ret.clone()),
+            },
+            ref obj => return Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+        }
+    }
+
+    Ok(ret)
+}}
+
+def_builtin! { "set", SetBuiltin, Evaluated, 2, 2, lsp, args; {
+    match take2!(args) {
+        (Some(&LispObj::Atm(name)), Some(val)) => {
+            lsp.set_var(name, val.clone())?;
+            Ok(val.clone())
+        },
+        (Some(LispObj::Sym(sym)), Some(val)) => {
+            sym.set_val(val.clone());
+            Ok(val.clone())
+        },
+        (Some(obj), Some(_)) => Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+        _ => Err(LispError::error("set requires two arguments")),
     }
 }}
 
-def_builtin! { "cons", ConsBuiltin, Evaluated, _lsp, args; {
+def_builtin! { "cond", CondBuiltin, Unevaluated, 0, many, lsp, args; {
+    for clause in args {
+        let clause = clause.cons_val()?;
+        let val = lsp.eval(&clause.car())?;
+
+        if !val.is_nil() {
+            let body = clause.cdr();
+
+            return if body.is_nil() {
+                Ok(val)
+            } else {
+                lsp.eval_progn(body.iter())
+            };
+        }
+    }
+
+    Ok(LispObj::nil())
+}}
+
+def_builtin! { "while", WhileBuiltin, Unevaluated, 1, many, lsp, args; {
+    let test = match args.next() {
+        Some(test) => test,
+        None => return Err(LispError::wrong_number_of_args(LispObj::Atm(lsp.atomize("while")), 0)),
+    };
+    let body = args.as_slice();
+
+    while !lsp.eval(test)?.is_nil() {
+        lsp.check_limits()?;
+        lsp.eval_progn(body)?;
+    }
+
+    Ok(LispObj::nil())
+}}
+
+def_builtin! { "and", AndBuiltin, Unevaluated, 0, many, lsp, args; {
+    let mut ret = LispObj::t();
+
+    for arg in args {
+        ret = lsp.eval(arg)?;
+        if ret.is_nil() {
+            break;
+        }
+    }
+
+    Ok(ret)
+}}
+
+def_builtin! { "or", OrBuiltin, Unevaluated, 0, many, lsp, args; {
+    for arg in args {
+        let val = lsp.eval(arg)?;
+        if !val.is_nil() {
+            return Ok(val);
+        }
+    }
+
+    Ok(LispObj::nil())
+}}
+
+def_builtin! { "not", NotBuiltin, Evaluated, 1, 1, _lsp, args; {
+    match take2!(args) {
+        (Some(obj), None) => Ok(if obj.is_nil() { LispObj::t() } else { LispObj::nil() }),
+        _ => Err(LispError::error("not requires one argument")),
+    }
+}}
+
+def_builtin! { "when", WhenBuiltin, Unevaluated, 1, many, lsp, args; {
+    match args.next() {
+        Some(cond) if !lsp.eval(cond)?.is_nil() => lsp.eval_progn(args),
+        Some(_) => Ok(LispObj::nil()),
+        None => Err(LispError::error("when requires a COND argument")),
+    }
+}}
+
+def_builtin! { "unless", UnlessBuiltin, Unevaluated, 1, many, lsp, args; {
+    match args.next() {
+        Some(cond) if lsp.eval(cond)?.is_nil() => lsp.eval_progn(args),
+        Some(_) => Ok(LispObj::nil()),
+        None => Err(LispError::error("unless requires a COND argument")),
+    }
+}}
+
+def_builtin! { "prog1", Prog1Builtin, Unevaluated, 1, many, lsp, args; {
+    let ret = match args.next() {
+        Some(first) => lsp.eval(first)?,
+        None => return Err(LispError::wrong_number_of_args(LispObj::Atm(lsp.atomize("prog1")), 0)),
+    };
+
+    lsp.eval_progn(args)?;
+    Ok(ret)
+}}
+
+def_builtin! { "prog2", Prog2Builtin, Unevaluated, 2, many, lsp, args; {
+    let (first, second) = match take2!(args) {
+        (Some(first), Some(second)) => (first, second),
+        (first, _) => {
+            let count = if first.is_some() { 1 } else { 0 };
+            return Err(LispError::wrong_number_of_args(LispObj::Atm(lsp.atomize("prog2")),
+                                                       count));
+        },
+    };
+
+    lsp.eval(first)?;
+    let ret = lsp.eval(second)?;
+    lsp.eval_progn(args)?;
+    Ok(ret)
+}}
+
+def_builtin! { "eq", EqBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (a, b) = two_args(lsp, "eq", args)?;
+    Ok(LispObj::from_bool(eq(a, b)))
+}}
+
+def_builtin! { "eql", EqlBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (a, b) = two_args(lsp, "eql", args)?;
+    Ok(LispObj::from_bool(eql(a, b)))
+}}
+
+def_builtin! { "equal", EqualBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (a, b) = two_args(lsp, "equal", args)?;
+    Ok(LispObj::from_bool(equal(lsp, a, b)?))
+}}
+
+// Strings have no text properties, so this is the same as equal
+def_builtin! { "equal-including-properties", EqualIncludingPropertiesBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (a, b) = two_args(lsp, "equal-including-properties", args)?;
+    Ok(LispObj::from_bool(equal(lsp, a, b)?))
+}}
+
+def_builtin! { "cons", ConsBuiltin, Evaluated, 2, 2, _lsp, args; {
     if let (Some(car), Some(cdr)) = take2!(args) {
-        let mut sxp = Sexp::new('(');
-        sxp.push(car.clone());
-        match cdr {
-            &LispObj::Sxp(ref s) => sxp.extend(s),
-            &LispObj::Atm(symbols::NIL) => (),
-            obj => sxp.push(obj.clone()),
-        };
-        Ok(LispObj::Sxp(sxp))
+        Ok(LispObj::cons(car.clone(), cdr.clone()))
     } else {
-        Err(format!("cons requires two arguments"))
+        Err(LispError::error("cons requires two arguments"))
     }
 }}
 
-def_builtin! { "car", CarBuiltin, Evaluated, _lsp, args; {
+def_builtin! { "car", CarBuiltin, Evaluated, 1, 1, _lsp, args; {
     if let Some(lst) = args.next() {
         match lst {
-            &LispObj::Sxp(ref sxp) => Ok(sxp.car()),
-            &LispObj::Ref(ref iref) => match &iref.borrow() as &LispObj {
-                &LispObj::Sxp(ref sxp) => Ok(sxp.car()),
-                &LispObj::Ref(_) => Err(format!("car: argument is a reference to a reference")),
-                _ => Err(format!("car: argument is not a list")),
+            LispObj::Ref(iref) => match &iref.borrow() as &LispObj {
+                &LispObj::Ref(_) => Err(LispError::error("car: argument is a reference to a reference")),
+                obj => obj.car(),
             },
-            _ => Err(format!("car: argument is not a list")),
+            obj => obj.car(),
         }
     } else {
-        Err(format!("car requires one argument"))
+        Err(LispError::error("car requires one argument"))
     }
 }}
 
-def_builtin! { "cdr", CdrBuiltin, Evaluated, _lsp, args; {
+def_builtin! { "cdr", CdrBuiltin, Evaluated, 1, 1, _lsp, args; {
     if let Some(lst) = args.next() {
         match lst {
-            &LispObj::Sxp(ref sxp) => Ok(sxp.cdr()),
-            &LispObj::Ref(ref iref) => match &iref.borrow() as &LispObj {
-                &LispObj::Sxp(ref sxp) => Ok(sxp.cdr()),
-                &LispObj::Ref(_) => Err(format!("cdr: argument is a reference to a reference")),
-                _ => Err(format!("cdr: argument is not a list")),
+            LispObj::Ref(iref) => match &iref.borrow() as &LispObj {
+                &LispObj::Ref(_) => Err(LispError::error("cdr: argument is a reference to a reference")),
+                obj => obj.cdr(),
             },
-            _ => Err(format!("cdr: argument is not a list")),
+            obj => obj.cdr(),
+        }
+    } else {
+        Err(LispError::error("cdr requires one argument"))
+    }
+}}
+
+def_builtin! { "setcar", SetcarBuiltin, Evaluated, 2, 2, _lsp, args; {
+    if let (Some(cell), Some(val)) = take2!(args) {
+        cell.cons_val()?.set_car(val.clone());
+        Ok(val.clone())
+    } else {
+        Err(LispError::error("setcar requires two arguments"))
+    }
+}}
+
+def_builtin! { "setcdr", SetcdrBuiltin, Evaluated, 2, 2, _lsp, args; {
+    if let (Some(cell), Some(val)) = take2!(args) {
+        cell.cons_val()?.set_cdr(val.clone());
+        Ok(val.clone())
+    } else {
+        Err(LispError::error("setcdr requires two arguments"))
+    }
+}}
+
+def_builtin! { "list", ListBuiltin, Evaluated, 0, many, _lsp, args; {
+    Ok(LispObj::list_from(args.as_slice()))
+}}
+
+def_builtin! { "nconc", NconcBuiltin, Evaluated, 0, many, lsp, args; {
+    let mut lists = args.filter( |lst| !lst.is_nil() ).peekable();
+    let head = match lists.next() {
+        Some(head) => head.clone(),
+        None => return Ok(LispObj::nil()),
+    };
+    let mut last = head.clone();
+
+    for next in lists {
+        let mut cell = last.cons_val()?.clone();
+        let mut cycle = CycleCheck::new(&last);
+        while let LispObj::Cons(cdr) = cell.cdr() {
+            lsp.check_limits()?;
+            cell = cdr;
+            if cycle.step(&LispObj::Cons(cell.clone())) {
+                return Err(LispError::circular_list(&last));
+            }
+        }
+        cell.set_cdr(next.clone());
+        last = next.clone();
+    }
+
+    Ok(head)
+}}
+
+def_builtin! { "consp", ConspBuiltin, Evaluated, 1, 1, _lsp, args; {
+    if let Some(obj) = args.next() {
+        if obj.is_cons() {
+            Ok(LispObj::t())
+        } else {
+            Ok(LispObj::nil())
         }
     } else {
-        Err(format!("cdr requires one argument"))
+        Err(LispError::error("consp requires one argument"))
     }
 }}
 
-def_builtin! { "listp", ListpBuiltin, Evaluated, _lsp, args; {
+def_builtin! { "garbage-collect", GarbageCollectBuiltin, Evaluated, 0, 0, lsp, _args; {
+    Ok(lsp.garbage_collect())
+}}
+
+def_builtin! { "listp", ListpBuiltin, Evaluated, 1, 1, _lsp, args; {
     if let Some(lst) = args.next() {
-        match lst {
-            &LispObj::Sxp(_) => Ok(LispObj::t()),
-            _ => Ok(LispObj::nil()),
+        if lst.is_list() {
+            Ok(LispObj::t())
+        } else {
+            Ok(LispObj::nil())
         }
     } else {
-        Err(format!("listp requires one argument"))
+        Err(LispError::error("listp requires one argument"))
     }
 }}
 
-def_builtin! { "load", LoadBuiltin, Unevaluated, lsp, args; {
+def_builtin! { "load", LoadBuiltin, Unevaluated, 1, 5, lsp, args; {
     if let Some(name) = args.next() {
         let biref;
-        let name = if let &LispObj::Ref(ref iref) = name {
+        let name = if let LispObj::Ref(iref) = name {
             biref = iref.borrow();
             &biref
         } else {
@@ -255,35 +776,821 @@ def_builtin! { "load", LoadBuiltin, Unevaluated, lsp, args; {
                 let name = lsp.stringify(name).to_owned();
                 lsp.load(&name)
             },
-            &LispObj::Str(ref name) => {
+            LispObj::Str(name) => {
                 lsp.load(name)
             },
-            thing => Err(lsp.error_print("load expects a symbol or string not", thing))
+            thing => Err(LispError::wrong_type(symbols::STRINGP, thing))
         }
     } else {
-        Err(format!("load requires one argument"))
+        Err(LispError::error("load requires one argument"))
+    }
+}}
+
+def_builtin! { "fset", FsetBuiltin, Evaluated, 2, 2, lsp, args; {
+    match take2!(args) {
+        (Some(sym), Some(def)) => {
+            symbol_cell(lsp, sym)?.set_fun(def.clone());
+            Ok(def.clone())
+        },
+        _ => Err(LispError::error("fset requires two arguments")),
+    }
+}}
+
+def_builtin! { "funcall", FuncallBuiltin, Evaluated, 1, many, lsp, args; {
+    match args.next() {
+        Some(fun) => lsp.funcall(fun, args.as_slice()),
+        None => Err(LispError::wrong_number_of_args(LispObj::Atm(lsp.atomize("funcall")), 0)),
+    }
+}}
+
+def_builtin! { "apply", ApplyBuiltin, Evaluated, 1, many, lsp, args; {
+    let args = args.as_slice();
+    let (fun, spread) = match args.len() {
+        0 => return Err(wrong_args(lsp, "apply", 0)),
+        // (apply '(fun . args))
+        1 => (args[0].car()?, args[0].cdr()?),
+        n => (args[0].clone(), args[n - 1].clone()),
+    };
+
+    let mut all = if args.len() > 1 { args[1..args.len() - 1].to_vec() } else { Vec::new() };
+    all.extend(list_items(lsp, &spread)?);
+    lsp.funcall(&fun, &all)
+}}
+
+def_builtin! { "eval", EvalBuiltin, Evaluated, 1, 2, lsp, args; {
+    let (form, lexical) = take2!(args);
+    // t is an empty lexical environment, otherwise it is an alist of bindings
+    let env = match lexical {
+        None | Some(&LispObj::Atm(symbols::NIL)) => LispObj::nil(),
+        Some(LispObj::Cons(env)) => LispObj::Cons(env.clone()),
+        Some(_) => LispObj::list_from(&[LispObj::t()]),
+    };
+    let form = form.unwrap();
+
+    lsp.with_scope(Namespace::new(), env, |lsp| lsp.eval(form))
+}}
+
+/// Read an object from text which is fetched a piece at a time
+///
+/// Each time the text so far ends part way through an object, more is
+/// fetched. An object is only taken to be complete when something follows
+/// it, because a symbol or number may continue in the next piece. Returns
+/// the object and what is left of the text.
+fn read_incrementally<F>(lsp: &mut Lsp, mut fetch: F) -> Result<(LispObj, String), LispError>
+    where F: FnMut(&mut Lsp) -> Result<Option<String>, LispError>
+{
+    let mut text = String::new();
+    let mut more = true;
+
+    loop {
+        if more {
+            match fetch(lsp)? {
+                Some(piece) => text.push_str(&piece),
+                None => more = false,
+            }
+        }
+
+        match lsp.read_from_str(&text, 0) {
+            Ok((obj, end)) if !more || end < text.chars().count() => {
+                let rest = text.chars().skip(end).collect();
+                return Ok((obj, rest));
+            },
+            Ok(_) => (),
+            Err(ref e) if e.symbol == symbols::END_OF_FILE && more => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Reads from a string, a function returning characters or stdin (t)
+def_builtin! { "read", ReadBuiltin, Evaluated, 0, 1, lsp, args; {
+    let stream = match args.next() {
+        None | Some(&LispObj::Atm(symbols::NIL)) => lsp.eval_atm_val(symbols::STANDARD_INPUT)?,
+        Some(stream) => stream.clone(),
+    };
+
+    match stream {
+        LispObj::Str(ref s) => Ok(lsp.read_from_str(s, 0)?.0),
+        LispObj::Atm(symbols::T) => {
+            let stdin = ::std::io::stdin();
+            let (obj, _) = read_incrementally(lsp, |_| {
+                let mut line = String::new();
+                match ::std::io::BufRead::read_line(&mut stdin.lock(), &mut line) {
+                    Ok(0) => Ok(None),
+                    Ok(_) => Ok(Some(line)),
+                    Err(e) => Err(LispError::file_error("Reading standard input", e)),
+                }
+            })?;
+            Ok(obj)
+        },
+        LispObj::Ext(_) => Err(LispError::error("Reading from this kind of stream is not supported")),
+        fun => {
+            // The function returns the next character, or nil at the end.
+            // Called with a character it should unread it.
+            let (obj, rest) = read_incrementally(lsp, |lsp| match lsp.funcall(&fun, &[])? {
+                LispObj::Atm(symbols::NIL) => Ok(None),
+                c => Ok(Some(char_arg(&c)?.to_string())),
+            })?;
+            for c in rest.chars().rev() {
+                lsp.funcall(&fun, &[LispObj::Int(c as i64)])?;
+            }
+            Ok(obj)
+        },
     }
 }}
 
-def_builtin! { "fset", FsetBuiltin, Evaluated, lsp, args; {
-    let (sym, def) = take2!(args);
+def_builtin! { "read-from-string", ReadFromStringBuiltin, Evaluated, 1, 3, lsp, args; {
+    let (text, start, end) = take3!(args);
+    let text = text.unwrap();
+    let chars: Vec<char> = text.str_val()?.chars().collect();
+
+    let index = |arg: Option<&LispObj>, default: usize| match arg {
+        None | Some(&LispObj::Atm(symbols::NIL)) => Ok(default as i64),
+        Some(&LispObj::Int(i)) if i < 0 => Ok(i + chars.len() as i64),
+        Some(&LispObj::Int(i)) => Ok(i),
+        Some(obj) => Err(LispError::wrong_type(symbols::INTEGERP, obj)),
+    };
+    let (from, to) = (index(start, 0)?, index(end, chars.len())?);
+    if from < 0 || from > to || to > chars.len() as i64 {
+        let nil = LispObj::nil();
+        return Err(LispError::args_out_of_range(&[text.clone(),
+                                                  start.unwrap_or(&nil).clone(),
+                                                  end.unwrap_or(&nil).clone()]));
+    }
+
+    let input: String = chars[..to as usize].iter().collect();
+    let (obj, pos) = lsp.read_from_str(&input, from as usize)?;
+    Ok(LispObj::cons(obj, LispObj::Int(pos as i64)))
+}}
 
-    let def = match def {
-        Some(ref obj) => obj.clone(),
-        _ => return Err(format!("fset requires two arguments")),
+/// The text of a buffer argument, or the value of current-buffer
+fn buffer_contents(lsp: &Lsp, buffer: Option<&LispObj>) -> Result<String, LispError> {
+    let buffer = match buffer {
+        None | Some(&LispObj::Atm(symbols::NIL)) => lsp.eval_atm_val(symbols::CURRENT_BUFFER)?,
+        Some(buffer) => buffer.clone(),
     };
 
-    let sym = sym.unwrap();
-    match sym {
-        &LispObj::Sym(ref s) => {
-            s.set_fun(def.clone());
+    match buffer {
+        LispObj::Ext(ref ext) => ext.borrow().contents(),
+        obj => Err(LispError::wrong_type(symbols::BUFFERP, &obj)),
+    }
+}
+
+// The print flag and other optional arguments are ignored
+def_builtin! { "eval-buffer", EvalBufferBuiltin, Evaluated, 0, 5, lsp, args; {
+    let src = buffer_contents(lsp, args.next())?;
+    let sexp = lsp.read(&src)?;
+
+    lsp.eval_source(&src, &sexp)?;
+    Ok(LispObj::nil())
+}}
+
+def_builtin! { "eval-region", EvalRegionBuiltin, Evaluated, 2, 4, lsp, args; {
+    let (start, end) = take2!(args);
+    let (start, end) = (start.unwrap(), end.unwrap());
+    let src = buffer_contents(lsp, None)?;
+    let len = src.chars().count() as i64;
+
+    // Buffer positions start at 1
+    let (from, to) = (start.int_val()?, end.int_val()?);
+    let (from, to) = (cmp::min(*from, *to), cmp::max(*from, *to));
+    if from < 1 || to > len + 1 {
+        return Err(LispError::args_out_of_range(&[start.clone(), end.clone()]));
+    }
+
+    let region: String = src.chars().skip(from as usize - 1).take((to - from) as usize).collect();
+    let sexp = lsp.read(&region)?;
+    lsp.eval_source(&src, &sexp)?;
+    Ok(LispObj::nil())
+}}
+
+/// Get the global symbol for an interned name or the symbol itself if it is
+/// uninterned
+fn symbol_cell(lsp: &mut Lsp, obj: &LispObj) -> Result<Symbol, LispError> {
+    match obj {
+        &LispObj::Atm(a) => Ok(lsp.globals.get_or_intern(a).clone()),
+        LispObj::Sym(s) => Ok(s.clone()),
+        obj => Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+    }
+}
+
+/// Take NAME and ARGLIST from the start of a defun or defmacro
+fn defun_name(lsp: &mut Lsp, defining: &str, args: &mut Iter<LispObj>)
+              -> Result<(Atom, LispObj), LispError> {
+    match take2!(args) {
+        (Some(&LispObj::Atm(name)), Some(arglist)) if arglist.is_list() =>
+            Ok((name, arglist.clone())),
+        (Some(&LispObj::Atm(_)), Some(arglist)) =>
+            Err(LispError::wrong_type(symbols::LISTP, arglist)),
+        (Some(&LispObj::Atm(_)), None) =>
+            Err(LispError::wrong_number_of_args(LispObj::Atm(lsp.atomize(defining)), 1)),
+        (None, _) =>
+            Err(LispError::wrong_number_of_args(LispObj::Atm(lsp.atomize(defining)), 0)),
+        (Some(obj), _) => Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+    }
+}
+
+def_builtin! { "defun", DefunBuiltin, Unevaluated, 2, many, lsp, args; {
+    let (name, arglist) = defun_name(lsp, "defun", args)?;
+    // The docstring and interactive spec are kept in the lambda body, where
+    // documentation and interactive-form find them
+    let lambda = lsp.closure(LispObj::list_with_tail(&[arglist],
+                                                     LispObj::list_from(args.as_slice())));
+
+    lsp.globals.get_or_intern(name).set_fun(lambda);
+    Ok(LispObj::Atm(name))
+}}
+
+def_builtin! { "defmacro", DefmacroBuiltin, Unevaluated, 2, many, lsp, args; {
+    let (name, arglist) = defun_name(lsp, "defmacro", args)?;
+    let lambda = lsp.closure(LispObj::list_with_tail(&[arglist],
+                                                     LispObj::list_from(args.as_slice())));
+
+    lsp.globals.get_or_intern(name).set_fun(LispObj::cons(LispObj::Atm(symbols::MACRO), lambda));
+    Ok(LispObj::Atm(name))
+}}
+
+/// Expand form once if it is a call to a macro
+fn macroexpand_1(lsp: &mut Lsp, form: &LispObj) -> Result<Option<LispObj>, LispError> {
+    if let LispObj::Cons(form) = form {
+        if let LispObj::Atm(name) = form.car() {
+            if let Some(LispObj::Cons(ref def)) = lsp.globals.get_fun(name) {
+                if def.car() == LispObj::Atm(symbols::MACRO) {
+                    let fun = Lsp::macro_function(def)?;
+                    return lsp.funcall(&fun, &form.cdr().list_to_vec()?).map(Some);
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+// There are no macro environments, so ENVIRONMENT is ignored
+def_builtin! { "macroexpand-1", Macroexpand1Builtin, Evaluated, 1, 2, lsp, args; {
+    let form = args.next().unwrap();
+    Ok(macroexpand_1(lsp, form)?.unwrap_or_else( || form.clone() ))
+}}
+
+def_builtin! { "macroexpand", MacroexpandBuiltin, Evaluated, 1, 2, lsp, args; {
+    let mut form = args.next().unwrap().clone();
+    while let Some(expansion) = macroexpand_1(lsp, &form)? {
+        form = expansion;
+    }
+    Ok(form)
+}}
+
+/// Declare a special variable, setting its value if init is given
+///
+/// If force is false then an existing global value is kept, as with defvar.
+fn defvar(lsp: &mut Lsp, defining: &str, force: bool, args: &mut Iter<LispObj>)
+          -> Result<LispObj, LispError> {
+    let (name, init, doc) = take3!(args);
+    let name = match name {
+        Some(&LispObj::Atm(name)) => name,
+        Some(obj) => return Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+        None => return Err(LispError::wrong_number_of_args(
+            LispObj::Atm(lsp.atomize(defining)), 0)),
+    };
+    if args.len() > 0 {
+        return Err(LispError::error(format!("Too many arguments to {}", defining)));
+    }
+    if lsp.is_constant(name) {
+        return Err(LispError::setting_constant(name));
+    }
+
+    if let Some(init) = init {
+        let sym = lsp.globals.get_or_intern(name).clone();
+
+        if force || sym.get_val().is_none() {
+            let val = lsp.eval(init)?;
+            sym.set_val(val);
+        }
+        if let Some(doc) = doc {
+            sym.put_prop(symbols::VARIABLE_DOCUMENTATION, doc.clone());
+        }
+        sym.set_special();
+    } else if force {
+        return Err(LispError::wrong_number_of_args(LispObj::Atm(lsp.atomize(defining)), 1));
+    } else {
+        // Declared special without being given a value
+        lsp.globals.get_or_intern(name).set_special();
+    }
+
+    Ok(LispObj::Atm(name))
+}
+
+def_builtin! { "defvar", DefvarBuiltin, Unevaluated, 1, 3, lsp, args; {
+    defvar(lsp, "defvar", false, args)
+}}
+
+def_builtin! { "defconst", DefconstBuiltin, Unevaluated, 2, 3, lsp, args; {
+    defvar(lsp, "defconst", true, args)
+}}
+
+def_builtin! { "symbol-function", SymbolFunctionBuiltin, Evaluated, 1, 1, lsp, args; {
+    match args.next() {
+        Some(sym) => Ok(symbol_cell(lsp, sym)?.get_fun().unwrap_or(LispObj::nil())),
+        None => Err(LispError::error("symbol-function requires one argument")),
+    }
+}}
+
+def_builtin! { "fboundp", FboundpBuiltin, Evaluated, 1, 1, lsp, args; {
+    match args.next() {
+        Some(sym) => Ok(LispObj::from_bool(symbol_cell(lsp, sym)?.get_fun().is_some())),
+        None => Err(LispError::error("fboundp requires one argument")),
+    }
+}}
+
+def_builtin! { "fmakunbound", FmakunboundBuiltin, Evaluated, 1, 1, lsp, args; {
+    match args.next() {
+        Some(sym) => {
+            symbol_cell(lsp, sym)?.unset_fun();
             Ok(sym.clone())
         },
-        &LispObj::Atm(a) => {
-            let s = Symbol::with_fun(a, def.clone());
-            lsp.globals.intern(s.clone());
-            Ok(LispObj::Sym(s))
+        None => Err(LispError::error("fmakunbound requires one argument")),
+    }
+}}
+
+def_builtin! { "boundp", BoundpBuiltin, Evaluated, 1, 1, lsp, args; {
+    match args.next() {
+        Some(&LispObj::Atm(name)) if lsp.is_constant(name) => Ok(LispObj::t()),
+        Some(&LispObj::Atm(name)) => {
+            let bound = lsp.binding(name).and_then( |sym| sym.get_val() ).is_some();
+            Ok(LispObj::from_bool(bound))
+        },
+        Some(LispObj::Sym(sym)) => Ok(LispObj::from_bool(sym.get_val().is_some())),
+        Some(obj) => Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+        None => Err(LispError::error("boundp requires one argument")),
+    }
+}}
+
+def_builtin! { "makunbound", MakunboundBuiltin, Evaluated, 1, 1, lsp, args; {
+    match args.next() {
+        Some(&LispObj::Atm(name)) if lsp.is_constant(name) => Err(LispError::setting_constant(name)),
+        Some(&LispObj::Atm(name)) => {
+            if let Some(sym) = lsp.binding(name) {
+                sym.unset_val();
+            }
+            Ok(LispObj::Atm(name))
+        },
+        Some(obj @ &LispObj::Sym(_)) => {
+            symbol_cell(lsp, obj)?.unset_val();
+            Ok(obj.clone())
+        },
+        Some(obj) => Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+        None => Err(LispError::error("makunbound requires one argument")),
+    }
+}}
+
+/// The symbol for an interned name or the symbol itself if it is uninterned
+///
+/// Unlike symbol_cell this does not intern the name, None is returned if it
+/// has no symbol yet.
+fn find_symbol(lsp: &Lsp, obj: &LispObj) -> Result<Option<Symbol>, LispError> {
+    match obj {
+        &LispObj::Atm(a) => Ok(lsp.globals.get(a).cloned()),
+        LispObj::Sym(s) => Ok(Some(s.clone())),
+        obj => Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+    }
+}
+
+/// A name given as either a string or a symbol
+pub fn name_arg(lsp: &Lsp, obj: &LispObj) -> Result<String, LispError> {
+    match obj {
+        LispObj::Str(s) => Ok(s.to_string()),
+        &LispObj::Atm(a) => Ok(lsp.stringify(a).to_owned()),
+        LispObj::Sym(s) => Ok(lsp.stringify(s.name).to_owned()),
+        obj => Err(LispError::wrong_type(symbols::STRINGP, obj)),
+    }
+}
+
+def_builtin! { "symbolp", SymbolpBuiltin, Evaluated, 1, 1, lsp, args; {
+    let obj = one_arg(lsp, "symbolp", args)?;
+    Ok(LispObj::from_bool(matches!(obj, &LispObj::Atm(_) | &LispObj::Sym(_))))
+}}
+
+def_builtin! { "keywordp", KeywordpBuiltin, Evaluated, 1, 1, lsp, args; {
+    match one_arg(lsp, "keywordp", args)? {
+        &LispObj::Atm(name) => Ok(LispObj::from_bool(lsp.atoms.is_keyword(name))),
+        _ => Ok(LispObj::nil()),
+    }
+}}
+
+def_builtin! { "symbol-name", SymbolNameBuiltin, Evaluated, 1, 1, lsp, args; {
+    let sym = one_arg(lsp, "symbol-name", args)?;
+    Ok(LispObj::str(lsp.stringify(symbol_name(sym)?)))
+}}
+
+// The dynamic value, lexical bindings are not visible
+def_builtin! { "symbol-value", SymbolValueBuiltin, Evaluated, 1, 1, lsp, args; {
+    let sym = one_arg(lsp, "symbol-value", args)?;
+    let val = match sym {
+        &LispObj::Atm(a) if lsp.is_constant(a) => Some(sym.clone()),
+        &LispObj::Atm(a) => lsp.binding(a).and_then( |s| s.get_val() ),
+        LispObj::Sym(s) => s.get_val(),
+        obj => return Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+    };
+
+    match val {
+        Some(val) => Ok(val),
+        None => Err(LispError::void_variable(symbol_name(sym)?)),
+    }
+}}
+
+def_builtin! { "get", GetBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (sym, prop) = two_args(lsp, "get", args)?;
+    let prop = symbol_name(prop)?;
+    Ok(find_symbol(lsp, sym)?.and_then( |sym| sym.get_prop(prop) ).unwrap_or_else(LispObj::nil))
+}}
+
+def_builtin! { "put", PutBuiltin, Evaluated, 3, 3, lsp, args; {
+    let (sym, prop, val) = take3!(args);
+    let prop = symbol_name(prop.unwrap())?;
+
+    symbol_cell(lsp, sym.unwrap())?.put_prop(prop, val.unwrap().clone());
+    Ok(val.unwrap().clone())
+}}
+
+def_builtin! { "symbol-plist", SymbolPlistBuiltin, Evaluated, 1, 1, lsp, args; {
+    let sym = one_arg(lsp, "symbol-plist", args)?;
+    let mut plist = Vec::new();
+
+    for (prop, val) in find_symbol(lsp, sym)?.map_or_else(Vec::new, |sym| sym.plist()) {
+        plist.push(LispObj::Atm(prop));
+        plist.push(val);
+    }
+    Ok(LispObj::list_from(&plist))
+}}
+
+// Only symbols can be used as properties
+def_builtin! { "setplist", SetplistBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (sym, plist) = two_args(lsp, "setplist", args)?;
+    let items = plist.list_to_vec()?;
+
+    if items.len() % 2 != 0 {
+        return Err(LispError::wrong_type(symbols::LISTP, plist));
+    }
+    let props = items.chunks(2)
+        .map( |pair| Ok((symbol_name(&pair[0])?, pair[1].clone())) )
+        .collect::<Result<Vec<_>, LispError>>()?;
+
+    symbol_cell(lsp, sym)?.set_plist(props);
+    Ok(plist.clone())
+}}
+
+// (function-get F PROP &optional AUTOLOAD)
+//
+// If F is an alias then the property is also looked for on the function it
+// refers to. Nothing is autoloaded.
+def_builtin! { "function-get", FunctionGetBuiltin, Evaluated, 2, 3, lsp, args; {
+    let (fun, prop) = take2!(args);
+    let prop = symbol_name(prop.unwrap())?;
+    let mut fun = fun.unwrap().clone();
+
+    // Follow aliases, but not forever if they form a loop
+    for _ in 0..100 {
+        let sym = match fun {
+            LispObj::Atm(_) | LispObj::Sym(_) if !fun.is_nil() => find_symbol(lsp, &fun)?,
+            _ => None,
+        };
+        let sym = match sym {
+            Some(sym) => sym,
+            None => break,
+        };
+
+        match sym.get_prop(prop) {
+            Some(ref val) if !val.is_nil() => return Ok(val.clone()),
+            _ => (),
+        }
+        fun = sym.get_fun().unwrap_or_else(LispObj::nil);
+    }
+
+    Ok(LispObj::nil())
+}}
+
+def_builtin! { "function-put", FunctionPutBuiltin, Evaluated, 3, 3, lsp, args; {
+    let (fun, prop, val) = take3!(args);
+    let prop = symbol_name(prop.unwrap())?;
+
+    symbol_cell(lsp, fun.unwrap())?.put_prop(prop, val.unwrap().clone());
+    Ok(val.unwrap().clone())
+}}
+
+def_builtin! { "make-symbol", MakeSymbolBuiltin, Evaluated, 1, 1, lsp, args; {
+    let name = one_arg(lsp, "make-symbol", args)?;
+    let name = match name {
+        LispObj::Str(s) => lsp.atoms.uninterned(s.to_string()),
+        obj => return Err(LispError::wrong_type(symbols::STRINGP, obj)),
+    };
+    Ok(LispObj::sym(name))
+}}
+
+// (gensym &optional PREFIX)
+//
+// An uninterned symbol named PREFIX, "g" by default, followed by the value of
+// gensym-counter, which is then incremented.
+def_builtin! { "gensym", GensymBuiltin, Evaluated, 0, 1, lsp, args; {
+    let prefix = match args.next() {
+        None | Some(&LispObj::Atm(symbols::NIL)) => "g".to_owned(),
+        Some(prefix) => name_arg(lsp, prefix)?,
+    };
+
+    let counter = lsp.globals.get_or_intern(symbols::GENSYM_COUNTER).clone();
+    let n = match counter.get_val() {
+        Some(LispObj::Int(n)) => n,
+        Some(obj) => return Err(LispError::wrong_type(symbols::INTEGERP, &obj)),
+        None => 0,
+    };
+    counter.set_val(LispObj::Int(n + 1));
+
+    let name = lsp.atoms.uninterned(format!("{}{}", prefix, n));
+    Ok(LispObj::sym(name))
+}}
+
+/// Follow a symbol's function definition through any aliases
+fn indirect_function(lsp: &mut Lsp, fun: &LispObj) -> Result<LispObj, LispError> {
+    let mut fun = fun.clone();
+
+    // Follow aliases, but not forever if they form a loop
+    for _ in 0..100 {
+        fun = match fun {
+            LispObj::Atm(symbols::NIL) => return Ok(fun),
+            LispObj::Atm(_) | LispObj::Sym(_) => match symbol_cell(lsp, &fun)?.get_fun() {
+                Some(def) => def,
+                None => return Err(LispError::void_function(symbol_name(&fun)?)),
+            },
+            _ => return Ok(fun),
+        };
+    }
+
+    Err(LispError::error("Function aliases form a loop"))
+}
+
+/// The definition of a function with any macro wrapper removed
+fn function_definition(lsp: &mut Lsp, fun: &LispObj) -> Result<LispObj, LispError> {
+    let def = indirect_function(lsp, fun)?;
+
+    if let LispObj::Cons(ref cons) = def {
+        if let LispObj::Atm(symbols::MACRO) = cons.car() {
+            return Ok(cons.cdr());
+        }
+    }
+    Ok(def)
+}
+
+/// The body of a lambda or macro definition, following symbols' functions
+fn lambda_body(lsp: &mut Lsp, fun: &LispObj) -> Result<LispObj, LispError> {
+    match function_definition(lsp, fun)? {
+        LispObj::Lambda(ref fun) => Ok(fun.body()),
+        LispObj::Cons(ref def) => match def.car() {
+            LispObj::Atm(symbols::LAMBDA) => def.cdr().cdr(),
+            LispObj::Atm(symbols::CLOSURE) => def.cdr().cdr()?.cdr(),
+            _ => Ok(LispObj::nil()),
+        },
+        _ => Ok(LispObj::nil()),
+    }
+}
+
+def_builtin! { "func-arity", FuncArityBuiltin, Evaluated, 1, 1, lsp, args; {
+    let fun = match args.next() {
+        Some(fun) => fun,
+        None => return Err(LispError::error("func-arity requires one argument")),
+    };
+
+    let arity = match function_definition(lsp, fun)? {
+        LispObj::Lambda(ref fun) => fun.arity(),
+        LispObj::ExtFun(ref fun) => match fun.eval_args() {
+            EvalOption::Unevaluated =>
+                return Ok(LispObj::cons(LispObj::Int(fun.arity().0 as i64),
+                                        LispObj::Atm(symbols::UNEVALLED))),
+            EvalOption::Evaluated => fun.arity(),
         },
-        obj => Err(format!("First argument of fset should be a symbol not: {:?}", obj)),
+        LispObj::Cons(ref def) => match def.car() {
+            LispObj::Atm(symbols::LAMBDA) => UserFunc::lambda(&def.cdr())?.arity(),
+            LispObj::Atm(symbols::CLOSURE) => UserFunc::closure(&def.cdr())?.arity(),
+            _ => return Err(LispError::invalid_function(fun)),
+        },
+        _ => return Err(LispError::invalid_function(fun)),
+    };
+
+    Ok(lambda::arity_to_lisp(arity))
+}}
+
+def_builtin! { "documentation", DocumentationBuiltin, Evaluated, 1, 2, lsp, args; {
+    let body = match args.next() {
+        Some(fun) => lambda_body(lsp, fun)?,
+        None => return Err(LispError::error("documentation requires one argument")),
+    };
+
+    match body.car()? {
+        doc @ LispObj::Str(_) => Ok(doc),
+        _ => Ok(LispObj::nil()),
+    }
+}}
+
+def_builtin! { "interactive-form", InteractiveFormBuiltin, Evaluated, 1, 1, lsp, args; {
+    let mut body = match args.next() {
+        Some(fun) => lambda_body(lsp, fun)?,
+        None => return Err(LispError::error("interactive-form requires one argument")),
+    };
+
+    if let LispObj::Str(_) = body.car()? {
+        body = body.cdr()?;
     }
+
+    match body.car()? {
+        LispObj::Cons(ref spec) if spec.car() == LispObj::Atm(symbols::INTERACTIVE) =>
+            Ok(LispObj::Cons(spec.clone())),
+        _ => Ok(LispObj::nil()),
+    }
+}}
+
+/// The wrong-number-of-arguments error for the builtin called name
+pub fn wrong_args(lsp: &mut Lsp, name: &str, count: usize) -> LispError {
+    LispError::wrong_number_of_args(LispObj::Atm(lsp.atomize(name)), count)
+}
+
+/// Take the only argument
+pub fn one_arg<'a>(lsp: &mut Lsp, name: &str, args: &mut Iter<'a, LispObj>)
+                   -> Result<&'a LispObj, LispError> {
+    match (args.next(), args.len()) {
+        (Some(arg), 0) => Ok(arg),
+        (arg, rest) => Err(wrong_args(lsp, name, arg.map_or(0, |_| 1 + rest))),
+    }
+}
+
+/// Take exactly two arguments
+pub fn two_args<'a>(lsp: &mut Lsp, name: &str, args: &mut Iter<'a, LispObj>)
+                    -> Result<(&'a LispObj, &'a LispObj), LispError> {
+    let count = args.len();
+    match take2!(args) {
+        (Some(a), Some(b)) if count == 2 => Ok((a, b)),
+        _ => Err(wrong_args(lsp, name, count)),
+    }
+}
+
+/// Get the name of a symbol argument
+pub fn symbol_name(obj: &LispObj) -> Result<Atom, LispError> {
+    match obj {
+        &LispObj::Atm(a) => Ok(a),
+        LispObj::Sym(s) => Ok(s.name),
+        obj => Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+    }
+}
+
+/// Format the message for error or user-error, as format-message does
+fn format_error(lsp: &Lsp, args: &mut Iter<LispObj>) -> Result<String, LispError> {
+    match args.next() {
+        Some(LispObj::Str(fmt)) => format(lsp, &curve_quotes(fmt), args.as_slice()),
+        Some(obj) => Err(LispError::wrong_type(symbols::STRINGP, obj)),
+        None => Err(LispError::error("error requires a format string")),
+    }
+}
+
+def_builtin! { "signal", SignalBuiltin, Evaluated, 2, 2, _lsp, args; {
+    match take2!(args) {
+        (Some(sym), Some(data)) => Err(LispError::new(symbol_name(sym)?, data.clone())),
+        _ => Err(LispError::error("signal requires two arguments")),
+    }
+}}
+
+def_builtin! { "error", ErrorBuiltin, Evaluated, 1, many, lsp, args; {
+    Err(LispError::error(format_error(lsp, args)?))
+}}
+
+def_builtin! { "user-error", UserErrorBuiltin, Evaluated, 1, many, lsp, args; {
+    let msg = format_error(lsp, args)?;
+    Err(LispError::new(symbols::USER_ERROR, LispObj::list_from(&[LispObj::string(msg)])))
+}}
+
+def_builtin! { "error-message-string", ErrorMessageStringBuiltin, Evaluated, 1, 1, lsp, args; {
+    match args.next() {
+        Some(LispObj::Cons(cons)) => {
+            let err = LispError::new(symbol_name(&cons.car())?, cons.cdr());
+            Ok(LispObj::string(lsp.error_message(&err)))
+        },
+        Some(obj) => Err(LispError::wrong_type(symbols::LISTP, obj)),
+        None => Err(LispError::error("error-message-string requires one argument")),
+    }
+}}
+
+def_builtin! { "define-error", DefineErrorBuiltin, Evaluated, 2, 3, lsp, args; {
+    let (name, message) = match take2!(args) {
+        (Some(name), Some(LispObj::Str(message))) => (symbol_name(name)?, message),
+        (Some(_), Some(obj)) => return Err(LispError::wrong_type(symbols::STRINGP, obj)),
+        _ => return Err(LispError::error("define-error requires two or three arguments")),
+    };
+    let parents = match args.next() {
+        None | Some(&LispObj::Atm(symbols::NIL)) => vec![symbols::ERROR],
+        Some(LispObj::Cons(cons)) => cons.iter()
+            .map( |parent| symbol_name(&parent) )
+            .collect::<Result<Vec<Atom>, LispError>>()?,
+        Some(parent) => vec![symbol_name(parent)?],
+    };
+
+    lsp.define_error(name, message, &parents);
+    Ok(LispObj::nil())
+}}
+
+def_builtin! { "condition-case", ConditionCaseBuiltin, Unevaluated, 2, many, lsp, args; {
+    let var = match args.next() {
+        Some(var) => symbol_name(var)?,
+        None => return Err(LispError::error("condition-case requires a variable")),
+    };
+    // A nil variable means the error is not bound
+    if var != symbols::NIL && lsp.is_constant(var) {
+        return Err(LispError::setting_constant(var));
+    }
+    let body = args.next().cloned().unwrap_or(LispObj::nil());
+    let success = lsp.atomize(":success");
+
+    lsp.handlers.push(args.as_slice().to_vec());
+    let res = lsp.eval(&body);
+    lsp.handlers.pop();
+
+    let (val, handler) = match res {
+        Ok(val) => {
+            let handler = args.find( |handler| match handler {
+                LispObj::Cons(h) => match h.car() {
+                    LispObj::Atm(a) => a == success,
+                    _ => false,
+                },
+                _ => false,
+            });
+
+            match handler {
+                Some(handler) => (val, handler),
+                None => return Ok(val),
+            }
+        },
+        // Only the catch for its tag can stop a throw
+        Err(err) if err.is_throw() => return Err(err),
+        Err(err) => {
+            let conditions = lsp.error_conditions(err.symbol);
+
+            match lsp.find_handler(&conditions, args.as_slice()) {
+                Some(handler) => (err.to_lisp(), handler),
+                None => return Err(err),
+            }
+        },
+    };
+
+    let body = handler.cdr()?;
+
+    if var == symbols::NIL {
+        return lsp.eval_progn(body.iter());
+    }
+
+    let mut ns = Namespace::new();
+    let mut env = lsp.lexenv.clone();
+    if lsp.binds_lexically(&env, var) {
+        env = LispObj::cons(LispObj::cons(LispObj::Atm(var), val), env);
+    } else {
+        ns.intern(Symbol::with_val(var, val));
+    }
+
+    lsp.with_scope(ns, env, |lsp| lsp.eval_progn(body.iter()))
+}}
+
+// (catch TAG BODY...)
+def_builtin! { "catch", CatchBuiltin, Unevaluated, 1, many, lsp, args; {
+    let tag = match args.next() {
+        Some(tag) => lsp.eval(tag)?,
+        None => return Err(wrong_args(lsp, "catch", 0)),
+    };
+
+    lsp.catches.push(tag.clone());
+    let res = lsp.eval_progn(args.as_slice());
+    lsp.catches.pop();
+
+    match res {
+        Err(ref e) if e.is_throw() && eq(&e.data.car()?, &tag) => e.data.cdr(),
+        res => res,
+    }
+}}
+
+// Signals no-catch straight away if there is no catch for TAG, so that the
+// error can be seen where it happened
+def_builtin! { "throw", ThrowBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (tag, value) = two_args(lsp, "throw", args)?;
+
+    if lsp.catches.iter().any( |c| eq(c, tag) ) {
+        Err(LispError::throw(tag.clone(), value.clone()))
+    } else {
+        Err(LispError::no_catch(tag.clone(), value.clone()))
+    }
+}}
+
+// (unwind-protect BODYFORM UNWINDFORMS...)
+//
+// The unwind forms are run however BODYFORM is left, by an error, a throw or
+// a quit. If they fail then their error replaces the result of BODYFORM.
+def_builtin! { "unwind-protect", UnwindProtectBuiltin, Unevaluated, 1, many, lsp, args; {
+    let res = match args.next() {
+        Some(body) => lsp.eval(body),
+        None => return Err(wrong_args(lsp, "unwind-protect", 0)),
+    };
+
+    lsp.eval_progn(args.as_slice())?;
+    res
 }}
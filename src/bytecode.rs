@@ -0,0 +1,824 @@
+// Copyright (C) 2017 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Byte code compiler and virtual machine
+//!
+//! A lambda or closure can be compiled into a `Compiled` function, which is
+//! a list of stack machine instructions along with the constants they refer
+//! to. Compiled functions are wrapped in `LispObj::ExtFun` so that the
+//! evaluator calls them like any builtin.
+//!
+//! In a closure the arguments and `let` bound variables which are not special
+//! live in slots on the VM's stack, so referring to them is an index instead
+//! of a search through the environment. Variables captured from the closure's
+//! environment refer directly to their `(VAR . VALUE)` cells. Everything else
+//! is looked up dynamically, as are all variables in a function which was
+//! defined without lexical binding.
+//!
+//! Macros are expanded when the function is compiled. Special forms which the
+//! compiler does not know are evaluated by the interpreter, with the local
+//! variables given to it as a lexical environment. Any changes it makes to
+//! them are copied back to the stack afterwards, but a closure created by the
+//! interpreter will not see later changes made by the compiled code.
+
+use std::mem;
+
+use super::*;
+use lambda::{ArgKind, ArgSpecs, arity_to_lisp};
+use debug::Activation;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    /// Push a constant
+    Const(usize),
+    /// Push the value in a stack slot
+    StackRef(usize),
+    /// Pop a value into a stack slot
+    StackSet(usize),
+    /// Push the value of a captured variable, the constant is its binding
+    EnvRef(usize),
+    /// Pop a value into a captured variable
+    EnvSet(usize),
+    /// Push the value of a dynamic variable named by a constant
+    VarRef(usize),
+    /// Pop a value into a dynamic variable
+    VarSet(usize),
+    /// Start a new set of dynamic bindings
+    PushScope,
+    /// Dynamically bind the variable named by a constant to a stack slot
+    Bind(usize, usize),
+    /// Remove the innermost set of dynamic bindings
+    PopScope,
+    /// Call a function with N arguments, which are above it on the stack
+    Call(usize),
+    Discard,
+    /// Discard N values under the top of the stack
+    DiscardUnder(usize),
+    Dup,
+    Goto(usize),
+    /// Pop a value and jump if it is nil
+    GotoIfNil(usize),
+    /// Jump if the top value is nil, otherwise pop it
+    GotoIfNilElsePop(usize),
+    /// Jump if the top value is not nil, otherwise pop it
+    GotoIfNotNilElsePop(usize),
+    /// Evaluate a form with the interpreter, see Compiled::fallbacks
+    Eval(usize),
+    Return,
+}
+
+/// A function compiled to byte code
+#[derive(Debug)]
+pub struct Compiled {
+    name: Atom,
+    args: ArgSpecs,
+    /// Whether this was a closure, otherwise the arguments are bound
+    /// dynamically
+    lexical: bool,
+    /// The captured environment, or `(t)` for a closure which captured
+    /// nothing
+    env: LispObj,
+    constants: Vec<LispObj>,
+    /// Forms for the interpreter and the stack slots of the lexical
+    /// variables they can see
+    fallbacks: Vec<(LispObj, Vec<(Atom, usize)>)>,
+    code: Vec<Op>,
+    max_depth: usize,
+}
+
+impl Compiled {
+    pub fn code(&self) -> &[Op] {
+        &self.code
+    }
+
+    /// Execute the byte code with the arguments in the first stack slots
+    fn run(&self, lsp: &mut Lsp, mut stack: Vec<LispObj>) -> Result<LispObj, LispError> {
+        let mut pc = 0;
+
+        loop {
+            match self.code[pc] {
+                Op::Const(i) => stack.push(self.constants[i].clone()),
+                Op::StackRef(i) => {
+                    let val = stack[i].clone();
+                    stack.push(val);
+                },
+                Op::StackSet(i) => stack[i] = stack.pop().unwrap(),
+                Op::EnvRef(i) => match self.constants[i] {
+                    LispObj::Cons(ref binding) => stack.push(binding.cdr()),
+                    _ => unreachable!(),
+                },
+                Op::EnvSet(i) => match self.constants[i] {
+                    LispObj::Cons(ref binding) => binding.set_cdr(stack.pop().unwrap()),
+                    _ => unreachable!(),
+                },
+                Op::VarRef(i) => match self.constants[i] {
+                    LispObj::Atm(name) => stack.push(lsp.eval_atm_val(name)?),
+                    _ => unreachable!(),
+                },
+                Op::VarSet(i) => match self.constants[i] {
+                    LispObj::Atm(name) => lsp.set_var(name, stack.pop().unwrap())?,
+                    _ => unreachable!(),
+                },
+                Op::PushScope => lsp.locals.push(Namespace::new()),
+                Op::Bind(i, slot) => match self.constants[i] {
+                    LispObj::Atm(name) => {
+                        let sym = Symbol::with_val(name, stack[slot].clone());
+                        lsp.locals.last_mut().unwrap().intern(sym);
+                    },
+                    _ => unreachable!(),
+                },
+                Op::PopScope => { lsp.locals.pop(); },
+                Op::Call(n) => {
+                    lsp.check_limits()?;
+                    let fun = stack.len() - n - 1;
+                    let val = lsp.funcall(&stack[fun], &stack[fun + 1..])?;
+                    stack.truncate(fun);
+                    stack.push(val);
+                },
+                Op::Discard => { stack.pop(); },
+                Op::DiscardUnder(n) => {
+                    let top = stack.pop().unwrap();
+                    let len = stack.len();
+                    stack.truncate(len - n);
+                    stack.push(top);
+                },
+                Op::Dup => {
+                    let top = stack.last().unwrap().clone();
+                    stack.push(top);
+                },
+                Op::Goto(to) => {
+                    // Jumping back is how loops go around
+                    if to < pc {
+                        lsp.check_limits()?;
+                    }
+                    pc = to;
+                    continue;
+                },
+                Op::GotoIfNil(to) => if stack.pop().unwrap().is_nil() {
+                    pc = to;
+                    continue;
+                },
+                Op::GotoIfNilElsePop(to) => if stack.last().unwrap().is_nil() {
+                    pc = to;
+                    continue;
+                } else {
+                    stack.pop();
+                },
+                Op::GotoIfNotNilElsePop(to) => if !stack.last().unwrap().is_nil() {
+                    pc = to;
+                    continue;
+                } else {
+                    stack.pop();
+                },
+                Op::Eval(i) => {
+                    let val = self.fallback(lsp, i, &mut stack)?;
+                    stack.push(val);
+                },
+                Op::Return => return Ok(stack.pop().unwrap()),
+            }
+            pc += 1;
+        }
+    }
+
+    /// Evaluate a form which could not be compiled
+    fn fallback(&self, lsp: &mut Lsp, i: usize, stack: &mut [LispObj])
+                -> Result<LispObj, LispError> {
+        let (ref form, ref vars) = self.fallbacks[i];
+
+        if !self.lexical {
+            return lsp.eval(form);
+        }
+
+        let mut env = self.env.clone();
+        let mut cells = Vec::with_capacity(vars.len());
+        for &(name, slot) in vars.iter() {
+            let cell = Cons::new(LispObj::Atm(name), stack[slot].clone());
+            env = LispObj::cons(LispObj::Cons(cell.clone()), env);
+            cells.push((slot, cell));
+        }
+
+        let outer = mem::replace(&mut lsp.lexenv, env);
+        let res = lsp.eval(form);
+        lsp.lexenv = outer;
+
+        for (slot, cell) in cells {
+            stack[slot] = cell.cdr();
+        }
+        res
+    }
+}
+
+impl Func for Compiled {
+    fn eval_args(&self) -> EvalOption { EvalOption::Evaluated }
+    fn name(&self) -> Atom { self.name }
+    fn arity(&self) -> (usize, Option<usize>) { self.args.arity() }
+
+    fn call(&self, lsp: &mut Lsp, args: &mut Iter<LispObj>) -> Result<LispObj, LispError> {
+        let (min, max) = self.arity();
+        let count = args.len();
+        if count < min || max.is_some_and(|max| count > max) {
+            return Err(LispError::wrong_number_of_args(arity_to_lisp((min, max)), count));
+        }
+
+        if gc::consed() > lsp.gc_check {
+            lsp.maybe_garbage_collect();
+        }
+
+        let mut stack = Vec::with_capacity(self.max_depth);
+        let mut ns = Namespace::new();
+        for spec in self.args.iter() {
+            if lsp.is_constant(spec.name()) {
+                return Err(LispError::setting_constant(spec.name()));
+            }
+            let val = match spec.kind() {
+                ArgKind::Rest => {
+                    let rest = LispObj::list_from(args.as_slice());
+                    args.by_ref().last();
+                    rest
+                },
+                _ => args.next().cloned().unwrap_or(LispObj::nil()),
+            };
+
+            if self.lexical {
+                stack.push(val);
+            } else {
+                ns.intern(Symbol::with_val(spec.name(), val));
+            }
+        }
+
+        lsp.check_eval_depth()?;
+        let depth = lsp.locals.len();
+        let env = if self.lexical { self.env.clone() } else { LispObj::nil() };
+        let outer = mem::replace(&mut lsp.lexenv, env);
+        lsp.locals.push(ns);
+        lsp.stack.push(Activation {
+            form: LispObj::Atm(self.name),
+            locals: depth + 1,
+            lexenv: lsp.lexenv.clone(),
+        });
+
+        let mut res = self.run(lsp, stack);
+
+        lsp.unwinding(&mut res);
+        lsp.stack.pop();
+        lsp.locals.truncate(depth);
+        lsp.lexenv = outer;
+        res
+    }
+}
+
+/// Where a variable can be found while compiling
+enum VarLoc {
+    Slot(usize),
+    /// The index of its binding cell in the constants
+    Env(usize),
+    Dynamic,
+}
+
+/// Compiles one function
+struct Compiler<'a> {
+    lsp: &'a mut Lsp,
+    lexical: bool,
+    env: LispObj,
+    constants: Vec<LispObj>,
+    fallbacks: Vec<(LispObj, Vec<(Atom, usize)>)>,
+    code: Vec<Op>,
+    /// The current and maximum depth of the stack
+    depth: usize,
+    max_depth: usize,
+    /// Lexical variables and their stack slots, innermost last
+    scope: Vec<(Atom, usize)>,
+}
+
+impl<'a> Compiler<'a> {
+    fn emit(&mut self, op: Op) -> usize {
+        match op {
+            Op::Const(_) | Op::StackRef(_) | Op::EnvRef(_) | Op::VarRef(_) | Op::Dup |
+            Op::Eval(_) => self.depth += 1,
+            Op::StackSet(_) | Op::EnvSet(_) | Op::VarSet(_) | Op::Discard | Op::GotoIfNil(_) |
+            Op::Return => self.depth -= 1,
+            Op::Call(n) | Op::DiscardUnder(n) => self.depth -= n,
+            _ => (),
+        }
+        if self.depth > self.max_depth {
+            self.max_depth = self.depth;
+        }
+
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    fn constant(&mut self, obj: LispObj) -> usize {
+        // Only atoms are shared, as other objects may be compared with eq
+        if let LispObj::Atm(_) = obj {
+            if let Some(i) = self.constants.iter().position( |c| *c == obj ) {
+                return i;
+            }
+        }
+        self.constants.push(obj);
+        self.constants.len() - 1
+    }
+
+    fn emit_const(&mut self, obj: LispObj) {
+        let i = self.constant(obj);
+        self.emit(Op::Const(i));
+    }
+
+    /// Set the target of the jump at `at` to the next instruction
+    fn patch(&mut self, at: usize) {
+        let to = self.code.len();
+        self.code[at] = match self.code[at] {
+            Op::Goto(_) => Op::Goto(to),
+            Op::GotoIfNil(_) => Op::GotoIfNil(to),
+            Op::GotoIfNilElsePop(_) => Op::GotoIfNilElsePop(to),
+            Op::GotoIfNotNilElsePop(_) => Op::GotoIfNotNilElsePop(to),
+            op => panic!("Can not patch {:?}", op),
+        };
+    }
+
+    /// Would a binding of name be on the stack
+    fn binds_lexically(&self, name: Atom) -> bool {
+        self.lexical && self.lsp.binds_lexically(&self.env, name)
+    }
+
+    fn locate(&mut self, name: Atom) -> VarLoc {
+        if let Some(&(_, slot)) = self.scope.iter().rev().find( |&&(var, _)| var == name ) {
+            return VarLoc::Slot(slot);
+        }
+
+        let mut next = self.env.clone();
+        while let LispObj::Cons(cell) = next {
+            if let LispObj::Cons(binding) = cell.car() {
+                if binding.car() == LispObj::Atm(name) {
+                    return VarLoc::Env(self.constant(LispObj::Cons(binding)));
+                }
+            }
+            next = cell.cdr();
+        }
+
+        VarLoc::Dynamic
+    }
+
+    fn compile_var_ref(&mut self, name: Atom) {
+        match self.locate(name) {
+            VarLoc::Slot(slot) => self.emit(Op::StackRef(slot)),
+            VarLoc::Env(i) => self.emit(Op::EnvRef(i)),
+            VarLoc::Dynamic => {
+                let i = self.constant(LispObj::Atm(name));
+                self.emit(Op::VarRef(i))
+            },
+        };
+    }
+
+    /// Set a variable to the value on top of the stack, leaving it there
+    fn compile_var_set(&mut self, name: Atom) {
+        self.emit(Op::Dup);
+        match self.locate(name) {
+            VarLoc::Slot(slot) => self.emit(Op::StackSet(slot)),
+            VarLoc::Env(i) => self.emit(Op::EnvSet(i)),
+            VarLoc::Dynamic => {
+                let i = self.constant(LispObj::Atm(name));
+                self.emit(Op::VarSet(i))
+            },
+        };
+    }
+
+    /// Compile a form, leaving its value on the stack
+    fn compile(&mut self, form: &LispObj) -> Result<(), LispError> {
+        match form {
+            &LispObj::Atm(name) if self.lsp.is_constant(name) => self.emit_const(form.clone()),
+            &LispObj::Atm(name) => self.compile_var_ref(name),
+            &LispObj::Sym(_) => self.compile_fallback(form.clone()),
+            LispObj::Cons(cons) => return self.compile_form(cons),
+            obj => self.emit_const(obj.clone()),
+        }
+        Ok(())
+    }
+
+    fn compile_progn(&mut self, forms: &[LispObj]) -> Result<(), LispError> {
+        if forms.is_empty() {
+            self.emit_const(LispObj::nil());
+        }
+
+        for (i, form) in forms.iter().enumerate() {
+            if i > 0 {
+                self.emit(Op::Discard);
+            }
+            self.compile(form)?;
+        }
+        Ok(())
+    }
+
+    /// Leave the form to the interpreter
+    ///
+    /// Uninterned symbols are only bound by the interpreter, so it evaluates
+    /// the forms which refer to them too.
+    fn compile_fallback(&mut self, form: LispObj) {
+        let vars = if self.lexical { self.scope.clone() } else { Vec::new() };
+        self.fallbacks.push((form, vars));
+        let i = self.fallbacks.len() - 1;
+        self.emit(Op::Eval(i));
+    }
+
+    fn compile_form(&mut self, form: &Cons) -> Result<(), LispError> {
+        let args = form.cdr().list_to_vec()?;
+        let name = match form.car() {
+            LispObj::Atm(name) => name,
+            _ => {
+                self.compile_fallback(LispObj::Cons(form.clone()));
+                return Ok(());
+            },
+        };
+
+        let special = match self.lsp.globals.get_fun(name) {
+            Some(LispObj::Cons(ref def)) if def.car() == LispObj::Atm(symbols::MACRO) => {
+                let fun = Lsp::macro_function(def)?;
+                let expansion = self.lsp.funcall(&fun, &args)?;
+                return self.compile(&expansion);
+            },
+            Some(LispObj::ExtFun(ref fun)) => match fun.eval_args() {
+                EvalOption::Unevaluated => true,
+                EvalOption::Evaluated => false,
+            },
+            _ => false,
+        };
+
+        if special {
+            let name = self.lsp.stringify(name).to_owned();
+            return self.compile_special(&name, form, &args);
+        }
+
+        self.emit_const(LispObj::Atm(name));
+        for arg in args.iter() {
+            self.compile(arg)?;
+        }
+        self.emit(Op::Call(args.len()));
+        Ok(())
+    }
+
+    fn compile_special(&mut self, name: &str, form: &Cons, args: &[LispObj])
+                       -> Result<(), LispError> {
+        match (name, args.len()) {
+            ("quote", 1) => self.emit_const(args[0].clone()),
+            ("function", 1) => match args[0] {
+                LispObj::Cons(_) if self.lexical => self.compile_fallback(LispObj::Cons(form.clone())),
+                ref fun => self.emit_const(fun.clone()),
+            },
+            ("progn", _) => self.compile_progn(args)?,
+            ("if", n) if n >= 2 => {
+                self.compile(&args[0])?;
+                let to_else = self.emit(Op::GotoIfNil(0));
+                self.compile(&args[1])?;
+                let to_end = self.emit(Op::Goto(0));
+                self.depth -= 1;
+                self.patch(to_else);
+                self.compile_progn(&args[2..])?;
+                self.patch(to_end);
+            },
+            ("when", n) | ("unless", n) if n >= 1 => {
+                self.compile(&args[0])?;
+                let to_else = self.emit(Op::GotoIfNil(0));
+                if name == "when" {
+                    self.compile_progn(&args[1..])?;
+                } else {
+                    self.emit_const(LispObj::nil());
+                }
+                let to_end = self.emit(Op::Goto(0));
+                self.depth -= 1;
+                self.patch(to_else);
+                if name == "when" {
+                    self.emit_const(LispObj::nil());
+                } else {
+                    self.compile_progn(&args[1..])?;
+                }
+                self.patch(to_end);
+            },
+            ("and", _) | ("or", _) => {
+                if args.is_empty() {
+                    self.emit_const(LispObj::from_bool(name == "and"));
+                }
+
+                let mut to_end = Vec::new();
+                for (i, arg) in args.iter().enumerate() {
+                    self.compile(arg)?;
+                    if i + 1 < args.len() {
+                        to_end.push(self.emit(if name == "and" {
+                            Op::GotoIfNilElsePop(0)
+                        } else {
+                            Op::GotoIfNotNilElsePop(0)
+                        }));
+                        self.depth -= 1;
+                    }
+                }
+                for at in to_end {
+                    self.patch(at);
+                }
+            },
+            ("cond", _) => {
+                let mut to_end = Vec::new();
+
+                for clause in args.iter() {
+                    let clause = clause.list_to_vec()?;
+                    if clause.is_empty() {
+                        continue;
+                    }
+
+                    self.compile(&clause[0])?;
+                    if clause.len() == 1 {
+                        to_end.push(self.emit(Op::GotoIfNotNilElsePop(0)));
+                        self.depth -= 1;
+                    } else {
+                        let to_next = self.emit(Op::GotoIfNil(0));
+                        self.compile_progn(&clause[1..])?;
+                        to_end.push(self.emit(Op::Goto(0)));
+                        self.depth -= 1;
+                        self.patch(to_next);
+                    }
+                }
+
+                self.emit_const(LispObj::nil());
+                for at in to_end {
+                    self.patch(at);
+                }
+            },
+            ("while", n) if n >= 1 => {
+                let start = self.code.len();
+                self.compile(&args[0])?;
+                let to_end = self.emit(Op::GotoIfNil(0));
+                self.compile_progn(&args[1..])?;
+                self.emit(Op::Discard);
+                self.emit(Op::Goto(start));
+                self.patch(to_end);
+                self.emit_const(LispObj::nil());
+            },
+            ("prog1", n) if n >= 1 => {
+                self.compile(&args[0])?;
+                for arg in args[1..].iter() {
+                    self.compile(arg)?;
+                    self.emit(Op::Discard);
+                }
+            },
+            ("setq", n) if n % 2 == 0 => {
+                if n == 0 {
+                    self.emit_const(LispObj::nil());
+                }
+
+                if args.chunks(2).any( |pair| is_uninterned(&pair[0]) ) {
+                    self.compile_fallback(LispObj::Cons(form.clone()));
+                    return Ok(());
+                }
+                for (i, pair) in args.chunks(2).enumerate() {
+                    let name = match pair[0] {
+                        LispObj::Atm(name) => name,
+                        ref obj => return Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+                    };
+                    if i > 0 {
+                        self.emit(Op::Discard);
+                    }
+                    self.compile(&pair[1])?;
+                    self.compile_var_set(name);
+                }
+            },
+            ("let", n) | ("let*", n) if n >= 1 && !binds_uninterned(&args[0]) =>
+                self.compile_let(name == "let*", args)?,
+            _ => self.compile_fallback(LispObj::Cons(form.clone())),
+        }
+        Ok(())
+    }
+
+    fn compile_let(&mut self, sequential: bool, args: &[LispObj]) -> Result<(), LispError> {
+        let bindings = args[0].list_to_vec()?;
+        let scope = self.scope.len();
+        let base = self.depth;
+        let mut dynamic = Vec::new();
+
+        let mut vars = Vec::with_capacity(bindings.len());
+        for binding in bindings.iter() {
+            vars.push(match binding {
+                &LispObj::Atm(name) => (name, None),
+                LispObj::Cons(b) => match b.car() {
+                    LispObj::Atm(name) => (name, b.cdr().car().ok()),
+                    obj => return Err(LispError::wrong_type(symbols::SYMBOLP, &obj)),
+                },
+                obj => return Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+            });
+        }
+        if let Some(&(name, _)) = vars.iter().find( |&&(name, _)| self.lsp.is_constant(name) ) {
+            return Err(LispError::setting_constant(name));
+        }
+
+        if sequential && vars.iter().any( |&(name, _)| !self.binds_lexically(name) ) {
+            self.emit(Op::PushScope);
+            dynamic.push(());
+        }
+
+        for (i, &(name, ref init)) in vars.iter().enumerate() {
+            match *init {
+                Some(ref init) => self.compile(init)?,
+                None => self.emit_const(LispObj::nil()),
+            }
+
+            if sequential {
+                self.bind(name, base + i);
+            }
+        }
+
+        if !sequential {
+            if vars.iter().any( |&(name, _)| !self.binds_lexically(name) ) {
+                self.emit(Op::PushScope);
+                dynamic.push(());
+            }
+            for (i, &(name, _)) in vars.iter().enumerate() {
+                self.bind(name, base + i);
+            }
+        }
+
+        self.compile_progn(&args[1..])?;
+
+        if !dynamic.is_empty() {
+            self.emit(Op::PopScope);
+        }
+        if !vars.is_empty() {
+            self.emit(Op::DiscardUnder(vars.len()));
+        }
+        self.scope.truncate(scope);
+        Ok(())
+    }
+
+    /// Bind a variable to the value in a stack slot
+    fn bind(&mut self, name: Atom, slot: usize) {
+        if self.binds_lexically(name) {
+            self.scope.push((name, slot));
+        } else {
+            let i = self.constant(LispObj::Atm(name));
+            self.emit(Op::Bind(i, slot));
+        }
+    }
+}
+
+fn is_uninterned(var: &LispObj) -> bool {
+    matches!(var, &LispObj::Sym(_))
+}
+
+/// Does a let varlist bind an uninterned symbol
+fn binds_uninterned(varlist: &LispObj) -> bool {
+    varlist.list_to_vec().unwrap_or_default().iter().any( |binding| match binding {
+        LispObj::Cons(b) => is_uninterned(&b.car()),
+        var => is_uninterned(var),
+    })
+}
+
+/// Compile `(ARGS . BODY)`, in env if this is a closure
+pub fn compile_lambda(lsp: &mut Lsp, name: Atom, def: &LispObj, env: Option<LispObj>)
+                      -> Result<Compiled, LispError> {
+    let args = ArgSpecs::parse(&def.car()?)?;
+    compile_body(lsp, name, args, &def.cdr()?, env)
+}
+
+fn compile_body(lsp: &mut Lsp, name: Atom, args: ArgSpecs, body: &LispObj, env: Option<LispObj>)
+                -> Result<Compiled, LispError> {
+    let mut body = body.list_to_vec()?;
+
+    // The documentation and interactive spec are not needed to run it
+    if body.len() > 1 {
+        if let LispObj::Str(_) = body[0] {
+            body.remove(0);
+        }
+    }
+    if body.len() > 1 {
+        if let LispObj::Cons(ref form) = body[0] {
+            if form.car() == LispObj::Atm(symbols::INTERACTIVE) {
+                body.remove(0);
+            }
+        }
+    }
+
+    let lexical = env.is_some();
+    let mut compiler = Compiler {
+        lsp,
+        lexical,
+        env: env.unwrap_or(LispObj::nil()),
+        constants: Vec::new(),
+        fallbacks: Vec::new(),
+        code: Vec::new(),
+        depth: 0,
+        max_depth: 0,
+        scope: Vec::new(),
+    };
+
+    if lexical {
+        // Special arguments are bound dynamically as well, in the scope which
+        // Compiled::call pushes
+        for (slot, spec) in args.iter().enumerate() {
+            compiler.depth += 1;
+            compiler.bind(spec.name(), slot);
+        }
+    }
+    let nargs = compiler.depth;
+
+    compiler.compile_progn(&body)?;
+    compiler.emit(Op::Return);
+    debug_assert_eq!(compiler.depth, nargs);
+
+    Ok(Compiled {
+        name,
+        args,
+        lexical,
+        env: compiler.env,
+        constants: compiler.constants,
+        fallbacks: compiler.fallbacks,
+        code: compiler.code,
+        max_depth: compiler.max_depth,
+    })
+}
+
+/// Compile a function definition
+///
+/// Lambda and closure forms, and interpreted functions, are compiled. Macros
+/// have their function compiled. Anything else is returned as it is.
+pub fn compile_definition(lsp: &mut Lsp, name: Atom, def: &LispObj)
+                          -> Result<LispObj, LispError> {
+    match def {
+        LispObj::Lambda(fun) => {
+            let env = fun.env().map( |env| LispObj::Cons(env.clone()) );
+            let compiled = compile_body(lsp, name, fun.args().clone(), &fun.body(), env)?;
+            Ok(LispObj::extern_fun(compiled))
+        },
+        LispObj::Cons(cons) => match cons.car() {
+            LispObj::Atm(symbols::LAMBDA) =>
+                Ok(LispObj::extern_fun(compile_lambda(lsp, name, &cons.cdr(), None)?)),
+            LispObj::Atm(symbols::CLOSURE) => {
+                let closure = cons.cdr();
+                let env = closure.car()?;
+                Ok(LispObj::extern_fun(compile_lambda(lsp, name, &closure.cdr()?, Some(env))?))
+            },
+            LispObj::Atm(symbols::MACRO) => {
+                let fun = Lsp::macro_function(cons)?;
+                let compiled = compile_definition(lsp, name, &fun)?;
+                Ok(LispObj::cons(LispObj::Atm(symbols::MACRO), compiled))
+            },
+            _ => Ok(def.clone()),
+        },
+        obj => Ok(obj.clone()),
+    }
+}
+
+def_builtin! { "byte-compile", ByteCompileBuiltin, Evaluated, 1, 1, lsp, args; {
+    match args.next() {
+        Some(&LispObj::Atm(name)) => {
+            let def = lsp.function(name)?;
+            let compiled = compile_definition(lsp, name, &def)?;
+            lsp.globals.get_or_intern(name).set_fun(compiled.clone());
+            Ok(compiled)
+        },
+        Some(def) => compile_definition(lsp, symbols::ANONYMOUS, def),
+        None => Err(LispError::error("byte-compile requires one argument")),
+    }
+}}
+
+// Evaluate a file and compile the functions and macros it defines. There is
+// no compiled file format, so rather than writing a `.elc` file this leaves
+// the compiled definitions in place, as if the result had been loaded.
+def_builtin! { "byte-compile-file", ByteCompileFileBuiltin, Evaluated, 1, 2, lsp, args; {
+    let path = match args.next() {
+        Some(LispObj::Str(path)) => path.to_string(),
+        Some(obj) => return Err(LispError::wrong_type(symbols::STRINGP, obj)),
+        None => return Err(LispError::error("byte-compile-file requires one argument")),
+    };
+
+    let mut src = String::new();
+    File::open(&path)
+        .and_then( |mut fh| fh.read_to_string(&mut src) )
+        .map_err( |e| LispError::file_error("Opening input file", e) )?;
+
+    let sexp = lsp.read_source(&src, Some(&path))?;
+    lsp.eval_source(&src, &sexp)?;
+
+    let defun = LispObj::Atm(lsp.atomize("defun"));
+    let defmacro = LispObj::Atm(lsp.atomize("defmacro"));
+    for form in sexp.cdr()?.iter() {
+        if let LispObj::Cons(form) = form {
+            if form.car() == defun || form.car() == defmacro {
+                if let LispObj::Atm(name) = form.cdr().car()? {
+                    let def = lsp.function(name)?;
+                    let compiled = compile_definition(lsp, name, &def)?;
+                    lsp.globals.get_or_intern(name).set_fun(compiled);
+                }
+            }
+        }
+    }
+
+    Ok(LispObj::t())
+}}
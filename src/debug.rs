@@ -0,0 +1,315 @@
+// Copyright (C) 2017 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Interactive debugger
+//!
+//! This is entered when an error is signalled and `debug-on-error` is set, on
+//! entry to a function named with `debug-on-entry`, when stepping or when
+//! `debug` is called. It reads commands from a `DebugIo`, which is stdin and
+//! stdout in the REPL, but may be anything which can supply lines of text.
+//!
+//! The commands are a terse version of those in the Emacs `*Backtrace*`
+//! buffer; see `HELP`.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::rc::Rc;
+
+use super::*;
+
+/// Where the debugger gets its commands from and writes its output to
+pub trait DebugIo {
+    /// Read one command, None if there is no more input
+    fn read_line(&mut self) -> Option<String>;
+    fn write(&mut self, text: &str);
+}
+
+/// Read commands from stdin and write to stdout
+pub struct StdDebugIo;
+
+impl DebugIo for StdDebugIo {
+    fn read_line(&mut self) -> Option<String> {
+        let stdin = io::stdin();
+        let mut line = String::new();
+
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line),
+        }
+    }
+
+    fn write(&mut self, text: &str) {
+        print!("{}", text);
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Read commands from a list and collect the output, for scripts and tests
+pub struct ScriptedDebugIo {
+    input: VecDeque<String>,
+    output: Rc<RefCell<String>>,
+}
+
+impl ScriptedDebugIo {
+    /// Returns the debugger input and a handle on what it writes
+    pub fn new(commands: &[&str]) -> (ScriptedDebugIo, Rc<RefCell<String>>) {
+        let output = Rc::new(RefCell::new(String::new()));
+        let io = ScriptedDebugIo {
+            input: commands.iter().map( |c| c.to_string() ).collect(),
+            output: Rc::clone(&output),
+        };
+        (io, output)
+    }
+}
+
+impl DebugIo for ScriptedDebugIo {
+    fn read_line(&mut self) -> Option<String> {
+        self.input.pop_front()
+    }
+
+    fn write(&mut self, text: &str) {
+        self.output.borrow_mut().push_str(text);
+    }
+}
+
+/// Why the debugger was entered
+pub enum DebugReason<'a> {
+    Error(&'a LispError),
+    Entry(Atom),
+    Step,
+    Explicit,
+}
+
+/// What to do once the debugger exits
+#[derive(Debug, PartialEq)]
+pub enum DebugAction {
+    Continue,
+    Quit,
+}
+
+/// A form on the evaluator's stack, with the bindings it can see
+pub struct Activation {
+    pub form: LispObj,
+    /// How many of `Lsp::locals` were pushed when the form was entered
+    pub locals: usize,
+    pub lexenv: LispObj,
+}
+
+const HELP: &str = "\
+bt          show the backtrace, the selected frame is marked with *
+f N         select frame N, 0 is the innermost
+l           show the variables bound in the selected frame
+e EXPR      evaluate EXPR in the selected frame
+d           continue, then stop again at the next form
+c           continue; an error is signalled as usual
+q           quit to top level
+";
+
+impl Lsp {
+    /// Use io for the debugger, without this the debugger is never entered
+    pub fn set_debug_io(&mut self, io: Box<dyn DebugIo>) {
+        self.debug_io = Some(io);
+    }
+
+    /// Debug on entry to the named function
+    pub fn debug_on_entry(&mut self, name: Atom) {
+        self.debug_entry.insert(name);
+    }
+
+    /// Stop debugging on entry to the function, or all functions if None
+    pub fn cancel_debug_on_entry(&mut self, name: Option<Atom>) {
+        match name {
+            Some(name) => { self.debug_entry.remove(&name); },
+            None => self.debug_entry.clear(),
+        }
+    }
+
+    /// Whether the debugger should be entered for this error
+    ///
+    /// That is if debug-on-error is t or contains one of the error's
+    /// conditions, and no condition-case will handle it.
+    pub fn wants_debugger(&mut self, err: &LispError) -> bool {
+        if self.debug_io.is_none() || self.debugging {
+            return false;
+        }
+
+        let conditions = self.error_conditions(err.symbol);
+        let debug = match self.eval_atm_val(symbols::DEBUG_ON_ERROR) {
+            Ok(LispObj::Atm(symbols::NIL)) | Err(_) => false,
+            Ok(LispObj::Cons(conds)) => conds.iter().any( |cond| match cond {
+                LispObj::Atm(c) => conditions.contains(&c),
+                _ => false,
+            }),
+            Ok(_) => conditions.contains(&symbols::ERROR),
+        };
+
+        debug && !self.handlers.iter().any( |handlers| {
+            self.find_handler(&conditions, handlers).is_some()
+        })
+    }
+
+    /// Run the debugger until it is told to continue or quit
+    pub fn debug(&mut self, reason: DebugReason) -> DebugAction {
+        let mut io = match self.debug_io.take() {
+            Some(io) => io,
+            None => return DebugAction::Continue,
+        };
+        self.debugging = true;
+
+        let header = match reason {
+            DebugReason::Error(err) => {
+                let mut data = String::new();
+                let _ = self.print(&mut data, &err.to_lisp());
+                format!("Debugger entered--Lisp error: {}\n", data)
+            },
+            DebugReason::Entry(name) =>
+                format!("Debugger entered--entering a function: {}\n", self.stringify(name)),
+            DebugReason::Step =>
+                "Debugger entered--beginning evaluation of function call form:\n".to_owned(),
+            DebugReason::Explicit => "Debugger entered\n".to_owned(),
+        };
+        io.write(&header);
+
+        let mut selected = 0;
+        io.write(&self.debug_backtrace(selected));
+
+        let action = loop {
+            io.write("Debug> ");
+            let line = match io.read_line() {
+                Some(line) => line,
+                None => break DebugAction::Quit,
+            };
+            let line = line.trim();
+            let (cmd, arg) = match line.find(' ') {
+                Some(i) => (&line[..i], line[i + 1..].trim()),
+                None => (line, ""),
+            };
+
+            match cmd {
+                "" => (),
+                "bt" => io.write(&self.debug_backtrace(selected)),
+                "f" => match arg.parse::<usize>() {
+                    Ok(n) if n < self.stack.len() => {
+                        selected = n;
+                        io.write(&self.debug_backtrace(selected));
+                    },
+                    _ => io.write("No such frame\n"),
+                },
+                "l" => io.write(&self.debug_locals(selected)),
+                "e" => io.write(&self.debug_eval(selected, arg)),
+                "d" => {
+                    self.debug_step = true;
+                    break DebugAction::Continue;
+                },
+                "c" => break DebugAction::Continue,
+                "q" => break DebugAction::Quit,
+                "h" | "?" => io.write(HELP),
+                _ => io.write(&format!("Unknown command {}, try h for help\n", cmd)),
+            }
+        };
+
+        self.debugging = false;
+        self.debug_io = Some(io);
+        action
+    }
+
+    /// The activation of frame n, where 0 is the innermost
+    fn activation(&self, n: usize) -> Option<&Activation> {
+        let len = self.stack.len();
+        if n < len {
+            Some(&self.stack[len - 1 - n])
+        } else {
+            None
+        }
+    }
+
+    fn debug_backtrace(&self, selected: usize) -> String {
+        let frames = self.backtrace();
+        let mut out = String::new();
+
+        for (i, line) in self.format_backtrace(&frames).lines().enumerate() {
+            let mark = if i == selected { '*' } else { ' ' };
+            out.push_str(&format!("{}{:2}{}\n", mark, i, line));
+        }
+        out
+    }
+
+    fn debug_locals(&self, selected: usize) -> String {
+        let act = match self.activation(selected) {
+            Some(act) => act,
+            None => return "No frame selected\n".to_owned(),
+        };
+        let mut out = String::new();
+
+        if let LispObj::Cons(ref env) = act.lexenv {
+            for binding in env.iter() {
+                if let LispObj::Cons(binding) = binding {
+                    let mut line = String::new();
+                    let _ = self.print(&mut line, &binding.car());
+                    line.push_str(" = ");
+                    let _ = self.print(&mut line, &binding.cdr());
+                    out.push_str(&format!("  {}\n", line));
+                }
+            }
+        }
+
+        for ns in self.locals[..act.locals].iter().rev() {
+            let mut syms: Vec<&Symbol> = ns.iter().collect();
+            syms.sort_by_key( |sym| self.stringify(sym.name) );
+
+            for sym in syms {
+                let mut line = format!("{} = ", self.stringify(sym.name));
+                match sym.get_val() {
+                    Some(val) => { let _ = self.print(&mut line, &val); },
+                    None => line.push_str("<void>"),
+                }
+                out.push_str(&format!("  {}\n", line));
+            }
+        }
+
+        if out.is_empty() {
+            out.push_str("No local variables\n");
+        }
+        out
+    }
+
+    /// Evaluate some source with the bindings of the selected frame
+    fn debug_eval(&mut self, selected: usize, src: &str) -> String {
+        let (depth, env) = match self.activation(selected) {
+            Some(act) => (act.locals, act.lexenv.clone()),
+            None => (self.locals.len(), self.lexenv.clone()),
+        };
+
+        let res = self.read(&src.to_owned()).and_then( |form| {
+            let inner = self.locals.split_off(depth);
+            let outer_env = mem::replace(&mut self.lexenv, env);
+            let res = self.eval(&form);
+            self.lexenv = outer_env;
+            self.locals.extend(inner);
+            res
+        });
+
+        match res {
+            Ok(val) => {
+                let mut out = String::new();
+                let _ = self.print(&mut out, &val);
+                out + "\n"
+            },
+            Err(e) => format!("{}\n", self.error_message(&e)),
+        }
+    }
+}
@@ -8,9 +8,11 @@ use std::cell::RefCell;
 use std::any::Any;
 use std::slice::Iter;
 
-use rselisp::{Lsp, LispObj, Sexp, LispForm, External};
+use rselisp::{Lsp, LispObj, LispForm, External};
 use rselisp::symbols::{self, Symbol, Atom, AtomRegistry};
 use rselisp::lambda::{Func, EvalOption};
+use rselisp::error::LispError;
+use rselisp::print::Output;
 
 use frame::{Frame, FrameProxy, OrbFrame, FrameCmd};
 use buffer::{Buffer};
@@ -164,12 +166,12 @@ impl LispForm for EventModifiers {
         ""
     }
 
-    fn to_lisp(&self) -> Result<LispObj, String> {
-        let mut mods = Sexp::new('[');
+    fn to_lisp(&self) -> Result<LispObj, LispError> {
+        let mut mods = Vec::new();
 
         macro_rules! c {
             ($field:ident) => {
-                if self.$field { mods.push(LispObj::Str("$field".to_owned())) }
+                if self.$field { mods.push(LispObj::str("$field")) }
             }
         }
 
@@ -178,10 +180,10 @@ impl LispForm for EventModifiers {
         c!(hyper);
         c!(alt);
 
-        Ok(LispObj::Sxp(mods))
+        Ok(LispObj::vec_from(&mods))
     }
 
-    fn as_any(&mut self) -> &mut Any {
+    fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
@@ -203,15 +205,15 @@ impl LispForm for BasicEvent {
         ""
     }
 
-    fn to_lisp(&self) -> Result<LispObj, String> {
+    fn to_lisp(&self) -> Result<LispObj, LispError> {
         Ok(match self {
-            &BasicEvent::Backspace => LispObj::Sxp(Sexp::vec_from(&[LispObj::Str("backspace".to_owned())])),
-            &BasicEvent::Del => LispObj::Sxp(Sexp::vec_from(&[LispObj::Str("delete".to_owned())])),
-            &BasicEvent::Char(c) => LispObj::Str(c.to_string().to_owned()),
+            &BasicEvent::Backspace => LispObj::vec_from(&[LispObj::str("backspace")]),
+            &BasicEvent::Del => LispObj::vec_from(&[LispObj::str("delete")]),
+            &BasicEvent::Char(c) => LispObj::string(c.to_string()),
         })
     }
 
-    fn as_any(&mut self) -> &mut Any {
+    fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
@@ -244,14 +246,14 @@ impl LispForm for Event {
         "event"
     }
 
-    fn to_lisp(&self) -> Result<LispObj, String> {
-        Ok(LispObj::Sxp(Sexp::from(&[
-            LispObj::Str("basic".to_owned()), self.basic.to_lisp()?,
-            LispObj::Str("modifiers".to_owned()), self.modifiers.to_lisp()?,
-        ])))
+    fn to_lisp(&self) -> Result<LispObj, LispError> {
+        Ok(LispObj::list_from(&[
+            LispObj::str("basic"), self.basic.to_lisp()?,
+            LispObj::str("modifiers"), self.modifiers.to_lisp()?,
+        ]))
     }
 
-    fn as_any(&mut self) -> &mut Any {
+    fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
@@ -313,7 +315,7 @@ impl Cursor {
         let frm = &*self.frame.borrow();
 
         buf.insert(self.index, text);
-        let (bounded_indx, content) = buf.layout(self.index as u16 + 1);
+        let (bounded_indx, content) = buf.layout((self.index + text.len()) as u16);
         self.index = bounded_indx as usize;
         frm.update(content)
     }
@@ -328,9 +330,31 @@ impl LispForm for Cursor {
         "cursor"
     }
 
-    fn as_any(&mut self) -> &mut Any {
+    fn as_any(&mut self) -> &mut dyn Any {
         self
     }
+
+    /// Printing to a cursor inserts at it, like printing to a marker
+    fn insert_text(&mut self, text: &str) -> Result<(), LispError> {
+        self.insert(text).map_err( |e| LispError::error(format!("Frame channel failed: {}", e)) )
+    }
+}
+
+/// Shows messages, and text printed to t, in the frame's echo area
+struct EchoArea {
+    frame: Rc<RefCell<FrameProxy>>,
+}
+
+impl Output for EchoArea {
+    fn write(&mut self, text: &str) {
+        self.message(text);
+    }
+
+    fn message(&mut self, text: &str) {
+        if let Err(e) = self.frame.borrow().echo(text.to_owned()) {
+            println!("FRAME CHANNEL FAILED: {}", e);
+        }
+    }
 }
 
 impl fmt::Debug for Cursor {
@@ -339,7 +363,7 @@ impl fmt::Debug for Cursor {
     }
 }
 
-def_builtin! { "forward-char", ForwardCharBuiltin, Evaluated, lsp, args; {
+def_builtin! { "forward-char", ForwardCharBuiltin, Evaluated, 0, 1, lsp, args; {
     let n = args.next().unwrap_or(&LispObj::Int(1)).int_val()?;
     let cur = &lsp.globals.get_val(symbols::CURRENT_CURSOR).unwrap();
 
@@ -368,18 +392,22 @@ pub fn start() {
     let mut lsp = Lsp::new();
 
     reg_funcs!(lsp; ForwardCharBuiltin, KeymapBuiltin, DefineKeyBuiltin);
+    lsp.set_output(Box::new(EchoArea { frame: Rc::clone(&framecell) }));
 
     lsp.set_global("global-map",
-                   LispObj::Ext(Rc::clone(&global_keymapcell) as External));
+                   LispObj::ext(Rc::clone(&global_keymapcell) as External));
     lsp.set_global("current-buffer",
-                   LispObj::Ext(Rc::clone(&bufcell) as External));
+                   LispObj::ext(Rc::clone(&bufcell) as External));
     lsp.set_global("current-frame",
-                   LispObj::Ext(Rc::clone(&framecell) as External));
+                   LispObj::ext(Rc::clone(&framecell) as External));
     lsp.set_global("current-cursor",
-                   LispObj::Ext(Rc::clone(&cursorcell) as External));
+                   LispObj::ext(Rc::clone(&cursorcell) as External));
 
     if let Err(e) = lsp.load("editor") {
-        println!("LISP ERROR: {}", e);
+        println!("LISP ERROR: {}", lsp.error_message(&e));
+        if let Some(ref frames) = e.backtrace {
+            print!("{}", lsp.format_backtrace(frames));
+        }
         return;
     }
 
@@ -417,8 +445,13 @@ pub fn start() {
                     })
                 };
                 if let Some(action) = lookup {
-                    match lsp.eval_inner(&action) {
-                        Err(e) => println!("LISP ERROR: {}", e),
+                    match lsp.eval(&action) {
+                        Err(e) => {
+                            println!("LISP ERROR: {}", lsp.error_message(&e));
+                            if let Some(ref frames) = e.backtrace {
+                                print!("{}", lsp.format_backtrace(frames));
+                            }
+                        },
                         Ok(LispObj::Atm(symbols::EXIT)) => break,
                         s => println!("LISP SAYS: {:?}", s),
                     }
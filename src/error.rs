@@ -0,0 +1,192 @@
+// Copyright (C) 2017 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::*;
+use symbols::Atom;
+use std::io;
+
+/// A signalled Lisp error
+///
+/// In Emacs an error is a pair of an error symbol and some data, which is
+/// usually a list. The error symbol has an `error-conditions` property which
+/// lists the conditions it belongs to (itself, its parents and `error`), this
+/// is what `condition-case` matches handlers against. The `error-message`
+/// property is used to produce a human readable description.
+///
+/// The backtrace is filled in by the evaluator as the error leaves the
+/// innermost form, it is not compared for equality.
+#[derive(Debug, Clone)]
+pub struct LispError {
+    pub symbol: Atom,
+    pub data: LispObj,
+    pub backtrace: Option<Vec<Frame>>,
+}
+
+impl PartialEq for LispError {
+    fn eq(&self, other: &LispError) -> bool {
+        self.symbol == other.symbol && self.data == other.data
+    }
+}
+
+impl From<fmt::Error> for LispError {
+    fn from(_: fmt::Error) -> LispError {
+        LispError::error("Error writing output")
+    }
+}
+
+impl LispError {
+    pub fn new(symbol: Atom, data: LispObj) -> LispError {
+        LispError {
+            symbol,
+            data,
+            backtrace: None,
+        }
+    }
+
+    /// A plain `error` with a message, the same as `(error "msg")`
+    pub fn error<S: Into<String>>(msg: S) -> LispError {
+        LispError::new(symbols::ERROR, LispObj::list_from(&[LispObj::string(msg.into())]))
+    }
+
+    /// Quit to top level
+    ///
+    /// The backtrace is empty so the debugger is not entered again.
+    pub fn quit() -> LispError {
+        LispError {
+            symbol: symbols::QUIT,
+            data: LispObj::nil(),
+            backtrace: Some(Vec::new()),
+        }
+    }
+
+    pub fn void_variable(name: Atom) -> LispError {
+        LispError::new(symbols::VOID_VARIABLE, LispObj::list_from(&[LispObj::atm(name)]))
+    }
+
+    pub fn void_function(name: Atom) -> LispError {
+        LispError::new(symbols::VOID_FUNCTION, LispObj::list_from(&[LispObj::atm(name)]))
+    }
+
+    pub fn invalid_function(obj: &LispObj) -> LispError {
+        LispError::new(symbols::INVALID_FUNCTION, LispObj::list_from(std::slice::from_ref(obj)))
+    }
+
+    /// The object did not satisfy the predicate, e.g. `(integerp obj)`
+    pub fn wrong_type(predicate: Atom, obj: &LispObj) -> LispError {
+        LispError::new(symbols::WRONG_TYPE_ARGUMENT,
+                       LispObj::list_from(&[LispObj::atm(predicate), obj.clone()]))
+    }
+
+    /// The function was called with the wrong number of arguments
+    pub fn wrong_number_of_args(fun: LispObj, count: usize) -> LispError {
+        LispError::new(symbols::WRONG_NUMBER_OF_ARGUMENTS,
+                       LispObj::list_from(&[fun, LispObj::Int(count as i64)]))
+    }
+
+    /// The reader found something it can not parse
+    pub fn invalid_read_syntax<S: Into<String>>(what: S) -> LispError {
+        LispError::new(symbols::INVALID_READ_SYNTAX,
+                       LispObj::list_from(&[LispObj::string(what.into())]))
+    }
+
+    /// The reader ran out of input in the middle of an object
+    pub fn end_of_file() -> LispError {
+        LispError::new(symbols::END_OF_FILE, LispObj::nil())
+    }
+
+    /// Evaluation went deeper than max-lisp-eval-depth, or the Rust stack
+    /// allows
+    pub fn excessive_lisp_nesting(depth: usize) -> LispError {
+        LispError::new(symbols::EXCESSIVE_LISP_NESTING,
+                       LispObj::list_from(&[LispObj::Int(depth as i64)]))
+    }
+
+    /// Division by zero
+    pub fn arith_error() -> LispError {
+        LispError::new(symbols::ARITH_ERROR, LispObj::nil())
+    }
+
+    /// A number was too large to be converted, such as an infinite float to
+    /// an integer
+    pub fn overflow_error(args: &[LispObj]) -> LispError {
+        LispError::new(symbols::OVERFLOW_ERROR, LispObj::list_from(args))
+    }
+
+    /// A throw to the catch for tag
+    ///
+    /// This is not an error but it unwinds the stack in the same way. The
+    /// backtrace is empty so that the debugger is not entered.
+    pub fn throw(tag: LispObj, value: LispObj) -> LispError {
+        LispError {
+            symbol: symbols::THROW,
+            data: LispObj::cons(tag, value),
+            backtrace: Some(Vec::new()),
+        }
+    }
+
+    /// Whether this is a throw rather than an error
+    pub fn is_throw(&self) -> bool {
+        self.symbol == symbols::THROW
+    }
+
+    /// There was no catch for a throw
+    pub fn no_catch(tag: LispObj, value: LispObj) -> LispError {
+        LispError::new(symbols::NO_CATCH, LispObj::list_from(&[tag, value]))
+    }
+
+    /// Evaluation took more steps than Lsp::set_step_limit allowed
+    pub fn step_limit_exceeded(limit: usize) -> LispError {
+        LispError::new(symbols::STEP_LIMIT_EXCEEDED, LispObj::list_from(&[LispObj::Int(limit as i64)]))
+    }
+
+    /// More was allocated than Lsp::set_alloc_limit allowed
+    pub fn memory_full() -> LispError {
+        LispError::new(symbols::MEMORY_FULL, LispObj::nil())
+    }
+
+    /// An attempt to set or bind nil, t or a keyword
+    pub fn setting_constant(name: Atom) -> LispError {
+        LispError::new(symbols::SETTING_CONSTANT, LispObj::list_from(&[LispObj::atm(name)]))
+    }
+
+    /// A list which should have an end loops back on itself
+    pub fn circular_list(list: &LispObj) -> LispError {
+        LispError::new(symbols::CIRCULAR_LIST, LispObj::list_from(std::slice::from_ref(list)))
+    }
+
+    /// An index or other argument was outside the range the function accepts
+    pub fn args_out_of_range(args: &[LispObj]) -> LispError {
+        LispError::new(symbols::ARGS_OUT_OF_RANGE, LispObj::list_from(args))
+    }
+
+    /// An I/O error while trying to do something with a file
+    pub fn file_error(doing: &str, e: io::Error) -> LispError {
+        let symbol = if e.kind() == io::ErrorKind::NotFound {
+            symbols::FILE_MISSING
+        } else {
+            symbols::FILE_ERROR
+        };
+
+        LispError::new(symbol, LispObj::list_from(&[LispObj::str(doing),
+                                                    LispObj::string(e.to_string())]))
+    }
+
+    /// The error as it would be bound by `condition-case`
+    ///
+    /// That is `(error-symbol . data)`.
+    pub fn to_lisp(&self) -> LispObj {
+        LispObj::cons(LispObj::atm(self.symbol), self.data.clone())
+    }
+}
@@ -22,6 +22,8 @@ pub trait Frame {
 pub enum FrameCmd {
     Show,
     Update(Content),
+    /// Show a message in the echo area
+    Echo(String),
     Quit,
 }
 
@@ -46,6 +48,10 @@ impl FrameProxy {
         self.send.send(FrameCmd::Update(cont))
     }
 
+    pub fn echo(&self, text: String) -> Result<(), SendError<FrameCmd>> {
+        self.send.send(FrameCmd::Echo(text))
+    }
+
     pub fn quit(&self) -> Result<(), SendError<FrameCmd>> {
         self.send.send(FrameCmd::Quit)
     }
@@ -64,7 +70,7 @@ impl LispForm for FrameProxy {
         "frame"
     }
 
-    fn as_any(&mut self) -> &mut Any {
+    fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
@@ -81,6 +87,7 @@ pub struct OrbFrame {
     send: Sender<UserEvent>,
     win: Option<Window>,
     mods: EventModifiers,
+    echo: String,
 }
 
 const FG_COLOUR: Color = Color::rgb(0xbd, 0xc3, 0xce);
@@ -95,6 +102,7 @@ impl OrbFrame {
             send: send,
             win: None,
             mods: EventModifiers::new(),
+            echo: String::new(),
         }
     }
 
@@ -165,9 +173,22 @@ impl OrbFrame {
             OrbFrame::draw_text_box(win, 0, h - 32, w, 16, "MODE LINE",
                                     Color::rgb(0xbd, 0xc3, 0xce),
                                     Color::rgb(0x24, 0x2a, 0x34));
-            OrbFrame::draw_text_box(win, 0, h - 16, w, 16, "Echo echo ...",
-                                    Color::rgb(0xbd, 0xc3, 0xce),
-                                    Color::rgb(0x2a, 0x2f, 0x38));
+            OrbFrame::draw_echo(win, &self.echo);
+            win.sync();
+        }
+    }
+
+    fn draw_echo(win: &mut Window, text: &str) {
+        let (w, h) = (win.width(), win.height());
+        OrbFrame::draw_text_box(win, 0, h - 16, w, 16, text,
+                                Color::rgb(0xbd, 0xc3, 0xce),
+                                Color::rgb(0x2a, 0x2f, 0x38));
+    }
+
+    fn echo(&mut self, text: String) {
+        self.echo = text;
+        if let Some(ref mut win) = self.win {
+            OrbFrame::draw_echo(win, &self.echo);
             win.sync();
         }
     }
@@ -184,6 +205,7 @@ impl OrbFrame {
                         FrameCmd::Show => self.show(),
                         FrameCmd::Quit => return ComResult::Quit,
                         FrameCmd::Update(doc) => self.update(doc),
+                        FrameCmd::Echo(text) => self.echo(text),
                     }
                 },
                 Err(TryRecvError::Empty) => break,
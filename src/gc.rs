@@ -0,0 +1,439 @@
+// Copyright (C) 2017 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Garbage collector for cyclic Lisp data
+//!
+//! Lisp objects are reference counted, which frees most things as soon as
+//! they are no longer used, but anything which refers to itself (a circular
+//! list, a closure which can see itself, a keymap containing its parent) will
+//! never reach a count of zero. So every heap object is also registered here
+//! and periodically we trace the heap to find and break such cycles.
+//!
+//! This works like the cycle detector in CPython. For each object we subtract
+//! the references held by other heap objects from its reference count. Any
+//! object left with a positive count must be referenced from outside the
+//! heap; from `Lsp::globals`, `Lsp::locals` or some handle on the Rust
+//! stack. These are the roots, everything reachable from them is marked and
+//! anything else can only be referenced by garbage. The unmarked objects are
+//! then cleared, which breaks the cycles and lets reference counting free
+//! them.
+//!
+//! The heap is per thread because `Rc` can not be shared between threads
+//! anyway.
+
+use std::rc::{Rc, Weak};
+use std::cell::{Cell, RefCell};
+use std::mem::{self, size_of};
+use fnv::FnvHashMap;
+
+use super::*;
+use symbols::SymbolData;
+
+/// Heap objects which are tracked by the collector
+enum Tracked {
+    Cons(Weak<RefCell<ConsCell>>),
+    Vector(Weak<RefCell<VectorCell>>),
+    Ref(Weak<RefCell<LispObj>>),
+    Symbol(Weak<RefCell<SymbolData>>),
+    Ext(Weak<RefCell<dyn LispForm>>),
+    HashTable(Weak<RefCell<HashTable>>),
+}
+
+/// A tracked object which is still alive
+enum Live {
+    Cons(Rc<RefCell<ConsCell>>),
+    Vector(VectorRef),
+    Ref(LispObjRef),
+    Symbol(Rc<RefCell<SymbolData>>),
+    Ext(External),
+    HashTable(HashTableRef),
+}
+
+/// The heap is not pruned of freed objects until it has at least this many
+const PRUNE_MIN: usize = 4096;
+
+thread_local! {
+    static HEAP: RefCell<Vec<Tracked>> = const { RefCell::new(Vec::new()) };
+    /// The length of HEAP at which freed objects are next removed from it
+    static PRUNE_AT: Cell<usize> = const { Cell::new(PRUNE_MIN) };
+    /// Bytes allocated since the last collection
+    static CONSED: Cell<usize> = const { Cell::new(0) };
+    /// Collect when this many bytes have been consed, see set_threshold
+    static THRESHOLD: Cell<usize> = const { Cell::new(2 * GC_DEFAULT_THRESHOLD) };
+    /// Bytes used by the objects which survived the last collection
+    static SURVIVED: Cell<usize> = const { Cell::new(0) };
+    /// A collection is running, so allocating must not start another
+    static COLLECTING: Cell<bool> = const { Cell::new(false) };
+    /// Bytes allocated in total, including strings
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The address of an object on the heap, used to identify it
+pub fn addr<T: ?Sized>(obj: &Rc<T>) -> usize {
+    &**obj as *const T as *const () as usize
+}
+
+fn track(obj: Tracked, size: usize) {
+    HEAP.with( |heap| {
+        let heap = &mut *heap.borrow_mut();
+        heap.push(obj);
+
+        // The weak references keep the memory of freed objects allocated
+        if heap.len() >= PRUNE_AT.with(Cell::get) {
+            heap.retain(Tracked::is_alive);
+            PRUNE_AT.with( |at| at.set(cmp::max(2 * heap.len(), PRUNE_MIN)) );
+        }
+    });
+    ALLOCATED.with( |total| total.set(total.get() + size) );
+
+    let consed = CONSED.with( |consed| {
+        consed.set(consed.get() + size);
+        consed.get()
+    });
+    // Waiting for as much to be consed as survived keeps the cost of tracing
+    // the survivors in proportion to the amount allocated
+    let threshold = cmp::max(THRESHOLD.with(Cell::get), SURVIVED.with(Cell::get));
+    if consed > threshold && !COLLECTING.with(Cell::get) {
+        collect();
+    }
+}
+
+/// How many bytes may be consed before allocating collects garbage
+///
+/// The interpreter collects when gc-cons-threshold is reached while
+/// evaluating, it sets this higher so that allocation only collects when it
+/// is not given the chance; in long running builtins or when Rust code is
+/// building Lisp data.
+pub fn set_threshold(bytes: usize) {
+    THRESHOLD.with( |threshold| threshold.set(bytes) );
+}
+
+/// Number of objects on the heap, including freed ones which have not been
+/// pruned yet
+pub fn tracked() -> usize {
+    HEAP.with( |heap| heap.borrow().len() )
+}
+
+/// Count the bytes used by a new string
+///
+/// Strings are stored by value so they are not tracked, but they still count
+/// towards Lsp::set_alloc_limit.
+pub fn count_string(size: usize) {
+    ALLOCATED.with( |total| total.set(total.get() + size) );
+}
+
+pub fn track_cons(cell: &Rc<RefCell<ConsCell>>) {
+    track(Tracked::Cons(Rc::downgrade(cell)), size_of::<ConsCell>());
+}
+
+pub fn track_vector(vec: &VectorRef) {
+    let size = size_of::<Vec<LispObj>>() + vec.borrow().len() * size_of::<LispObj>();
+    track(Tracked::Vector(Rc::downgrade(vec)), size);
+}
+
+pub fn track_ref(iref: &LispObjRef) {
+    track(Tracked::Ref(Rc::downgrade(iref)), size_of::<LispObj>());
+}
+
+pub fn track_symbol(data: &Rc<RefCell<SymbolData>>) {
+    track(Tracked::Symbol(Rc::downgrade(data)), size_of::<SymbolData>());
+}
+
+/// Track an external object so that cycles through it can be collected
+///
+/// Only the Lisp objects reported by `LispForm::trace` are followed.
+pub fn track_external(ext: &External) {
+    track(Tracked::Ext(Rc::downgrade(ext)), 0);
+}
+
+pub fn track_hash_table(table: &HashTableRef) {
+    track(Tracked::HashTable(Rc::downgrade(table)), size_of::<HashTable>());
+}
+
+/// Number of bytes allocated on the Lisp heap since the last collection
+pub fn consed() -> usize {
+    CONSED.with(Cell::get)
+}
+
+/// Number of bytes used by the objects which survived the last collection
+pub fn survived() -> usize {
+    SURVIVED.with(Cell::get)
+}
+
+/// Number of bytes of Lisp data this thread has ever allocated
+pub fn allocated() -> usize {
+    ALLOCATED.with(Cell::get)
+}
+
+/// Call f with the address of each heap object directly held by obj
+pub fn edges(obj: &LispObj, f: &mut dyn FnMut(usize)) {
+    match obj {
+        LispObj::Cons(cons) => f(addr(&cons.0)),
+        LispObj::Vector(vec) => f(addr(vec)),
+        LispObj::Ref(iref) => f(addr(iref)),
+        LispObj::Sym(sym) => f(sym.addr()),
+        LispObj::Ext(ext) => f(addr(ext)),
+        LispObj::HashTable(table) => f(addr(table)),
+        LispObj::Lambda(fun) => fun.edges(f),
+        _ => (),
+    }
+}
+
+impl Tracked {
+    fn is_alive(&self) -> bool {
+        match self {
+            Tracked::Cons(w) => Weak::strong_count(w) > 0,
+            Tracked::Vector(w) => Weak::strong_count(w) > 0,
+            Tracked::Ref(w) => Weak::strong_count(w) > 0,
+            Tracked::Symbol(w) => Weak::strong_count(w) > 0,
+            Tracked::Ext(w) => Weak::strong_count(w) > 0,
+            Tracked::HashTable(w) => Weak::strong_count(w) > 0,
+        }
+    }
+
+    fn upgrade(&self) -> Option<Live> {
+        match self {
+            Tracked::Cons(w) => w.upgrade().map(Live::Cons),
+            Tracked::Vector(w) => w.upgrade().map(Live::Vector),
+            Tracked::Ref(w) => w.upgrade().map(Live::Ref),
+            Tracked::Symbol(w) => w.upgrade().map(Live::Symbol),
+            Tracked::Ext(w) => w.upgrade().map(Live::Ext),
+            Tracked::HashTable(w) => w.upgrade().map(Live::HashTable),
+        }
+    }
+}
+
+impl Live {
+    fn addr(&self) -> usize {
+        match self {
+            Live::Cons(rc) => addr(rc),
+            Live::Vector(rc) => addr(rc),
+            Live::Ref(rc) => addr(rc),
+            Live::Symbol(rc) => addr(rc),
+            Live::Ext(rc) => addr(rc),
+            Live::HashTable(rc) => addr(rc),
+        }
+    }
+
+    fn kind(&self) -> usize {
+        match *self {
+            Live::Cons(_) => 0,
+            Live::Vector(_) => 1,
+            Live::Ref(_) => 2,
+            Live::Symbol(_) => 3,
+            Live::Ext(_) => 4,
+            Live::HashTable(_) => 5,
+        }
+    }
+
+    /// References from outside of our own list of live objects
+    fn strong_count(&self) -> usize {
+        let count = match self {
+            Live::Cons(rc) => Rc::strong_count(rc),
+            Live::Vector(rc) => Rc::strong_count(rc),
+            Live::Ref(rc) => Rc::strong_count(rc),
+            Live::Symbol(rc) => Rc::strong_count(rc),
+            Live::Ext(rc) => Rc::strong_count(rc),
+            Live::HashTable(rc) => Rc::strong_count(rc),
+        };
+        count - 1
+    }
+
+    /// Call f with each object held by this one
+    ///
+    /// Returns false if the object is currently borrowed for writing by
+    /// someone else, in which case it can't be traced.
+    fn trace(&self, f: &mut dyn FnMut(&LispObj)) -> bool {
+        match self {
+            Live::Cons(rc) => match rc.try_borrow() {
+                Ok(cell) => {
+                    f(&cell.car);
+                    f(&cell.cdr);
+                },
+                Err(_) => return false,
+            },
+            Live::Vector(rc) => match rc.try_borrow() {
+                Ok(vec) => for item in vec.iter() {
+                    f(item);
+                },
+                Err(_) => return false,
+            },
+            Live::Ref(rc) => match rc.try_borrow() {
+                Ok(obj) => f(&obj),
+                Err(_) => return false,
+            },
+            Live::Symbol(rc) => match rc.try_borrow() {
+                Ok(data) => {
+                    if let Some(ref val) = data.value {
+                        f(val);
+                    }
+                    if let Some(ref fun) = data.function {
+                        f(fun);
+                    }
+                    if let Some(ref props) = data.properties {
+                        for (_, val) in props.iter() {
+                            f(val);
+                        }
+                    }
+                },
+                Err(_) => return false,
+            },
+            Live::Ext(rc) => match rc.try_borrow() {
+                Ok(ext) => ext.trace(f),
+                Err(_) => return false,
+            },
+            Live::HashTable(rc) => match rc.try_borrow() {
+                Ok(table) => table.trace(f),
+                Err(_) => return false,
+            },
+        }
+        true
+    }
+
+    /// Drop everything this object refers to, moving it into garbage
+    ///
+    /// The garbage is dropped once the collector has finished with the heap.
+    /// Collection can happen while a builtin is allocating, so anything which
+    /// is borrowed is left alone; it will be freed by a later collection.
+    fn clear(&self, garbage: &mut Vec<LispObj>) {
+        match self {
+            Live::Cons(rc) => if let Ok(mut cell) = rc.try_borrow_mut() {
+                garbage.push(mem::replace(&mut cell.car, LispObj::nil()));
+                garbage.push(mem::replace(&mut cell.cdr, LispObj::nil()));
+            },
+            Live::Vector(rc) => if let Ok(mut vec) = rc.try_borrow_mut() {
+                garbage.extend(vec.drain(..));
+            },
+            Live::Ref(rc) => if let Ok(mut obj) = rc.try_borrow_mut() {
+                garbage.push(mem::replace(&mut *obj, LispObj::nil()));
+            },
+            Live::Symbol(rc) => if let Ok(mut data) = rc.try_borrow_mut() {
+                garbage.extend(data.value.take());
+                garbage.extend(data.function.take());
+                if let Some(props) = data.properties.take() {
+                    garbage.extend(props.into_iter().map( |(_, val)| val ));
+                }
+            },
+            Live::Ext(rc) => if let Ok(mut ext) = rc.try_borrow_mut() {
+                ext.clear();
+            },
+            Live::HashTable(rc) => if let Ok(mut table) = rc.try_borrow_mut() {
+                garbage.extend(table.drain());
+            },
+        }
+    }
+}
+
+/// The names and sizes of the object types in GcStats
+pub const KINDS: [(&str, usize); 6] = [
+    ("conses", size_of::<ConsCell>()),
+    ("vectors", size_of::<Vec<LispObj>>()),
+    ("refs", size_of::<LispObj>()),
+    ("symbols", size_of::<SymbolData>()),
+    ("externals", 0),
+    ("hash-tables", size_of::<HashTable>()),
+];
+
+/// How many objects of each type survived and how many were freed
+///
+/// Indexed in the same order as KINDS.
+#[derive(Debug, Default)]
+pub struct GcStats {
+    pub used: [usize; 6],
+    pub freed: [usize; 6],
+}
+
+/// Find and break reference cycles which are not reachable from any root
+pub fn collect() -> GcStats {
+    COLLECTING.with( |collecting| collecting.set(true) );
+    let mut stats = GcStats::default();
+    let tracked = HEAP.with( |heap| std::mem::take(&mut *heap.borrow_mut()) );
+    let live: Vec<Live> = tracked.iter().filter_map(Tracked::upgrade).collect();
+    drop(tracked);
+
+    let index: FnvHashMap<usize, usize> = live.iter().enumerate()
+        .map( |(i, obj)| (obj.addr(), i) )
+        .collect();
+
+    // Subtract the references from other heap objects, what is left must come
+    // from outside the heap.
+    let mut refs: Vec<usize> = live.iter().map(Live::strong_count).collect();
+    for obj in live.iter() {
+        obj.trace(&mut |child| edges(child, &mut |to| {
+            if let Some(&j) = index.get(&to) {
+                refs[j] -= 1;
+            }
+        }));
+    }
+
+    let mut marked: Vec<bool> = refs.iter().map( |&count| count > 0 ).collect();
+    let mut stack: Vec<usize> = (0..live.len()).filter( |&i| marked[i] ).collect();
+
+    while let Some(i) = stack.pop() {
+        let traced = live[i].trace(&mut |child| edges(child, &mut |to| {
+            if let Some(&j) = index.get(&to) {
+                if !marked[j] {
+                    marked[j] = true;
+                    stack.push(j);
+                }
+            }
+        }));
+
+        // Something is writing to this object, so we can't see what it
+        // holds; assume everything is reachable from it.
+        if !traced {
+            for (j, mark) in marked.iter_mut().enumerate() {
+                if !*mark {
+                    *mark = true;
+                    stack.push(j);
+                }
+            }
+        }
+    }
+
+    let mut garbage = Vec::new();
+    let mut survivors = Vec::new();
+    for (obj, &mark) in live.iter().zip(marked.iter()) {
+        if mark {
+            stats.used[obj.kind()] += 1;
+            survivors.push(match obj {
+                Live::Cons(rc) => Tracked::Cons(Rc::downgrade(rc)),
+                Live::Vector(rc) => Tracked::Vector(Rc::downgrade(rc)),
+                Live::Ref(rc) => Tracked::Ref(Rc::downgrade(rc)),
+                Live::Symbol(rc) => Tracked::Symbol(Rc::downgrade(rc)),
+                Live::Ext(rc) => Tracked::Ext(Rc::downgrade(rc)),
+                Live::HashTable(rc) => Tracked::HashTable(Rc::downgrade(rc)),
+            });
+        } else {
+            stats.freed[obj.kind()] += 1;
+            obj.clear(&mut garbage);
+        }
+    }
+
+    HEAP.with( |heap| {
+        let heap = &mut *heap.borrow_mut();
+        survivors.append(heap);
+        *heap = survivors;
+        PRUNE_AT.with( |at| at.set(cmp::max(2 * heap.len(), PRUNE_MIN)) );
+    });
+    CONSED.with( |consed| consed.set(0) );
+    let survived = stats.used.iter().zip(KINDS.iter()).map( |(&n, &(_, size))| n * size ).sum();
+    SURVIVED.with( |bytes| bytes.set(survived) );
+
+    drop(garbage);
+    drop(live);
+    COLLECTING.with( |collecting| collecting.set(false) );
+    stats
+}
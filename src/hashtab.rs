@@ -0,0 +1,633 @@
+// Copyright (C) 2017 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Hash tables
+//!
+//! Keys are compared with `eq`, `eql`, `equal` or a test registered with
+//! `define-hash-table-test`. The entries are kept in the order they were
+//! added, which is the order maphash visits them and the order they are
+//! printed in.
+//!
+//! The equality predicates, and the sxhash functions which agree with them,
+//! are also defined here.
+//!
+//! A user defined test is a pair of Lisp functions which may do anything,
+//! including modifying the table being searched. So the table is never
+//! borrowed while one of them is running.
+
+use std::hash::{Hash, Hasher};
+use fnv::{FnvHasher, FnvHashMap};
+
+use super::*;
+
+/// How deep into nested lists and vectors sxhash_equal looks
+const SXHASH_MAX_DEPTH: usize = 3;
+/// How many elements of a list or vector sxhash_equal looks at
+const SXHASH_MAX_LEN: usize = 7;
+/// How deeply nested lists and vectors equal will compare
+const EQUAL_MAX_DEPTH: usize = 200;
+
+pub type HashTableRef = Rc<RefCell<HashTable>>;
+
+/// How the keys of a hash table are compared
+#[derive(Clone)]
+pub enum HashTest {
+    Eq,
+    Eql,
+    Equal,
+    /// Defined with define-hash-table-test; the name, test and hash function
+    User(Atom, LispObj, LispObj),
+}
+
+pub struct HashTable {
+    test: HashTest,
+    /// The hash code, key and value of each entry, removed entries are None
+    entries: Vec<Option<(u64, LispObj, LispObj)>>,
+    /// The entries with each hash code
+    index: FnvHashMap<u64, Vec<usize>>,
+    count: usize,
+}
+
+impl fmt::Debug for HashTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HashTable({:?}, {:?})", self.test.name(), self.iter().collect::<Vec<_>>())
+    }
+}
+
+fn hash_of<T: Hash>(val: T) -> u64 {
+    let mut hasher = FnvHasher::default();
+    val.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Whether two objects are the same object
+///
+/// Floats are stored by value in a LispObj, so there is no identity to
+/// compare and they are eq when their bits are.
+pub fn eq(a: &LispObj, b: &LispObj) -> bool {
+    match (a, b) {
+        (&LispObj::Int(x), &LispObj::Int(y)) => x == y,
+        (&LispObj::Float(x), &LispObj::Float(y)) => x.to_bits() == y.to_bits(),
+        (LispObj::Big(x), LispObj::Big(y)) => Rc::ptr_eq(x, y),
+        (LispObj::Str(x), LispObj::Str(y)) => Rc::ptr_eq(x, y),
+        (&LispObj::Atm(x), &LispObj::Atm(y)) => x == y,
+        (LispObj::Sym(x), LispObj::Sym(y)) => x.addr() == y.addr(),
+        (LispObj::Cons(x), LispObj::Cons(y)) => x.ptr_eq(y),
+        (LispObj::Vector(x), LispObj::Vector(y)) => Rc::ptr_eq(x, y),
+        (LispObj::Lambda(x), LispObj::Lambda(y)) => x.addr() == y.addr(),
+        (LispObj::Ref(x), LispObj::Ref(y)) => Rc::ptr_eq(x, y),
+        (LispObj::Ext(x), LispObj::Ext(y)) => gc::addr(x) == gc::addr(y),
+        (LispObj::ExtFun(x), LispObj::ExtFun(y)) => gc::addr(x) == gc::addr(y),
+        (LispObj::HashTable(x), LispObj::HashTable(y)) => Rc::ptr_eq(x, y),
+        _ => false,
+    }
+}
+
+/// Whether two objects are eq or are numbers of the same type and value
+pub fn eql(a: &LispObj, b: &LispObj) -> bool {
+    match (a, b) {
+        (LispObj::Big(x), LispObj::Big(y)) => x == y,
+        _ => eq(a, b),
+    }
+}
+
+/// Whether two objects are eql or are strings, lists or vectors with equal
+/// contents
+///
+/// As in Emacs this fails on a circular list, or a structure nested too
+/// deeply to compare, rather than looping or overflowing the stack. Each
+/// pair of elements compared counts as a step, see Lsp::check_limits.
+pub fn equal(lsp: &Lsp, a: &LispObj, b: &LispObj) -> Result<bool, LispError> {
+    equal_depth(lsp, a, b, 0)
+}
+
+fn equal_depth(lsp: &Lsp, a: &LispObj, b: &LispObj, depth: usize) -> Result<bool, LispError> {
+    if depth > EQUAL_MAX_DEPTH {
+        return Err(LispError::error("Stack overflow in equal"));
+    }
+    lsp.check_limits()?;
+
+    match (a, b) {
+        (LispObj::Cons(x), LispObj::Cons(y)) => {
+            let (mut x, mut y) = (x.clone(), y.clone());
+            // Brent's algorithm; a loop in x is found when x catches up with
+            // the tortoise, which is moved to x at every power of two steps
+            let (mut tortoise, mut steps, mut power) = (x.clone(), 0, 1);
+
+            loop {
+                if x.ptr_eq(&y) {
+                    return Ok(true);
+                }
+                if !equal_depth(lsp, &x.car(), &y.car(), depth + 1)? {
+                    return Ok(false);
+                }
+
+                match (x.cdr(), y.cdr()) {
+                    (LispObj::Cons(c), LispObj::Cons(d)) => {
+                        x = c;
+                        y = d;
+                    },
+                    (ref c, ref d) => return equal_depth(lsp, c, d, depth + 1),
+                }
+
+                if x.ptr_eq(&tortoise) {
+                    return Err(LispError::circular_list(a));
+                }
+                steps += 1;
+                if steps == power {
+                    tortoise = x.clone();
+                    steps = 0;
+                    power *= 2;
+                }
+            }
+        },
+        (LispObj::Vector(x), LispObj::Vector(y)) => {
+            if Rc::ptr_eq(x, y) {
+                return Ok(true);
+            }
+            let (x, y) = (x.borrow(), y.borrow());
+            if x.len() != y.len() {
+                return Ok(false);
+            }
+            for (a, b) in x.iter().zip(y.iter()) {
+                if !equal_depth(lsp, a, b, depth + 1)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        },
+        (LispObj::Str(x), LispObj::Str(y)) => Ok(x == y),
+        _ => Ok(eql(a, b)),
+    }
+}
+
+/// A hash code which is the same for objects which are eq
+pub fn sxhash_eq(obj: &LispObj) -> u64 {
+    match obj {
+        &LispObj::Int(i) => hash_of(i),
+        &LispObj::Float(f) => hash_of(f.to_bits()),
+        LispObj::Big(i) => hash_of(gc::addr(i)),
+        LispObj::Str(s) => hash_of(gc::addr(s)),
+        &LispObj::Atm(a) => hash_of(a),
+        LispObj::Sym(s) => hash_of(s.addr()),
+        LispObj::Cons(cons) => hash_of(gc::addr(&cons.0)),
+        LispObj::Vector(v) => hash_of(gc::addr(v)),
+        LispObj::Lambda(fun) => hash_of(fun.addr()),
+        LispObj::Ref(iref) => hash_of(gc::addr(iref)),
+        LispObj::Ext(ext) => hash_of(gc::addr(ext)),
+        LispObj::ExtFun(fun) => hash_of(gc::addr(fun)),
+        LispObj::HashTable(table) => hash_of(gc::addr(table)),
+    }
+}
+
+/// A hash code which is the same for objects which are eql
+pub fn sxhash_eql(obj: &LispObj) -> u64 {
+    match obj {
+        LispObj::Big(i) => hash_of(i),
+        obj => sxhash_eq(obj),
+    }
+}
+
+/// A hash code which is the same for objects which are equal
+///
+/// Only the first few elements of lists and vectors, to a limited depth, are
+/// used so that hashing a large structure is cheap.
+pub fn sxhash_equal(obj: &LispObj) -> u64 {
+    sxhash_depth(obj, 0)
+}
+
+fn sxhash_depth(obj: &LispObj, depth: usize) -> u64 {
+    let mut hasher = FnvHasher::default();
+
+    match obj {
+        &LispObj::Cons(_) if depth < SXHASH_MAX_DEPTH => {
+            for item in obj.iter().take(SXHASH_MAX_LEN) {
+                sxhash_depth(&item, depth + 1).hash(&mut hasher);
+            }
+        },
+        LispObj::Vector(v) if depth < SXHASH_MAX_DEPTH => {
+            let v = v.borrow();
+            v.len().hash(&mut hasher);
+            for item in v.iter().take(SXHASH_MAX_LEN) {
+                sxhash_depth(item, depth + 1).hash(&mut hasher);
+            }
+        },
+        LispObj::Str(s) => s.hash(&mut hasher),
+        // Too deep to look inside, but equal objects must still hash the same
+        &LispObj::Cons(_) => 1u8.hash(&mut hasher),
+        &LispObj::Vector(_) => 2u8.hash(&mut hasher),
+        obj => return sxhash_eql(obj),
+    }
+
+    hasher.finish()
+}
+
+impl HashTest {
+    /// The test named name, either one of the builtin tests or one defined
+    /// with define-hash-table-test
+    pub fn named(lsp: &Lsp, name: Atom) -> Result<HashTest, LispError> {
+        match name {
+            symbols::EQ => return Ok(HashTest::Eq),
+            symbols::EQL => return Ok(HashTest::Eql),
+            symbols::EQUAL => return Ok(HashTest::Equal),
+            _ => (),
+        }
+
+        let def = lsp.globals.get(name)
+            .and_then( |sym| sym.get_prop(symbols::HASH_TABLE_TEST) );
+        match def.map( |def| def.list_to_vec() ) {
+            Some(Ok(ref funs)) if funs.len() == 2 =>
+                Ok(HashTest::User(name, funs[0].clone(), funs[1].clone())),
+            _ => Err(LispError::error(format!("Invalid hash table test: {}",
+                                              lsp.stringify(name)))),
+        }
+    }
+
+    pub fn name(&self) -> Atom {
+        match *self {
+            HashTest::Eq => symbols::EQ,
+            HashTest::Eql => symbols::EQL,
+            HashTest::Equal => symbols::EQUAL,
+            HashTest::User(name, _, _) => name,
+        }
+    }
+
+    fn hash(&self, lsp: &mut Lsp, key: &LispObj) -> Result<u64, LispError> {
+        match self {
+            &HashTest::Eq => Ok(sxhash_eq(key)),
+            &HashTest::Eql => Ok(sxhash_eql(key)),
+            &HashTest::Equal => Ok(sxhash_equal(key)),
+            HashTest::User(_, _, hash) => Ok(sxhash_eql(&lsp.funcall(hash, std::slice::from_ref(key))?)),
+        }
+    }
+
+    fn matches(&self, lsp: &mut Lsp, a: &LispObj, b: &LispObj) -> Result<bool, LispError> {
+        match self {
+            &HashTest::Eq => Ok(eq(a, b)),
+            &HashTest::Eql => Ok(eql(a, b)),
+            &HashTest::Equal => equal(lsp, a, b),
+            HashTest::User(_, test, _) =>
+                Ok(!lsp.funcall(test, &[a.clone(), b.clone()])?.is_nil()),
+        }
+    }
+}
+
+impl HashTable {
+    pub fn new(test: HashTest) -> HashTableRef {
+        let table = Rc::new(RefCell::new(HashTable {
+            test,
+            entries: Vec::new(),
+            index: FnvHashMap::default(),
+            count: 0,
+        }));
+        gc::track_hash_table(&table);
+        table
+    }
+
+    pub fn test(&self) -> &HashTest {
+        &self.test
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The keys and values in the order they were added
+    pub fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=(&'a LispObj, &'a LispObj)> + 'a> {
+        Box::new(self.entries.iter().filter_map( |entry| match *entry {
+            Some((_, ref key, ref val)) => Some((key, val)),
+            None => None,
+        }))
+    }
+
+    /// The test functions and every key and value, for the garbage collector
+    pub fn trace(&self, f: &mut dyn FnMut(&LispObj)) {
+        if let HashTest::User(_, ref test, ref hash) = self.test {
+            f(test);
+            f(hash);
+        }
+        for (key, val) in self.iter() {
+            f(key);
+            f(val);
+        }
+    }
+
+    /// Remove every entry, returning them so they can be dropped later
+    pub fn drain(&mut self) -> Vec<LispObj> {
+        self.index.clear();
+        self.count = 0;
+        self.entries.drain(..)
+            .filter_map( |entry| entry.map( |(_, key, val)| vec![key, val] ) )
+            .flat_map( |kv| kv.into_iter() )
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.index.clear();
+        self.count = 0;
+    }
+
+    /// Squeeze out removed entries once they make up most of the table
+    fn compact(&mut self) {
+        if self.entries.len() < 16 || self.entries.len() < self.count * 2 {
+            return;
+        }
+
+        let entries: Vec<_> = self.entries.drain(..).flatten().collect();
+        self.index.clear();
+        for (i, entry) in entries.into_iter().enumerate() {
+            self.index.entry(entry.0).or_default().push(i);
+            self.entries.push(Some(entry));
+        }
+    }
+
+    fn remove_at(&mut self, i: usize) {
+        if let Some((code, _, _)) = self.entries[i].take() {
+            if let Some(bucket) = self.index.get_mut(&code) {
+                bucket.retain( |&j| j != i );
+            }
+            self.count -= 1;
+        }
+    }
+}
+
+/// Find the entry for key, returning its hash code and index
+fn find(lsp: &mut Lsp, table: &HashTableRef, key: &LispObj)
+        -> Result<(u64, Option<usize>), LispError> {
+    let test = table.borrow().test.clone();
+    let code = test.hash(lsp, key)?;
+
+    for n in 0.. {
+        let (i, other) = {
+            let table = table.borrow();
+            let i = match table.index.get(&code).and_then( |bucket| bucket.get(n) ) {
+                Some(&i) => i,
+                None => break,
+            };
+            match table.entries.get(i) {
+                Some(&Some((_, ref other, _))) => (i, other.clone()),
+                _ => continue,
+            }
+        };
+
+        if test.matches(lsp, key, &other)? {
+            return Ok((code, Some(i)));
+        }
+    }
+
+    Ok((code, None))
+}
+
+/// The value stored under key
+pub fn get(lsp: &mut Lsp, table: &HashTableRef, key: &LispObj)
+           -> Result<Option<LispObj>, LispError> {
+    let (_, found) = find(lsp, table, key)?;
+    let table = table.borrow();
+    Ok(found.and_then( |i| table.entries.get(i) )
+       .and_then( |entry| entry.as_ref().map( |(_, _, val)| val.clone() ) ))
+}
+
+/// Store val under key, replacing any existing value
+pub fn put(lsp: &mut Lsp, table: &HashTableRef, key: LispObj, val: LispObj)
+           -> Result<(), LispError> {
+    let (code, found) = find(lsp, table, &key)?;
+    let table = &mut *table.borrow_mut();
+
+    if let Some(&mut Some((_, _, ref mut old))) = found.and_then( |i| table.entries.get_mut(i) ) {
+        *old = val;
+        return Ok(());
+    }
+
+    table.compact();
+    let i = table.entries.len();
+    table.entries.push(Some((code, key, val)));
+    table.index.entry(code).or_default().push(i);
+    table.count += 1;
+    Ok(())
+}
+
+/// Remove the entry for key, if there is one
+pub fn remove(lsp: &mut Lsp, table: &HashTableRef, key: &LispObj) -> Result<(), LispError> {
+    if let (_, Some(i)) = find(lsp, table, key)? {
+        let table = &mut *table.borrow_mut();
+        if i < table.entries.len() {
+            table.remove_at(i);
+        }
+    }
+    Ok(())
+}
+
+/// Call fun with each key and value
+///
+/// fun may change or remove the entry it is given, other changes to the
+/// table have unpredictable results as in Emacs.
+pub fn map(lsp: &mut Lsp, fun: &LispObj, table: &HashTableRef) -> Result<(), LispError> {
+    for i in 0.. {
+        let entry = match table.borrow().entries.get(i) {
+            Some(entry) => entry.clone(),
+            None => break,
+        };
+
+        if let Some((_, key, val)) = entry {
+            lsp.funcall(fun, &[key, val])?;
+        }
+    }
+    Ok(())
+}
+
+/// A hash code as a fixnum
+fn sxhash_obj(code: u64) -> LispObj {
+    LispObj::Int((code >> 1) as i64)
+}
+
+/// Make a table from the arguments of make-hash-table
+///
+/// These are keyword arguments, of which only :test matters. The size and
+/// rehash parameters are accepted but ignored and weak tables are not
+/// supported.
+fn make(lsp: &mut Lsp, args: &[LispObj]) -> Result<HashTableRef, LispError> {
+    let mut test = HashTest::Eql;
+    let mut itr = args.iter();
+
+    while let Some(kw) = itr.next() {
+        let kw = match kw {
+            &LispObj::Atm(a) => lsp.stringify(a).to_owned(),
+            obj => return Err(LispError::wrong_type(symbols::SYMBOLP, obj)),
+        };
+
+        let val = itr.next().cloned().unwrap_or_else(LispObj::nil);
+        match kw.as_str() {
+            ":test" => test = HashTest::named(lsp, symbol_name(&val)?)?,
+            ":weakness" if val.is_nil() => (),
+            ":weakness" => return Err(LispError::error("Weak hash tables are not supported")),
+            ":size" | ":rehash-size" | ":rehash-threshold" | ":purecopy" => (),
+            _ => return Err(LispError::error(format!("Invalid argument list: {}", kw))),
+        }
+    }
+
+    Ok(HashTable::new(test))
+}
+
+/// Make a table from the contents of a `#s(hash-table ...)` form
+///
+/// The properties other than test and data are ignored, as make-hash-table
+/// ignores the size and rehash parameters.
+pub fn read_hash_table(lsp: &mut Lsp, props: &[LispObj]) -> Result<LispObj, LispError> {
+    let mut test = HashTest::Eql;
+    let mut data = Vec::new();
+
+    if !props.len().is_multiple_of(2) {
+        return Err(LispError::invalid_read_syntax("#s"));
+    }
+
+    for prop in props.chunks(2) {
+        match prop[0] {
+            LispObj::Atm(symbols::TEST) => test = HashTest::named(lsp, symbol_name(&prop[1])?)?,
+            LispObj::Atm(symbols::DATA) => data = prop[1].list_to_vec()?,
+            _ => (),
+        }
+    }
+
+    if data.len() % 2 != 0 {
+        return Err(LispError::error("Odd number of elements in hash table data"));
+    }
+
+    let table = HashTable::new(test);
+    for kv in data.chunks(2) {
+        put(lsp, &table, kv[0].clone(), kv[1].clone())?;
+    }
+    Ok(LispObj::HashTable(table))
+}
+
+/// Print a table so that read_hash_table can read it back
+///
+/// As in Emacs 30, the test is left out when it is the default and the data
+/// when the table is empty.
+pub fn print_hash_table<O: Write>(lsp: &Lsp, p: &mut Printer<O>, table: &HashTable)
+                                  -> Result<(), LispError> {
+    write!(p, "#s(hash-table")?;
+    if table.test.name() != symbols::EQL {
+        write!(p, " test ")?;
+        lsp.print_with(p, &LispObj::Atm(table.test.name()))?;
+    }
+
+    if table.count > 0 {
+        write!(p, " data (")?;
+        let mut sep = "";
+        for (key, val) in table.iter() {
+            write!(p, "{}", sep)?;
+            lsp.print_with(p, key)?;
+            write!(p, " ")?;
+            lsp.print_with(p, val)?;
+            sep = " ";
+        }
+        write!(p, ")")?;
+    }
+    write!(p, ")")?;
+    Ok(())
+}
+
+fn table_arg(obj: &LispObj) -> Result<&HashTableRef, LispError> {
+    match obj {
+        LispObj::HashTable(table) => Ok(table),
+        obj => Err(LispError::wrong_type(symbols::HASH_TABLE_P, obj)),
+    }
+}
+
+def_builtin! { "sxhash-eq", SxhashEqBuiltin, Evaluated, 1, 1, lsp, args; {
+    Ok(sxhash_obj(sxhash_eq(one_arg(lsp, "sxhash-eq", args)?)))
+}}
+
+def_builtin! { "sxhash-eql", SxhashEqlBuiltin, Evaluated, 1, 1, lsp, args; {
+    Ok(sxhash_obj(sxhash_eql(one_arg(lsp, "sxhash-eql", args)?)))
+}}
+
+def_builtin! { "sxhash-equal", SxhashEqualBuiltin, Evaluated, 1, 1, lsp, args; {
+    Ok(sxhash_obj(sxhash_equal(one_arg(lsp, "sxhash-equal", args)?)))
+}}
+
+def_builtin! { "make-hash-table", MakeHashTableBuiltin, Evaluated, 0, many, lsp, args; {
+    let table = make(lsp, args.as_slice())?;
+    Ok(LispObj::HashTable(table))
+}}
+
+def_builtin! { "hash-table-p", HashTablePBuiltin, Evaluated, 1, 1, lsp, args; {
+    let obj = one_arg(lsp, "hash-table-p", args)?;
+    Ok(LispObj::from_bool(matches!(obj, &LispObj::HashTable(_))))
+}}
+
+// (gethash KEY TABLE &optional DFLT)
+def_builtin! { "gethash", GethashBuiltin, Evaluated, 2, 3, lsp, args; {
+    let (key, table, dflt) = take3!(args);
+    let table = table_arg(table.unwrap())?;
+
+    match get(lsp, table, key.unwrap())? {
+        Some(val) => Ok(val),
+        None => Ok(dflt.cloned().unwrap_or_else(LispObj::nil)),
+    }
+}}
+
+// (puthash KEY VALUE TABLE)
+def_builtin! { "puthash", PuthashBuiltin, Evaluated, 3, 3, lsp, args; {
+    let (key, val, table) = take3!(args);
+    let table = table_arg(table.unwrap())?;
+
+    put(lsp, table, key.unwrap().clone(), val.unwrap().clone())?;
+    Ok(val.unwrap().clone())
+}}
+
+def_builtin! { "remhash", RemhashBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (key, table) = two_args(lsp, "remhash", args)?;
+    remove(lsp, table_arg(table)?, key)?;
+    Ok(LispObj::nil())
+}}
+
+def_builtin! { "clrhash", ClrhashBuiltin, Evaluated, 1, 1, lsp, args; {
+    let table = one_arg(lsp, "clrhash", args)?;
+    table_arg(table)?.borrow_mut().clear();
+    Ok(table.clone())
+}}
+
+def_builtin! { "maphash", MaphashBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (fun, table) = two_args(lsp, "maphash", args)?;
+    map(lsp, fun, table_arg(table)?)?;
+    Ok(LispObj::nil())
+}}
+
+def_builtin! { "hash-table-count", HashTableCountBuiltin, Evaluated, 1, 1, lsp, args; {
+    let table = one_arg(lsp, "hash-table-count", args)?;
+    Ok(LispObj::Int(table_arg(table)?.borrow().count() as i64))
+}}
+
+def_builtin! { "hash-table-keys", HashTableKeysBuiltin, Evaluated, 1, 1, lsp, args; {
+    let table = one_arg(lsp, "hash-table-keys", args)?;
+    let keys: Vec<LispObj> = table_arg(table)?.borrow().iter()
+        .map( |(key, _)| key.clone() )
+        .collect();
+    Ok(LispObj::list_from(&keys))
+}}
+
+// (define-hash-table-test NAME TEST HASH)
+//
+// As in Emacs the functions are stored in NAME's hash-table-test property.
+def_builtin! { "define-hash-table-test", DefineHashTableTestBuiltin, Evaluated, 3, 3, lsp, args; {
+    let (name, test, hash) = take3!(args);
+    let name = symbol_name(name.unwrap())?;
+    let def = LispObj::list_from(&[test.unwrap().clone(), hash.unwrap().clone()]);
+
+    lsp.globals.get_or_intern(name).put_prop(symbols::HASH_TABLE_TEST, def.clone());
+    Ok(def)
+}}
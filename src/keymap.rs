@@ -13,8 +13,11 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use rselisp::{Lsp, LispObj, LispForm, Sexp};
+use rselisp::{Lsp, LispObj, LispForm};
+use rselisp::{CHAR_ALT, CHAR_SUPER, CHAR_HYPER, CHAR_SHIFT, CHAR_CTL, CHAR_META,
+              CHAR_MODIFIER_MASK};
 use rselisp::lambda::{Func, EvalOption};
+use rselisp::error::LispError;
 use rselisp::symbols::{Atom, AtomRegistry};
 use editor::{Event, BasicEvent, EventModifiers};
 use fnv::FnvHashMap;
@@ -66,7 +69,13 @@ impl Keymap {
                     }
                 },
                 c => {
-                    basic = Some(BasicEvent::Char(c));
+                    let mut code = c as u32;
+                    // "\M-x" is read as x with the 8th bit set
+                    if (0x80..0x100).contains(&code) {
+                        mods.alt = true;
+                        code &= 0x7f;
+                    }
+                    basic = Some(Keymap::basic_event(code, &mut mods)?);
                     break;
                 }
             }
@@ -81,11 +90,39 @@ impl Keymap {
         }
     }
 
+    /// Convert a character, such as `?\C-x`, into an event
+    pub fn parse_key_code(&mut self, code: i32) -> Result<Event, String> {
+        let mut mods = EventModifiers::new();
+
+        mods.control = code & CHAR_CTL != 0;
+        mods.shift = code & CHAR_SHIFT != 0;
+        mods.alt = code & (CHAR_META | CHAR_ALT) != 0;
+        mods.hyper = code & (CHAR_HYPER | CHAR_SUPER) != 0;
+
+        let basic = Keymap::basic_event((code & !CHAR_MODIFIER_MASK) as u32, &mut mods)?;
+        Ok(Event::new(basic, mods))
+    }
+
+    /// The key for a character code, ASCII control characters are C-<letter>
+    fn basic_event(code: u32, mods: &mut EventModifiers) -> Result<BasicEvent, String> {
+        let code = if code < 0x20 {
+            mods.control = true;
+            code + 0x60
+        } else {
+            code
+        };
+
+        match ::std::char::from_u32(code) {
+            Some(c) => Ok(BasicEvent::Char(c)),
+            None => Err(format!("Invalid character code: {}", code)),
+        }
+    }
+
     pub fn lookup_key(&self, key: &Event) -> Option<&LispObj> {
         self.map.get(key)
     }
 
-    pub fn is_keymap(obj: &LispForm) -> bool {
+    pub fn is_keymap(obj: &dyn LispForm) -> bool {
         obj.lisp_name() == "keymap"
     }
 }
@@ -99,45 +136,60 @@ impl LispForm for Keymap {
         "keymap"
     }
 
-    fn to_lisp(&self) -> Result<LispObj, String> {
-        let mut sxp = Sexp::from(&[LispObj::Str("keymap".to_owned())]);
+    fn to_lisp(&self) -> Result<LispObj, LispError> {
+        let mut items = vec![LispObj::str("keymap")];
 
         for (evt, act) in self.map.iter() {
-            sxp.push(LispObj::pair(evt.to_lisp()?, act.clone()));
+            items.push(LispObj::pair(evt.to_lisp()?, act.clone()));
         }
 
-        Ok(LispObj::Sxp(sxp))
+        Ok(LispObj::list_from(&items))
     }
 
-    fn as_any(&mut self) -> &mut Any {
+    fn as_any(&mut self) -> &mut dyn Any {
         self
     }
+
+    fn trace(&self, f: &mut dyn FnMut(&LispObj)) {
+        for act in self.map.values() {
+            f(act);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+    }
 }
 
-def_builtin! { "keymapp", KeymapBuiltin, Evaluated, _lsp, args; {
+def_builtin! { "keymapp", KeymapBuiltin, Evaluated, 1, 1, _lsp, args; {
     if let Some(s) = args.next() {
         match s {
             &LispObj::Ext(ref ext) if Keymap::is_keymap(&*ext.borrow()) => Ok(LispObj::t()),
             _ => Ok(LispObj::nil()),
         }
     } else {
-        Err(format!("keymapp requires one argument"))
+        Err(LispError::error("keymapp requires one argument"))
     }
 }}
 
-def_builtin! { "define-key", DefineKeyBuiltin, Evaluated, lsp, args; {
+def_builtin! { "define-key", DefineKeyBuiltin, Evaluated, 3, 4, lsp, args; {
     if let (Some(keymap), Some(evt), Some(act)) = take3!(args) {
         Ok(with_downcast!(lsp, keymap, Keymap; {
-            let evt = match evt {
-                &LispObj::Ext(_) => with_downcast!(lsp, evt, Event; { evt.clone() } )?,
-                &LispObj::Str(ref s) => keymap.parse_key(s)?,
-                _ => return Err(format!("Expected event string or external Event type")),
+            let evt = match *evt {
+                LispObj::Ext(_) => with_downcast!(lsp, evt, Event; { evt.clone() } )?,
+                LispObj::Str(ref s) => keymap.parse_key(s).map_err(LispError::error)?,
+                LispObj::Vector(ref v) => match v.borrow()[..] {
+                    [LispObj::Int(code)] => keymap.parse_key_code(code as i32).map_err(LispError::error)?,
+                    _ => return Err(LispError::error("Expected a vector of one character")),
+                },
+                _ => return Err(LispError::error(
+                    "Expected event string, vector or external Event type")),
             };
 
             keymap.define_key(evt.clone(), act.clone());
             keymap.lookup_key(&evt).unwrap().clone()
         })?)
     } else {
-        Err(format!("define-key requires more arguments"))
+        Err(LispError::error("define-key requires more arguments"))
     }
 }}
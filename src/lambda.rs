@@ -1,5 +1,6 @@
 use super::*;
 use symbols::Atom;
+use error::LispError;
 use std::fmt;
 
 /// Whether function arguments are self quoting or evaluated
@@ -16,25 +17,105 @@ pub trait Func: fmt::Debug {
     /// The canonical name of this function
     fn name(&self) -> Atom;
     /// Evaluate this function
-    fn call(&self, &mut Lsp, &mut Iter<LispObj>) -> Result<LispObj, String>;
+    fn call(&self, lsp: &mut Lsp, args: &mut Iter<LispObj>) -> Result<LispObj, LispError>;
+    /// The minimum and maximum number of arguments, None if there is no maximum
+    ///
+    /// Builtins declare theirs with `def_builtin!`, by default anything is accepted.
+    fn arity(&self) -> (usize, Option<usize>) {
+        (0, None)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgKind {
+    Required,
+    /// After `&optional`, bound to nil if not given
+    Optional,
+    /// After `&rest`, bound to a list of the remaining arguments
+    Rest,
 }
 
 #[derive(Clone, Debug)]
 pub struct ArgSpec {
     name: Atom,
+    kind: ArgKind,
 }
 
 impl ArgSpec {
-    fn new(name: Atom) -> ArgSpec {
+    fn new(name: Atom, kind: ArgKind) -> ArgSpec {
         ArgSpec {
-            name: name,
+            name,
+            kind,
         }
     }
+
+    pub fn name(&self) -> Atom {
+        self.name
+    }
+
+    pub fn kind(&self) -> ArgKind {
+        self.kind
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct ArgSpecs(Vec<ArgSpec>);
 
+impl ArgSpecs {
+    /// Parse a lambda list such as `(a b &optional c &rest d)`
+    pub fn parse(arglist: &LispObj) -> Result<ArgSpecs, LispError> {
+        let mut specs = Vec::new();
+        let mut kind = ArgKind::Required;
+        let malformed = || LispError::invalid_function(arglist);
+
+        for arg in arglist.list_to_vec()? {
+            match arg {
+                LispObj::Atm(symbols::AND_OPTIONAL) if kind == ArgKind::Required =>
+                    kind = ArgKind::Optional,
+                LispObj::Atm(symbols::AND_REST) if kind != ArgKind::Rest =>
+                    kind = ArgKind::Rest,
+                LispObj::Atm(symbols::AND_OPTIONAL) | LispObj::Atm(symbols::AND_REST) =>
+                    return Err(malformed()),
+                LispObj::Atm(name) => {
+                    // Only one variable may follow &rest
+                    if kind == ArgKind::Rest && specs.last().is_some_and(|s: &ArgSpec| {
+                        s.kind == ArgKind::Rest
+                    }) {
+                        return Err(malformed());
+                    }
+                    specs.push(ArgSpec::new(name, kind));
+                },
+                obj => return Err(LispError::wrong_type(symbols::SYMBOLP, &obj)),
+            }
+        }
+
+        if kind == ArgKind::Rest && specs.last().is_none_or(|s| s.kind != ArgKind::Rest) {
+            return Err(malformed());
+        }
+
+        Ok(ArgSpecs(specs))
+    }
+
+    pub fn arity(&self) -> (usize, Option<usize>) {
+        let min = self.iter().filter( |s| s.kind == ArgKind::Required ).count();
+
+        if self.iter().any( |s| s.kind == ArgKind::Rest ) {
+            (min, None)
+        } else {
+            (min, Some(self.len()))
+        }
+    }
+}
+
+/// Arity as Lisp data, `(MIN . MAX)` where MAX may be `many`
+pub fn arity_to_lisp(arity: (usize, Option<usize>)) -> LispObj {
+    let max = match arity.1 {
+        Some(max) => LispObj::Int(max as i64),
+        None => LispObj::Atm(symbols::MANY),
+    };
+    LispObj::cons(LispObj::Int(arity.0 as i64), max)
+}
+
 impl std::ops::Deref for ArgSpecs {
     type Target = Vec<ArgSpec>;
 
@@ -47,36 +128,71 @@ impl std::ops::Deref for ArgSpecs {
 #[derive(Clone, Debug)]
 pub struct UserFunc {
     args: ArgSpecs,
+    /// The list of body forms
     body: LispObjRef,
+    /// The captured lexical environment if this is a closure
+    env: Option<Cons>,
 }
 
 impl UserFunc {
-    fn new(args: Vec<ArgSpec>, body: LispObjRef) -> UserFunc {
+    fn new(args: ArgSpecs, body: LispObjRef) -> UserFunc {
         UserFunc {
-            args: ArgSpecs(args),
-            body: body,
+            args,
+            body,
+            env: None,
         }
     }
 
-    pub fn lambda(args: &mut Iter<LispObj>) -> Result<UserFunc, String> {
-        match take2!(args) {
-            (Some(&LispObj::Sxp(ref args_sxp)), Some(body)) => {
-                let largs: Result<Vec<ArgSpec>, String> = args_sxp.lst.iter().map(
-                    |arg| -> Result<ArgSpec, String> {
-                        match arg {
-                            &LispObj::Atm(name) => Ok(ArgSpec::new(name)),
-                            _ => Err(format!("Lambda arguments must be symbols")),
-                        }
-                    }
-                ).collect();
+    /// The list of body forms
+    pub fn body(&self) -> LispObj {
+        self.body.borrow().clone()
+    }
+
+    pub fn args(&self) -> &ArgSpecs {
+        &self.args
+    }
+
+    /// The captured lexical environment, None if this is not a closure
+    pub fn env(&self) -> Option<&Cons> {
+        self.env.as_ref()
+    }
+
+    /// Call f with the address of each heap object held by this function
+    /// Identifies the function, copies of it share the same body
+    pub fn addr(&self) -> usize {
+        gc::addr(&self.body)
+    }
+
+    pub fn edges(&self, f: &mut dyn FnMut(usize)) {
+        f(gc::addr(&self.body));
+        if let Some(ref env) = self.env {
+            f(gc::addr(&env.0));
+        }
+    }
+
+    /// Create a function from the cdr of a lambda form; `(ARGS . BODY)`
+    pub fn lambda(def: &LispObj) -> Result<UserFunc, LispError> {
+        match def {
+            LispObj::Cons(def) if def.car().is_list() => {
+                let args = ArgSpecs::parse(&def.car())?;
+                Ok(UserFunc::new(args, def.cdr().into_ref()))
+            },
+            _ => Err(LispError::error("(lambda ([args]) [body])")),
+        }
+    }
 
-                Ok(UserFunc::new(largs?, match body {
-                        &LispObj::Ref(ref iref) => iref.clone(),
-                        _ => body.clone().into_ref(),
-                    })
-                )
+    /// Create a function from the cdr of a closure form; `(ENV ARGS . BODY)`
+    pub fn closure(def: &LispObj) -> Result<UserFunc, LispError> {
+        match def {
+            LispObj::Cons(def) => match def.car() {
+                LispObj::Cons(env) => {
+                    let mut fun = UserFunc::lambda(&def.cdr())?;
+                    fun.env = Some(env);
+                    Ok(fun)
+                },
+                _ => Err(LispError::error("(closure ENV ([args]) [body])")),
             },
-            _ => Err(format!("(lambda ([args]) [body])")),
+            _ => Err(LispError::error("(closure ENV ([args]) [body])")),
         }
     }
 }
@@ -84,20 +200,47 @@ impl UserFunc {
 impl Func for UserFunc {
     fn eval_args(&self) -> EvalOption { EvalOption::Evaluated }
     fn name(&self) -> Atom { symbols::ANONYMOUS }
+    fn arity(&self) -> (usize, Option<usize>) { self.args.arity() }
 
-    fn call(&self, lsp: &mut Lsp, args: &mut Iter<LispObj>) -> Result<LispObj, String> {
+    /// Bind the arguments and evaluate the body
+    ///
+    /// A closure evaluates its body in the environment it captured, with the
+    /// arguments bound lexically. Otherwise lexical binding is turned off and
+    /// all the arguments are dynamically bound.
+    fn call(&self, lsp: &mut Lsp, args: &mut Iter<LispObj>) -> Result<LispObj, LispError> {
         let mut ns = Namespace::new();
+        let mut env = match self.env {
+            Some(ref env) => LispObj::Cons(env.clone()),
+            None => LispObj::nil(),
+        };
+
+        let (min, max) = self.arity();
+        let count = args.len();
+        if count < min || max.is_some_and(|max| count > max) {
+            return Err(LispError::wrong_number_of_args(arity_to_lisp((min, max)), count));
+        }
+
         for spec in self.args.iter() {
-            if let Some(arg) = args.next() {
-                ns.intern(Symbol::with_val(spec.name, arg.clone()));
+            if lsp.is_constant(spec.name) {
+                return Err(LispError::setting_constant(spec.name));
+            }
+            let val = match spec.kind {
+                ArgKind::Rest => {
+                    let rest = LispObj::list_from(args.as_slice());
+                    args.by_ref().last();
+                    rest
+                },
+                _ => args.next().cloned().unwrap_or(LispObj::nil()),
+            };
+
+            if lsp.binds_lexically(&env, spec.name) {
+                env = LispObj::cons(LispObj::cons(LispObj::Atm(spec.name), val), env);
             } else {
-                return Err(format!("'{}' expected '{}' argument",
-                                   &lsp.stringify(self.name()), &lsp.stringify(spec.name)));
+                ns.intern(Symbol::with_val(spec.name, val));
             }
         }
-        lsp.locals.push(ns);
-        let ret = lsp.eval_ref(&self.body);
-        lsp.locals.pop();
-        ret
+
+        let body = self.body.borrow().iter();
+        lsp.with_scope(ns, env, |lsp| lsp.eval_progn(body))
     }
 }
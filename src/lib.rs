@@ -17,13 +17,18 @@ extern crate fnv;
 
 use std::slice::Iter;
 use std::iter::{Peekable, Iterator};
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::cell::{Cell, RefCell};
 use std::fmt::{self, Write};
 use std::any::Any;
 use std::fs::File;
 use std::path::Path;
 use std::io::Read;
+use std::mem;
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use fnv::{FnvHashMap, FnvHashSet};
 
 #[macro_export]
 macro_rules! take2 {
@@ -47,33 +52,75 @@ use symbols::{Atom, AtomRegistry, Symbol, Namespace};
 pub mod lambda;
 use lambda::{EvalOption, Func, UserFunc};
 
+pub mod error;
+use error::LispError;
+
+pub mod gc;
+
+pub mod debug;
+use debug::{Activation, DebugIo, DebugReason, DebugAction};
+
+pub mod bignum;
+use bignum::BigInt;
+
+pub mod numbers;
+use numbers::*;
+
+pub mod regexp;
+
+pub mod strings;
+use strings::*;
+
+pub mod print;
+use print::*;
+
+pub mod hashtab;
+use hashtab::*;
+
+pub mod seq;
+use seq::*;
+
+pub mod obarray;
+use obarray::*;
+
+pub mod bytecode;
+use bytecode::{ByteCompileBuiltin, ByteCompileFileBuiltin};
+
 /// A Lisp object
 ///
 /// Each item in this enumeration should have a single member which is the
 /// actual data. If you copy this data structure, you are also copying inner
-/// data, unless it is a Ref or Ext. However this structure is usually passed
-/// around as a plain (native Rust) reference within the lisp interpreter.
+/// data, unless it is a Ref, Ext, Cons or Vector. However this structure is
+/// usually passed around as a plain (native Rust) reference within the lisp
+/// interpreter.
 ///
-/// When a Lisp object needs to be (potentially) referred to in more than one
-/// place, then it can be wrapped in an LispObj::Ref. Currently this happens
-/// when an object is assigned to a variable. I haven't given much time to
-/// studying the semantics of setting and getting elisp memory locations
-/// (setf), so the way things currently work is probably wrong. On the other
-/// hand using an enum of concrete values, then switching to a reference when
-/// needed seems like a reasonable way to handle data efficiently in an
-/// interpreter. Although resolving LispObj::Refs is currently a mess.
+/// Lists are made from cons cells and vectors are shared, mutable arrays, so
+/// cloning either of these just creates another reference to the same
+/// structure, as it would be in Emacs.
+///
+/// When some other Lisp object needs to be (potentially) referred to in more
+/// than one place, then it can be wrapped in an LispObj::Ref. I haven't given
+/// much time to studying the semantics of setting and getting elisp memory
+/// locations (setf), so the way things currently work is probably
+/// wrong. Resolving LispObj::Refs is currently a mess.
 #[derive(Debug, Clone)]
 pub enum LispObj {
-    /// Integer
-    Int(i32),
-    /// String
-    Str(String),
+    /// Integer which fits in a machine word, a fixnum
+    Int(i64),
+    /// Integer which does not fit in a fixnum, a bignum
+    Big(Rc<BigInt>),
+    /// Floating point number
+    Float(f64),
+    /// String, shared so that it has an identity for eq
+    Str(Rc<str>),
     /// An Atom
     Atm(Atom),
     /// A Symbol
     Sym(Symbol),
-    /// S-Expression or list
-    Sxp(Sexp),
+    /// A cons cell, lists are chains of these ending in nil
+    Cons(Cons),
+    /// A vector
+    Vector(VectorRef),
     /// Function defined by the user
     ///
     /// The object UserFunc is bulking up the size of LispObj
@@ -84,20 +131,22 @@ pub enum LispObj {
     Ext(External),
     /// A reference to a native function
     ExtFun(ExternalFun),
+    /// A hash table, shared like a vector
+    HashTable(HashTableRef),
 }
 
 pub type LispObjRef = Rc<RefCell<LispObj>>;
-pub type External = Rc<RefCell<LispForm>>;
-pub type ExternalFun = Rc<Func>;
+pub type VectorRef = Rc<RefCell<VectorCell>>;
+pub type External = Rc<RefCell<dyn LispForm>>;
+pub type ExternalFun = Rc<dyn Func>;
 
 macro_rules! gen_to_vals {
-    ( $( $fn:ident, $inner:ident, $type:ident );+ ) => ($(
-        pub fn $fn(&self) -> Result<&$type, String> {
+    ( $( $fn:ident, $inner:ident, $type:ident, $pred:ident );+ ) => ($(
+        pub fn $fn(&self) -> Result<&$type, LispError> {
             if let &LispObj::$inner(ref val) = self {
                 Ok(val)
             } else {
-                Err(format!(concat!("Expected LispObj:", stringify!($inner), ", but instead found {:?}"),
-                            self))
+                Err(LispError::wrong_type(symbols::$pred, self))
             }
         }
     )+)
@@ -118,34 +167,36 @@ macro_rules! gen_is_x {
 #[allow(dead_code)]
 impl LispObj {
 
-    gen_to_vals!{int_val, Int, i32;
-                 str_val, Str, String;
-                 atm_val, Atm, Atom;
-                 sym_val, Sym, Symbol;
-                 sxp_val, Sxp, Sexp;
-                 lam_val, Lambda, UserFunc;
-                 ref_val, Ref, LispObjRef;
-                 ext_val, Ext, External}
+    gen_to_vals!{int_val, Int, i64, INTEGERP;
+                 str_val, Str, str, STRINGP;
+                 atm_val, Atm, Atom, SYMBOLP;
+                 sym_val, Sym, Symbol, SYMBOLP;
+                 cons_val, Cons, Cons, CONSP;
+                 vec_val, Vector, VectorRef, VECTORP;
+                 lam_val, Lambda, UserFunc, FUNCTIONP}
 
     gen_is_x!{is_int, Int;
               is_str, Str;
               is_atm, Atm;
               is_sym, Sym;
-              is_sxp, Sxp;
+              is_cons, Cons;
+              is_vec, Vector;
               is_lam, Lambda;
               is_ref, Ref;
               is_ext, Ext}
 
-    fn ref_sxp(&mut self) -> &mut Sexp {
-        if let &mut LispObj::Sxp(ref mut sxp) = self {
-            sxp
-        } else {
-            panic!("Not an Sexp");
+    /// An integer, which is only a bignum if it does not fit in a fixnum
+    pub fn integer(i: BigInt) -> LispObj {
+        match i.to_i64() {
+            Some(i) => LispObj::Int(i),
+            None => LispObj::Big(Rc::new(i)),
         }
     }
 
     pub fn into_ref(self) -> LispObjRef {
-        Rc::new(RefCell::new(self))
+        let iref = Rc::new(RefCell::new(self));
+        gc::track_ref(&iref);
+        iref
     }
 
     pub fn nil() -> LispObj {
@@ -156,6 +207,15 @@ impl LispObj {
         LispObj::atm(symbols::T)
     }
 
+    /// t if b is true otherwise nil
+    pub fn from_bool(b: bool) -> LispObj {
+        if b {
+            LispObj::t()
+        } else {
+            LispObj::nil()
+        }
+    }
+
     pub fn atm(name: Atom) -> LispObj {
         LispObj::Atm(name)
     }
@@ -165,23 +225,101 @@ impl LispObj {
     }
 
     pub fn str(strng: &str) -> LispObj {
-        LispObj::Str(strng.to_owned())
+        LispObj::Str(Rc::from(strng))
+    }
+
+    pub fn string(strng: String) -> LispObj {
+        LispObj::Str(Rc::from(strng))
     }
 
+    pub fn cons(car: LispObj, cdr: LispObj) -> LispObj {
+        LispObj::Cons(Cons::new(car, cdr))
+    }
+
+    /// A dotted pair, the same as cons
     pub fn pair(a: LispObj, b: LispObj) -> LispObj {
-        LispObj::Sxp(Sexp::from(&[a, b]))
+        LispObj::cons(a, b)
     }
 
     pub fn list_from(items: &[LispObj]) -> LispObj {
-        LispObj::Sxp(Sexp::from(items))
+        LispObj::list_with_tail(items, LispObj::nil())
+    }
+
+    /// Create a list from items where the last cdr is tail instead of nil
+    pub fn list_with_tail(items: &[LispObj], tail: LispObj) -> LispObj {
+        items.iter().rev().fold(tail, |cdr, car| LispObj::cons(car.clone(), cdr))
+    }
+
+    pub fn vec_from(items: &[LispObj]) -> LispObj {
+        let vec = Rc::new(RefCell::new(VectorCell(Vec::from(items))));
+        gc::track_vector(&vec);
+        LispObj::Vector(vec)
+    }
+
+    /// Whether dropping this may drop other objects it holds, see drop_nested
+    fn is_nested(&self) -> bool {
+        matches!(*self, LispObj::Cons(_) | LispObj::Vector(_) | LispObj::Ref(_))
+    }
+
+    /// Is this nil or a cons
+    pub fn is_list(&self) -> bool {
+        self.is_nil() || self.is_cons()
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, &LispObj::Atm(a) if a == symbols::NIL)
     }
 
-    fn is_nil(&self) -> bool {
+    /// The car of a list, nil if this is nil
+    pub fn car(&self) -> Result<LispObj, LispError> {
         match self {
-            &LispObj::Atm(a) if a == symbols::NIL => true,
-            &LispObj::Sxp(ref sxp) if sxp.lst.len() == 0 => true,
-            _ => false,
+            LispObj::Cons(cons) => Ok(cons.car()),
+            obj if obj.is_nil() => Ok(LispObj::nil()),
+            obj => Err(LispError::wrong_type(symbols::LISTP, obj)),
+        }
+    }
+
+    /// The cdr of a list, nil if this is nil
+    pub fn cdr(&self) -> Result<LispObj, LispError> {
+        match self {
+            LispObj::Cons(cons) => Ok(cons.cdr()),
+            obj if obj.is_nil() => Ok(LispObj::nil()),
+            obj => Err(LispError::wrong_type(symbols::LISTP, obj)),
+        }
+    }
+
+    /// Iterate over the elements of a list
+    ///
+    /// If this is not a list, or once a non-cons cdr is reached, the iterator
+    /// stops.
+    pub fn iter(&self) -> ListIter {
+        ListIter {
+            next: self.clone(),
+            cycle: CycleCheck::new(self),
+            circular: false,
+        }
+    }
+
+    /// Collect the elements of a proper list into a Vec
+    pub fn list_to_vec(&self) -> Result<Vec<LispObj>, LispError> {
+        let mut items = Vec::new();
+        let mut itr = self.iter();
+
+        for item in itr.by_ref() {
+            items.push(item);
         }
+
+        itr.finish(self)?;
+        Ok(items)
+    }
+
+    /// Share an external object with Lisp
+    ///
+    /// The object is registered with the garbage collector so that cycles
+    /// through it, see LispForm::trace, can be freed.
+    pub fn ext(ext: External) -> LispObj {
+        gc::track_external(&ext);
+        LispObj::Ext(ext)
     }
 
     pub fn extern_fun<F: 'static + Func>(fun: F) -> LispObj {
@@ -206,89 +344,258 @@ impl std::cmp::PartialEq for LispObj {
         }
 
         match self {
-            &LispObj::Int(ref i) => exact_eq!(i, Int),
-            &LispObj::Str(ref s) => exact_eq!(s, Str),
-            &LispObj::Sym(ref s) => exact_eq!(s, Sym),
-            &LispObj::Sxp(ref s) => exact_eq!(s, Sxp),
-            _ => panic!("Equality not implemented for {:?}", self),
+            LispObj::Int(i) => exact_eq!(i, Int),
+            LispObj::Big(i) => exact_eq!(i, Big),
+            LispObj::Float(f) => exact_eq!(f, Float),
+            LispObj::Str(s) => exact_eq!(s, Str),
+            LispObj::Atm(a) => exact_eq!(a, Atm),
+            LispObj::Sym(s) => exact_eq!(s, Sym),
+            LispObj::Cons(c) => exact_eq!(c, Cons),
+            LispObj::Vector(v) => exact_eq!(v, Vector),
+            LispObj::HashTable(h) => if let LispObj::HashTable(o) = other {
+                Rc::ptr_eq(h, o)
+            } else {
+                false
+            },
+            _ => eq(self, other),
         }
     }
 }
 
-/// S-Expression, list or vector
-///
-/// Usually a list in Lisp is a string of cons cells (linked-list). Here we
-/// are using a Rust vector and emulating cons cells. This is mainly because
-/// it allows me to reuse the Rust Vec/slice types and associated iterator
-/// functions. It is probably also more efficient for larger lists and the
-/// same for smaller.
-#[derive(Debug, Clone, PartialEq)]
-pub struct Sexp {
-    delim: char,
-    lst: Vec<LispObj>,
+/// The contents of a cons cell
+pub struct ConsCell {
+    car: LispObj,
+    cdr: LispObj,
 }
 
-impl Sexp {
-    pub fn root(func: Atom) -> Sexp {
-        Sexp {
-            delim: 'R',
-            lst: vec![LispObj::Atm(func)],
+impl Drop for ConsCell {
+    /// Drop the car and cdr iteratively, see drop_nested
+    fn drop(&mut self) {
+        if self.car.is_nested() || self.cdr.is_nested() {
+            drop_nested(vec![mem::replace(&mut self.car, LispObj::nil()),
+                             mem::replace(&mut self.cdr, LispObj::nil())]);
         }
     }
+}
 
-    pub fn nil() -> Sexp {
-        Sexp {
-            delim: '(',
-            lst: Vec::new(),
-        }
+/// The elements of a vector
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VectorCell(pub Vec<LispObj>);
+
+impl std::ops::Deref for VectorCell {
+    type Target = Vec<LispObj>;
+
+    fn deref(&self) -> &Vec<LispObj> {
+        &self.0
     }
+}
 
-    pub fn new(delim: char) -> Sexp {
-        Sexp {
-            delim: delim,
-            lst: Vec::new(),
-        }
+impl std::ops::DerefMut for VectorCell {
+    fn deref_mut(&mut self) -> &mut Vec<LispObj> {
+        &mut self.0
     }
+}
 
-    pub fn from(lst: &[LispObj]) -> Sexp {
-        Sexp {
-            delim: '(',
-            lst: Vec::from(lst),
+impl Drop for VectorCell {
+    /// Drop the elements iteratively, see drop_nested
+    fn drop(&mut self) {
+        if self.0.iter().any(LispObj::is_nested) {
+            drop_nested(mem::take(&mut self.0));
         }
     }
+}
 
-    pub fn vec_from(lst: &[LispObj]) -> Sexp {
-        Sexp {
-            delim: '[',
-            lst: Vec::from(lst),
+/// Drop objects without recursing into the objects they hold
+///
+/// Otherwise dropping a long list, or data nested deeply in cars or vectors,
+/// would recurse once for each cell and overflow the stack. Cells only held
+/// by the work list are emptied onto it, so that when they are dropped they
+/// hold nothing more to drop.
+fn drop_nested(mut work: Vec<LispObj>) {
+    while let Some(obj) = work.pop() {
+        match obj {
+            LispObj::Cons(Cons(cell)) => if let Ok(cell) = Rc::try_unwrap(cell) {
+                let mut cell = cell.into_inner();
+                work.push(mem::replace(&mut cell.car, LispObj::nil()));
+                work.push(mem::replace(&mut cell.cdr, LispObj::nil()));
+            },
+            LispObj::Vector(vec) => if let Ok(vec) = Rc::try_unwrap(vec) {
+                work.append(&mut vec.into_inner());
+            },
+            LispObj::Ref(iref) => if let Ok(iref) = Rc::try_unwrap(iref) {
+                work.push(iref.into_inner());
+            },
+            _ => (),
         }
     }
+}
 
-    pub fn push(&mut self, child: LispObj) {
-        self.lst.push(child);
+/// A reference to a cons cell
+///
+/// Cloning this creates another reference to the same cell, so modifying the
+/// car or cdr is visible to everything which shares the cell, as with setcar
+/// and setcdr in Emacs.
+#[derive(Clone)]
+pub struct Cons(Rc<RefCell<ConsCell>>);
+
+impl Cons {
+    pub fn new(car: LispObj, cdr: LispObj) -> Cons {
+        let cell = Rc::new(RefCell::new(ConsCell {
+            car,
+            cdr,
+        }));
+        gc::track_cons(&cell);
+        Cons(cell)
     }
 
     pub fn car(&self) -> LispObj {
-        match self.lst.first() {
-            Some(car) => car.clone(),
-            None => LispObj::nil(),
-        }
+        self.0.borrow().car.clone()
     }
 
     pub fn cdr(&self) -> LispObj {
-        match self.lst.split_first() {
-            Some((_, rest)) => LispObj::Sxp(Sexp::from(rest)),
-            _ => LispObj::nil(),
+        self.0.borrow().cdr.clone()
+    }
+
+    pub fn set_car(&self, car: LispObj) {
+        self.0.borrow_mut().car = car;
+    }
+
+    pub fn set_cdr(&self, cdr: LispObj) {
+        self.0.borrow_mut().cdr = cdr;
+    }
+
+    /// Is this the very same cell as other
+    pub fn ptr_eq(&self, other: &Cons) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// Iterate over the list starting at this cell
+    pub fn iter(&self) -> ListIter {
+        LispObj::Cons(self.clone()).iter()
+    }
+}
+
+impl fmt::Debug for Cons {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cell = self.0.borrow();
+        write!(f, "Cons({:?} . {:?})", cell.car, cell.cdr)
+    }
+}
+
+impl std::cmp::PartialEq for Cons {
+    /// Compare the lists element by element
+    ///
+    /// The cdrs are followed iteratively so that long lists do not use up the
+    /// stack.
+    fn eq(&self, other: &Cons) -> bool {
+        let (mut a, mut b) = (self.clone(), other.clone());
+
+        loop {
+            if a.ptr_eq(&b) {
+                return true;
+            }
+            if a.car() != b.car() {
+                return false;
+            }
+
+            match (a.cdr(), b.cdr()) {
+                (LispObj::Cons(c), LispObj::Cons(d)) => {
+                    a = c;
+                    b = d;
+                },
+                (ref c, ref d) => return c == d,
+            }
+        }
+    }
+}
+
+/// Finds a loop in the cdrs of a list with Brent's algorithm
+///
+/// Each tail is passed to step as the list is followed. A loop is found when
+/// a tail comes back round to the tortoise, which is moved up to the latest
+/// tail at every power of two steps, as in hashtab::equal.
+pub struct CycleCheck {
+    tortoise: LispObj,
+    steps: usize,
+    power: usize,
+}
+
+impl CycleCheck {
+    pub fn new(list: &LispObj) -> CycleCheck {
+        CycleCheck {
+            tortoise: list.clone(),
+            steps: 0,
+            power: 1,
+        }
+    }
+
+    /// Whether the list has looped back round to tail
+    pub fn step(&mut self, tail: &LispObj) -> bool {
+        self.steps += 1;
+        if let (LispObj::Cons(a), LispObj::Cons(b)) = (tail, &self.tortoise) {
+            if a.ptr_eq(b) {
+                return true;
+            }
+        }
+
+        if self.steps == self.power {
+            self.tortoise = tail.clone();
+            self.steps = 0;
+            self.power *= 2;
         }
+        false
+    }
+
+    /// The number of conses in the loop, once step has found it
+    pub fn cycle_len(&self) -> usize {
+        self.steps
+    }
+}
+
+/// Iterates over the elements of a list
+///
+/// The iterator ends early if the list loops back on itself, see finish.
+pub struct ListIter {
+    next: LispObj,
+    cycle: CycleCheck,
+    circular: bool,
+}
+
+impl ListIter {
+    /// What is left of the list
+    ///
+    /// Once the iterator is exhausted this is nil for a proper list or the
+    /// final cdr for a dotted list.
+    pub fn tail(&self) -> &LispObj {
+        &self.next
     }
 
-    pub fn extend(&mut self, other: &Sexp) {
-        self.lst.extend(other.lst.iter().cloned());
+    /// Check that list, which this iterated over, ended in nil
+    ///
+    /// Signals circular-list if it looped back on itself, as Emacs does.
+    pub fn finish(&self, list: &LispObj) -> Result<(), LispError> {
+        if self.circular {
+            Err(LispError::circular_list(list))
+        } else if self.next.is_nil() {
+            Ok(())
+        } else {
+            Err(LispError::wrong_type(symbols::LISTP, list))
+        }
     }
+}
 
-    fn new_inner_sxp(&mut self, delim: char) -> &mut Sexp {
-        self.lst.push(LispObj::Sxp(Sexp::new(delim)));
-        self.lst.last_mut().unwrap().ref_sxp()
+impl Iterator for ListIter {
+    type Item = LispObj;
+
+    fn next(&mut self) -> Option<LispObj> {
+        let (car, cdr) = match self.next {
+            LispObj::Cons(ref cons) if !self.circular => (cons.car(), cons.cdr()),
+            _ => return None,
+        };
+
+        self.circular = self.cycle.step(&cdr);
+        self.next = cdr;
+        Some(car)
     }
 }
 
@@ -297,17 +604,41 @@ pub trait LispForm: fmt::Debug {
     fn rust_name(&self) -> &'static str;
     fn lisp_name(&self) -> &'static str;
 
-    fn to_lisp(&self) -> Result<LispObj, String> {
-        Err(format!("Type {} ({}) can not be converted to Lisp",
-                    self.rust_name(), self.lisp_name()))
+    fn to_lisp(&self) -> Result<LispObj, LispError> {
+        Err(LispError::error(format!("Type {} ({}) can not be converted to Lisp",
+                                     self.rust_name(), self.lisp_name())))
+    }
+
+    fn from_lisp(&self, _obj: LispObj) -> Result<LispObj, LispError> {
+        Err(LispError::error(format!("Type {} ({}) can not be created from Lisp",
+                                     self.rust_name(), self.lisp_name())))
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any;
+
+    /// Call f with each Lisp object held by this one
+    ///
+    /// Used by the garbage collector to find cycles which pass through
+    /// external objects. Only needed if the object holds Lisp data.
+    fn trace(&self, _f: &mut dyn FnMut(&LispObj)) { }
+
+    /// The text of a buffer, for eval-buffer and eval-region
+    fn contents(&self) -> Result<String, LispError> {
+        Err(LispError::error(format!("Type {} ({}) is not a buffer",
+                                     self.rust_name(), self.lisp_name())))
     }
 
-    fn from_lisp(&self, LispObj) -> Result<LispObj, String> {
-        Err(format!("Type {} ({}) can not be created from Lisp",
-                    self.rust_name(), self.lisp_name()))
+    /// Insert printed text, when this is used as a printcharfun
+    fn insert_text(&mut self, _text: &str) -> Result<(), LispError> {
+        Err(LispError::error(format!("Can not print to type {} ({})",
+                                     self.rust_name(), self.lisp_name())))
     }
 
-    fn as_any(&mut self) -> &mut Any;
+    /// Drop any Lisp objects held by this one
+    ///
+    /// Called by the garbage collector when this object is only reachable from
+    /// garbage, to break the cycle it is part of.
+    fn clear(&mut self) { }
 }
 
 /// Try to downcast an External trait to its concrete type
@@ -325,16 +656,70 @@ macro_rules! with_downcast {
             if let Some($value) = ext.as_any().downcast_mut::<$as>() {
                 Ok($do)
             } else {
-                Err(format!(
+                Err($crate::error::LispError::error(format!(
                     concat!("Downcast expected ", stringify!($as), " not {} ({})"),
-                    lname, rname))
+                    lname, rname)))
             }
         } else {
-            Err($lsp.error_print("Only external objects can be downcast, not", $value))
+            Err($crate::error::LispError::error(
+                $lsp.error_print("Only external objects can be downcast, not", $value)))
         }
     )
 }
 
+/// The default for gc-cons-threshold, the same as Emacs
+pub const GC_DEFAULT_THRESHOLD: usize = 800000;
+/// Smaller values of gc-cons-threshold are treated as this
+pub const GC_MIN_THRESHOLD: usize = GC_DEFAULT_THRESHOLD / 10;
+/// The default for gc-cons-percentage
+///
+/// Emacs uses 0.1, but tracing the heap costs much more here, so by default
+/// as much must be consed as survived the last collection, like gc::track.
+pub const GC_DEFAULT_PERCENTAGE: f64 = 1.0;
+
+/// The default for max-lisp-eval-depth, the same as Emacs
+pub const EVAL_DEPTH_DEFAULT: usize = 1600;
+/// Smaller values of max-lisp-eval-depth are treated as this
+pub const EVAL_DEPTH_MIN: usize = 100;
+/// How far past max-lisp-eval-depth the debugger may evaluate
+pub const EVAL_DEPTH_DEBUG: usize = 100;
+/// The default number of bytes of Rust stack evaluation may use
+///
+/// This leaves some of the 2MiB given to new threads for whoever called the
+/// evaluator. Each nested form takes around 1KiB in a release build, but
+/// several times that in a debug build.
+pub const STACK_LIMIT_DEFAULT: usize = 3 * 1024 * 1024 / 2;
+
+/// Where a form was read from
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourcePos {
+    pub file: Rc<String>,
+    pub line: usize,
+}
+
+impl fmt::Display for SourcePos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// A form which was being evaluated, innermost first in a backtrace
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub form: LispObj,
+    pub pos: Option<SourcePos>,
+}
+
+/// Modifier bits of a character, such as the one read from `?\C-x`
+pub const CHAR_ALT: i32 = 1 << 22;
+pub const CHAR_SUPER: i32 = 1 << 23;
+pub const CHAR_HYPER: i32 = 1 << 24;
+pub const CHAR_SHIFT: i32 = 1 << 25;
+pub const CHAR_CTL: i32 = 1 << 26;
+pub const CHAR_META: i32 = 1 << 27;
+pub const CHAR_MODIFIER_MASK: i32 = CHAR_ALT | CHAR_SUPER | CHAR_HYPER | CHAR_SHIFT | CHAR_CTL |
+                                    CHAR_META;
+
 /// The Lisp interpreter
 ///
 /// Possibly also the compiler in the future. Currently this just executes the
@@ -343,6 +728,60 @@ pub struct Lsp {
     pub globals: Namespace,
     pub locals: Vec<Namespace>,
     atoms: AtomRegistry,
+    /// When to next check gc-cons-threshold, in bytes consed
+    gc_check: usize,
+    /// The lexical environment, an alist of `(VAR . VALUE)`
+    ///
+    /// This is nil when lexical binding is off, so that all variables are
+    /// dynamically bound in `locals`. Otherwise it is always a list, which
+    /// ends in `t` when there are no lexical variables, as in Emacs.
+    lexenv: LispObj,
+    /// The forms currently being evaluated, outermost first
+    stack: Vec<Activation>,
+    /// Where each list read from a file started, by the address of its first
+    /// cons
+    ///
+    /// The weak reference stops the address being reused while the entry
+    /// exists; dead entries are removed by garbage_collect.
+    positions: FnvHashMap<usize, (Weak<RefCell<ConsCell>>, SourcePos)>,
+    /// The handlers of each condition-case being evaluated
+    handlers: Vec<Vec<LispObj>>,
+    /// The tags of each catch being evaluated
+    catches: Vec<LispObj>,
+    debug_io: Option<Box<dyn DebugIo>>,
+    /// Where text printed to t and messages go
+    output: Box<dyn Output>,
+    /// Functions which enter the debugger when called
+    debug_entry: FnvHashSet<Atom>,
+    /// Enter the debugger at the start of the next form
+    debug_step: bool,
+    /// The debugger is running, so should not be entered again
+    debugging: bool,
+    /// The address of the Rust stack when the outermost form was entered
+    stack_base: usize,
+    /// How many bytes past stack_base evaluation may go
+    stack_limit: usize,
+    /// Steps evaluation has taken since the step limit was set
+    steps: Cell<usize>,
+    /// How many steps evaluation may take, see set_step_limit
+    step_limit: usize,
+    /// The value of gc::allocated() at which memory-full is signalled
+    alloc_limit: usize,
+    /// Set by an Interrupt to quit
+    interrupt: Arc<AtomicBool>,
+}
+
+/// Interrupts evaluation from another thread, as C-g does in Emacs
+///
+/// Evaluation signals quit at its next step, unless inhibit-quit is non-nil
+/// in which case it waits until inhibit-quit is nil again.
+#[derive(Clone)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
 }
 
 impl Tokenizer for Lsp {
@@ -351,6 +790,12 @@ impl Tokenizer for Lsp {
     }
 }
 
+impl Default for Lsp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Lsp {
     pub fn new() -> Lsp {
         let mut ar = AtomRegistry::with_capacity(1000);
@@ -366,29 +811,320 @@ impl Lsp {
         register_ext_funcs!(
             PlusBuiltin,
             MinusBuiltin,
+            TimesBuiltin,
+            QuoBuiltin,
+            RemBuiltin,
+            ModBuiltin,
+            Add1Builtin,
+            Sub1Builtin,
+            EqlsignBuiltin,
+            LssBuiltin,
+            GtrBuiltin,
+            LeqBuiltin,
+            GeqBuiltin,
+            NeqBuiltin,
+            MaxBuiltin,
+            MinBuiltin,
+            AbsBuiltin,
+            FloatBuiltin,
+            TruncateBuiltin,
+            FloorBuiltin,
+            CeilingBuiltin,
+            RoundBuiltin,
+            ExptBuiltin,
+            SqrtBuiltin,
+            NumberpBuiltin,
+            IntegerpBuiltin,
+            FloatpBuiltin,
+            NatnumpBuiltin,
+            FixnumpBuiltin,
+            BignumpBuiltin,
+            ZeropBuiltin,
+            LogandBuiltin,
+            LogiorBuiltin,
+            LogxorBuiltin,
+            LognotBuiltin,
+            AshBuiltin,
+            StringpBuiltin,
+            CharacterpBuiltin,
+            CharEqualBuiltin,
+            LengthBuiltin,
+            ConcatBuiltin,
+            StringBuiltin,
+            MakeStringBuiltin,
+            SubstringBuiltin,
+            StringEqualBuiltin,
+            StringLessBuiltin,
+            StringGreaterBuiltin,
+            StringPrefixPBuiltin,
+            StringSuffixPBuiltin,
+            SplitStringBuiltin,
+            StringJoinBuiltin,
+            StringTrimLeftBuiltin,
+            StringTrimRightBuiltin,
+            StringTrimBuiltin,
+            UpcaseBuiltin,
+            DowncaseBuiltin,
+            CapitalizeBuiltin,
+            UpcaseInitialsBuiltin,
+            StringToNumberBuiltin,
+            NumberToStringBuiltin,
+            StringToCharBuiltin,
+            CharToStringBuiltin,
+            FormatBuiltin,
+            FormatMessageBuiltin,
+            MessageBuiltin,
+            Prin1Builtin,
+            PrincBuiltin,
+            PrintBuiltin,
+            TerpriBuiltin,
+            Prin1ToStringBuiltin,
             QuoteBuiltin,
+            BackquoteBuiltin,
+            FunctionBuiltin,
+            LambdaBuiltin,
             InteractiveBuiltin,
-            PrintBuiltin,
+            BacktraceBuiltin,
+            DebugBuiltin,
+            DebugOnEntryBuiltin,
+            CancelDebugOnEntryBuiltin,
             ExitBuiltin,
             PrognBuiltin,
             IfBuiltin,
+            LetBuiltin,
+            LetStarBuiltin,
+            SetqBuiltin,
+            SetBuiltin,
+            CondBuiltin,
+            WhileBuiltin,
+            AndBuiltin,
+            OrBuiltin,
+            NotBuiltin,
+            WhenBuiltin,
+            UnlessBuiltin,
+            Prog1Builtin,
+            Prog2Builtin,
             EqBuiltin,
+            EqlBuiltin,
+            EqualBuiltin,
+            EqualIncludingPropertiesBuiltin,
             ConsBuiltin,
             CarBuiltin,
             CdrBuiltin,
+            SetcarBuiltin,
+            SetcdrBuiltin,
+            ListBuiltin,
+            NconcBuiltin,
+            ConspBuiltin,
             ListpBuiltin,
             LoadBuiltin,
-            FsetBuiltin
+            FsetBuiltin,
+            FuncallBuiltin,
+            ApplyBuiltin,
+            EvalBuiltin,
+            ReadBuiltin,
+            ReadFromStringBuiltin,
+            EvalBufferBuiltin,
+            EvalRegionBuiltin,
+            FuncArityBuiltin,
+            ByteCompileBuiltin,
+            ByteCompileFileBuiltin,
+            DefunBuiltin,
+            DefmacroBuiltin,
+            MacroexpandBuiltin,
+            Macroexpand1Builtin,
+            DefvarBuiltin,
+            DefconstBuiltin,
+            SymbolFunctionBuiltin,
+            FboundpBuiltin,
+            FmakunboundBuiltin,
+            BoundpBuiltin,
+            MakunboundBuiltin,
+            SymbolpBuiltin,
+            KeywordpBuiltin,
+            SymbolNameBuiltin,
+            SymbolValueBuiltin,
+            GetBuiltin,
+            PutBuiltin,
+            SymbolPlistBuiltin,
+            SetplistBuiltin,
+            FunctionGetBuiltin,
+            FunctionPutBuiltin,
+            MakeSymbolBuiltin,
+            GensymBuiltin,
+            DocumentationBuiltin,
+            InteractiveFormBuiltin,
+            SignalBuiltin,
+            ErrorBuiltin,
+            UserErrorBuiltin,
+            ConditionCaseBuiltin,
+            CatchBuiltin,
+            ThrowBuiltin,
+            UnwindProtectBuiltin,
+            ErrorMessageStringBuiltin,
+            DefineErrorBuiltin,
+            SxhashEqBuiltin,
+            SxhashEqlBuiltin,
+            SxhashEqualBuiltin,
+            MakeHashTableBuiltin,
+            HashTablePBuiltin,
+            GethashBuiltin,
+            PuthashBuiltin,
+            RemhashBuiltin,
+            ClrhashBuiltin,
+            MaphashBuiltin,
+            HashTableCountBuiltin,
+            HashTableKeysBuiltin,
+            DefineHashTableTestBuiltin,
+            MakeVectorBuiltin,
+            VectorBuiltin,
+            ArefBuiltin,
+            AsetBuiltin,
+            VconcatBuiltin,
+            EltBuiltin,
+            NthBuiltin,
+            NthcdrBuiltin,
+            AppendBuiltin,
+            ReverseBuiltin,
+            NreverseBuiltin,
+            SortBuiltin,
+            MapcarBuiltin,
+            MapcBuiltin,
+            MapconcatBuiltin,
+            DolistBuiltin,
+            DotimesBuiltin,
+            MemberBuiltin,
+            MemqBuiltin,
+            AssqBuiltin,
+            AssocBuiltin,
+            DelqBuiltin,
+            RemoveBuiltin,
+            LastBuiltin,
+            ButlastBuiltin,
+            NumberSequenceBuiltin,
+            ObarrayMakeBuiltin,
+            ObarraypBuiltin,
+            ObarrayClearBuiltin,
+            InternBuiltin,
+            InternSoftBuiltin,
+            UninternBuiltin,
+            MapatomsBuiltin,
+            GarbageCollectBuiltin
         );
 
+        // The builtin only expands the template, which makes backquote a
+        // macro that macroexpand and the compiler see through
+        let backquote = LispObj::cons(LispObj::Atm(symbols::MACRO),
+                                      g.get_fun(symbols::BACKQUOTE).unwrap());
+        g.get_or_intern(symbols::BACKQUOTE).set_fun(backquote);
+
         g.intern(Symbol::with_val(symbols::LOAD_PATH,
                                   LispObj::list_from(&[LispObj::str("lisp")])));
-
-        Lsp {
+        g.intern(Symbol::with_val(symbols::GC_CONS_THRESHOLD,
+                                  LispObj::Int(GC_DEFAULT_THRESHOLD as i64)));
+        g.intern(Symbol::with_val(symbols::GC_CONS_PERCENTAGE,
+                                  LispObj::Float(GC_DEFAULT_PERCENTAGE)));
+        g.intern(Symbol::with_val(symbols::GCS_DONE, LispObj::Int(0)));
+        g.intern(Symbol::with_val(symbols::LEXICAL_BINDING, LispObj::nil()));
+        g.intern(Symbol::with_val(symbols::DEBUG_ON_ERROR, LispObj::nil()));
+        g.get_or_intern(symbols::DEBUG_ON_ERROR).set_special();
+        g.intern(Symbol::with_val(symbols::MAX_LISP_EVAL_DEPTH,
+                                  LispObj::Int(EVAL_DEPTH_DEFAULT as i64)));
+        g.get_or_intern(symbols::MAX_LISP_EVAL_DEPTH).set_special();
+        g.intern(Symbol::with_val(symbols::CASE_FOLD_SEARCH, LispObj::t()));
+        g.get_or_intern(symbols::CASE_FOLD_SEARCH).set_special();
+        g.intern(Symbol::with_val(symbols::STANDARD_OUTPUT, LispObj::t()));
+        g.get_or_intern(symbols::STANDARD_OUTPUT).set_special();
+        g.intern(Symbol::with_val(symbols::STANDARD_INPUT, LispObj::t()));
+        g.get_or_intern(symbols::STANDARD_INPUT).set_special();
+        g.intern(Symbol::with_val(symbols::GENSYM_COUNTER, LispObj::Int(0)));
+        g.get_or_intern(symbols::GENSYM_COUNTER).set_special();
+        g.intern(Symbol::with_val(symbols::OBARRAY, global_obarray()));
+        g.get_or_intern(symbols::OBARRAY).set_special();
+        g.intern(Symbol::with_val(symbols::INHIBIT_QUIT, LispObj::nil()));
+        g.get_or_intern(symbols::INHIBIT_QUIT).set_special();
+
+        let mut lsp = Lsp {
             globals: g,
             locals: Vec::new(),
             atoms: ar,
+            gc_check: GC_MIN_THRESHOLD,
+            lexenv: LispObj::nil(),
+            stack: Vec::new(),
+            positions: FnvHashMap::default(),
+            handlers: Vec::new(),
+            catches: Vec::new(),
+            debug_io: None,
+            output: Box::new(StdOutput),
+            debug_entry: FnvHashSet::default(),
+            debug_step: false,
+            debugging: false,
+            stack_base: 0,
+            stack_limit: STACK_LIMIT_DEFAULT,
+            steps: Cell::new(0),
+            step_limit: usize::MAX,
+            alloc_limit: usize::MAX,
+            interrupt: Arc::new(AtomicBool::new(false)),
+        };
+
+        lsp.define_error(symbols::ERROR, "error", &[]);
+        lsp.define_error(symbols::QUIT, "Quit", &[]);
+        lsp.define_error(symbols::USER_ERROR, "", &[symbols::ERROR]);
+        lsp.define_error(symbols::VOID_VARIABLE, "Symbol's value as variable is void",
+                         &[symbols::ERROR]);
+        lsp.define_error(symbols::VOID_FUNCTION, "Symbol's function definition is void",
+                         &[symbols::ERROR]);
+        lsp.define_error(symbols::INVALID_FUNCTION, "Invalid function", &[symbols::ERROR]);
+        lsp.define_error(symbols::WRONG_TYPE_ARGUMENT, "Wrong type argument", &[symbols::ERROR]);
+        lsp.define_error(symbols::WRONG_NUMBER_OF_ARGUMENTS, "Wrong number of arguments",
+                         &[symbols::ERROR]);
+        lsp.define_error(symbols::FILE_ERROR, "File error", &[symbols::ERROR]);
+        lsp.define_error(symbols::FILE_MISSING, "File is missing", &[symbols::FILE_ERROR]);
+        lsp.define_error(symbols::INVALID_READ_SYNTAX, "Invalid read syntax", &[symbols::ERROR]);
+        lsp.define_error(symbols::END_OF_FILE, "End of file during parsing", &[symbols::ERROR]);
+        lsp.define_error(symbols::RECURSION_ERROR, "Excessive recursive calling error",
+                         &[symbols::ERROR]);
+        lsp.define_error(symbols::EXCESSIVE_LISP_NESTING,
+                         "Lisp nesting exceeds `max-lisp-eval-depth'",
+                         &[symbols::RECURSION_ERROR]);
+        lsp.define_error(symbols::ARITH_ERROR, "Arithmetic error", &[symbols::ERROR]);
+        lsp.define_error(symbols::RANGE_ERROR, "Arithmetic range error", &[symbols::ARITH_ERROR]);
+        lsp.define_error(symbols::OVERFLOW_ERROR, "Arithmetic overflow error",
+                         &[symbols::RANGE_ERROR]);
+        lsp.define_error(symbols::ARGS_OUT_OF_RANGE, "Args out of range", &[symbols::ERROR]);
+        lsp.define_error(symbols::INVALID_REGEXP, "Invalid regexp", &[symbols::ERROR]);
+        lsp.define_error(symbols::CIRCULAR_LIST, "List contains a loop", &[symbols::ERROR]);
+        lsp.define_error(symbols::NO_CATCH, "No catch for tag", &[symbols::ERROR]);
+        lsp.define_error(symbols::SETTING_CONSTANT, "Attempt to set a constant symbol",
+                         &[symbols::ERROR]);
+        // Like quit these are not errors, so that code which handles errors
+        // can not carry on regardless
+        lsp.define_error(symbols::STEP_LIMIT_EXCEEDED, "Evaluation step limit exceeded", &[]);
+        lsp.define_error(symbols::MEMORY_FULL, "Memory exhausted", &[]);
+
+        lsp
+    }
+
+    /// An interpreter where only the named functions are defined
+    ///
+    /// Every other builtin is left void, so untrusted code run in it can't
+    /// call things like load or reach the filesystem unless that is allowed.
+    /// Special forms such as quote, let and if are builtins too, so they
+    /// need to be named if they are wanted, except for progn which read
+    /// wraps its result in. The variables are the same as in Lsp::new and,
+    /// as each Lsp has its own globals, anything the code defines or changes
+    /// stays in the sandbox.
+    pub fn sandbox(builtins: &[&str]) -> Lsp {
+        let mut lsp = Lsp::new();
+        let mut allowed: FnvHashSet<Atom> = builtins.iter().map( |name| lsp.atomize(name) ).collect();
+        allowed.insert(lsp.atomize("progn"));
+
+        for sym in lsp.globals.iter() {
+            if !allowed.contains(&sym.name) {
+                sym.unset_fun();
+            }
         }
+        lsp
     }
 
     pub fn atomize(&mut self, name: &str) -> Atom {
@@ -401,267 +1137,991 @@ impl Lsp {
 
     pub fn set_global(&mut self, name: &str, value: LispObj) {
         let name = self.atoms.atomize(name);
-        self.globals.intern(Symbol::with_val(name, value));
-    }
-
-    pub fn read(&mut self, input: &String) -> Result<Sexp, String> {
-        match self.tokenize(input) {
-            Ok(toks) => {
-                //Iterator
-                let mut itr = toks.iter().peekable();
-                //AST root
-                let mut tree = Sexp::root(self.atoms.atomize("progn"));
-                //Are we inside a (quote ...)
-                let mut quot = false;
-
-                {
-                    let mut anc = vec![&mut tree];
-
-                    while let Some(t) = itr.next() {
-                        let cur = anc.pop().unwrap();
-
-                        match t {
-                            &Token::Lbr(c) => unsafe {
-                                let cur = cur as *mut Sexp;
-                                let nsxp = (&mut *cur).new_inner_sxp(c);
-                                // Quote only holds one item, so don't add it back to the stack
-                                if quot {
-                                    quot = false;
-                                } else {
-                                    anc.push(&mut *cur);
-                                }
-                                anc.push(nsxp);
-                            },
-                            &Token::Rbr(c) => {
-                                if cur.delim == 'R' {
-                                    return Err(format!("There are more '{}' than '{}'", c, Lsp::inv_brk(c)));
-                                } else if cur.delim != Lsp::inv_brk(c) {
-                                    return Err(format!("Mismatch '{}' with '{}'", cur.delim, c));
-                                } else if quot {
-                                    return Err(format!("Can't quote closing delimiter '{}'", c));
-                                }
-                            },
-                            &Token::Atm(a) => {
-                                cur.push(LispObj::atm(a));
-                                if quot {
-                                    quot = false;
-                                } else {
-                                    anc.push(cur);
-                                }
-                            },
-                            &Token::Num(ref n) => {
-                                cur.push(LispObj::Int(n.significand.clone()));
-                                if quot {
-                                    quot = false;
-                                } else {
-                                    anc.push(cur);
-                                }
-                            },
-                            &Token::Str(ref s) => {
-                                cur.push(LispObj::str(s));
-                                if quot {
-                                    quot = false;
-                                } else {
-                                    anc.push(cur);
-                                }
-                            },
-                            &Token::Qot => unsafe {
-                                let cur = cur as *mut Sexp;
-                                let nsxp = (&mut *cur).new_inner_sxp('(');
-                                nsxp.push(LispObj::atm(symbols::QUOTE));
-                                if !quot {
-                                    anc.push(&mut *cur);
-                                }
-                                anc.push(nsxp);
-                                quot = true;
-                            },
-                            &Token::Spc => panic!("Space token not supported"),
-                        }
-                    }
-                }
-                Ok(tree)
-            },
-            Err(e) => Err(String::from(e)),
-        }
+        self.globals.get_or_intern(name).set_val(value);
     }
 
-    pub fn print<O: Write>(&self, stream: &mut O, ast: &LispObj) -> fmt::Result {
-        match ast {
-            &LispObj::Int(i) => write!(stream, "{}", i),
-            &LispObj::Str(ref s) => write!(stream, "\"{}\"", s),
-            &LispObj::Atm(a) => write!(stream, "{}", self.stringify(a)),
-            &LispObj::Sym(ref s) => write!(stream, "{}", self.stringify(s.name)),
-            &LispObj::Sxp(ref sxp) => self.print_sxp(stream, sxp),
-            &LispObj::Lambda(ref fun) =>
-                write!(stream, "#<lambda/{}>", self.stringify(fun.name())),
-            &LispObj::Ref(ref iref) => self.print(stream, &iref.borrow()),
-            &LispObj::Ext(ref ext) => {
-                let ext = ext.borrow();
-                match ext.to_lisp() {
-                    Ok(l) => self.print(stream, &l),
-                    Err(_) => write!(stream, "#<{}>", ext.rust_name())
-                }
-            },
-            &LispObj::ExtFun(ref fun) =>
-                write!(stream, "#<extfunc/{}>", self.stringify(fun.name())),
-        }
+    /// Turn lexical binding on or off for code evaluated from now on
+    ///
+    /// This is what the `lexical-binding` file local variable does when a file
+    /// is loaded.
+    pub fn set_lexical_binding(&mut self, on: bool) {
+        self.lexenv = if on {
+            LispObj::list_from(&[LispObj::t()])
+        } else {
+            LispObj::nil()
+        };
+        self.globals.get_or_intern(symbols::LEXICAL_BINDING).set_val(LispObj::from_bool(on));
     }
 
-    pub fn print_sxp<O: Write>(&self, stream: &mut O, ast: &Sexp) -> fmt::Result {
-        let mut itr = ast.lst.iter().peekable();
-
-        if let None = itr.peek() {
-            return write!(stream, "nil");
-        }
-
-        write!(stream, "(")?;
-        self.print_itr(stream, itr);
-        write!(stream, ")")
+    /// Is lexical binding on for the code currently being evaluated
+    pub fn is_lexical(&self) -> bool {
+        !self.lexenv.is_nil()
     }
 
-    pub fn print_itr<'a, O, T>(&self, stream: &mut O, mut itr: Peekable<T>) -> fmt::Result
-        where O: Write, T: Iterator<Item=&'a LispObj>
-    {
-        while let Some(obj) = itr.next() {
-            self.print(stream, obj)?;
-            if let Some(_) = itr.peek() {
-                write!(stream, " ")?;
-            }
-        }
-        Ok(())
+    /// The `(VAR . VALUE)` cell for a lexically bound variable
+    pub fn lexical_binding(&self, name: Atom) -> Option<Cons> {
+        self.find_lexical( |var| match var {
+            &LispObj::Atm(var) => var == name,
+            _ => false,
+        })
     }
 
-    pub fn error_print(&self, msg: &str, obj: &LispObj) -> String {
-        let mut s = String::new();
-        write!(s, "{}: ", msg);
-        self.print(&mut s, obj);
-        s
+    /// The `(VAR . VALUE)` cell for a lexically bound uninterned symbol
+    pub fn lexical_symbol_binding(&self, sym: &Symbol) -> Option<Cons> {
+        self.find_lexical( |var| match var {
+            LispObj::Sym(var) => var == sym,
+            _ => false,
+        })
     }
 
-    #[inline]
-    fn eval_atm_val(&self, atm: Atom) -> Result<LispObj, String> {
-        match atm {
-            symbols::NIL | symbols::T => return Ok(LispObj::Atm(atm)),
-            _ => (),
-        };
+    fn find_lexical<P>(&self, is_var: P) -> Option<Cons>
+        where P: Fn(&LispObj) -> bool
+    {
+        let mut next = self.lexenv.clone();
 
-        for ns in self.locals.iter().rev() {
-            if let Some(var) = ns.get_val(atm) {
-                return Ok(var);
+        while let LispObj::Cons(cell) = next {
+            if let LispObj::Cons(binding) = cell.car() {
+                if is_var(&binding.0.borrow().car) {
+                    return Some(binding.clone());
+                }
             }
+            next = cell.cdr();
         }
 
-        if let Some(var) = self.globals.get_val(atm) {
-            Ok(var)
-        } else {
-            Err(format!("No variable named {}", self.atoms.stringify(atm)))
-        }
+        None
     }
 
-    #[inline]
-    fn eval_sym_val(&self, sym: &Symbol) -> Result<LispObj, String> {
-        if let Some(var) = sym.get_val() {
-            Ok(var)
-        } else {
-            Err(format!("No variable named: {}", self.atoms.stringify(sym.name)))
-        }
+    /// Would a new binding of the variable in env be lexical
+    ///
+    /// That is if env has lexical binding enabled and the variable has not
+    /// been declared special with defvar or defconst.
+    pub fn binds_lexically(&self, env: &LispObj, name: Atom) -> bool {
+        !env.is_nil() && !self.globals.get(name).is_some_and(Symbol::is_special)
     }
 
-    #[inline]
-    fn eval_ref(&mut self, iref: &LispObjRef) -> Result<LispObj, String> {
-        self.eval_inner(&iref.borrow())
+    /// Run f with the dynamic bindings in ns and the lexical environment env
+    ///
+    /// Both are restored afterwards, whether or not f succeeds.
+    pub fn with_scope<F, T>(&mut self, ns: Namespace, env: LispObj, f: F) -> T
+        where F: FnOnce(&mut Lsp) -> T
+    {
+        let outer = mem::replace(&mut self.lexenv, env);
+        self.locals.push(ns);
+        let ret = f(self);
+        self.locals.pop();
+        self.lexenv = outer;
+        ret
     }
 
-    #[inline]
-    pub fn eval_inner(&mut self, ast: &LispObj) -> Result<LispObj, String> {
-        match ast {
-            &LispObj::Atm(a) => self.eval_atm_val(a),
-            &LispObj::Sym(ref s) => self.eval_sym_val(s),
-            &LispObj::Sxp(ref sxp) => self.eval(sxp),
-            &LispObj::Ref(ref iref) => self.eval_ref(iref),
-            _ => Ok(ast.clone()),
+    /// Create a closure over the current lexical environment
+    ///
+    /// The cdr of the lambda form, `(ARGS . BODY)`, is given. If lexical
+    /// binding is off then this is just the lambda form.
+    pub fn closure(&self, def: LispObj) -> LispObj {
+        if self.is_lexical() {
+            LispObj::list_with_tail(&[LispObj::Atm(symbols::CLOSURE), self.lexenv.clone()], def)
+        } else {
+            LispObj::cons(LispObj::Atm(symbols::LAMBDA), def)
         }
     }
 
-    #[inline]
-    fn eval_rest(&mut self, args: &mut Iter<LispObj>) -> Result<Vec<LispObj>, String> {
-        args.map( |arg| self.eval_inner(arg) ).collect()
+    /// The innermost binding of a variable
+    ///
+    /// This is the symbol in the most recently pushed local namespace which
+    /// has the name, or the global symbol if there is none.
+    pub fn binding(&self, name: Atom) -> Option<&Symbol> {
+        for ns in self.locals.iter().rev() {
+            if let Some(sym) = ns.get(name) {
+                return Some(sym);
+            }
+        }
+
+        self.globals.get(name)
     }
 
-    #[inline]
-    fn apply(&mut self, fun: &Func, args: &mut Iter<LispObj>) -> Result<LispObj, String> {
-        match fun.eval_args() {
-            EvalOption::Evaluated => {
-                let ev_args = self.eval_rest(args)?;
-                fun.call(self, &mut ev_args.iter())
-            },
-            EvalOption::Unevaluated => fun.call(self, args),
-        }
+    /// Is the variable nil, t or a keyword, which always evaluate to themselves
+    pub fn is_constant(&self, name: Atom) -> bool {
+        name == symbols::NIL || name == symbols::T || self.atoms.is_keyword(name)
     }
 
-    #[inline]
-    fn eval_fn(&mut self, fun: &LispObj, args: &mut Iter<LispObj>) -> Result<LispObj, String> {
-        use std::borrow::Borrow;
+    /// Set the innermost binding of a variable
+    ///
+    /// If the variable is not bound in any local namespace then its global
+    /// value is set, as with setq in Emacs. Constants can't be set.
+    pub fn set_var(&mut self, name: Atom, value: LispObj) -> Result<(), LispError> {
+        if self.is_constant(name) {
+            return Err(LispError::setting_constant(name));
+        }
 
-        match fun {
-            &LispObj::Lambda(ref lmbda) => self.apply(lmbda, args),
-            &LispObj::ExtFun(ref extf) => self.apply(Rc::borrow(extf) as &Func, args),
-            &LispObj::Sxp(ref x) => self.eval_primitive(x, args),
-            obj => Err(self.error_print("Invalid as a function", obj)),
+        if let Some(sym) = self.binding(name) {
+            sym.set_val(value);
+            return Ok(());
         }
+
+        self.globals.get_or_intern(name).set_val(value);
+        Ok(())
     }
 
-    #[inline]
-    fn eval_atm_fn(&mut self, atm: Atom, args: &mut Iter<LispObj>) -> Result<LispObj, String> {
-        self.locals.iter().rev()
-            .map( |ns| ns.get_fun(atm) )
-            .find( |fun| fun.is_some() )
-            .unwrap_or_else( || self.globals.get_fun(atm) )
-            .map_or(Err(format!("Unrecognised function: {}", self.stringify(atm))),
-                    |fun| self.eval_fn(&fun, args) )
-    }
-
-    pub fn eval_primitive(&mut self, ast: &Sexp, args: &mut Iter<LispObj>)
-                          -> Result<LispObj, String> {
-        let mut itr = ast.lst.iter();
-
-        itr.next().ok_or(format!("nil is not a function")).and_then( |name| {
-            match name {
-                &LispObj::Atm(symbols::LAMBDA) => UserFunc::lambda(&mut itr)
-                    .and_then( |l| self.apply(&l, args) ),
-                &LispObj::Atm(symbols::MACRO) => itr.next()
-                    .ok_or(format!("Macro form should be ((macro . lambda) args..)"))
-                    .and_then( |obj| self.eval_fn(obj, args) )
-                    .and_then( |obj| self.eval_inner(&obj) ),
-                obj => Err(self.error_print("Expected lambda or macro, but got", obj)),
-            }
-        })
+    pub fn read(&mut self, input: &String) -> Result<LispObj, LispError> {
+        self.read_source(input, None)
     }
 
-    pub fn eval(&mut self, ast: &Sexp) -> Result<LispObj, String> {
-        let mut itr = ast.lst.iter();
+    /// Read the input, remembering where each list started if it came from
+    /// a file
+    ///
+    /// All the objects in the input are read and returned in a progn.
+    pub fn read_source(&mut self, input: &str, file: Option<&str>)
+                       -> Result<LispObj, LispError> {
+        let mut src = Source::new(input);
+        let file = file.map( |f| Rc::new(f.to_owned()) );
+        let mut forms = vec![LispObj::atm(self.atoms.atomize("progn"))];
+
+        while let Some(obj) = self.read_next(&mut src, &file)? {
+            forms.push(obj);
+        }
+        Ok(LispObj::list_from(&forms))
+    }
 
-        if let Some(first) = itr.next() {
-            match first {
-                &LispObj::Atm(a) => self.eval_atm_fn(a, &mut itr),
-                &LispObj::Lambda(ref fun) => self.apply(fun, &mut itr),
-                &LispObj::Sxp(ref x) => self.eval_primitive(x, &mut itr),
-                &LispObj::Sym(_) => Err(format!("Eval Symbol as func not implemented")),
-                _ => Err(self.error_print("Invalid as a function", first)),
-            }
-        } else {
-            Ok(LispObj::nil())
+    /// Read one object from input, starting at the character index start
+    ///
+    /// Returns the object and the index of the character after it.
+    pub fn read_from_str(&mut self, input: &str, start: usize)
+                         -> Result<(LispObj, usize), LispError> {
+        let mut src = Source::new(input);
+        src.skip(start);
+
+        match self.read_next(&mut src, &None)? {
+            Some(obj) => Ok((obj, src.pos())),
+            None => Err(LispError::end_of_file()),
         }
     }
 
-    pub fn load(&mut self, name: &str) -> Result<LispObj, String> {
-        let mut src = String::new();
-        {
-            let lpaths = &self.globals.get_val(symbols::LOAD_PATH).unwrap();
-            let lpaths = lpaths.sxp_val()?.lst.iter().map( |dir_path| -> &str {
-                if let &LispObj::Str(ref dir_path) = dir_path {
-                    dir_path
-                } else {
+    /// Read the next complete object, None if there is nothing left to read
+    fn read_next(&mut self, src: &mut Source, file: &Option<Rc<String>>)
+                 -> Result<Option<LispObj>, LispError> {
+        // The lists which are currently being read; their opening delimiter,
+        // items so far and starting line. A quote, backquote or comma is
+        // treated as a list which is closed as soon as it contains one
+        // item. The tail of a dotted list is read into its own list, opened
+        // by the '.'.
+        let mut anc = vec![('R', Vec::new(), 0)];
+
+        while let Some((t, line)) = self.next_token(src)? {
+            let t = &t;
+            let obj = match t {
+                &Token::Lbr(c) => {
+                    anc.push((c, Vec::new(), line));
+                    continue;
+                },
+                &Token::Qot => {
+                    anc.push(('\'', vec![LispObj::atm(symbols::QUOTE)], line));
+                    continue;
+                },
+                &Token::Bqt => {
+                    anc.push(('`', vec![LispObj::atm(symbols::BACKQUOTE)], line));
+                    continue;
+                },
+                &Token::Cma => {
+                    anc.push((',', vec![LispObj::atm(symbols::COMMA)], line));
+                    continue;
+                },
+                &Token::Spl => {
+                    anc.push(('@', vec![LispObj::atm(symbols::COMMA_AT)], line));
+                    continue;
+                },
+                &Token::Fun => {
+                    anc.push(('#', vec![LispObj::atm(symbols::FUNCTION)], line));
+                    continue;
+                },
+                &Token::Dot => {
+                    match anc.last() {
+                        Some(&('(', ref items, _)) if !items.is_empty() => (),
+                        _ => return Err(LispError::invalid_read_syntax(".")),
+                    }
+                    anc.push(('.', Vec::new(), line));
+                    continue;
+                },
+                &Token::Rbr(c) => {
+                    let (mut delim, mut items, mut start) = anc.pop().unwrap();
+                    let mut tail = LispObj::nil();
+
+                    if delim == '.' {
+                        if items.len() != 1 {
+                            return Err(LispError::invalid_read_syntax(c.to_string()));
+                        }
+                        tail = items.pop().unwrap();
+                        let (d, i, l) = anc.pop().unwrap();
+                        delim = d;
+                        items = i;
+                        start = l;
+                    }
+
+                    if delim == 'R' || Lsp::is_quote(delim) || Lsp::closing(delim) != c {
+                        return Err(LispError::invalid_read_syntax(c.to_string()));
+                    }
+
+                    match delim {
+                        '[' => LispObj::vec_from(&items),
+                        's' => match items.split_first() {
+                            Some((&LispObj::Atm(symbols::HASH_TABLE), props)) =>
+                                read_hash_table(self, props)?,
+                            // Other records are not supported
+                            _ => return Err(LispError::invalid_read_syntax("#s")),
+                        },
+                        _ => {
+                            let list = LispObj::list_with_tail(&items, tail);
+                            if let (Some(file), LispObj::Cons(cons)) = (file, &list) {
+                                let pos = SourcePos { file: Rc::clone(file), line: start };
+                                self.positions.insert(gc::addr(&cons.0),
+                                                      (Rc::downgrade(&cons.0), pos));
+                            }
+                            list
+                        },
+                    }
+                },
+                &Token::Atm(a) => LispObj::atm(a),
+                &Token::Sym(a) => LispObj::sym(a),
+                Token::Num(n) => LispObj::Int(n.significand),
+                Token::Big(i) => LispObj::integer(i.clone()),
+                &Token::Flt(f) => LispObj::Float(f),
+                Token::Str(s) => LispObj::str(s),
+                &Token::Spc => panic!("Space token not supported"),
+            };
+
+            Lsp::read_push(&mut anc, obj);
+            if anc.len() == 1 {
+                return Ok(anc.pop().unwrap().1.pop());
+            }
+        }
+
+        if anc.len() == 1 {
+            Ok(None)
+        } else {
+            Err(LispError::end_of_file())
+        }
+    }
+
+    /// The closing delimiter of a list opened by delim
+    fn closing(delim: char) -> char {
+        match delim {
+            '(' | 's' => ')',
+            c => Lsp::inv_brk(c),
+        }
+    }
+
+    /// Whether a delimiter read by `read` is one of the quoting prefixes
+    fn is_quote(delim: char) -> bool {
+        matches!(delim, '\'' | '`' | ',' | '@' | '#')
+    }
+
+    /// Add an object to the list being read, closing any quotes
+    fn read_push(anc: &mut Vec<(char, Vec<LispObj>, usize)>, obj: LispObj) {
+        let mut obj = obj;
+
+        loop {
+            {
+                let cur = anc.last_mut().unwrap();
+                cur.1.push(obj);
+                if !Lsp::is_quote(cur.0) {
+                    return;
+                }
+            }
+
+            let (_, quoted, _) = anc.pop().unwrap();
+            obj = LispObj::list_from(&quoted);
+        }
+    }
+
+    /// Print an object so that it can be read back, as prin1 does
+    pub fn print<O: Write>(&self, stream: &mut O, ast: &LispObj) -> fmt::Result {
+        self.print_obj(stream, ast, true)
+    }
+
+    /// Print an object without quoting strings or escaping symbols, as princ
+    /// does
+    pub fn princ<O: Write>(&self, stream: &mut O, ast: &LispObj) -> fmt::Result {
+        self.print_obj(stream, ast, false)
+    }
+
+    /// Print an object, escaping it if it should be readable
+    ///
+    /// Printing fails if the object is nested too deeply, see print_with.
+    pub fn print_obj<O: Write>(&self, stream: &mut O, ast: &LispObj, escape: bool) -> fmt::Result {
+        self.print_with(&mut Printer::new(stream, escape), ast).map_err( |_| fmt::Error )
+    }
+
+    /// Print an object as print_obj does, signalling the error if it can't be
+    /// printed
+    ///
+    /// This is for printing from Lisp, so each object printed is a step and
+    /// the text is counted against the allocation limit, see check_limits.
+    pub fn print_checked<O: Write>(&self, stream: &mut O, ast: &LispObj, escape: bool)
+                                   -> Result<(), LispError> {
+        let mut p = Printer::new(stream, escape);
+        p.checked = true;
+        self.print_with(&mut p, ast)
+    }
+
+    /// Print an object inside the ones the printer is already printing
+    ///
+    /// As in Emacs, an object inside itself is printed as `#N`, where N is how
+    /// many levels out it started, and a list which loops back on itself ends
+    /// with `. #N)`, where N is the index of the element it loops back to.
+    /// Objects nested more than PRINT_MAX_DEPTH deep signal an error.
+    pub fn print_with<O: Write>(&self, p: &mut Printer<O>, ast: &LispObj) -> Result<(), LispError> {
+        if p.checked {
+            self.check_limits()?;
+            self.check_alloc(p.written())?;
+        }
+
+        match ast {
+            &LispObj::Int(i) => write!(p, "{}", i)?,
+            LispObj::Big(i) => write!(p, "{}", i)?,
+            &LispObj::Float(f) => write!(p, "{}", float_to_string(f))?,
+            LispObj::Str(s) if p.escape => print_string(p, s)?,
+            LispObj::Str(s) => write!(p, "{}", s)?,
+            &LispObj::Atm(a) => print_symbol(p, self.stringify(a), p.escape)?,
+            LispObj::Sym(s) => print_symbol(p, self.stringify(s.name), p.escape)?,
+            &LispObj::Cons(_) | &LispObj::Vector(_) | &LispObj::HashTable(_) => {
+                if let Some(level) = p.level_of(ast) {
+                    write!(p, "#{}", level)?;
+                    return Ok(());
+                }
+
+                p.enter(ast)?;
+                match ast {
+                    LispObj::Vector(v) => {
+                        write!(p, "[")?;
+                        self.print_itr(p, v.borrow().iter().peekable())?;
+                        write!(p, "]")?;
+                    },
+                    LispObj::HashTable(table) => print_hash_table(self, p, &table.borrow())?,
+                    _ => self.print_list(p, ast)?,
+                }
+                p.leave();
+            },
+            LispObj::Lambda(fun) =>
+                write!(p, "#<lambda/{}>", self.stringify(fun.name()))?,
+            LispObj::Ref(iref) => self.print_with(p, &iref.borrow())?,
+            LispObj::Ext(ext) => {
+                let ext = ext.borrow();
+                match ext.to_lisp() {
+                    Ok(l) => self.print_with(p, &l)?,
+                    Err(_) => write!(p, "#<{}>", ext.rust_name())?,
+                }
+            },
+            LispObj::ExtFun(fun) =>
+                write!(p, "#<extfunc/{}>", self.stringify(fun.name()))?,
+        }
+        Ok(())
+    }
+
+    /// Print a list, including the final cdr if it is not nil
+    ///
+    /// A loop in the cdrs is found with Brent's algorithm, as in
+    /// hashtab::equal.
+    pub fn print_list<O: Write>(&self, p: &mut Printer<O>, lst: &LispObj) -> Result<(), LispError> {
+        let mut next = lst.clone();
+        let mut sep = "";
+        let (mut index, mut tortoise, mut tortoise_index) = (0, lst.clone(), 0);
+        let (mut steps, mut power) = (0, 1);
+
+        write!(p, "(")?;
+        while let LispObj::Cons(cons) = next {
+            write!(p, "{}", sep)?;
+            self.print_with(p, &cons.car())?;
+            next = cons.cdr();
+            sep = " ";
+
+            index += 1;
+            if eq(&next, &tortoise) {
+                write!(p, " . #{})", tortoise_index)?;
+                return Ok(());
+            }
+            steps += 1;
+            if steps == power {
+                tortoise = next.clone();
+                tortoise_index = index;
+                steps = 0;
+                power *= 2;
+            }
+        }
+
+        if !next.is_nil() {
+            write!(p, " . ")?;
+            self.print_with(p, &next)?;
+        }
+        write!(p, ")")?;
+        Ok(())
+    }
+
+    pub fn print_itr<'a, O, T>(&self, p: &mut Printer<O>, mut itr: Peekable<T>)
+                               -> Result<(), LispError>
+        where O: Write, T: Iterator<Item=&'a LispObj>
+    {
+        while let Some(obj) = itr.next() {
+            self.print_with(p, obj)?;
+            if itr.peek().is_some() {
+                write!(p, " ")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a new error symbol, like `define-error` in Emacs
+    ///
+    /// The error's conditions are its own name followed by the conditions of
+    /// each of its parents.
+    pub fn define_error(&mut self, name: Atom, message: &str, parents: &[Atom]) {
+        let mut conditions = vec![LispObj::atm(name)];
+
+        for parent in parents {
+            for cond in self.error_conditions(*parent) {
+                if !conditions.contains(&LispObj::atm(cond)) {
+                    conditions.push(LispObj::atm(cond));
+                }
+            }
+        }
+
+        let sym = self.globals.get_or_intern(name);
+        sym.put_prop(symbols::ERROR_CONDITIONS, LispObj::list_from(&conditions));
+        sym.put_prop(symbols::ERROR_MESSAGE, LispObj::str(message));
+    }
+
+    /// The first of a condition-case's handlers which matches the conditions
+    pub fn find_handler<'a>(&self, conditions: &[Atom], handlers: &'a [LispObj])
+                            -> Option<&'a LispObj> {
+        let matches = |cond: &LispObj| match *cond {
+            LispObj::Atm(symbols::T) => true,
+            LispObj::Atm(c) => conditions.contains(&c),
+            _ => false,
+        };
+
+        handlers.iter().find( |handler| match handler {
+            &LispObj::Cons(h) => match h.car() {
+                LispObj::Cons(ref conds) => conds.iter().any( |cond| matches(&cond) ),
+                ref cond => matches(cond),
+            },
+            _ => false,
+        })
+    }
+
+    /// The list of conditions an error symbol belongs to
+    pub fn error_conditions(&self, name: Atom) -> Vec<Atom> {
+        let conds = self.globals.get(name)
+            .and_then( |sym| sym.get_prop(symbols::ERROR_CONDITIONS) );
+
+        match conds {
+            Some(conds) => conds.iter().filter_map( |cond| {
+                if let LispObj::Atm(a) = cond { Some(a) } else { None }
+            }).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// A human readable description of an error, like `error-message-string`
+    pub fn error_message(&self, err: &LispError) -> String {
+        let mut s = String::new();
+        let mut data = err.data.iter();
+
+        let msg = if err.symbol == symbols::ERROR {
+            data.next()
+        } else {
+            self.globals.get(err.symbol)
+                .and_then( |sym| sym.get_prop(symbols::ERROR_MESSAGE) )
+        };
+
+        let princ = err.symbol == symbols::USER_ERROR ||
+            self.error_conditions(err.symbol).contains(&symbols::FILE_ERROR);
+        let mut sep = ": ";
+        match msg {
+            Some(LispObj::Str(ref msg)) if msg.is_empty() => sep = "",
+            Some(LispObj::Str(ref msg)) => s.push_str(msg),
+            _ => s.push_str("peculiar error"),
+        }
+
+        for obj in &mut data {
+            s.push_str(sep);
+            sep = ", ";
+            let _ = match obj {
+                LispObj::Str(ref txt) if princ => write!(s, "{}", txt),
+                ref obj => self.print(&mut s, obj),
+            };
+        }
+
+        let tail = data.tail();
+        if !tail.is_nil() {
+            s.push_str(sep);
+            let _ = self.print(&mut s, tail);
+        }
+        s
+    }
+
+    pub fn error_print(&self, msg: &str, obj: &LispObj) -> String {
+        let mut s = String::new();
+        let _ = write!(s, "{}: ", msg);
+        let _ = self.print(&mut s, obj);
+        s
+    }
+
+    #[inline]
+    fn eval_atm_val(&self, atm: Atom) -> Result<LispObj, LispError> {
+        match atm {
+            symbols::NIL | symbols::T => return Ok(LispObj::Atm(atm)),
+            _ => (),
+        };
+
+        if let Some(binding) = self.lexical_binding(atm) {
+            return Ok(binding.cdr());
+        }
+
+        // Keywords can't be bound, so they only need checking for here
+        match self.binding(atm).and_then( |sym| sym.get_val() ) {
+            Some(var) => Ok(var),
+            None if self.atoms.is_keyword(atm) => Ok(LispObj::Atm(atm)),
+            None => Err(LispError::void_variable(atm)),
+        }
+    }
+
+    #[inline]
+    fn eval_sym_val(&self, sym: &Symbol) -> Result<LispObj, LispError> {
+        if let Some(binding) = self.lexical_symbol_binding(sym) {
+            Ok(binding.cdr())
+        } else if let Some(var) = sym.get_val() {
+            Ok(var)
+        } else {
+            Err(LispError::void_variable(sym.name))
+        }
+    }
+
+    #[inline]
+    fn eval_ref(&mut self, iref: &LispObjRef) -> Result<LispObj, LispError> {
+        self.eval(&iref.borrow())
+    }
+
+    #[inline]
+    pub fn eval(&mut self, ast: &LispObj) -> Result<LispObj, LispError> {
+        match ast {
+            &LispObj::Atm(a) => self.eval_atm_val(a),
+            LispObj::Sym(s) => self.eval_sym_val(s),
+            LispObj::Cons(cons) => self.eval_form(cons),
+            LispObj::Ref(iref) => self.eval_ref(iref),
+            _ => Ok(ast.clone()),
+        }
+    }
+
+    #[inline]
+    fn eval_rest(&mut self, args: &mut Iter<LispObj>) -> Result<Vec<LispObj>, LispError> {
+        args.map( |arg| self.eval(arg) ).collect()
+    }
+
+    /// Evaluate each form in turn returning the value of the last
+    pub fn eval_progn<I>(&mut self, forms: I) -> Result<LispObj, LispError>
+        where I: IntoIterator, I::Item: std::borrow::Borrow<LispObj>
+    {
+        use std::borrow::Borrow;
+        let mut ret = LispObj::nil();
+
+        for form in forms {
+            ret = self.eval(form.borrow())?;
+        }
+        Ok(ret)
+    }
+
+    #[inline]
+    fn apply(&mut self, fun: &dyn Func, args: &mut Iter<LispObj>) -> Result<LispObj, LispError> {
+        match fun.eval_args() {
+            EvalOption::Evaluated => {
+                let ev_args = self.eval_rest(args)?;
+                fun.call(self, &mut ev_args.iter())
+            },
+            EvalOption::Unevaluated => fun.call(self, args),
+        }
+    }
+
+    #[inline]
+    fn eval_fn(&mut self, fun: &LispObj, args: &mut Iter<LispObj>) -> Result<LispObj, LispError> {
+        use std::borrow::Borrow;
+
+        match fun {
+            LispObj::Lambda(lmbda) => self.apply(lmbda, args),
+            LispObj::ExtFun(extf) => self.apply(Rc::borrow(extf) as &dyn Func, args),
+            LispObj::Cons(x) => self.eval_primitive(x, args),
+            obj => Err(LispError::invalid_function(obj)),
+        }
+    }
+
+    /// Find the function definition for a name
+    ///
+    /// As in Emacs, function definitions are global; let and friends only
+    /// bind variables. So there is no need to search through the locals,
+    /// which would make calls slower the deeper the stack is.
+    pub fn function(&self, atm: Atom) -> Result<LispObj, LispError> {
+        self.globals.get_fun(atm).ok_or(LispError::void_function(atm))
+    }
+
+    /// Enter the debugger if the function is marked with debug-on-entry
+    #[inline]
+    fn check_debug_entry(&mut self, atm: Atom) -> Result<(), LispError> {
+        if !self.debug_entry.is_empty() && self.debug_entry.contains(&atm) &&
+            self.debug(DebugReason::Entry(atm)) == DebugAction::Quit
+        {
+            return Err(LispError::quit());
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn eval_atm_fn(&mut self, atm: Atom, args: &mut Iter<LispObj>) -> Result<LispObj, LispError> {
+        self.check_debug_entry(atm)?;
+        let fun = self.function(atm)?;
+        self.eval_fn(&fun, args)
+    }
+
+    #[inline]
+    fn eval_sym_fn(&mut self, sym: &Symbol, args: &mut Iter<LispObj>) -> Result<LispObj, LispError> {
+        let fun = sym.get_fun().ok_or(LispError::void_function(sym.name))?;
+        self.eval_fn(&fun, args)
+    }
+
+    /// Get the function from a macro form
+    ///
+    /// This is usually `(macro lambda ARGS . BODY)` or `(macro closure ENV
+    /// ARGS . BODY)`, but `(macro FUNCTION)` is also accepted, as is `(macro
+    /// . COMPILED)`.
+    fn macro_function(form: &Cons) -> Result<LispObj, LispError> {
+        match form.cdr() {
+            LispObj::Cons(ref def) => match def.car() {
+                LispObj::Atm(symbols::LAMBDA) | LispObj::Atm(symbols::CLOSURE) =>
+                    Ok(LispObj::Cons(def.clone())),
+                fun => Ok(fun),
+            },
+            fun @ LispObj::ExtFun(_) => Ok(fun),
+            _ => Err(LispError::error("Macro form should be (macro . lambda)")),
+        }
+    }
+
+    pub fn eval_primitive(&mut self, ast: &Cons, args: &mut Iter<LispObj>)
+                          -> Result<LispObj, LispError> {
+        match ast.car() {
+            LispObj::Atm(symbols::LAMBDA) => UserFunc::lambda(&ast.cdr())
+                .and_then( |l| self.apply(&l, args) ),
+            LispObj::Atm(symbols::CLOSURE) => UserFunc::closure(&ast.cdr())
+                .and_then( |l| self.apply(&l, args) ),
+            LispObj::Atm(symbols::MACRO) => {
+                let def = Lsp::macro_function(ast)?;
+                let expansion = self.funcall(&def, args.as_slice())?;
+                self.eval(&expansion)
+            },
+            _ => Err(LispError::invalid_function(&LispObj::Cons(ast.clone()))),
+        }
+    }
+
+    /// Call a function with arguments which have already been evaluated
+    pub fn funcall(&mut self, fun: &LispObj, args: &[LispObj]) -> Result<LispObj, LispError> {
+        match fun {
+            &LispObj::Atm(a) => {
+                self.check_debug_entry(a)?;
+                let fun = self.function(a)?;
+                self.funcall(&fun, args)
+            },
+            LispObj::Sym(sym) => {
+                let fun = sym.get_fun().ok_or(LispError::void_function(sym.name))?;
+                self.funcall(&fun, args)
+            },
+            LispObj::Lambda(lmbda) => lmbda.call(self, &mut args.iter()),
+            LispObj::ExtFun(extf) => extf.call(self, &mut args.iter()),
+            LispObj::Cons(x) => match x.car() {
+                LispObj::Atm(symbols::LAMBDA) => UserFunc::lambda(&x.cdr())?
+                    .call(self, &mut args.iter()),
+                LispObj::Atm(symbols::CLOSURE) => UserFunc::closure(&x.cdr())?
+                    .call(self, &mut args.iter()),
+                _ => Err(LispError::invalid_function(fun)),
+            },
+            obj => Err(LispError::invalid_function(obj)),
+        }
+    }
+
+    /// Evaluate a list as a function call or special form
+    pub fn eval_form(&mut self, form: &Cons) -> Result<LispObj, LispError> {
+        if gc::consed() > self.gc_check {
+            self.maybe_garbage_collect();
+        }
+        self.check_limits()?;
+
+        let args = form.cdr().list_to_vec()?;
+        let mut itr = args.iter();
+
+        self.check_eval_depth()?;
+        self.stack.push(Activation {
+            form: LispObj::Cons(form.clone()),
+            locals: self.locals.len(),
+            lexenv: self.lexenv.clone(),
+        });
+
+        if self.debug_step {
+            self.debug_step = false;
+            if self.debug(DebugReason::Step) == DebugAction::Quit {
+                self.stack.pop();
+                return Err(LispError::quit());
+            }
+        }
+
+        let mut res = match form.car() {
+            LispObj::Atm(a) => self.eval_atm_fn(a, &mut itr),
+            LispObj::Lambda(ref fun) => self.apply(fun, &mut itr),
+            LispObj::Cons(ref x) => self.eval_primitive(x, &mut itr),
+            LispObj::Sym(ref sym) => self.eval_sym_fn(sym, &mut itr),
+            ref first => Err(LispError::invalid_function(first)),
+        };
+
+        self.unwinding(&mut res);
+        self.stack.pop();
+        res
+    }
+
+    /// Signal excessive-lisp-nesting if another activation would go too deep
+    ///
+    /// Both the number of activations, which is limited by
+    /// max-lisp-eval-depth, and the amount of Rust stack used are checked. The
+    /// variable is only looked up once the depth passes the minimum limit.
+    #[inline]
+    fn check_eval_depth(&mut self) -> Result<(), LispError> {
+        let marker = 0u8;
+        let here = &marker as *const u8 as usize;
+        let depth = self.stack.len();
+
+        if depth == 0 {
+            self.stack_base = here;
+        }
+        let used = cmp::max(here, self.stack_base) - cmp::min(here, self.stack_base);
+
+        if depth < EVAL_DEPTH_MIN && used < self.stack_limit {
+            return Ok(());
+        }
+
+        let mut max = match self.eval_atm_val(symbols::MAX_LISP_EVAL_DEPTH) {
+            Ok(LispObj::Int(i)) if i > 0 => cmp::max(i as usize, EVAL_DEPTH_MIN),
+            _ => EVAL_DEPTH_MIN,
+        };
+        if self.debugging {
+            max += EVAL_DEPTH_DEBUG;
+        }
+
+        if depth >= max || used >= self.stack_limit {
+            Err(LispError::excessive_lisp_nesting(depth + 1))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Set how many bytes of Rust stack evaluation may use
+    ///
+    /// This should be somewhat less than the size of the thread's stack.
+    pub fn set_stack_limit(&mut self, bytes: usize) {
+        self.stack_limit = bytes;
+    }
+
+    /// Limit how many more steps evaluation may take, or remove the limit
+    ///
+    /// A step is evaluating a form, calling a function from byte code, going
+    /// around a loop, backtracking in a regexp match, or taking an element of
+    /// a list, comparing one with equal or printing one. The count starts from
+    /// zero each time this is called and once the limit is passed
+    /// step-limit-exceeded is signalled at every step, until this is called
+    /// again.
+    pub fn set_step_limit(&mut self, steps: Option<usize>) {
+        self.steps.set(0);
+        self.step_limit = steps.unwrap_or(usize::MAX);
+    }
+
+    /// Limit how many more bytes of Lisp data may be allocated, or remove the
+    /// limit
+    ///
+    /// Freeing data does not give the bytes back, so this is a budget rather
+    /// than a cap on the memory in use. Allocations are counted for the whole
+    /// thread, like the heap in gc. The limit is checked at each step, and
+    /// before a builtin makes an object of a size it was asked for, see
+    /// check_alloc.
+    pub fn set_alloc_limit(&mut self, bytes: Option<usize>) {
+        self.alloc_limit = match bytes {
+            Some(bytes) => gc::allocated().saturating_add(bytes),
+            None => usize::MAX,
+        };
+    }
+
+    /// Signal memory-full if allocating this many more bytes would go over
+    /// the limit
+    ///
+    /// The bytes are counted when the object is made, this only stops an
+    /// object being made which would be too big.
+    pub fn check_alloc(&self, bytes: usize) -> Result<(), LispError> {
+        if gc::allocated().saturating_add(bytes) > self.alloc_limit {
+            Err(LispError::memory_full())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// A handle which another thread can use to interrupt evaluation
+    pub fn interrupt_handle(&self) -> Interrupt {
+        Interrupt(self.interrupt.clone())
+    }
+
+    /// Count a step of evaluation, signalling if a limit has been reached or
+    /// evaluation has been interrupted
+    #[inline]
+    pub fn check_limits(&self) -> Result<(), LispError> {
+        self.steps.set(self.steps.get() + 1);
+
+        if self.steps.get() > self.step_limit || gc::allocated() > self.alloc_limit ||
+            self.interrupt.load(Ordering::Relaxed) {
+            self.limit_reached()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn limit_reached(&self) -> Result<(), LispError> {
+        if self.steps.get() > self.step_limit {
+            // Don't let the count wrap back under the limit
+            self.steps.set(self.step_limit);
+            return Err(LispError::step_limit_exceeded(self.step_limit));
+        }
+        if gc::allocated() > self.alloc_limit {
+            return Err(LispError::memory_full());
+        }
+
+        match self.eval_atm_val(symbols::INHIBIT_QUIT) {
+            Ok(ref inhibit) if !inhibit.is_nil() => Ok(()),
+            _ => {
+                self.interrupt.store(false, Ordering::SeqCst);
+                Err(LispError::new(symbols::QUIT, LispObj::nil()))
+            },
+        }
+    }
+
+    /// Called with the result of the innermost activation on the stack
+    ///
+    /// If this is a new error then record the backtrace and, if wanted, enter
+    /// the debugger before the stack is unwound.
+    fn unwinding(&mut self, res: &mut Result<LispObj, LispError>) {
+        let mut quit = false;
+
+        if let Err(ref mut e) = *res {
+            if e.backtrace.is_none() {
+                e.backtrace = Some(self.backtrace());
+                quit = self.wants_debugger(e) &&
+                    self.debug(DebugReason::Error(e)) == DebugAction::Quit;
+            }
+        }
+        if quit {
+            *res = Err(LispError::quit());
+        }
+    }
+
+    /// Where a form was read from, if it was read from a file
+    pub fn source_pos(&self, form: &LispObj) -> Option<SourcePos> {
+        match form {
+            LispObj::Cons(cons) => self.positions.get(&gc::addr(&cons.0))
+                .map( |(_, pos)| pos.clone() ),
+            _ => None,
+        }
+    }
+
+    /// The forms currently being evaluated, innermost first
+    pub fn backtrace(&self) -> Vec<Frame> {
+        self.stack.iter().rev().map( |act| Frame {
+            form: act.form.clone(),
+            pos: self.source_pos(&act.form),
+        }).collect()
+    }
+
+    /// Describe a backtrace, one frame per line
+    ///
+    /// Long forms are cut short, the location is given where it is known.
+    pub fn format_backtrace(&self, frames: &[Frame]) -> String {
+        const MAX_WIDTH: usize = 72;
+        let mut out = String::new();
+
+        for frame in frames {
+            let mut form = String::new();
+            let _ = self.print(&mut form, &frame.form);
+            if form.chars().count() > MAX_WIDTH {
+                form = form.chars().take(MAX_WIDTH - 3).collect::<String>() + "...";
+            }
+
+            match frame.pos {
+                Some(ref pos) => out.push_str(&format!("  {}  [{}]\n", form, pos)),
+                None => out.push_str(&format!("  {}\n", form)),
+            }
+        }
+        out
+    }
+
+    /// Collect garbage if more than gc-cons-threshold bytes, or the
+    /// gc-cons-percentage of the heap which survived the last collection,
+    /// have been consed
+    ///
+    /// Looking the variables up on every call would be slow, so they are only
+    /// checked each time another GC_MIN_THRESHOLD bytes have been consed. Like
+    /// Emacs, smaller thresholds are treated as the minimum. Growing the
+    /// threshold with the heap keeps the time spent tracing survivors in
+    /// proportion to the amount allocated.
+    fn maybe_garbage_collect(&mut self) {
+        let threshold = match self.eval_atm_val(symbols::GC_CONS_THRESHOLD) {
+            Ok(LispObj::Int(i)) if i > 0 => i as usize,
+            _ => GC_DEFAULT_THRESHOLD,
+        };
+        let percentage = match self.eval_atm_val(symbols::GC_CONS_PERCENTAGE) {
+            Ok(LispObj::Float(f)) if f > 0.0 => f,
+            _ => GC_DEFAULT_PERCENTAGE,
+        };
+        let threshold = cmp::max(threshold, (percentage * gc::survived() as f64) as usize);
+        let consed = gc::consed();
+        gc::set_threshold(2 * cmp::max(threshold, GC_MIN_THRESHOLD));
+
+        if consed > threshold {
+            self.garbage_collect();
+        } else {
+            self.gc_check = consed + GC_MIN_THRESHOLD;
+        }
+    }
+
+    /// Find and free unreachable cyclic data
+    ///
+    /// Returns the statistics in the same form as Emacs; a list of
+    /// `(NAME SIZE USED FREED)` for each type of object.
+    pub fn garbage_collect(&mut self) -> LispObj {
+        let stats = gc::collect();
+        self.gc_check = GC_MIN_THRESHOLD;
+        self.positions.retain( |_, &mut (ref cons, _)| cons.upgrade().is_some() );
+
+        let gcs_done = self.globals.get_or_intern(symbols::GCS_DONE);
+        let done = match gcs_done.get_val() {
+            Some(LispObj::Int(i)) => i + 1,
+            _ => 1,
+        };
+        gcs_done.set_val(LispObj::Int(done));
+
+        let kinds: Vec<LispObj> = gc::KINDS.iter().enumerate().map( |(i, &(name, size))| {
+            LispObj::list_from(&[LispObj::Atm(self.atomize(name)),
+                                 LispObj::Int(size as i64),
+                                 LispObj::Int(stats.used[i] as i64),
+                                 LispObj::Int(stats.freed[i] as i64)])
+        }).collect();
+        LispObj::list_from(&kinds)
+    }
+
+    pub fn load(&mut self, name: &str) -> Result<LispObj, LispError> {
+        let mut src = String::new();
+        let file_name;
+        {
+            let lpaths = self.globals.get_val(symbols::LOAD_PATH).unwrap().list_to_vec()?;
+            let lpaths = lpaths.iter().map( |dir_path| -> &str {
+                if let LispObj::Str(dir_path) = dir_path {
+                    dir_path
+                } else {
                     ""
                 }
             });
@@ -670,7 +2130,7 @@ impl Lsp {
                 match Path::new(dir_path).read_dir() {
                     Ok(mut items) => {
                         items.find( |item| {
-                            if let &Ok(ref item) = item {
+                            if let Ok(item) = item {
                                 if !item.file_type().unwrap().is_dir() {
                                     let path = item.path();
                                     if let (Some(stem), Some(ext)) = (path.file_stem(), path.extension()) {
@@ -691,91 +2151,1782 @@ impl Lsp {
                 }
             }).find( |load_file| load_file.is_some() );
 
-            if file.is_none() {
-                return Err(format!("Can not find {:?} in load-path", name));
-            }
-            let path = file.unwrap().unwrap().unwrap().path();
+            if file.is_none() {
+                return Err(LispError::new(symbols::FILE_MISSING, LispObj::list_from(&[
+                    LispObj::str("Cannot open load file"),
+                    LispObj::str("No such file or directory"),
+                    LispObj::str(&name.to_string_lossy()),
+                ])));
+            }
+            let path = file.unwrap().unwrap().unwrap().path();
+            file_name = path.to_string_lossy().into_owned();
+
+            match File::open(path) {
+                Ok(mut fh) => {
+                    if let Err(e) = fh.read_to_string(&mut src) {
+                        return Err(LispError::file_error("Reading file", e));
+                    }
+                },
+                Err(e) => {
+                    return Err(LispError::file_error("Opening input file", e));
+                },
+            }
+        }
+
+        let sexp = self.read_source(&src, Some(&file_name))?;
+        self.eval_source(&src, &sexp)
+    }
+
+    /// Evaluate what was read from src, with lexical binding if its first
+    /// line asks for it
+    pub fn eval_source(&mut self, src: &str, sexp: &LispObj) -> Result<LispObj, LispError> {
+        let env = if lexical_binding_cookie(src) {
+            LispObj::list_from(&[LispObj::t()])
+        } else {
+            LispObj::nil()
+        };
+        let mut ns = Namespace::new();
+        ns.intern(Symbol::with_val(symbols::LEXICAL_BINDING, LispObj::from_bool(!env.is_nil())));
+
+        self.with_scope(ns, env, |lsp| lsp.eval(sexp))
+    }
+}
+
+/// Format a float the way Emacs prints it
+///
+/// That is the shortest representation which reads back as the same number,
+/// with at least 15 significant digits before switching to an exponent.
+pub fn float_to_string(f: f64) -> String {
+    if f.is_nan() {
+        return if f.is_sign_negative() { "-0.0e+NaN" } else { "0.0e+NaN" }.to_owned();
+    } else if f.is_infinite() {
+        return if f < 0.0 { "-1.0e+INF" } else { "1.0e+INF" }.to_owned();
+    }
+
+    let sci = format!("{:e}", f);
+    let (mant, exp) = sci.split_at(sci.find('e').unwrap());
+    let exp: i32 = exp[1..].parse().unwrap();
+    let digits = mant.chars().filter( |c| c.is_ascii_digit() ).count() as i32;
+
+    if exp < -4 || exp >= cmp::max(15, digits) {
+        format!("{}e{}{:02}", mant, if exp < 0 { '-' } else { '+' }, exp.abs())
+    } else {
+        let fixed = format!("{}", f);
+        if fixed.contains('.') { fixed } else { fixed + ".0" }
+    }
+}
+
+/// Print a string in double quotes, escaping quotes and backslashes
+fn print_string<O: Write>(stream: &mut O, s: &str) -> fmt::Result {
+    stream.write_char('"')?;
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            stream.write_char('\\')?;
+        }
+        stream.write_char(c)?;
+    }
+    stream.write_char('"')
+}
+
+/// Print a symbol's name, escaping anything which would stop it reading
+/// back as the same symbol
+fn print_symbol<O: Write>(stream: &mut O, name: &str, escape: bool) -> fmt::Result {
+    if !escape {
+        return write!(stream, "{}", name);
+    }
+    if name.is_empty() {
+        return write!(stream, "##");
+    }
+    // These would be read as a number, a character or a dotted pair
+    if tokenizer::looks_like_number(name) || name.starts_with('?') || name == "." {
+        stream.write_char('\\')?;
+    }
+
+    for c in name.chars() {
+        match c {
+            '"' | '\\' | '\'' | ';' | '#' | '(' | ')' | ',' | '`' | '[' | ']' =>
+                stream.write_char('\\')?,
+            c if c <= ' ' || c == '\u{a0}' => stream.write_char('\\')?,
+            _ => (),
+        }
+        stream.write_char(c)?;
+    }
+    Ok(())
+}
+
+/// Does the first line of the source set lexical-binding to non-nil
+///
+/// This looks for the file local variables line, for example:
+/// `;;; foo.el --- Foo -*- lexical-binding: t -*-`
+fn lexical_binding_cookie(src: &str) -> bool {
+    let line = src.lines().next().unwrap_or("");
+    let vars = match line.find("-*-") {
+        Some(start) => {
+            let rest = &line[start + 3..];
+            match rest.find("-*-") {
+                Some(end) => &rest[..end],
+                None => return false,
+            }
+        },
+        None => return false,
+    };
+
+    vars.split(';').any( |var| {
+        let mut parts = var.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(val)) => name.trim() == "lexical-binding" && val.trim() != "nil",
+            _ => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+
+    /// Evaluate each source and compare what it prints as with the expected text
+    fn assert_evals(lsp: &mut Lsp, cases: &[(&str, &str)]) {
+        for &(src, expected) in cases.iter() {
+            let ast = lsp.read(&src.to_owned()).unwrap();
+            let res = lsp.eval(&ast).unwrap_or_else( |e| panic!("{}: {:?}", src, e) );
+            let mut out = String::new();
+            lsp.print(&mut out, &res).unwrap();
+            assert_eq!((src, out.as_str()), (src, expected));
+        }
+    }
+
+    /// Evaluate each source and check that it signals the expected error
+    fn assert_signals(lsp: &mut Lsp, errors: &[(&str, Atom)]) {
+        for &(src, symbol) in errors.iter() {
+            let ast = lsp.read(&src.to_owned()).unwrap();
+            assert_eq!((src, lsp.eval(&ast).unwrap_err().symbol), (src, symbol));
+        }
+    }
+
+    #[test]
+    fn reasonabl_obj_size() {
+        let lisp_obj_size = size_of::<LispObj>();
+        println!("size of String = {}", size_of::<String>());
+        println!("size of char = {}", size_of::<char>());
+        println!("size of Cons = {}", size_of::<Cons>());
+        println!("size of UserFunc = {}", size_of::<UserFunc>());
+        println!("size of LispObjRef = {}", size_of::<LispObjRef>());
+        println!("size of LispObj = {}", lisp_obj_size);
+        println!("size of Vec<LispObj> = {}", size_of::<Vec<LispObj>>());
+        assert!(lisp_obj_size <= 64);
+    }
+
+    #[test]
+    fn lambda_primitive_no_args() {
+        let mut lsp = Lsp::new();
+        let src = "((lambda () (+ 1 2)))".to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast), Ok(LispObj::Int(3)));
+    }
+
+    #[test]
+    fn lambda_primitive() {
+        let mut lsp = Lsp::new();
+        let src = "((lambda (a b) (+ a b)) 1 2)".to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast), Ok(LispObj::Int(3)));
+    }
+
+    #[test]
+    fn macro_primitive() {
+        let mut lsp = Lsp::new();
+        let src = "((macro (lambda (a b) (cons '+ (cons a (cons b nil))))) 1 2)".to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast), Ok(LispObj::Int(3)));
+    }
+
+    #[test]
+    fn cons_list() {
+        let mut lsp = Lsp::new();
+        let src = "(cons 1 (cons 2 (cons 3 nil)))".to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast),
+                   Ok(LispObj::list_from(&[LispObj::Int(1), LispObj::Int(2), LispObj::Int(3)])));
+    }
+
+    #[test]
+    fn signal_uncaught() {
+        let mut lsp = Lsp::new();
+        let src = "(signal 'wrong-type-argument '(integerp \"a\"))".to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        let err = lsp.eval(ast).unwrap_err();
+        assert_eq!(err.symbol, symbols::WRONG_TYPE_ARGUMENT);
+        assert_eq!(lsp.error_message(&err), "Wrong type argument: integerp, \"a\"");
+    }
+
+    #[test]
+    fn condition_case() {
+        let mut lsp = Lsp::new();
+        let src = "(condition-case err (car 1) (void-variable 'void) (wrong-type-argument (cdr err)))"
+            .to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast),
+                   Ok(LispObj::list_from(&[LispObj::atm(symbols::LISTP), LispObj::Int(1)])));
+
+        let src = "(condition-case err (quote a b) (wrong-number-of-arguments (cdr err)))".to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        let quote = LispObj::atm(lsp.atomize("quote"));
+        assert_eq!(lsp.eval(ast), Ok(LispObj::list_from(&[quote, LispObj::Int(2)])));
+    }
+
+    #[test]
+    fn condition_case_parent() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(define-error 'my-error "My error" 'file-error)
+(condition-case err
+    (signal 'my-error '("oops"))
+  (error (error-message-string err)))
+"#.to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast), Ok(LispObj::str("My error: oops")));
+
+        let src = "(condition-case nil (foo) ((arith-error file-error) 1))".to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast).unwrap_err().symbol, symbols::VOID_FUNCTION);
+    }
+
+    #[test]
+    fn user_error() {
+        let mut lsp = Lsp::new();
+        let src = "(user-error \"Bad %s: %S\" \"thing\" \"str\")".to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        let err = lsp.eval(ast).unwrap_err();
+        assert_eq!(err.symbol, symbols::USER_ERROR);
+        assert_eq!(lsp.error_message(&err), "Bad thing: \"str\"");
+    }
+
+    #[test]
+    fn print() {
+        let mut lsp = Lsp::new();
+        let src = "(a b (c))";
+        let mut out = String::with_capacity(10);
+
+        let ast = &lsp.read(&src.to_owned()).unwrap();
+        assert!(lsp.print(&mut out, ast).is_ok());
+        assert_eq!(out, format!("(progn {})", src));
+    }
+
+    #[test]
+    fn print_dotted() {
+        let mut lsp = Lsp::new();
+        let src = "(cons 1 (cons 2 3))".to_owned();
+        let mut out = String::new();
+
+        let ast = &lsp.read(&src).unwrap();
+        let res = lsp.eval(ast).unwrap();
+        assert!(lsp.print(&mut out, &res).is_ok());
+        assert_eq!(out, "(1 2 . 3)");
+    }
+
+    #[test]
+    fn shared_structure() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(fset 'share '(lambda (l) (setcar (cdr l) 'b) l))
+(share (list 1 2 3))
+"#.to_owned();
+        let mut out = String::new();
+
+        let ast = &lsp.read(&src).unwrap();
+        let res = lsp.eval(ast).unwrap();
+        assert!(lsp.print(&mut out, &res).is_ok());
+        assert_eq!(out, "(1 b 3)");
+
+        let src = "(nconc (list 1) nil (list 2 3) (list 4))".to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast),
+                   Ok(LispObj::list_from(&[LispObj::Int(1), LispObj::Int(2),
+                                           LispObj::Int(3), LispObj::Int(4)])));
+    }
+
+    #[test]
+    fn let_and_setq() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(setq x 1 y 2)
+(let ((x 10) (y x) z)
+  (setq z (+ x y))
+  (list x y z))
+"#.to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast), Ok(LispObj::list_from(&[LispObj::Int(10), LispObj::Int(1),
+                                                          LispObj::Int(11)])));
+
+        let src = "(let* ((x 10) (y x)) (setq x 3) (list x y))".to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast), Ok(LispObj::list_from(&[LispObj::Int(3), LispObj::Int(10)])));
+
+        let src = "(list x y)".to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast), Ok(LispObj::list_from(&[LispObj::Int(1), LispObj::Int(2)])));
+
+        let src = "(setq x)".to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast).unwrap_err().symbol, symbols::WRONG_NUMBER_OF_ARGUMENTS);
+    }
+
+    #[test]
+    fn dynamic_binding() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(setq depth 0)
+(fset 'get-depth '(lambda () depth))
+(fset 'bump '(lambda () (setq depth (+ depth 1))))
+(list (let ((depth 5)) (bump) (get-depth)) depth)
+"#.to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast), Ok(LispObj::list_from(&[LispObj::Int(6), LispObj::Int(0)])));
+    }
+
+    #[test]
+    fn while_loop() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(let ((i 5) (acc nil))
+  (while (not (eq i 0))
+    (setq acc (cons i acc))
+    (setq i (- i 1)))
+  acc)
+"#.to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast),
+                   Ok(LispObj::list_from(&[LispObj::Int(1), LispObj::Int(2), LispObj::Int(3),
+                                           LispObj::Int(4), LispObj::Int(5)])));
+    }
+
+    #[test]
+    fn conditionals() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(list (cond ((eq 1 2) 'a) ((+ 1 1)) (t 'c))
+      (cond ((eq 1 2) 'a))
+      (and) (and 1 nil 2) (and 1 2)
+      (or) (or nil 2 3)
+      (when t 1 2) (when nil 1)
+      (unless nil 3) (unless t 3)
+      (prog1 1 2 3) (prog2 1 2 3))
+"#.to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        let res = lsp.eval(ast).unwrap();
+        let mut out = String::new();
+        assert!(lsp.print(&mut out, &res).is_ok());
+        assert_eq!(out, "(2 nil t nil 2 nil 2 2 nil 3 nil 1 2)");
+    }
 
-            match File::open(path) {
-                Ok(mut fh) => {
-                    if let Err(e) = fh.read_to_string(&mut src) {
-                        return Err(format!("LOAD I/O ERROR: {}", e));
-                    }
-                },
-                Err(e) => {
-                    return Err(format!("LOAD FILE ERROR: {}", e));
-                },
-            }
-        }
+    #[test]
+    fn defun_defmacro() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(defun add (a b)
+  "Add A to B."
+  (interactive "p")
+  (+ a b))
+(defmacro my-inc (var)
+  (list 'setq var (list '+ var 1)))
+(setq n (add 1 2))
+(my-inc n)
+(list n (documentation 'add) (interactive-form 'add) (fboundp 'add) (fboundp 'nope))
+"#.to_owned();
+        let mut out = String::new();
+
+        let ast = &lsp.read(&src).unwrap();
+        let res = lsp.eval(ast).unwrap();
+        assert!(lsp.print(&mut out, &res).is_ok());
+        assert_eq!(out, r#"(4 "Add A to B." (interactive "p") t nil)"#);
 
-        let sexp = self.read(&src)?;
-        self.eval(&sexp)
+        let src = "(fmakunbound 'add) (add 1 2)".to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast).unwrap_err().symbol, symbols::VOID_FUNCTION);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::mem::size_of;
+    #[test]
+    fn defvar_defconst() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(setq a 1)
+(defvar a 2 "A variable.")
+(defvar b 3)
+(defvar b 4)
+(defconst c 5)
+(defconst c 6)
+(list a b c (boundp 'd) (progn (defvar d) (boundp 'd)))
+"#.to_owned();
+
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast),
+                   Ok(LispObj::list_from(&[LispObj::Int(1), LispObj::Int(3), LispObj::Int(6),
+                                           LispObj::nil(), LispObj::nil()])));
+
+        let a = lsp.atomize("a");
+        let sym = lsp.globals.get(a).unwrap();
+        assert!(sym.is_special());
+        assert_eq!(sym.get_prop(symbols::VARIABLE_DOCUMENTATION), Some(LispObj::str("A variable.")));
+
+        let src = "(let ((a 10)) (makunbound 'a) (boundp 'a))".to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast), Ok(LispObj::nil()));
+        let src = "(list a (makunbound 'a) (boundp 'a))".to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast),
+                   Ok(LispObj::list_from(&[LispObj::Int(1), LispObj::Atm(a), LispObj::nil()])));
+    }
 
     #[test]
-    fn reasonabl_obj_size() {
-        let lisp_obj_size = size_of::<LispObj>();
-        println!("size of String = {}", size_of::<String>());
-        println!("size of char = {}", size_of::<char>());
-        println!("size of Sexp = {}", size_of::<Sexp>());
-        println!("size of UserFunc = {}", size_of::<UserFunc>());
-        println!("size of LispObjRef = {}", size_of::<LispObjRef>());
-        println!("size of LispObj = {}", lisp_obj_size);
-        println!("size of Vec<LispObj> = {}", size_of::<Vec<LispObj>>());
-        assert!(lisp_obj_size <= 64);
+    fn lexical_closure() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(defun make-counter (start)
+  (let ((n start))
+    (lambda () (setq n (+ n 1)))))
+(setq c1 (make-counter 10))
+(setq c2 (make-counter 20))
+(funcall c1)
+(list (funcall c1) (funcall c2) (boundp 'n))
+"#.to_owned();
+
+        lsp.set_lexical_binding(true);
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast),
+                   Ok(LispObj::list_from(&[LispObj::Int(12), LispObj::Int(21),
+                                           LispObj::nil()])));
     }
 
     #[test]
-    fn lambda_primitive_no_args() {
+    fn optional_and_rest_args() {
         let mut lsp = Lsp::new();
-        let src = "((lambda () (+ 1 2)))".to_owned();
+        let src = r#"
+(defun opt (a &optional b &rest c) (list a b c))
+(list (opt 1) (opt 1 2) (opt 1 2 3 4)
+      (func-arity 'opt) (func-arity (lambda (a b) a)) (func-arity 'car) (func-arity 'setq)
+      (func-arity '+) (func-arity 'if))
+"#.to_owned();
+        let mut out = String::new();
 
         let ast = &lsp.read(&src).unwrap();
-        assert_eq!(lsp.eval(ast), Ok(LispObj::Int(3)));
+        let res = lsp.eval(ast).unwrap();
+        assert!(lsp.print(&mut out, &res).is_ok());
+        assert_eq!(out, "((1 nil nil) (1 2 nil) (1 2 (3 4)) (1 . many) (2 . 2) \
+                         (1 . 1) (0 . unevalled) (0 . many) (2 . unevalled))");
     }
 
     #[test]
-    fn lambda_primitive() {
+    fn wrong_number_of_args() {
         let mut lsp = Lsp::new();
-        let src = "((lambda (a b) (+ a b)) 1 2)".to_owned();
+        let src = "(defun two (a &optional b) a)".to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        lsp.eval(ast).unwrap();
+
+        for &(src, count) in [("(two)", 0), ("(two 1 2 3)", 3)].iter() {
+            let ast = &lsp.read(&src.to_owned()).unwrap();
+            let err = lsp.eval(ast).unwrap_err();
+            assert_eq!(err.symbol, symbols::WRONG_NUMBER_OF_ARGUMENTS);
+            assert_eq!(err.data, LispObj::list_from(&[
+                LispObj::cons(LispObj::Int(1), LispObj::Int(2)), LispObj::Int(count)
+            ]));
+        }
 
+        // Builtins are checked against the arity they declare
+        for &(src, name, count) in [("(car)", "car", 0), ("(cons 1 2 3)", "cons", 3),
+                                    ("(funcall 'car 1 2)", "car", 2), ("(if t)", "if", 1)].iter() {
+            let ast = &lsp.read(&src.to_owned()).unwrap();
+            let err = lsp.eval(ast).unwrap_err();
+            assert_eq!(err.symbol, symbols::WRONG_NUMBER_OF_ARGUMENTS);
+            assert_eq!(err.data, LispObj::list_from(&[
+                LispObj::Atm(lsp.atomize(name)), LispObj::Int(count)
+            ]));
+        }
+
+        // progn takes no arguments, as does the form a source is read into
+        assert_evals(&mut lsp, &[("(progn)", "nil"), ("", "nil"), ("; only a comment\n", "nil"),
+                                 ("(func-arity 'progn)", "(0 . unevalled)")]);
+
+        let src = "(lambda (&rest) nil)".to_owned();
         let ast = &lsp.read(&src).unwrap();
-        assert_eq!(lsp.eval(ast), Ok(LispObj::Int(3)));
+        assert_eq!(lsp.eval(ast).and_then( |f| lsp.funcall(&f, &[]) ).unwrap_err().symbol,
+                   symbols::INVALID_FUNCTION);
     }
 
     #[test]
-    fn macro_primitive() {
+    fn backquote() {
         let mut lsp = Lsp::new();
-        let src = "((macro (lambda (a b) (cons '+ (cons a b)))) 1 2)".to_owned();
+        let src = r#"
+(defmacro inc-by (var &rest amounts)
+  `(setq ,var (+ ,var ,@amounts)))
+(setq n 1 xs '(2 3))
+(inc-by n 1 2)
+(list n `(a [b ,n ,@xs] (c ,@xs . d)) `(1 `(2 ,(3 ,n ,@xs))))
+"#.to_owned();
+        let mut out = String::new();
 
         let ast = &lsp.read(&src).unwrap();
-        assert_eq!(lsp.eval(ast), Ok(LispObj::Int(3)));
+        let res = lsp.eval(ast).unwrap();
+        assert!(lsp.print(&mut out, &res).is_ok());
+        assert_eq!(out, "(4 (a [b 4 2 3] (c 2 3 . d)) (1 (\\` (2 (\\, (3 4 2 3))))))");
+
+        // `(1 . ,n) is read as (` (1 \, n))
+        let tmpl = LispObj::list_from(&[LispObj::Int(1), LispObj::Atm(symbols::COMMA),
+                                        LispObj::Atm(lsp.atomize("n"))]);
+        let form = LispObj::list_from(&[LispObj::Atm(symbols::BACKQUOTE), tmpl]);
+        assert_eq!(lsp.eval(&form), Ok(LispObj::cons(LispObj::Int(1), LispObj::Int(4))));
+
+        // Backquote is a macro which expands to list building forms, so it
+        // works on lexical variables in compiled code
+        let src = r#"
+(defun pair-up (a b) `(,a ,'b [,b] ,@b . ,a))
+(byte-compile 'pair-up)
+(list (macroexpand '`(a ,b ,@c d)) (macroexpand-1 '`(a (b c))) (macroexpand '(a ,b))
+      (pair-up 1 '(2 3)))
+"#.to_owned();
+        lsp.set_lexical_binding(true);
+        let mut out = String::new();
+        let ast = &lsp.read(&src).unwrap();
+        let res = lsp.eval(ast).unwrap();
+        assert!(lsp.print(&mut out, &res).is_ok());
+        assert_eq!(out, "((append (list (quote a) b) c (list (quote d))) (quote (a (b c))) \
+                         (a (\\, b)) (1 b [(2 3)] 2 3 . 1))");
     }
 
     #[test]
-    fn cons_list() {
+    fn reader_syntax() {
         let mut lsp = Lsp::new();
-        let src = "(cons 1 (cons 2 (cons 3 nil)))".to_owned();
+        let src = r#"
+(quote ((a . b) (a b . c) (?a ?\n ?\C-a ?\^? ?\M-x ?\x41 ?\101 ?\() #'car
+        (#x1F #o17 #b101 #24r1k #x-f) (1.5 .5 -1e3 1. 1.0e+INF) foo\ bar \1 "a\tb\x41\ c"
+        #[1 2] 10000000000 1e20 0.0001 1e-05))
+"#.to_owned();
+        let mut out = String::new();
 
         let ast = &lsp.read(&src).unwrap();
-        assert_eq!(lsp.eval(ast),
-                   Ok(LispObj::list_from(&[LispObj::Int(1), LispObj::Int(2), LispObj::Int(3)])));
+        let res = lsp.eval(ast).unwrap();
+        assert!(lsp.print(&mut out, &res).is_ok());
+        assert_eq!(out, "((a . b) (a b . c) (97 10 1 127 134217848 65 65 40) (function car) \
+                         (31 15 5 44 -15) (1.5 0.5 -1000.0 1 1.0e+INF) foo\\ bar \\1 \"a\tbAc\" \
+                         [1 2] 10000000000 1e+20 0.0001 1e-05)");
     }
 
     #[test]
-    fn print() {
+    fn reader_errors() {
         let mut lsp = Lsp::new();
-        let src = "(a b (c))";
-        let mut out = String::with_capacity(10);
 
-        let ast = &lsp.read(&src.to_owned()).unwrap();
-        assert!(lsp.print_sxp(&mut out, ast).is_ok());
-        assert_eq!(out, format!("(progn {})", src));
+        for src in ["(a . b c)", "( . a)", "a)", "(a]", "?ab", "#z", "#xZZ"].iter() {
+            let err = lsp.read(&src.to_string()).unwrap_err();
+            assert_eq!(err.symbol, symbols::INVALID_READ_SYNTAX, "{}", src);
+        }
+
+        for src in ["(a", "\"abc", "?", "'", "'("].iter() {
+            let err = lsp.read(&src.to_string()).unwrap_err();
+            assert_eq!(err.symbol, symbols::END_OF_FILE, "{}", src);
+        }
+    }
+
+    #[test]
+    fn backtrace() {
+        let mut lsp = Lsp::new();
+        let src = r#"(defun inner (n)
+  (+ n
+     undefined-var))
+(defun outer ()
+  (inner 1))
+(outer)
+"#.to_owned();
+
+        let ast = &lsp.read_source(&src, Some("test.el")).unwrap();
+        let err = lsp.eval(ast).unwrap_err();
+        assert_eq!(err.symbol, symbols::VOID_VARIABLE);
+
+        let frames = err.backtrace.unwrap();
+        let lines: Vec<Option<usize>> = frames.iter()
+            .map( |f| f.pos.as_ref().map( |p| p.line ) )
+            .collect();
+        assert_eq!(lines, vec![Some(2), Some(5), Some(6), None]);
+        assert_eq!(lsp.format_backtrace(&frames[..3]),
+                   "  (+ n undefined-var)  [test.el:2]\n  (inner 1)  [test.el:5]\n  \
+                    (outer)  [test.el:6]\n");
+        assert!(lsp.backtrace().is_empty());
+
+        // The backtrace function prints to standard-output
+        let (output, text) = print::StringOutput::new();
+        lsp.set_output(Box::new(output));
+        let src = r#"
+(defvar chars nil)
+(defun show () (backtrace))
+(show)
+(let ((standard-output (lambda (c) (setq chars (cons c chars)))))
+  (backtrace))
+(concat (reverse chars))
+"#.to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        let res = lsp.eval(ast).unwrap();
+        assert!(text.borrow().starts_with("  (backtrace)\n  (show)\n  (progn (defvar chars nil) "));
+        assert!(res.str_val().unwrap().starts_with("  (backtrace)\n  (let ((standard-output "));
+    }
+
+    #[test]
+    fn debug_on_error() {
+        let mut lsp = Lsp::new();
+        let (io, output) = debug::ScriptedDebugIo::new(&["l", "f 1", "e (+ n 10)", "c"]);
+        let src = r#"
+(defun inner (n) (+ n undefined-var))
+(setq debug-on-error t)
+(condition-case nil (inner 0) (void-variable 'caught))
+(inner 1)
+"#.to_owned();
+
+        lsp.set_debug_io(Box::new(io));
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast).unwrap_err().symbol, symbols::VOID_VARIABLE);
+        assert_eq!(*output.borrow(), "\
+Debugger entered--Lisp error: (void-variable undefined-var)
+* 0  (+ n undefined-var)
+  1  (inner 1)
+  2  (progn (defun inner (n) (+ n undefined-var)) (setq debug-on-error t) ...
+Debug>   n = 1
+Debug>   0  (+ n undefined-var)
+* 1  (inner 1)
+  2  (progn (defun inner (n) (+ n undefined-var)) (setq debug-on-error t) ...
+Debug> Symbol's value as variable is void: n
+Debug> ");
+    }
+
+    #[test]
+    fn debug_on_entry() {
+        let mut lsp = Lsp::new();
+        let (io, output) = debug::ScriptedDebugIo::new(&["d", "e n", "q"]);
+        let src = r#"
+(defun twice (n) (* n 2))
+(debug-on-entry 'twice)
+(twice 3)
+"#.to_owned();
+
+        lsp.set_debug_io(Box::new(io));
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast).unwrap_err().symbol, symbols::QUIT);
+        let output = output.borrow();
+        assert!(output.starts_with("Debugger entered--entering a function: twice\n"));
+        assert!(output.contains("Debugger entered--beginning evaluation of function call form:\n\
+                                 * 0  (* n 2)\n"));
+        assert!(output.ends_with("Debug> 3\nDebug> "));
+    }
+
+    #[test]
+    fn special_stays_dynamic() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(defvar depth 0)
+(setq x 0)
+(defun get-depth () depth)
+(defun get-x () x)
+(list (let ((depth 5) (x 5)) (list (get-depth) (get-x))) depth x)
+"#.to_owned();
+
+        lsp.set_lexical_binding(true);
+        let ast = &lsp.read(&src).unwrap();
+        let res = lsp.eval(ast).unwrap();
+        let mut out = String::new();
+        assert!(lsp.print(&mut out, &res).is_ok());
+        assert_eq!(out, "((5 0) 0 0)");
+
+        let src = r#"
+(defvar declared)
+(defun get-declared () declared)
+(list (let ((declared 5)) (get-declared)) (boundp 'declared))
+"#.to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        let res = lsp.eval(ast).unwrap();
+        let mut out = String::new();
+        assert!(lsp.print(&mut out, &res).is_ok());
+        assert_eq!(out, "(5 nil)");
+
+        // With dynamic binding the let binding of x is visible
+        lsp.set_lexical_binding(false);
+        let src = "(defun get-x () x) (let ((x 5)) (get-x))".to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        assert_eq!(lsp.eval(ast), Ok(LispObj::Int(5)));
+    }
+
+    #[test]
+    fn lexical_binding_cookie() {
+        assert!(super::lexical_binding_cookie(";;; foo.el --- Foo  -*- lexical-binding: t -*-\n(foo)"));
+        assert!(super::lexical_binding_cookie(";; -*- mode: emacs-lisp; lexical-binding:t; -*-"));
+        assert!(!super::lexical_binding_cookie(";; -*- lexical-binding: nil -*-"));
+        assert!(!super::lexical_binding_cookie("(foo)\n;; -*- lexical-binding: t -*-"));
+        assert!(!super::lexical_binding_cookie(""));
+    }
+
+    #[test]
+    fn gc_frees_cycles() {
+        let mut lsp = Lsp::new();
+        let a = Cons::new(LispObj::Int(1), LispObj::nil());
+        let b = Cons::new(LispObj::Int(2), LispObj::Cons(a.clone()));
+        a.set_cdr(LispObj::Cons(b.clone()));
+        let weak = Rc::downgrade(&a.0);
+
+        drop(a);
+        drop(b);
+        assert!(weak.upgrade().is_some());
+
+        let stats = lsp.garbage_collect();
+        assert!(weak.upgrade().is_none());
+        assert_eq!(stats.car().unwrap().car(), Ok(LispObj::Atm(lsp.atomize("conses"))));
+    }
+
+    #[test]
+    fn gc_from_allocation() {
+        let _lsp = Lsp::new();
+
+        // Without collecting, the freed objects are still pruned from the heap
+        gc::set_threshold(usize::MAX);
+        for i in 0..100000 {
+            LispObj::cons(LispObj::Int(i), LispObj::nil());
+        }
+        assert!(gc::tracked() < 10000, "{} objects tracked", gc::tracked());
+
+        // No forms are evaluated, so only allocating can free the cycle
+        gc::set_threshold(2 * GC_DEFAULT_THRESHOLD);
+        let a = Cons::new(LispObj::Int(1), LispObj::nil());
+        a.set_cdr(LispObj::Cons(a.clone()));
+        let weak = Rc::downgrade(&a.0);
+        drop(a);
+        for i in 0..100000 {
+            LispObj::cons(LispObj::Int(i), LispObj::nil());
+        }
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn gc_keeps_reachable() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(fset 'circular '(lambda (l) (setcdr (cdr l) l) l))
+(fset 'garbage '(lambda () (circular (list 'x 'y)) nil))
+(garbage)
+"#.to_owned();
+        let ast = &lsp.read(&src).unwrap();
+        lsp.eval(ast).unwrap();
+
+        let ast = &lsp.read(&"(circular (list 1 2))".to_owned()).unwrap();
+        let kept = lsp.eval(ast).unwrap();
+        let ast = &lsp.read(&"(garbage-collect)".to_owned()).unwrap();
+        let stats = lsp.eval(ast).unwrap();
+
+        let conses = stats.car().unwrap().list_to_vec().unwrap();
+        assert!(*conses[3].int_val().unwrap() >= 2);
+        let third = kept.cdr().and_then( |l| l.cdr() ).and_then( |l| l.car() );
+        assert_eq!(third, Ok(LispObj::Int(1)));
+    }
+
+    #[test]
+    fn gc_cons_threshold() {
+        let mut lsp = Lsp::new();
+        let ast = &lsp.read(&"(list 1 2)".to_owned()).unwrap();
+        let gcs_done = |lsp: &Lsp| lsp.globals.get_val(symbols::GCS_DONE);
+
+        lsp.garbage_collect();
+        for _ in 0..(GC_DEFAULT_THRESHOLD / 2 / mem::size_of::<ConsCell>()) {
+            LispObj::cons(LispObj::Int(1), LispObj::nil());
+        }
+        lsp.eval(ast).unwrap();
+        assert_eq!(gcs_done(&lsp), Some(LispObj::Int(1)));
+
+        lsp.set_global("gc-cons-threshold", LispObj::Int(1));
+        for _ in 0..(GC_MIN_THRESHOLD / mem::size_of::<ConsCell>()) {
+            LispObj::cons(LispObj::Int(1), LispObj::nil());
+        }
+        lsp.eval(ast).unwrap();
+        assert_eq!(gcs_done(&lsp), Some(LispObj::Int(2)));
+
+        // The threshold grows with the heap which survived
+        let heap: Vec<LispObj> = (0..(GC_DEFAULT_THRESHOLD / mem::size_of::<ConsCell>()))
+            .map( |_| LispObj::cons(LispObj::Int(1), LispObj::nil()) )
+            .collect();
+        lsp.set_global("gc-cons-percentage", LispObj::Float(0.5));
+        lsp.garbage_collect();
+        for _ in 0..(GC_MIN_THRESHOLD / mem::size_of::<ConsCell>()) {
+            LispObj::cons(LispObj::Int(1), LispObj::nil());
+        }
+        lsp.eval(ast).unwrap();
+        assert_eq!(gcs_done(&lsp), Some(LispObj::Int(3)));
+
+        for _ in 0..(GC_DEFAULT_THRESHOLD / 2 / mem::size_of::<ConsCell>()) {
+            LispObj::cons(LispObj::Int(1), LispObj::nil());
+        }
+        lsp.eval(ast).unwrap();
+        assert_eq!(gcs_done(&lsp), Some(LispObj::Int(4)));
+        drop(heap);
+    }
+
+    #[test]
+    fn byte_compile() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(defvar depth 0)
+(defmacro inc (var) (list 'setq var (list '+ var 1)))
+(fset 'count-down
+  '(lambda (n &optional acc &rest more)
+    "Count down from N."
+    (let* ((i n) (l acc))
+      (while (not (eq i 0))
+        (setq l (cons i l))
+        (inc depth)
+        (setq i (- i 1)))
+      (cond ((eq n 0) (list 'zero more))
+            (more (cons more l))
+            (t l)))))
+(fset 'show-depth '(lambda () depth))
+(fset 'call-with-depth '(lambda (depth) (show-depth)))
+"#.to_owned();
+        let ast = lsp.read(&src).unwrap();
+        lsp.eval(&ast).unwrap();
+
+        let calls = "(list (count-down 3) (count-down 0 nil 'x) depth (call-with-depth 7))";
+        let calls = lsp.read(&calls.to_owned()).unwrap();
+        let interpreted = lsp.eval(&calls).unwrap();
+
+        let ast = lsp.read(&"(byte-compile 'count-down) (byte-compile 'call-with-depth)".to_owned());
+        lsp.eval(&ast.unwrap()).unwrap();
+        let name = lsp.atomize("count-down");
+        match lsp.function(name) {
+            Ok(LispObj::ExtFun(_)) => (),
+            fun => panic!("Not compiled: {:?}", fun),
+        }
+
+        lsp.set_global("depth", LispObj::Int(0));
+        assert_eq!(lsp.eval(&calls), Ok(interpreted));
+
+        let ast = lsp.read(&"(count-down)".to_owned()).unwrap();
+        assert_eq!(lsp.eval(&ast).map_err( |e| e.symbol ),
+                   Err(symbols::WRONG_NUMBER_OF_ARGUMENTS));
+    }
+
+    #[test]
+    fn byte_compile_lexical() {
+        let mut lsp = Lsp::new();
+        lsp.set_lexical_binding(true);
+        let src = r#"
+(defun make-counter (n)
+  (lambda () (setq n (+ n 1))))
+(defun sum-with (f xs)
+  (let ((total 0))
+    (while xs
+      (setq total (+ total (funcall f (car xs))))
+      (setq xs (cdr xs)))
+    total))
+(defun nested (x)
+  (let ((y 1))
+    (condition-case nil
+        (setq y (+ x y))
+      (error nil))
+    (list x y)))
+(byte-compile 'make-counter)
+(byte-compile 'sum-with)
+(byte-compile 'nested)
+"#.to_owned();
+        let ast = lsp.read(&src).unwrap();
+        lsp.eval(&ast).unwrap();
+
+        let src = r#"
+(list (let ((c (make-counter 10))) (funcall c) (funcall c))
+      (sum-with (lambda (x) (+ x 1)) '(1 2 3))
+      (nested 2)
+      (funcall (byte-compile (lambda (a &rest b) (cons a b))) 1 2 3))
+"#.to_owned();
+        let ast = lsp.read(&src).unwrap();
+        let res = lsp.eval(&ast).unwrap();
+        let mut out = String::new();
+        lsp.print(&mut out, &res).unwrap();
+        assert_eq!(out, "(12 9 (2 3) (1 2 3))");
+
+        // Special arguments are bound dynamically, compiled or not
+        let src = r#"
+(defvar depth 0)
+(defun show-depth () depth)
+(defun with-depth (depth &optional other) (list (show-depth) other))
+(list (with-depth 5) (progn (byte-compile 'with-depth) (with-depth 5 6)) depth)
+"#.to_owned();
+        let ast = lsp.read(&src).unwrap();
+        let res = lsp.eval(&ast).unwrap();
+        let mut out = String::new();
+        lsp.print(&mut out, &res).unwrap();
+        assert_eq!(out, "((5 nil) (5 6) 0)");
+    }
+
+    #[test]
+    fn excessive_lisp_nesting() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(fset 'down '(lambda (n) (if (eq n 0) 0 (+ 1 (down (- n 1))))))
+(list (condition-case err (down 100000) (recursion-error (car err)))
+      (let ((max-lisp-eval-depth 130))
+        (condition-case err (down 100) (error err)))
+      (let ((max-lisp-eval-depth 10))
+        (down 10))
+      (down 20))
+"#.to_owned();
+        let ast = lsp.read(&src).unwrap();
+        let res = lsp.eval(&ast).unwrap();
+        let mut out = String::new();
+        lsp.print(&mut out, &res).unwrap();
+        assert_eq!(out, "(excessive-lisp-nesting (excessive-lisp-nesting 131) 10 20)");
+
+        let ast = lsp.read(&"(byte-compile 'down) (down 100000)".to_owned()).unwrap();
+        let err = lsp.eval(&ast).unwrap_err();
+        assert_eq!(err.symbol, symbols::EXCESSIVE_LISP_NESTING);
+        assert_eq!(lsp.error_message(&err).split(':').next(),
+                   Some("Lisp nesting exceeds `max-lisp-eval-depth'"));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let mut lsp = Lsp::new();
+        let cases = [
+            ("(+ 1 2 3)", "6"),
+            ("(+ 1 2.5)", "3.5"),
+            ("(- 5)", "-5"),
+            ("(- 10 1 2)", "7"),
+            ("(* 2 3.0)", "6.0"),
+            ("(/ 7 2)", "3"),
+            ("(/ -7 2)", "-3"),
+            ("(/ 5 2 2.0)", "1.25"),
+            ("(/ 2)", "0"),
+            ("(% -7 2)", "-1"),
+            ("(mod -7 2)", "1"),
+            ("(mod 5.5 2)", "1.5"),
+            ("(1+ 9223372036854775807)", "9223372036854775808"),
+            ("(1- -9223372036854775808)", "-9223372036854775809"),
+            ("(* 4294967296 4294967296)", "18446744073709551616"),
+            ("(- 18446744073709551616 18446744073709551615)", "1"),
+            ("(fixnump (- 18446744073709551616 18446744073709551615))", "t"),
+            ("(bignump 18446744073709551616)", "t"),
+            ("(list (= 1 1.0) (< 1 2 3) (< 1 3 2) (>= 3 3 2) (/= 1 2))", "(t t nil t t)"),
+            ("(= 9007199254740993 9007199254740992.0)", "nil"),
+            ("(< 1 0.0e+NaN)", "nil"),
+            ("(list (max 1 2.0) (max 3 2.0) (min 1 -1))", "(2.0 3 -1)"),
+            ("(list (abs -3) (abs -1.5) (float 3))", "(3 1.5 3.0)"),
+            ("(list (truncate -2.5) (floor -2.5) (ceiling -2.5) (round -2.5) (round 3.5))",
+             "(-2 -3 -2 -2 4)"),
+            ("(list (truncate 7 2) (floor -7 2) (ceiling 7 2) (round 5 2) (round 7 2))",
+             "(3 -4 4 2 4)"),
+            ("(floor 7.5 2)", "3"),
+            ("(truncate 1e20)", "100000000000000000000"),
+            ("(list (expt 2 10) (expt 2 0.5) (expt 2 -1))", "(1024 1.4142135623730951 0.5)"),
+            ("(expt 3 50)", "717897987691852588770249"),
+            ("(sqrt 16)", "4.0"),
+            ("(list (numberp 1.0) (integerp 1.0) (floatp 1.0) (zerop -0.0) (natnump -1))",
+             "(t nil t t nil)"),
+            ("(list (logand 12 10) (logior 12 10) (logxor 12 10) (lognot 5))", "(8 14 6 -6)"),
+            ("(list (ash 1 70) (ash -5 -1) (ash (ash 1 70) -69))", "(1180591620717411303424 -3 2)"),
+            ("(logand (ash 1 70) (lognot 0))", "1180591620717411303424"),
+        ];
+
+        assert_evals(&mut lsp, &cases);
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+        let mut lsp = Lsp::new();
+        let src = r#"(defmacro signalled (form) `(condition-case err ,form (error err)))"#;
+        let ast = lsp.read(&src.to_owned()).unwrap();
+        lsp.eval(&ast).unwrap();
+
+        let cases = [
+            ("(signalled (/ 1 0))", "(arith-error)"),
+            ("(signalled (% 1 0))", "(arith-error)"),
+            ("(signalled (+ 1 'a))", "(wrong-type-argument number-or-marker-p a)"),
+            ("(signalled (% 1.5 1))", "(wrong-type-argument integer-or-marker-p 1.5)"),
+            ("(signalled (truncate 1.0e+INF))", "(overflow-error \"truncate\" 1.0e+INF)"),
+            ("(signalled (zerop 'a))", "(wrong-type-argument numberp a)"),
+            ("(signalled (ash 1 100000))", "(overflow-error)"),
+            ("(signalled (<))", "(wrong-number-of-arguments < 0)"),
+        ];
+        assert_evals(&mut lsp, &cases);
+
+        let ast = lsp.read(&"(/ 1.0 0)".to_owned()).unwrap();
+        assert_eq!(lsp.eval(&ast), Ok(LispObj::Float(f64::INFINITY)));
+    }
+
+    #[test]
+    fn strings() {
+        let mut lsp = Lsp::new();
+        let cases = [
+            (r#"(concat "ab" '(?c ?d) [?é] nil)"#, r#""abcdé""#),
+            (r#"(list (length "héllo") (length '(1 2)) (length [1]) (length nil))"#, "(5 2 1 0)"),
+            (r#"(list (substring "héllo" 1 3) (substring "héllo" -2) (substring [1 2 3] 1))"#,
+             r#"("él" "lo" [2 3])"#),
+            (r#"(list (string= "a" 'a) (string< "abc" "abd") (string< "b" "abc") (string> "b" "a"))"#,
+             "(t t nil t)"),
+            (r#"(list (string-prefix-p "Fo" "foo" t) (string-prefix-p "Fo" "foo") (string-suffix-p "oo" "foo"))"#,
+             "(t nil t)"),
+            (r#"(split-string "  two words ")"#, r#"("two" "words")"#),
+            (r#"(split-string "a,b,,c" ",")"#, r#"("a" "b" "" "c")"#),
+            (r#"(split-string "a,b,,c" "," t)"#, r#"("a" "b" "c")"#),
+            (r#"(split-string "abc" "")"#, r#"("" "a" "b" "c" "")"#),
+            (r#"(split-string " a , b " "," t "[ ]+")"#, r#"("a" "b")"#),
+            (r#"(list (split-string "aXbxc" "x") (let ((case-fold-search nil)) (split-string "aXbxc" "x")))"#,
+             r#"(("a" "b" "c") ("aXb" "c"))"#),
+            (r#"(string-join '("a" "b" "c") ", ")"#, r#""a, b, c""#),
+            (r#"(list (upcase "straße") (downcase "ÀB") (capitalize "hello wORLD") (upcase-initials "hello wORLD"))"#,
+             r#"("STRASSE" "àb" "Hello World" "Hello WORLD")"#),
+            ("(list (upcase ?a) (upcase ?ß) (downcase ?Σ))", "(65 223 963)"),
+            (r#"(list (string-to-number " 12abc") (string-to-number "-1.5e2") (string-to-number ".5")
+                      (string-to-number "1.") (string-to-number "x") (string-to-number "ff" 16)
+                      (string-to-number "1e3") (string-to-number "99999999999999999999"))"#,
+             "(12 -150.0 0.5 1 0 255 1000.0 99999999999999999999)"),
+            (r#"(list (number-to-string 42) (number-to-string 1.5) (number-to-string -0.0))"#,
+             r#"("42" "1.5" "-0.0")"#),
+            (r#"(list (string-to-char "éa") (string-to-char "") (char-to-string ?λ) (string ?a ?b))"#,
+             r#"(233 0 "λ" "ab")"#),
+            (r#"(make-string 3 ?x)"#, r#""xxx""#),
+            (r#"(list (string-trim "  a b \n") (string-trim-left "xxab" "x+") (string-trim-right "abyy" "y+"))"#,
+             r#"("a b" "ab" "ab")"#),
+            ("(list (characterp ?a) (characterp -1) (characterp 4194304) (characterp \"a\"))",
+             "(t nil nil nil)"),
+            ("(list (char-equal ?a ?A) (let ((case-fold-search nil)) (char-equal ?a ?A)))", "(t nil)"),
+        ];
+
+        assert_evals(&mut lsp, &cases);
+
+        let errors = [
+            (r#"(substring "abc" 2 5)"#, symbols::ARGS_OUT_OF_RANGE),
+            (r#"(concat "a" 1)"#, symbols::WRONG_TYPE_ARGUMENT),
+            ("(length '(1 . 2))", symbols::WRONG_TYPE_ARGUMENT),
+            (r#"(split-string "a" "\\(")"#, symbols::INVALID_REGEXP),
+            ("(char-to-string 4194303)", symbols::ERROR),
+        ];
+        assert_signals(&mut lsp, &errors);
+    }
+
+    #[test]
+    fn format() {
+        let mut lsp = Lsp::new();
+        let cases = [
+            (r#"(format "%s|%S|%s" "a\"b" "a\"b" 'x)"#, r#""a\"b|\"a\\\"b\"|x""#),
+            (r#"(format "%d %5d|%-5d|%05d %+d" 42 42 42 -42 7)"#, r#""42    42|42   |-0042 +7""#),
+            (r#"(format "%x %X %#x %o %d" 255 255 255 8 99999999999999999999)"#,
+             r#""ff FF 0xff 10 99999999999999999999""#),
+            (r#"(format "%c%c %.2s %3$s" ?a ?λ "xyz")"#, r#""aλ xy xyz""#),
+            (r#"(format "%f %.2f %e %g %g %g" 1.5 2.345 1234.5 0.0001 1e10 100)"#,
+             r#""1.500000 2.35 1.234500e+03 0.0001 1e+10 100""#),
+            (r#"(format "%d%%" 1.9)"#, r#""1%""#),
+            (r#"(format-message "`%s'" 'a)"#, r#""‘a’""#),
+            (r#"(prin1-to-string '(a "b" \( \1 ##))"#, r#""(a \"b\" \\( \\1 ##)""#),
+            (r#"(prin1-to-string "a\\b" t)"#, r#""a\\b""#),
+        ];
+
+        assert_evals(&mut lsp, &cases);
+
+        for src in [r#"(format "%d" "a")"#, r#"(format "%s")"#, r#"(format "%q" 1)"#].iter() {
+            let ast = lsp.read(&src.to_string()).unwrap();
+            assert_eq!(lsp.eval(&ast).unwrap_err().symbol, symbols::ERROR);
+        }
+    }
+
+    #[test]
+    fn print_to_output() {
+        let mut lsp = Lsp::new();
+        let (output, text) = print::StringOutput::new();
+        lsp.set_output(Box::new(output));
+
+        let src = r#"
+(defvar chars nil)
+(prin1 "a")
+(princ "b")
+(print 'c)
+(terpri)
+(let ((standard-output (lambda (c) (setq chars (cons c chars)))))
+  (princ "xy"))
+(prin1 1 (lambda (c) (setq chars (cons c chars))))
+(message "%s=%d" "n" 1)
+(list chars (message nil))
+"#.to_owned();
+        let ast = lsp.read(&src).unwrap();
+        let res = lsp.eval(&ast).unwrap();
+        let mut out = String::new();
+        lsp.print(&mut out, &res).unwrap();
+
+        assert_eq!(out, "((49 121 120) nil)");
+        assert_eq!(*text.borrow(), "\"a\"b\nc\n\nn=1\n\n");
+    }
+
+    #[test]
+    fn print_circular() {
+        let mut lsp = Lsp::new();
+        let cases = [
+            ("(let ((l (list 1))) (setcdr l l) (prin1-to-string l))", "\"(1 . #0)\""),
+            ("(let ((l (list 1 2))) (setcdr (cdr l) l) (format \"%S\" l))", "\"(1 2 1 . #1)\""),
+            ("(let ((l (list 1 2))) (setcar l l) (prin1-to-string l))", "\"(#0 2)\""),
+            ("(let ((l (list 1 2))) (setcar (cdr l) (cdr l)) (prin1-to-string l))", "\"(1 (#1))\""),
+            ("(let ((v (vector 1 nil))) (aset v 1 v) (format \"%s\" v))", "\"[1 #0]\""),
+            ("(let ((h (make-hash-table))) (puthash 'k h h) (prin1-to-string h))",
+             "\"#s(hash-table data (k #0))\""),
+            ("(let ((l (list 1))) (list l l))", "((1) (1))"),
+        ];
+        assert_evals(&mut lsp, &cases);
+
+        let errors = [
+            ("(let ((x nil)) (dotimes (i 300) (setq x (list x))) (prin1-to-string x))", symbols::ERROR),
+            ("(let ((x nil)) (dotimes (i 300) (setq x (vector x))) (format \"%s\" x))", symbols::ERROR),
+        ];
+        assert_signals(&mut lsp, &errors);
+
+        let mut deep = LispObj::nil();
+        for _ in 0..(PRINT_MAX_DEPTH + 1) {
+            deep = LispObj::list_from(&[deep]);
+        }
+        assert!(lsp.print(&mut String::new(), &deep).is_err());
+        assert!(lsp.print(&mut String::new(), &deep.car().unwrap()).is_ok());
+    }
+
+    #[derive(Debug)]
+    struct TestBuffer(String);
+
+    impl LispForm for TestBuffer {
+        fn rust_name(&self) -> &'static str {
+            "tests::TestBuffer"
+        }
+
+        fn lisp_name(&self) -> &'static str {
+            "buffer"
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        fn contents(&self) -> Result<String, LispError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn read_and_eval() {
+        let mut lsp = Lsp::new();
+        let buffer = TestBuffer("(setq from-buffer (+ 1 2)) (setq in-region 'yes)".to_owned());
+        lsp.set_global("current-buffer", LispObj::ext(Rc::new(RefCell::new(buffer))));
+
+        let cases = [
+            (r#"(read-from-string "(a b) c")"#, "((a b) . 5)"),
+            (r#"(read-from-string "foo bar")"#, "(foo . 3)"),
+            (r#"(read-from-string "foo bar" 3)"#, "(bar . 7)"),
+            (r#"(read-from-string "'x")"#, "((quote x) . 2)"),
+            (r#"(read "12 13")"#, "12"),
+            ("(eval '(+ 1 2))", "3"),
+            ("(eval (list 'car ''(a b)))", "a"),
+            ("(let ((x 1)) (eval '(let ((x 2)) (funcall (lambda () x))) t))", "2"),
+            ("(funcall (eval '(let ((y 5)) (lambda () y)) t))", "5"),
+            ("(eval 'z '((z . 7)))", "7"),
+            ("(list (apply '+ 1 2 '(3 4)) (apply '+ nil) (apply '(+ 1 2)))", "(10 0 3)"),
+            ("(apply #'list 'a '(b))", "(a b)"),
+            (r#"(let* ((chars (append-chars "(x y) z"))
+                       (next (lambda (&optional c)
+                               (if c
+                                   (setq chars (cons c chars))
+                                 (prog1 (car chars) (setq chars (cdr chars)))))))
+                  (list (read next) (read next) chars))"#, "((x y) z nil)"),
+            ("(progn (eval-buffer) from-buffer)", "3"),
+            ("(progn (eval-region 28 49) in-region)", "yes"),
+        ];
+
+        let ast = lsp.read(&r#"
+(defun append-chars (s)
+  (let ((i (length s)) (l nil))
+    (while (> i 0)
+      (setq i (1- i))
+      (setq l (cons (string-to-char (substring s i)) l)))
+    l))"#.to_owned()).unwrap();
+        lsp.eval(&ast).unwrap();
+
+        assert_evals(&mut lsp, &cases);
+
+        let errors = [
+            (r#"(read-from-string "(a")"#, symbols::END_OF_FILE),
+            (r#"(read-from-string "  ")"#, symbols::END_OF_FILE),
+            (r#"(read-from-string ")")"#, symbols::INVALID_READ_SYNTAX),
+            (r#"(read-from-string "a" 2)"#, symbols::ARGS_OUT_OF_RANGE),
+            ("(eval-region 0 5)", symbols::ARGS_OUT_OF_RANGE),
+        ];
+        assert_signals(&mut lsp, &errors);
+    }
+
+    #[test]
+    fn hash_tables() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(setq eq-table (make-hash-table ':test 'eq))
+(setq equal-table (make-hash-table ':test 'equal))
+(setq eql-table (make-hash-table))
+(puthash "k" 1 equal-table)
+(puthash '(a b) 2 equal-table)
+(puthash 'x 3 eq-table)
+(puthash 'x 4 eq-table)
+(puthash 1.5 5 eql-table)
+(defun case-fold-equal (a b) (string= (upcase a) (upcase b)))
+(defun case-fold-hash (a) (length a))
+(define-hash-table-test 'case-fold 'case-fold-equal 'case-fold-hash)
+(setq fold-table (make-hash-table ':test 'case-fold))
+(puthash "Foo" 'yes fold-table)"#;
+        let ast = lsp.read(&src.to_owned()).unwrap();
+        lsp.eval(&ast).unwrap();
+
+        let cases = [
+            (r#"(gethash "k" equal-table)"#, "1"),
+            ("(gethash (list 'a 'b) equal-table)", "2"),
+            ("(gethash (list 'a 'b) (make-hash-table))", "nil"),
+            ("(gethash 'x eq-table)", "4"),
+            ("(gethash 1.5 eql-table)", "5"),
+            ("(gethash 'missing eq-table 'default)", "default"),
+            (r#"(gethash "FOO" fold-table)"#, "yes"),
+            ("(hash-table-count equal-table)", "2"),
+            ("(hash-table-keys equal-table)", "(\"k\" (a b))"),
+            ("(progn (remhash \"k\" equal-table) (hash-table-keys equal-table))", "((a b))"),
+            ("(let ((sum 0)) (maphash (lambda (k v) (setq sum (+ sum v))) eql-table) sum)", "5"),
+            ("(hash-table-count (clrhash eq-table))", "0"),
+            ("(list (hash-table-p eq-table) (hash-table-p '(x)))", "(t nil)"),
+            ("equal-table", "#s(hash-table test equal data ((a b) 2))"),
+            ("(make-hash-table)", "#s(hash-table)"),
+            (r##"(gethash 'b (car (read-from-string "#s(hash-table test eq data (a 1 b 2))")))"##,
+             "2"),
+            (r##"(car (read-from-string "#s(hash-table data (\"s\" 1))"))"##,
+             r#"#s(hash-table data ("s" 1))"#),
+        ];
+
+        assert_evals(&mut lsp, &cases);
+
+        let errors = [
+            ("(make-hash-table ':test 'no-such-test)", symbols::ERROR),
+            ("(gethash 'a '(a))", symbols::WRONG_TYPE_ARGUMENT),
+            (r##"(read-from-string "#s(record 1)")"##, symbols::INVALID_READ_SYNTAX),
+        ];
+        assert_signals(&mut lsp, &errors);
+
+        // A table which contains itself is only freed by the collector
+        let table = HashTable::new(HashTest::Eq);
+        let weak = Rc::downgrade(&table);
+        let key = LispObj::HashTable(table.clone());
+        hashtab::put(&mut lsp, &table, key.clone(), key).unwrap();
+        drop(table);
+        assert!(weak.upgrade().is_some());
+        lsp.garbage_collect();
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn sequences() {
+        let mut lsp = Lsp::new();
+        let cases = [
+            ("[1 (a) \"s\"]", "[1 (a) \"s\"]"),
+            ("(make-vector 3 'x)", "[x x x]"),
+            ("(let ((v (vector 1 2 3))) (aset v 1 'b) (list (aref v 1) (aref \"abc\" 2)))", "(b 99)"),
+            ("(vconcat '(1) [2] \"c\")", "[1 2 99]"),
+            ("(list (length [1 2]) (elt '(a b) 1) (elt [a b] 0) (nth 5 '(a)))", "(2 b a nil)"),
+            ("(list (nthcdr 2 '(a b c)) (nthcdr 0 '(a)))", "((c) (a))"),
+            ("(append '(1) [2] nil '(3) 'tail)", "(1 2 3 . tail)"),
+            ("(list (reverse '(1 2 3)) (reverse [1 2]) (reverse \"ab\"))", "((3 2 1) [2 1] \"ba\")"),
+            ("(let ((l (list 1 2 3))) (list (nreverse l) l))", "((3 2 1) (1))"),
+            ("(sort (list 3 1 2) '<)", "(1 2 3)"),
+            ("(let ((v (vector 3 1 2))) (sort v '>) v)", "[3 2 1]"),
+            ("(sort (list '(1 . a) '(0 . b) '(1 . c)) (lambda (x y) (< (car x) (car y))))",
+             "((0 . b) (1 . a) (1 . c))"),
+            ("(mapcar '1+ [1 2])", "(2 3)"),
+            ("(let ((sum 0)) (mapc (lambda (x) (setq sum (+ sum x))) '(1 2)) sum)", "3"),
+            ("(mapconcat 'number-to-string '(1 2 3) \"-\")", "\"1-2-3\""),
+            ("(let (r) (dolist (x '(a b) r) (setq r (cons x r))))", "(b a)"),
+            ("(let (r) (dotimes (i 3) (setq r (cons i r))) r)", "(2 1 0)"),
+            ("(list (member \"b\" '(\"a\" \"b\")) (memq 'c '(a b)) (memq 'b '(a b c)))",
+             "((\"b\") nil (b c))"),
+            ("(list (assq 'b '((a . 1) (b . 2))) (assoc \"b\" '((\"b\" . 3))))",
+             "((b . 2) (\"b\" . 3))"),
+            ("(assoc 2 '((1 . a) (3 . b)) (lambda (x k) (> x k)))", "(3 . b)"),
+            ("(let ((l (list 'a 'b 'a 'c))) (delq 'a l))", "(b c)"),
+            ("(list (remove 1 '(1 2 1)) (remove ?a \"abc\") (remove 'x [x y]))",
+             "((2) \"bc\" [y])"),
+            ("(list (last '(1 2 3)) (last '(1 2 3) 2) (butlast '(1 2 3)) (butlast '(1 2 3) 2))",
+             "((3) (2 3) (1 2) (1))"),
+            ("(list (number-sequence 1 4) (number-sequence 5 1 -2) (number-sequence 3))",
+             "((1 2 3 4) (5 3 1) (3))"),
+            ("(number-sequence 0 1 0.5)", "(0.0 0.5 1.0)"),
+        ];
+
+        assert_evals(&mut lsp, &cases);
+
+        let errors = [
+            ("(aref [1 2] 2)", symbols::ARGS_OUT_OF_RANGE),
+            ("(aref '(1) 0)", symbols::WRONG_TYPE_ARGUMENT),
+            ("(nth 1 '(a . b))", symbols::WRONG_TYPE_ARGUMENT),
+            ("(sort 'a '<)", symbols::WRONG_TYPE_ARGUMENT),
+            ("(number-sequence 1 2 0)", symbols::ERROR),
+        ];
+        assert_signals(&mut lsp, &errors);
+    }
+
+    #[test]
+    fn circular_lists() {
+        let mut lsp = Lsp::new();
+        let circular = |body: &str| format!("(let ((l (list 1 2))) (setcdr (cdr l) l) {})", body);
+
+        let cases = [
+            (circular("(list (nth 5 l) (nth 1000000000000 l) (elt l 3) (car (nthcdr 7 l)))"), "(2 1 2 2)"),
+            (circular("(list (car (memq 2 l)) (car (member 2 l)))"), "(2 2)"),
+            (circular("(let ((a (list (cons 'a 1)))) (setcdr a a) (assq 'a a))"), "(a . 1)"),
+        ];
+        for &(ref src, expected) in cases.iter() {
+            assert_evals(&mut lsp, &[(src.as_str(), expected)]);
+        }
+
+        for body in ["(length l)", "(append l nil)", "(reverse l)", "(mapcar (lambda (x) x) l)",
+                     "(memq 3 l)", "(member 3 l)", "(assq 'a l)", "(assoc 'a l)", "(delq 3 l)",
+                     "(last l)", "(nconc l (list 3))", "(apply '+ l)", "(sort l '<)",
+                     "(dolist (x l) x)", "(vconcat l)", "(nreverse l)"].iter() {
+            assert_signals(&mut lsp, &[(circular(body).as_str(), symbols::CIRCULAR_LIST)]);
+        }
+    }
+
+    #[test]
+    fn equality() {
+        let mut lsp = Lsp::new();
+        let cases = [
+            ("(eq 'a 'a)", "t"),
+            ("(eq (list 1) (list 1))", "nil"),
+            ("(let ((l (list 1))) (eq l l))", "t"),
+            ("(let ((f (lambda () 1))) (list (eq f f) (eq #'car #'car)))", "(t t)"),
+            ("(eq 1.0 1)", "nil"),
+            ("(list (eq \"a\" \"a\") (let ((s \"a\")) (eq s s)) (eq \"ab\" (concat \"a\" \"b\"))
+                   (equal \"ab\" (concat \"a\" \"b\")))", "(nil t nil t)"),
+            ("(let ((h (make-hash-table :test 'eq)) (k \"k\"))
+                (puthash k 1 h) (list (gethash k h) (gethash (concat k) h)))", "(1 nil)"),
+            ("(let ((h (make-hash-table :test 'equal)))
+                (puthash \"k\" 1 h) (gethash (concat \"k\") h))", "1"),
+            ("(list (memq \"a\" '(\"a\")) (member \"a\" '(\"a\")))", "(nil (\"a\"))"),
+            ("(list (eql 1.0 1.0) (eql 0.0 -0.0) (eql 1.0 1))", "(t nil nil)"),
+            ("(list (eq (expt 2 70) (expt 2 70)) (eql (expt 2 70) (expt 2 70)))", "(nil t)"),
+            ("(list (equal '(1 (2 [3 \"x\"])) (list 1 (list 2 (vector 3 \"x\")))) (equal '(1) '(2)))",
+             "(t nil)"),
+            ("(equal '(1 . 2) '(1 . 2))", "t"),
+            ("(equal-including-properties \"a\" \"a\")", "t"),
+            ("(let ((l (list 1 2))) (setcdr (cdr l) l) (equal l l))", "t"),
+            ("(= (sxhash-equal (list 1 \"a\" [b])) (sxhash-equal (list 1 \"a\" [b])))", "t"),
+            ("(= (sxhash-eql (expt 2 70)) (sxhash-eql (expt 2 70)))", "t"),
+        ];
+
+        assert_evals(&mut lsp, &cases);
+
+        let errors = [
+            ("(let ((a (list 1 1)) (b (list 1 1))) (setcdr (cdr a) a) (setcdr (cdr b) b) (equal a b))",
+             symbols::CIRCULAR_LIST),
+            ("(eq 1)", symbols::WRONG_NUMBER_OF_ARGUMENTS),
+        ];
+        assert_signals(&mut lsp, &errors);
+
+        let mut deep = LispObj::nil();
+        for _ in 0..1000 {
+            deep = LispObj::list_from(&[deep]);
+        }
+        assert_eq!(hashtab::equal(&lsp, &deep, &deep.car().unwrap()).unwrap_err().symbol, symbols::ERROR);
+    }
+
+    #[test]
+    fn non_local_exits() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(defvar dyn 'global)
+(defvar log nil)
+(defun thrower (tag) (let ((dyn 'inner)) (throw tag dyn)))"#;
+        let ast = lsp.read(&src.to_owned()).unwrap();
+        lsp.eval(&ast).unwrap();
+
+        let cases = [
+            ("(catch 'done (thrower 'done) 'not-reached)", "inner"),
+            ("(catch 'done 1 2)", "2"),
+            ("(catch 'outer (catch 'inner (throw 'outer 'o)) 'not-reached)", "o"),
+            ("(catch 'x (catch 'x (throw 'x 1)) 2)", "2"),
+            ("(list (catch 'done (let ((dyn 'bound)) (thrower 'done))) dyn)", "(inner global)"),
+            ("(catch 'done (condition-case nil (throw 'done 'thrown) (t 'handled)))", "thrown"),
+            ("(condition-case e (throw 'nowhere 1) (no-catch e))", "(no-catch nowhere 1)"),
+            ("(progn (unwind-protect 1 (setq log 'cleaned)) log)", "cleaned"),
+            ("(progn (setq log nil)
+                     (catch 'done (unwind-protect (throw 'done 1) (setq log (cons 'a log))))
+                     (condition-case nil (unwind-protect (car 1) (setq log (cons 'b log))) (error nil))
+                     log)", "(b a)"),
+            ("(unwind-protect 'value (+ 1 2))", "value"),
+            ("(condition-case nil (unwind-protect 'value (car 1)) (wrong-type-argument 'cleanup-failed))",
+             "cleanup-failed"),
+        ];
+
+        assert_evals(&mut lsp, &cases);
+
+        let ast = lsp.read(&"(let ((dyn 'bound)) (thrower 'missing))".to_owned()).unwrap();
+        assert_eq!(lsp.eval(&ast).unwrap_err().symbol, symbols::NO_CATCH);
+        let (dyn, global) = (lsp.atomize("dyn"), lsp.atomize("global"));
+        assert_eq!(lsp.eval_atm_val(dyn).unwrap(), LispObj::atm(global));
+        assert!(lsp.locals.is_empty() && lsp.catches.is_empty());
+    }
+
+    #[test]
+    fn symbols_api() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(defvar counter 0)
+(defun target () nil)
+(fset 'alias 'target)"#;
+        let ast = lsp.read(&src.to_owned()).unwrap();
+        lsp.eval(&ast).unwrap();
+
+        let cases = [
+            ("(progn (put 'sym 'a 1) (put 'sym 'b 2) (put 'sym 'a 3) (symbol-plist 'sym))", "(b 2 a 3)"),
+            ("(list (get 'sym 'a) (get 'sym 'missing) (get 'never-seen 'a))", "(3 nil nil)"),
+            ("(progn (setplist 'sym '(x 1 y 2)) (list (get 'sym 'x) (get 'sym 'a) (symbol-plist 'sym)))",
+             "(1 nil (x 1 y 2))"),
+            ("(progn (function-put 'target 'prop 'val) (list (function-get 'alias 'prop) (get 'alias 'prop)))",
+             "(val nil)"),
+            ("(list (symbol-name 'foo) (symbol-value 'counter) (symbol-value nil))", r#"("foo" 0 nil)"#),
+            ("(let ((a (make-symbol \"foo\")) (b (make-symbol \"foo\"))) (list (eq a b) (eq a 'foo) (symbol-name a)))",
+             r#"(nil nil "foo")"#),
+            ("(let ((s (make-symbol \"s\"))) (put s 'p 1) (list (get s 'p) (get 's 'p)))", "(1 nil)"),
+            ("(list (symbol-name (gensym)) (symbol-name (gensym \"x\")) gensym-counter)", r#"("g0" "x1" 2)"#),
+            ("(list (eq (intern \"foo\") 'foo) (intern-soft \"no-such-symbol\") (intern-soft \"counter\"))",
+             "(t nil counter)"),
+            ("(intern-soft (make-symbol \"counter\"))", "nil"),
+            ("(progn (put 'gone 'p 1) (list (unintern \"gone\") (unintern \"gone\") (get 'gone 'p)
+                                           (get (intern \"gone\") 'p) (eq 'gone (intern \"gone\"))))",
+             "(t nil 1 nil nil)"),
+            ("(progn 'seen-by-reader (list (intern-soft \"seen-by-reader\") (intern-soft \"only-a-string\")))",
+             "(seen-by-reader nil)"),
+            ("(progn (make-symbol \"made-not-read\") (gensym \"gensym-not-read\")
+                    (list (intern-soft \"made-not-read\") (intern-soft \"gensym-not-read2\")))", "(nil nil)"),
+            ("(let ((n 0)) (mapatoms (lambda (s) (if (eq s 'counter) (setq n (1+ n))))) n)", "1"),
+            ("(list (symbolp 'a) (symbolp (make-symbol \"a\")) (symbolp nil) (symbolp \"a\"))", "(t t t nil)"),
+        ];
+
+        assert_evals(&mut lsp, &cases);
+
+        let errors = [
+            ("(setplist 'sym '(a 1 b))", symbols::WRONG_TYPE_ARGUMENT),
+            ("(setplist 'sym '(1 a))", symbols::WRONG_TYPE_ARGUMENT),
+            ("(symbol-value 'unbound-variable)", symbols::VOID_VARIABLE),
+            ("(intern 'foo)", symbols::WRONG_TYPE_ARGUMENT),
+            ("(unintern \"nil\")", symbols::ERROR),
+        ];
+
+        assert_signals(&mut lsp, &errors);
+    }
+
+    #[test]
+    fn uninterned_symbols() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(defmacro post-inc (place)
+  (let ((old (make-symbol "old")))
+    `(let ((,old ,place))
+       (setq ,place (1+ ,old))
+       ,old)))
+(defun post-inc-twice (old) (list (post-inc old) (post-inc old) old))
+(defun post-inc-compiled (old) (list (post-inc old) (post-inc old) old))
+(byte-compile 'post-inc-compiled)"#;
+        let ast = lsp.read(&src.to_owned()).unwrap();
+        lsp.eval(&ast).unwrap();
+
+        let cases = [
+            ("(let ((old 1)) (list (post-inc old) old))", "(1 2)"),
+            ("(post-inc-twice 5)", "(5 6 7)"),
+            ("(post-inc-compiled 5)", "(5 6 7)"),
+            ("(let* ((g (make-symbol \"g\"))) (eval `(let* ((,g 1) (,g (1+ ,g))) (setq ,g (* ,g 10)) ,g)))", "20"),
+            ("(let ((g (make-symbol \"g\"))) (eval `(let ((,g 1)) ,g)) (boundp g))", "nil"),
+            ("(let ((f (make-symbol \"f\"))) (fset f (lambda (x) (* x 2))) (list (eval (list f 3)) (funcall f 4)))",
+             "(6 8)"),
+            ("(list (eq '#:a '#:a) (eq '#:a 'a) (symbol-name '#:a) (symbolp '#:a) (intern-soft \"read-uninterned\"))",
+             "(nil nil \"a\" t nil)"),
+            ("(list (symbol-name '#:read-uninterned) (intern-soft \"read-uninterned\"))", "(\"read-uninterned\" nil)"),
+        ];
+
+        assert_evals(&mut lsp, &cases);
+        lsp.set_lexical_binding(true);
+        let ast = lsp.read(&src.to_owned()).unwrap();
+        lsp.eval(&ast).unwrap();
+        assert_evals(&mut lsp, &cases);
+
+        let errors = [
+            ("(let ((f (make-symbol \"f\"))) (eval (list f)))", symbols::VOID_FUNCTION),
+            ("(let ((g (make-symbol \"g\"))) (eval g))", symbols::VOID_VARIABLE),
+        ];
+        assert_signals(&mut lsp, &errors);
+    }
+
+    #[test]
+    fn keywords() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(defun keyword-test () (list :key :test))
+(byte-compile 'keyword-test)
+(defun set-t () (setq t 1))
+(byte-compile 'set-t)"#;
+        let ast = lsp.read(&src.to_owned()).unwrap();
+        lsp.eval(&ast).unwrap();
+
+        let cases = [
+            (":test", ":test"),
+            ("(list :a (eq :a ':a) (keyword-test))", "(:a t (:key :test))"),
+            ("(list (keywordp :a) (keywordp 'a) (keywordp (make-symbol \":a\")) (keywordp \":a\"))",
+             "(t nil nil nil)"),
+            ("(list (symbol-value :a) (boundp :a) (symbol-name :a))", r#"(:a t ":a")"#),
+            ("(let ((h (make-hash-table :test 'equal))) (puthash (list 1) 1 h) (gethash (list 1) h))", "1"),
+            ("(condition-case e (setq :a 1) (setting-constant e))", "(setting-constant :a)"),
+            ("(condition-case nil (signal 'error nil) (error 'caught))", "caught"),
+        ];
+
+        assert_evals(&mut lsp, &cases);
+
+        let errors = [
+            "(setq nil 1)",
+            "(setq t 1)",
+            "(set :a 1)",
+            "(let ((:a 1)) :a)",
+            "(let* ((x 1) (nil x)) x)",
+            "(defvar :a 1)",
+            "(makunbound t)",
+            "(set-t)",
+            "(funcall (byte-compile (lambda () (let ((:a 1)) :a))))",
+            "(funcall (lambda (:k) :k) 1)",
+            "(funcall (lambda (x &optional t) x) 1)",
+            "(funcall (byte-compile (lambda (&rest :r) :r)))",
+            "(progn (defun keyword-arg (nil) 1) (keyword-arg 2))",
+            "(condition-case :e (signal 'error nil) (error 1))",
+            "(condition-case t 1 (error 1))",
+            "(dolist (:x '(1 2)) :x)",
+            "(dotimes (t 2) 1)",
+        ];
+
+        let errors: Vec<(&str, Atom)> = errors.iter().map( |&src| (src, symbols::SETTING_CONSTANT) ).collect();
+        assert_signals(&mut lsp, &errors);
+        assert_eq!(lsp.eval_atm_val(symbols::T).unwrap(), LispObj::t());
+    }
+
+    #[test]
+    fn obarrays() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(defvar ob (obarray-make))
+(defvar local-foo (intern "foo" ob))"#;
+        let ast = lsp.read(&src.to_owned()).unwrap();
+        lsp.eval(&ast).unwrap();
+
+        let cases = [
+            ("(list (obarrayp ob) (obarrayp obarray) (obarrayp [1 2]) (obarrayp nil))", "(t t nil nil)"),
+            ("(list (eq local-foo (intern \"foo\" ob)) (eq local-foo 'foo) (eq (intern \"foo\" obarray) 'foo))",
+             "(t nil t)"),
+            ("(progn (set local-foo 1) (setq foo 2) (list (symbol-value local-foo) foo))", "(1 2)"),
+            ("(progn (put local-foo 'p 'local) (list (get local-foo 'p) (get 'foo 'p)))", "(local nil)"),
+            ("(list (eq (intern-soft \"foo\" ob) local-foo) (intern-soft \"bar\" ob) (intern-soft 'foo ob))",
+             "(t nil nil)"),
+            ("(let (names) (intern \"bar\" ob) (mapatoms (lambda (s) (setq names (cons (symbol-name s) names))) ob)
+                (sort names 'string<))", r#"("bar" "foo")"#),
+            ("(list (unintern 'foo ob) (unintern \"foo\" ob) (unintern \"foo\" ob) (intern-soft \"foo\" ob)
+                   (eq (intern \"foo\" ob) local-foo))", "(nil t nil nil nil)"),
+            ("(progn (obarray-clear ob) (intern-soft \"bar\" ob))", "nil"),
+        ];
+
+        assert_evals(&mut lsp, &cases);
+
+        let ast = lsp.read(&"(intern \"foo\" [])".to_owned()).unwrap();
+        assert_eq!(lsp.eval(&ast).unwrap_err().symbol, symbols::WRONG_TYPE_ARGUMENT);
+
+        // An obarray holding a symbol whose value is the obarray is a cycle
+        let ast = lsp.read(&"(let ((ob (obarray-make))) (set (intern \"self\" ob) ob) ob)".to_owned()).unwrap();
+        let weak = match lsp.eval(&ast).unwrap() {
+            LispObj::Ext(ext) => Rc::downgrade(&ext),
+            obj => panic!("Not an obarray: {:?}", obj),
+        };
+        assert!(weak.upgrade().is_some());
+        lsp.garbage_collect();
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn sandbox() {
+        let mut lsp = Lsp::sandbox(&["quote", "car", "cons", "fset", "symbol-function"]);
+
+        let ast = lsp.read(&"(car (cons 1 2))".to_owned()).unwrap();
+        assert_eq!(lsp.eval(&ast).unwrap(), LispObj::Int(1));
+
+        for src in ["(load \"init.el\")", "(eval '(car nil))", "(symbol-function 'load)"].iter() {
+            let ast = lsp.read(&src.to_string()).unwrap();
+            match lsp.eval(&ast) {
+                Err(e) => assert_eq!((*src, e.symbol), (*src, symbols::VOID_FUNCTION)),
+                Ok(obj) => assert!(obj.is_nil(), "{}", src),
+            }
+        }
+
+        // Redefining a builtin in the sandbox does not affect anyone else
+        let ast = lsp.read(&"(fset 'car (symbol-function 'cons)) (car 1 2)".to_owned()).unwrap();
+        assert_eq!(lsp.eval(&ast).unwrap(), LispObj::cons(LispObj::Int(1), LispObj::Int(2)));
+
+        let mut other = Lsp::new();
+        let ast = other.read(&"(car '(1 2))".to_owned()).unwrap();
+        assert_eq!(other.eval(&ast).unwrap(), LispObj::Int(1));
+    }
+
+    #[test]
+    fn resource_limits() {
+        let mut lsp = Lsp::new();
+        let src = r#"
+(defun spin () (while t))
+(byte-compile 'spin)
+(setq long-list (number-sequence 1 100000)
+      long-copy (number-sequence 1 100000))"#;
+        let ast = lsp.read(&src.to_owned()).unwrap();
+        lsp.eval(&ast).unwrap();
+
+        let steps = [
+            "(while t)",
+            "(condition-case nil (while t) (error 'caught))",
+            "(dotimes (i 4611686018427387903))",
+            "(spin)",
+            "(let ((n 0)) (while t (condition-case nil (while t) (t (setq n (1+ n))))))",
+            "(split-string (make-string 40 ?a) \"\\\\(a*\\\\)*b\")",
+            "(string-trim (make-string 40 ?a) \"\\\\(a*\\\\)*b\")",
+            "(length long-list)",
+            "(append long-list nil)",
+            "(reverse long-list)",
+            "(nth 99999 long-list)",
+            "(memq 0 long-list)",
+            "(member 0 long-list)",
+            "(assq 0 long-list)",
+            "(last long-list)",
+            "(concat long-list)",
+            "(equal long-list long-copy)",
+            "(let ((x (list 1))) (dotimes (i 40) (setq x (list x x))) (prin1-to-string x))",
+            "(let ((x (list 1)) (y (list 1))) (dotimes (i 40) (setq x (list x x) y (list y y))) (equal x y))",
+        ];
+
+        for &src in steps.iter() {
+            let ast = lsp.read(&src.to_owned()).unwrap();
+            lsp.set_step_limit(Some(10000));
+            let err = lsp.eval(&ast).unwrap_err();
+            assert_eq!((src, err.symbol), (src, symbols::STEP_LIMIT_EXCEEDED));
+            assert!(lsp.locals.is_empty() && lsp.stack.is_empty());
+        }
+        lsp.set_step_limit(None);
+
+        let allocs = [
+            "(let (l) (while t (setq l (cons 1 l))))",
+            "(let ((s \"x\")) (while t (setq s (concat s s))))",
+            "(make-vector 100000000 0)",
+            "(make-string 100000000 ?a)",
+            "(number-sequence 1 100000000)",
+            "(number-sequence 0.0 1.0 1e-8)",
+            "(format \"%100000000d\" 1)",
+            "(format \"%.100000000f\" 1.0)",
+            "(let ((x (list 1))) (dotimes (i 40) (setq x (list x x))) (prin1-to-string x))",
+            "(let ((x (list 1))) (dotimes (i 40) (setq x (list x x))) (format \"%S\" x))",
+        ];
+
+        for &src in allocs.iter() {
+            let ast = lsp.read(&src.to_owned()).unwrap();
+            lsp.set_alloc_limit(Some(1 << 20));
+            assert_eq!((src, lsp.eval(&ast).unwrap_err().symbol), (src, symbols::MEMORY_FULL));
+        }
+        lsp.set_alloc_limit(None);
+
+        let ast = lsp.read(&"(let ((n 0)) (dotimes (i 100) (setq n (+ n i))) n)".to_owned()).unwrap();
+        lsp.set_step_limit(Some(10000));
+        assert_eq!(lsp.eval(&ast).unwrap(), LispObj::Int(4950));
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut lsp = Lsp::new();
+        let handle = lsp.interrupt_handle();
+        let ast = lsp.read(&"(while t)".to_owned()).unwrap();
+
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            handle.interrupt();
+        });
+        assert_eq!(lsp.eval(&ast).unwrap_err().symbol, symbols::QUIT);
+        interrupter.join().unwrap();
+
+        // The quit waits until inhibit-quit is nil, then happens once
+        let cases = [
+            ("(dotimes (i 100)) 'done", Ok(LispObj::Atm(lsp.atomize("done")))),
+            ("(setq inhibit-quit nil)", Ok(LispObj::nil())),
+            ("(+ 1 2)", Err(symbols::QUIT)),
+            ("(+ 1 2)", Ok(LispObj::Int(3))),
+        ];
+
+        let ast = lsp.read(&"(setq inhibit-quit t)".to_owned()).unwrap();
+        lsp.eval(&ast).unwrap();
+        lsp.interrupt_handle().interrupt();
+        for &(src, ref expected) in cases.iter() {
+            let ast = lsp.read(&src.to_owned()).unwrap();
+            let res = lsp.eval(&ast).map_err( |e| e.symbol );
+            assert_eq!((src, &res), (src, expected));
+        }
+
+        let handle = lsp.interrupt_handle();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            handle.interrupt();
+        });
+        let ast = lsp.read(&"(condition-case nil (while t) (error 'error) (quit 'quit))".to_owned()).unwrap();
+        assert_eq!(lsp.eval(&ast).unwrap(), LispObj::Atm(lsp.atomize("quit")));
+        interrupter.join().unwrap();
+
+        // Builtins which can run for a long time without evaluating anything
+        let builtins = [
+            "(let ((x (list 1))) (dotimes (i 40) (setq x (list x x))) (prin1-to-string x))",
+            "(let ((x (list 1)) (y (list 1))) (dotimes (i 40) (setq x (list x x) y (list y y))) (equal x y))",
+        ];
+
+        for &src in builtins.iter() {
+            let handle = lsp.interrupt_handle();
+            let interrupter = std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                handle.interrupt();
+            });
+            let ast = lsp.read(&src.to_owned()).unwrap();
+            assert_eq!((src, lsp.eval(&ast).unwrap_err().symbol), (src, symbols::QUIT));
+            interrupter.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn drop_long_list() {
+        let items = vec![LispObj::Int(1); 1000000];
+        let lst = LispObj::list_from(&items);
+
+        assert_eq!(lst.iter().count(), 1000000);
+    }
+
+    #[test]
+    fn drop_deep_nesting() {
+        let mut lsp = Lsp::new();
+        let cases = [
+            ("(let ((x nil)) (dotimes (i 20000) (setq x (list x))) (integerp (sxhash-equal x)))", "t"),
+            ("(let ((x nil)) (dotimes (i 20000) (setq x (vector (list x)))) (length x))", "1"),
+        ];
+        assert_evals(&mut lsp, &cases);
+
+        let errors = [
+            ("(let ((x nil) (y nil)) (dotimes (i 20000) (setq x (list x) y (list y))) (equal x y))",
+             symbols::ERROR),
+        ];
+        assert_signals(&mut lsp, &errors);
     }
 }
@@ -18,6 +18,7 @@
 use std::io::stdin;
 use std::fs::File;
 use std::io::prelude::*;
+use std::thread;
 
 extern crate fnv;
 extern crate orbclient;
@@ -26,12 +27,25 @@ extern crate orbclient;
 extern crate rselisp;
 use rselisp::{Lsp, LispObj};
 use rselisp::symbols;
+use rselisp::debug;
 
 mod editor;
 mod buffer;
 mod frame;
 mod keymap;
 
+/// The size of the stack Lisp is evaluated on, which limits how deep
+/// recursion can go
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Create an interpreter which may use most of the stack of a thread started
+/// by on_lisp_thread
+pub fn new_lsp() -> Lsp {
+    let mut lsp = Lsp::new();
+    lsp.set_stack_limit(STACK_SIZE - 1024 * 1024);
+    lsp
+}
+
 enum Mode {
     Repl,
     Editor,
@@ -39,14 +53,17 @@ enum Mode {
 }
 
 fn repl() {
-    let mut lsp = Lsp::new();
+    let mut lsp = new_lsp();
     let mut obuf = String::new();
 
+    lsp.set_debug_io(Box::new(debug::StdDebugIo));
+
     println!("'(rselisp repl v0.0 (C) 2017 Richard Palethorpe)");
 
     loop {
         let mut line = String::new();
         match stdin().read_line(&mut line) {
+            Ok(0) => break,
             Ok(_) => {
                 match lsp.read(&line) {
                     Ok(sexp) => match lsp.eval(&sexp) {
@@ -56,9 +73,14 @@ fn repl() {
                             println!("-> {}", obuf);
                             obuf.clear();
                         },
-                        Err(e) => println!("EVAL ERROR: {}", e),
+                        Err(e) => {
+                            println!("EVAL ERROR: {}", lsp.error_message(&e));
+                            if let Some(ref frames) = e.backtrace {
+                                print!("{}", lsp.format_backtrace(frames));
+                            }
+                        },
                     },
-                    Err(e) => println!("READ ERROR: {}", e),
+                    Err(e) => println!("READ ERROR: {}", lsp.error_message(&e)),
                 }
             },
             Err(e) => { println!("I/O ERROR: {}", e); break; },
@@ -70,7 +92,7 @@ fn repl() {
 
 fn exec_file(name: &str) {
     let mut src = String::new();
-    let mut lsp = Lsp::new();
+    let mut lsp = new_lsp();
 
     match File::open(name) {
         Ok(mut file) => {
@@ -85,14 +107,28 @@ fn exec_file(name: &str) {
         },
     }
 
-    match lsp.read(&src) {
-        Ok(sexp) => if let Err(e) = lsp.eval(&sexp) {
-            println!("EVAL ERROR: {}", e)
+    match lsp.read_source(&src, Some(name)) {
+        Ok(sexp) => if let Err(e) = lsp.eval_source(&src, &sexp) {
+            println!("EVAL ERROR: {}", lsp.error_message(&e));
+            if let Some(ref frames) = e.backtrace {
+                print!("{}", lsp.format_backtrace(frames));
+            }
         },
-        Err(e) => println!("READ ERROR: {}", e),
+        Err(e) => println!("READ ERROR: {}", lsp.error_message(&e)),
     };
 }
 
+/// Run f on a thread with a stack big enough for new_lsp and wait for it
+fn on_lisp_thread<F: FnOnce() + Send + 'static>(f: F) {
+    let run = thread::Builder::new().stack_size(STACK_SIZE).spawn(f);
+
+    if let Err(e) = run.and_then( |handle| handle.join().map_err( |_| {
+        std::io::Error::other("Lisp thread panicked")
+    })) {
+        println!("ERROR: {}", e);
+    }
+}
+
 fn main() {
     let mut mode = Mode::Repl;
     let mut start_file: Option<String> = None;
@@ -112,14 +148,13 @@ fn main() {
     }
 
     match mode {
+        // Windowing backends expect their events to be handled on the main
+        // thread, so the editor keeps the default stack limit
         Mode::Editor => editor::start(),
-        Mode::ExecFile => {
-            if let Some(ref file) = start_file {
-                exec_file(file);
-            } else {
-                println!("Argument --exec requires a file path");
-            }
+        Mode::ExecFile => match start_file {
+            Some(file) => on_lisp_thread(move || exec_file(&file)),
+            None => println!("Argument --exec requires a file path"),
         },
-        Mode::Repl => repl(),
+        Mode::Repl => on_lisp_thread(repl),
     }
 }
@@ -0,0 +1,604 @@
+// Copyright (C) 2017 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Numbers and arithmetic
+//!
+//! Integers are fixnums until an operation overflows, when the result becomes
+//! a bignum. Bignums are turned back into fixnums whenever they fit, so the
+//! same integer always has the same representation. As in Emacs, floats are
+//! contagious; an operation on a float and an integer gives a float.
+
+use std::cmp::Ordering;
+
+use super::*;
+use bignum::BigInt;
+
+/// The largest bignum, in bits, as Emacs' integer-width
+pub const INTEGER_WIDTH: usize = 65536;
+
+/// A number taken from a LispObj
+#[derive(Debug, Clone)]
+enum Num {
+    Int(i64),
+    Big(BigInt),
+    Float(f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Rounding {
+    Truncate,
+    Floor,
+    Ceiling,
+    Round,
+}
+
+impl Num {
+    fn from_lisp(obj: &LispObj) -> Result<Num, LispError> {
+        match obj {
+            &LispObj::Int(i) => Ok(Num::Int(i)),
+            LispObj::Big(i) => Ok(Num::Big((**i).clone())),
+            &LispObj::Float(f) => Ok(Num::Float(f)),
+            obj => Err(LispError::wrong_type(symbols::NUMBER_OR_MARKER_P, obj)),
+        }
+    }
+
+    fn integer_from_lisp(obj: &LispObj) -> Result<Num, LispError> {
+        match obj {
+            &LispObj::Float(_) => Err(LispError::wrong_type(symbols::INTEGER_OR_MARKER_P, obj)),
+            obj => Num::from_lisp(obj)
+                .map_err( |_| LispError::wrong_type(symbols::INTEGER_OR_MARKER_P, obj) ),
+        }
+    }
+
+    /// A fixnum if the bignum fits in one
+    fn big(i: BigInt) -> Num {
+        match i.to_i64() {
+            Some(i) => Num::Int(i),
+            None => Num::Big(i),
+        }
+    }
+
+    fn into_lisp(self) -> LispObj {
+        match self {
+            Num::Int(i) => LispObj::Int(i),
+            Num::Big(i) => LispObj::Big(Rc::new(i)),
+            Num::Float(f) => LispObj::Float(f),
+        }
+    }
+
+    fn is_float(&self) -> bool {
+        matches!(self, &Num::Float(_))
+    }
+
+    fn to_f64(&self) -> f64 {
+        match self {
+            &Num::Int(i) => i as f64,
+            Num::Big(i) => i.to_f64(),
+            &Num::Float(f) => f,
+        }
+    }
+
+    /// An integer as a bignum, floats are truncated
+    fn to_big(&self) -> BigInt {
+        match self {
+            &Num::Int(i) => BigInt::from_i64(i),
+            Num::Big(i) => i.clone(),
+            &Num::Float(f) => BigInt::from_f64(f).unwrap_or(BigInt::from_i64(0)),
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        match *self {
+            Num::Int(i) => i == 0,
+            Num::Big(_) => false,
+            Num::Float(f) => f == 0.0,
+        }
+    }
+
+    fn is_negative(&self) -> bool {
+        match self {
+            &Num::Int(i) => i < 0,
+            Num::Big(i) => i.is_negative(),
+            &Num::Float(f) => f < 0.0,
+        }
+    }
+
+    fn is_even(&self) -> bool {
+        match self {
+            &Num::Int(i) => i % 2 == 0,
+            Num::Big(i) => i.is_even(),
+            &Num::Float(f) => f % 2.0 == 0.0,
+        }
+    }
+
+    fn bits(&self) -> usize {
+        match self {
+            &Num::Int(i) => 64 - if i < 0 { (!i).leading_zeros() } else { i.leading_zeros() } as usize,
+            Num::Big(i) => i.bits(),
+            &Num::Float(_) => 64,
+        }
+    }
+}
+
+/// Apply an integer operation, on fixnums if it doesn't overflow
+fn int_op<F, G>(a: &Num, b: &Num, fix: F, big: G) -> Num
+    where F: Fn(i64, i64) -> Option<i64>, G: Fn(&BigInt, &BigInt) -> BigInt
+{
+    if let (&Num::Int(x), &Num::Int(y)) = (a, b) {
+        if let Some(z) = fix(x, y) {
+            return Num::Int(z);
+        }
+    }
+    Num::big(big(&a.to_big(), &b.to_big()))
+}
+
+/// Apply an arithmetic operation, in floating point if either is a float
+fn arith<F, G, H>(a: &Num, b: &Num, fix: F, big: G, float: H) -> Num
+    where F: Fn(i64, i64) -> Option<i64>, G: Fn(&BigInt, &BigInt) -> BigInt,
+          H: Fn(f64, f64) -> f64
+{
+    if a.is_float() || b.is_float() {
+        Num::Float(float(a.to_f64(), b.to_f64()))
+    } else {
+        int_op(a, b, fix, big)
+    }
+}
+
+fn add(a: &Num, b: &Num) -> Num {
+    arith(a, b, i64::checked_add, BigInt::add, |x, y| x + y)
+}
+
+fn sub(a: &Num, b: &Num) -> Num {
+    arith(a, b, i64::checked_sub, BigInt::sub, |x, y| x - y)
+}
+
+fn mul(a: &Num, b: &Num) -> Num {
+    arith(a, b, i64::checked_mul, BigInt::mul, |x, y| x * y)
+}
+
+fn neg(a: &Num) -> Num {
+    match a {
+        &Num::Int(i) => i.checked_neg().map_or_else( || Num::big(BigInt::from_i64(i).neg()), Num::Int),
+        Num::Big(i) => Num::big(i.neg()),
+        &Num::Float(f) => Num::Float(-f),
+    }
+}
+
+fn abs(a: &Num) -> Num {
+    if a.is_negative() { neg(a) } else { a.clone() }
+}
+
+/// Division, of integers rounding towards zero
+fn div(a: &Num, b: &Num) -> Result<Num, LispError> {
+    if a.is_float() || b.is_float() {
+        return Ok(Num::Float(a.to_f64() / b.to_f64()));
+    }
+    if b.is_zero() {
+        return Err(LispError::arith_error());
+    }
+    Ok(int_op(a, b, i64::checked_div, |x, y| x.div_rem(y).unwrap().0))
+}
+
+/// The remainder of integer division, which has the sign of a
+fn rem(a: &Num, b: &Num) -> Result<Num, LispError> {
+    if b.is_zero() {
+        return Err(LispError::arith_error());
+    }
+    Ok(int_op(a, b, i64::checked_rem, |x, y| x.div_rem(y).unwrap().1))
+}
+
+/// The remainder of division rounding down, which has the sign of b
+fn modulo(a: &Num, b: &Num) -> Result<Num, LispError> {
+    let r = if a.is_float() || b.is_float() {
+        Num::Float(a.to_f64() % b.to_f64())
+    } else {
+        rem(a, b)?
+    };
+
+    if !r.is_zero() && r.is_negative() != b.is_negative() {
+        Ok(add(&r, b))
+    } else {
+        Ok(r)
+    }
+}
+
+/// Compare two numbers exactly, None if either is NaN
+fn compare(a: &Num, b: &Num) -> Option<Ordering> {
+    match (a, b) {
+        (&Num::Int(x), &Num::Int(y)) => Some(x.cmp(&y)),
+        (&Num::Float(x), &Num::Float(y)) => x.partial_cmp(&y),
+        (&Num::Float(_), _) => compare(b, a).map(Ordering::reverse),
+        (_, &Num::Float(y)) => {
+            if y.is_nan() {
+                None
+            } else if y.is_infinite() {
+                Some(if y > 0.0 { Ordering::Less } else { Ordering::Greater })
+            } else {
+                // Compare with the integer part, then the fraction
+                let t = y.trunc();
+                match a.to_big().cmp(&BigInt::from_f64(t).unwrap()) {
+                    Ordering::Equal => 0.0.partial_cmp(&(y - t)),
+                    ord => Some(ord),
+                }
+            }
+        },
+        _ => Some(a.to_big().cmp(&b.to_big())),
+    }
+}
+
+fn round_float(f: f64, mode: Rounding) -> f64 {
+    match mode {
+        Rounding::Truncate => f.trunc(),
+        Rounding::Floor => f.floor(),
+        Rounding::Ceiling => f.ceil(),
+        // Halfway cases go to the even neighbour
+        Rounding::Round if (f - f.trunc()).abs() == 0.5 => 2.0 * (f / 2.0).round(),
+        Rounding::Round => f.round(),
+    }
+}
+
+/// Divide integers, rounding the quotient as mode says
+fn round_div(a: &Num, b: &Num, mode: Rounding) -> Result<Num, LispError> {
+    let q = div(a, b)?;
+    let r = rem(a, b)?;
+    if r.is_zero() {
+        return Ok(q);
+    }
+
+    let one = Num::Int(1);
+    let negative = a.is_negative() != b.is_negative();
+    let away = if negative { sub(&q, &one) } else { add(&q, &one) };
+
+    Ok(match mode {
+        Rounding::Truncate => q,
+        Rounding::Floor => if negative { away } else { q },
+        Rounding::Ceiling => if negative { q } else { away },
+        Rounding::Round => match compare(&abs(&mul(&r, &Num::Int(2))), &abs(b)) {
+            Some(Ordering::Less) => q,
+            Some(Ordering::Equal) if q.is_even() => q,
+            _ => away,
+        },
+    })
+}
+
+/// Fold the numbers in args with op, starting from init
+fn fold_numbers<F>(args: &mut Iter<LispObj>, init: Num, op: F) -> Result<LispObj, LispError>
+    where F: Fn(&Num, &Num) -> Num
+{
+    let mut acc = init;
+    for arg in args {
+        acc = op(&acc, &Num::from_lisp(arg)?);
+    }
+    Ok(acc.into_lisp())
+}
+
+/// Check each argument is related to the next as pred says
+fn compare_numbers<F>(lsp: &mut Lsp, name: &str, args: &mut Iter<LispObj>, pred: F)
+                      -> Result<LispObj, LispError>
+    where F: Fn(Ordering) -> bool
+{
+    let mut prev = match args.next() {
+        Some(arg) => Num::from_lisp(arg)?,
+        None => return Err(wrong_args(lsp, name, 0)),
+    };
+
+    let mut res = true;
+    for arg in args {
+        let num = Num::from_lisp(arg)?;
+        res = res && compare(&prev, &num).is_some_and(&pred);
+        prev = num;
+    }
+    Ok(LispObj::from_bool(res))
+}
+
+/// The argument which is furthest in the direction of want
+fn extremum(lsp: &mut Lsp, name: &str, args: &mut Iter<LispObj>, want: Ordering)
+            -> Result<LispObj, LispError> {
+    let mut best = match args.next() {
+        Some(arg) => (Num::from_lisp(arg)?, arg),
+        None => return Err(wrong_args(lsp, name, 0)),
+    };
+
+    for arg in args {
+        let num = Num::from_lisp(arg)?;
+        match compare(&num, &best.0) {
+            // NaN wins
+            None if num.to_f64().is_nan() => best = (num, arg),
+            Some(ord) if ord == want => best = (num, arg),
+            _ => (),
+        }
+    }
+    Ok(best.1.clone())
+}
+
+fn rounding(lsp: &mut Lsp, name: &str, mode: Rounding, args: &mut Iter<LispObj>)
+            -> Result<LispObj, LispError> {
+    let count = args.len();
+    let (arg, divisor) = match take2!(args) {
+        (Some(arg), divisor) if count <= 2 => (Num::from_lisp(arg)?, divisor),
+        _ => return Err(wrong_args(lsp, name, count)),
+    };
+
+    let num = match divisor {
+        None | Some(&LispObj::Atm(symbols::NIL)) => arg,
+        Some(divisor) => {
+            let divisor = Num::from_lisp(divisor)?;
+            if !arg.is_float() && !divisor.is_float() {
+                return Ok(round_div(&arg, &divisor, mode)?.into_lisp());
+            }
+            if divisor.is_zero() {
+                return Err(LispError::arith_error());
+            }
+            div(&arg, &divisor)?
+        },
+    };
+
+    match num {
+        Num::Float(f) => {
+            let r = round_float(f, mode);
+            if r.is_finite() && r.abs() < 9.2e18 {
+                Ok(LispObj::Int(r as i64))
+            } else {
+                BigInt::from_f64(r).map(LispObj::integer).ok_or_else( || {
+                    LispError::overflow_error(&[LispObj::str(name), LispObj::Float(f)])
+                })
+            }
+        },
+        num => Ok(num.into_lisp()),
+    }
+}
+
+/// Combine the integers in args bit by bit
+fn bitwise<F, G>(args: &mut Iter<LispObj>, init: i64, fix: F, big: G)
+                 -> Result<LispObj, LispError>
+    where F: Fn(i64, i64) -> i64, G: Fn(&BigInt, &BigInt) -> BigInt
+{
+    let mut acc = Num::Int(init);
+    for arg in args {
+        acc = int_op(&acc, &Num::integer_from_lisp(arg)?, |x, y| Some(fix(x, y)), &big);
+    }
+    Ok(acc.into_lisp())
+}
+
+def_builtin! { "+", PlusBuiltin, Evaluated, 0, many, _lsp, args; {
+    fold_numbers(args, Num::Int(0), add)
+}}
+
+def_builtin! { "-", MinusBuiltin, Evaluated, 0, many, _lsp, args; {
+    match (args.next(), args.len()) {
+        (None, _) => Ok(LispObj::Int(0)),
+        (Some(arg), 0) => Ok(neg(&Num::from_lisp(arg)?).into_lisp()),
+        (Some(arg), _) => fold_numbers(args, Num::from_lisp(arg)?, sub),
+    }
+}}
+
+def_builtin! { "*", TimesBuiltin, Evaluated, 0, many, _lsp, args; {
+    fold_numbers(args, Num::Int(1), mul)
+}}
+
+def_builtin! { "/", QuoBuiltin, Evaluated, 1, many, lsp, args; {
+    let mut nums = args.map(Num::from_lisp).collect::<Result<Vec<Num>, LispError>>()?;
+
+    // If any argument is a float then they all are
+    if nums.iter().any(Num::is_float) {
+        nums = nums.iter().map( |n| Num::Float(n.to_f64()) ).collect();
+    }
+
+    let mut acc = match nums.len() {
+        0 => return Err(wrong_args(lsp, "/", 0)),
+        1 => return Ok(div(&Num::Int(1), &nums[0])?.into_lisp()),
+        _ => nums[0].clone(),
+    };
+    for num in nums[1..].iter() {
+        acc = div(&acc, num)?;
+    }
+    Ok(acc.into_lisp())
+}}
+
+def_builtin! { "%", RemBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (a, b) = two_args(lsp, "%", args)?;
+    Ok(rem(&Num::integer_from_lisp(a)?, &Num::integer_from_lisp(b)?)?.into_lisp())
+}}
+
+def_builtin! { "mod", ModBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (a, b) = two_args(lsp, "mod", args)?;
+    Ok(modulo(&Num::from_lisp(a)?, &Num::from_lisp(b)?)?.into_lisp())
+}}
+
+def_builtin! { "1+", Add1Builtin, Evaluated, 1, 1, lsp, args; {
+    let arg = one_arg(lsp, "1+", args)?;
+    Ok(add(&Num::from_lisp(arg)?, &Num::Int(1)).into_lisp())
+}}
+
+def_builtin! { "1-", Sub1Builtin, Evaluated, 1, 1, lsp, args; {
+    let arg = one_arg(lsp, "1-", args)?;
+    Ok(sub(&Num::from_lisp(arg)?, &Num::Int(1)).into_lisp())
+}}
+
+def_builtin! { "=", EqlsignBuiltin, Evaluated, 1, many, lsp, args; {
+    compare_numbers(lsp, "=", args, |ord| ord == Ordering::Equal)
+}}
+
+def_builtin! { "<", LssBuiltin, Evaluated, 1, many, lsp, args; {
+    compare_numbers(lsp, "<", args, |ord| ord == Ordering::Less)
+}}
+
+def_builtin! { ">", GtrBuiltin, Evaluated, 1, many, lsp, args; {
+    compare_numbers(lsp, ">", args, |ord| ord == Ordering::Greater)
+}}
+
+def_builtin! { "<=", LeqBuiltin, Evaluated, 1, many, lsp, args; {
+    compare_numbers(lsp, "<=", args, |ord| ord != Ordering::Greater)
+}}
+
+def_builtin! { ">=", GeqBuiltin, Evaluated, 1, many, lsp, args; {
+    compare_numbers(lsp, ">=", args, |ord| ord != Ordering::Less)
+}}
+
+def_builtin! { "/=", NeqBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (a, b) = two_args(lsp, "/=", args)?;
+    let ord = compare(&Num::from_lisp(a)?, &Num::from_lisp(b)?);
+    Ok(LispObj::from_bool(ord != Some(Ordering::Equal)))
+}}
+
+def_builtin! { "max", MaxBuiltin, Evaluated, 1, many, lsp, args; {
+    extremum(lsp, "max", args, Ordering::Greater)
+}}
+
+def_builtin! { "min", MinBuiltin, Evaluated, 1, many, lsp, args; {
+    extremum(lsp, "min", args, Ordering::Less)
+}}
+
+def_builtin! { "abs", AbsBuiltin, Evaluated, 1, 1, lsp, args; {
+    let arg = one_arg(lsp, "abs", args)?;
+    Ok(abs(&Num::from_lisp(arg)?).into_lisp())
+}}
+
+def_builtin! { "float", FloatBuiltin, Evaluated, 1, 1, lsp, args; {
+    let arg = one_arg(lsp, "float", args)?;
+    Ok(LispObj::Float(Num::from_lisp(arg)?.to_f64()))
+}}
+
+def_builtin! { "truncate", TruncateBuiltin, Evaluated, 1, 2, lsp, args; {
+    rounding(lsp, "truncate", Rounding::Truncate, args)
+}}
+
+def_builtin! { "floor", FloorBuiltin, Evaluated, 1, 2, lsp, args; {
+    rounding(lsp, "floor", Rounding::Floor, args)
+}}
+
+def_builtin! { "ceiling", CeilingBuiltin, Evaluated, 1, 2, lsp, args; {
+    rounding(lsp, "ceiling", Rounding::Ceiling, args)
+}}
+
+def_builtin! { "round", RoundBuiltin, Evaluated, 1, 2, lsp, args; {
+    rounding(lsp, "round", Rounding::Round, args)
+}}
+
+def_builtin! { "expt", ExptBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (a, b) = two_args(lsp, "expt", args)?;
+    let (base, exp) = (Num::from_lisp(a)?, Num::from_lisp(b)?);
+
+    match (&base, &exp) {
+        (_, &Num::Int(e)) if e >= 0 && !base.is_float() => {
+            if let Num::Int(b) = base {
+                if e <= u32::MAX as i64 {
+                    if let Some(p) = b.checked_pow(e as u32) {
+                        return Ok(LispObj::Int(p));
+                    }
+                }
+            }
+
+            let big = base.to_big();
+            let trivial = big.abs().to_i64().is_some_and(|b| b <= 1);
+            if !trivial && (base.bits() as i64).saturating_mul(e) > INTEGER_WIDTH as i64 {
+                return Err(LispError::overflow_error(&[]));
+            }
+            Ok(LispObj::integer(big.pow(e as u64)))
+        },
+        _ => Ok(LispObj::Float(base.to_f64().powf(exp.to_f64()))),
+    }
+}}
+
+def_builtin! { "sqrt", SqrtBuiltin, Evaluated, 1, 1, lsp, args; {
+    let arg = one_arg(lsp, "sqrt", args)?;
+    Ok(LispObj::Float(Num::from_lisp(arg)?.to_f64().sqrt()))
+}}
+
+def_builtin! { "numberp", NumberpBuiltin, Evaluated, 1, 1, lsp, args; {
+    let obj = one_arg(lsp, "numberp", args)?;
+    Ok(LispObj::from_bool(matches!(obj, &LispObj::Int(_) | &LispObj::Big(_) | &LispObj::Float(_))))
+}}
+
+def_builtin! { "integerp", IntegerpBuiltin, Evaluated, 1, 1, lsp, args; {
+    Ok(LispObj::from_bool(matches!(one_arg(lsp, "integerp", args)?, &LispObj::Int(_) | &LispObj::Big(_))))
+}}
+
+def_builtin! { "floatp", FloatpBuiltin, Evaluated, 1, 1, lsp, args; {
+    Ok(LispObj::from_bool(matches!(one_arg(lsp, "floatp", args)?, &LispObj::Float(_))))
+}}
+
+def_builtin! { "natnump", NatnumpBuiltin, Evaluated, 1, 1, lsp, args; {
+    Ok(LispObj::from_bool(match one_arg(lsp, "natnump", args)? {
+        &LispObj::Int(i) => i >= 0,
+        LispObj::Big(i) => !i.is_negative(),
+        _ => false,
+    }))
+}}
+
+def_builtin! { "fixnump", FixnumpBuiltin, Evaluated, 1, 1, lsp, args; {
+    Ok(LispObj::from_bool(matches!(one_arg(lsp, "fixnump", args)?, &LispObj::Int(_))))
+}}
+
+def_builtin! { "bignump", BignumpBuiltin, Evaluated, 1, 1, lsp, args; {
+    Ok(LispObj::from_bool(matches!(one_arg(lsp, "bignump", args)?, &LispObj::Big(_))))
+}}
+
+def_builtin! { "zerop", ZeropBuiltin, Evaluated, 1, 1, lsp, args; {
+    match one_arg(lsp, "zerop", args)? {
+        &LispObj::Int(i) => Ok(LispObj::from_bool(i == 0)),
+        &LispObj::Big(_) => Ok(LispObj::nil()),
+        &LispObj::Float(f) => Ok(LispObj::from_bool(f == 0.0)),
+        obj => Err(LispError::wrong_type(symbols::NUMBERP, obj)),
+    }
+}}
+
+def_builtin! { "logand", LogandBuiltin, Evaluated, 0, many, _lsp, args; {
+    bitwise(args, -1, |x, y| x & y, BigInt::and)
+}}
+
+def_builtin! { "logior", LogiorBuiltin, Evaluated, 0, many, _lsp, args; {
+    bitwise(args, 0, |x, y| x | y, BigInt::or)
+}}
+
+def_builtin! { "logxor", LogxorBuiltin, Evaluated, 0, many, _lsp, args; {
+    bitwise(args, 0, |x, y| x ^ y, BigInt::xor)
+}}
+
+def_builtin! { "lognot", LognotBuiltin, Evaluated, 1, 1, lsp, args; {
+    match Num::integer_from_lisp(one_arg(lsp, "lognot", args)?)? {
+        Num::Int(i) => Ok(LispObj::Int(!i)),
+        num => Ok(LispObj::integer(num.to_big().not())),
+    }
+}}
+
+def_builtin! { "ash", AshBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (value, count) = two_args(lsp, "ash", args)?;
+    let value = Num::integer_from_lisp(value)?;
+    let count = match count {
+        &LispObj::Int(count) => count,
+        obj => return Err(LispError::wrong_type(symbols::INTEGERP, obj)),
+    };
+
+    if count < 0 {
+        return Ok(match value {
+            Num::Int(i) => LispObj::Int(i >> cmp::min(-count, 63)),
+            num => LispObj::integer(num.to_big().shr((-count) as usize)),
+        });
+    }
+
+    if let Num::Int(i) = value {
+        if count < 63 && (i << count) >> count == i {
+            return Ok(LispObj::Int(i << count));
+        }
+    }
+    if value.is_zero() {
+        return Ok(LispObj::Int(0));
+    }
+    if value.bits() as i64 + count > INTEGER_WIDTH as i64 {
+        return Err(LispError::overflow_error(&[]));
+    }
+    Ok(LispObj::integer(value.to_big().shl(count as usize)))
+}}
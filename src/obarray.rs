@@ -0,0 +1,251 @@
+// Copyright (C) 2017 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Obarrays, the tables symbols are interned in
+//!
+//! The global obarray is the table of names in the `AtomRegistry` and its
+//! symbols appear in Lisp as atoms. An obarray made with obarray-make holds
+//! its own symbols, which are distinct from the global symbols of the same
+//! name, so they appear in Lisp as symbols which are not interned globally.
+//! The value of the variable `obarray` stands for the global obarray, as does
+//! nil.
+
+use std::any::Any;
+use super::*;
+
+pub struct Obarray {
+    syms: FnvHashMap<String, Symbol>,
+    /// This is the value of `obarray`, the symbols are in `Lsp::globals`
+    global: bool,
+}
+
+impl Default for Obarray {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Obarray {
+    pub fn new() -> Obarray {
+        Obarray {
+            syms: FnvHashMap::default(),
+            global: false,
+        }
+    }
+
+    fn global() -> Obarray {
+        Obarray {
+            syms: FnvHashMap::default(),
+            global: true,
+        }
+    }
+
+    /// The symbol with this name, creating it if necessary
+    ///
+    /// The names are kept here rather than in the global table, so a new
+    /// symbol's name is an uninterned atom.
+    pub fn intern(&mut self, atoms: &mut AtomRegistry, name: &str) -> Symbol {
+        if let Some(sym) = self.syms.get(name) {
+            return sym.clone();
+        }
+        let sym = Symbol::new(atoms.uninterned(name.to_owned()));
+        self.syms.insert(name.to_owned(), sym.clone());
+        sym
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<Symbol> {
+        self.syms.get(name).cloned()
+    }
+
+    /// Remove the symbol with this name, returning it if there was one
+    pub fn remove(&mut self, name: &str) -> Option<Symbol> {
+        self.syms.remove(name)
+    }
+
+    pub fn symbols(&self) -> Vec<Symbol> {
+        self.syms.values().cloned().collect()
+    }
+
+    pub fn is_obarray(obj: &dyn LispForm) -> bool {
+        obj.lisp_name() == "obarray"
+    }
+}
+
+impl fmt::Debug for Obarray {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Obarray {{ symbols: {}, global: {} }}", self.syms.len(), self.global)
+    }
+}
+
+impl LispForm for Obarray {
+    fn rust_name(&self) -> &'static str {
+        "obarray::Obarray"
+    }
+
+    fn lisp_name(&self) -> &'static str {
+        "obarray"
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn trace(&self, f: &mut dyn FnMut(&LispObj)) {
+        for sym in self.syms.values() {
+            f(&LispObj::Sym(sym.clone()));
+        }
+    }
+
+    fn clear(&mut self) {
+        self.syms = FnvHashMap::default();
+    }
+}
+
+/// The value given to the variable `obarray`
+pub fn global_obarray() -> LispObj {
+    LispObj::ext(Rc::new(RefCell::new(Obarray::global())))
+}
+
+/// The obarray a function was given, None if it is the global one
+fn obarray_arg(obarray: Option<&LispObj>) -> Result<Option<External>, LispError> {
+    match obarray {
+        None | Some(&LispObj::Atm(symbols::NIL)) => Ok(None),
+        Some(LispObj::Ext(ext)) => {
+            let global = match ext.borrow_mut().as_any().downcast_mut::<Obarray>() {
+                Some(obarray) => obarray.global,
+                None => return Err(LispError::wrong_type(symbols::OBARRAYP, obarray.unwrap())),
+            };
+            Ok(if global { None } else { Some(ext.clone()) })
+        },
+        Some(obj) => Err(LispError::wrong_type(symbols::OBARRAYP, obj)),
+    }
+}
+
+/// Call f with an obarray returned by obarray_arg
+fn with_obarray<F, T>(ext: &External, f: F) -> T
+    where F: FnOnce(&mut Obarray) -> T
+{
+    let ext = &mut *ext.borrow_mut();
+    f(ext.as_any().downcast_mut::<Obarray>().expect("obarray_arg checked the type"))
+}
+
+/// The symbol interned in the obarray for a name given to intern-soft or
+/// unintern
+///
+/// If a symbol is given, rather than a string, then it is only found if it is
+/// the one interned in the obarray, so None is returned for the name of a
+/// global symbol when looking in another obarray and vice versa.
+fn lookup_name(lsp: &Lsp, name: &LispObj, obarray: &Option<External>)
+               -> Result<Option<LispObj>, LispError> {
+    match (name, obarray) {
+        (LispObj::Str(s), &None) => Ok(lsp.atoms.get(s).map(LispObj::Atm)),
+        (LispObj::Str(s), Some(ext)) => Ok(with_obarray(ext, |ob| ob.lookup(s))
+                                                     .map(LispObj::Sym)),
+        (&LispObj::Atm(a), &None) if lsp.atoms.is_interned(a) => Ok(Some(LispObj::Atm(a))),
+        (LispObj::Sym(sym), Some(ext)) => {
+            let found = with_obarray(ext, |ob| ob.lookup(lsp.stringify(sym.name)));
+            Ok(if found.as_ref() == Some(sym) { found.map(LispObj::Sym) } else { None })
+        },
+        (&LispObj::Atm(_), _) | (&LispObj::Sym(_), _) => Ok(None),
+        (obj, _) => Err(LispError::wrong_type(symbols::STRINGP, obj)),
+    }
+}
+
+// (obarray-make &optional SIZE)
+//
+// The size is only a hint, which we ignore.
+def_builtin! { "obarray-make", ObarrayMakeBuiltin, Evaluated, 0, 1, _lsp, args; {
+    match args.next() {
+        None | Some(&LispObj::Atm(symbols::NIL)) => (),
+        Some(size) => { natnum_arg(size)?; },
+    }
+    Ok(LispObj::ext(Rc::new(RefCell::new(Obarray::new()))))
+}}
+
+def_builtin! { "obarrayp", ObarraypBuiltin, Evaluated, 1, 1, lsp, args; {
+    match one_arg(lsp, "obarrayp", args)? {
+        LispObj::Ext(ext) => Ok(LispObj::from_bool(Obarray::is_obarray(&*ext.borrow()))),
+        _ => Ok(LispObj::nil()),
+    }
+}}
+
+def_builtin! { "obarray-clear", ObarrayClearBuiltin, Evaluated, 1, 1, lsp, args; {
+    let obarray = one_arg(lsp, "obarray-clear", args)?;
+    match obarray_arg(Some(obarray))? {
+        Some(ext) => with_obarray(&ext, |ob| ob.clear()),
+        None => return Err(LispError::error("The global obarray can not be cleared")),
+    }
+    Ok(LispObj::nil())
+}}
+
+// (intern NAME &optional OBARRAY)
+def_builtin! { "intern", InternBuiltin, Evaluated, 1, 2, lsp, args; {
+    let (name, obarray) = take2!(args);
+
+    let name = match name.unwrap() {
+        LispObj::Str(s) => s,
+        obj => return Err(LispError::wrong_type(symbols::STRINGP, obj)),
+    };
+    match obarray_arg(obarray)? {
+        Some(ext) => Ok(LispObj::Sym(with_obarray(&ext, |ob| ob.intern(&mut lsp.atoms, name)))),
+        None => Ok(LispObj::Atm(lsp.atomize(name))),
+    }
+}}
+
+// (intern-soft NAME &optional OBARRAY)
+//
+// NAME may also be a symbol, which is returned if it is the symbol interned
+// in the obarray.
+def_builtin! { "intern-soft", InternSoftBuiltin, Evaluated, 1, 2, lsp, args; {
+    let (name, obarray) = take2!(args);
+    let obarray = obarray_arg(obarray)?;
+
+    Ok(lookup_name(lsp, name.unwrap(), &obarray)?.unwrap_or_else(LispObj::nil))
+}}
+
+// (unintern NAME &optional OBARRAY)
+//
+// Only the name is removed from the obarray, the symbol keeps its value,
+// function and properties for anything still referring to it. The next time
+// the name is interned it refers to a new symbol.
+def_builtin! { "unintern", UninternBuiltin, Evaluated, 1, 2, lsp, args; {
+    let (name, obarray) = take2!(args);
+    let obarray = obarray_arg(obarray)?;
+
+    let removed = match (lookup_name(lsp, name.unwrap(), &obarray)?, obarray) {
+        (Some(LispObj::Atm(a)), _) if a == symbols::NIL || a == symbols::T =>
+            return Err(LispError::error("Attempt to unintern t or nil")),
+        (Some(LispObj::Atm(a)), _) => lsp.atoms.unintern(a),
+        (Some(LispObj::Sym(sym)), Some(ext)) =>
+            with_obarray(&ext, |ob| ob.remove(lsp.atoms.stringify(sym.name))).is_some(),
+        _ => false,
+    };
+    Ok(LispObj::from_bool(removed))
+}}
+
+// (mapatoms FUNCTION &optional OBARRAY)
+def_builtin! { "mapatoms", MapatomsBuiltin, Evaluated, 1, 2, lsp, args; {
+    let (fun, obarray) = take2!(args);
+
+    // Collected first so that FUNCTION can intern and unintern symbols
+    let syms: Vec<LispObj> = match obarray_arg(obarray)? {
+        Some(ext) => with_obarray(&ext, |ob| ob.symbols()).into_iter().map(LispObj::Sym).collect(),
+        None => lsp.atoms.interned().into_iter().map(LispObj::Atm).collect(),
+    };
+    for sym in syms {
+        lsp.funcall(fun.unwrap(), &[sym])?;
+    }
+    Ok(LispObj::nil())
+}}
@@ -0,0 +1,485 @@
+// Copyright (C) 2017 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Printing and formatting
+//!
+//! Printed text goes to a printcharfun, as in Emacs. This is either t, for
+//! the interpreter's `Output`, a function which is called with each
+//! character or an external object which can have text inserted into it,
+//! such as a buffer. When a print function is not given one, it uses the
+//! value of `standard-output`.
+
+use std::io::{self, Write as IoWrite};
+
+use super::*;
+
+/// Where `message` and text printed to t end up
+pub trait Output {
+    /// Write text printed with printcharfun t
+    fn write(&mut self, text: &str);
+    /// Display a message from `message`, replacing any previous one
+    fn message(&mut self, text: &str);
+}
+
+/// Print to stdout and write messages to stderr, like Emacs in batch mode
+pub struct StdOutput;
+
+impl Output for StdOutput {
+    fn write(&mut self, text: &str) {
+        print!("{}", text);
+        let _ = io::stdout().flush();
+    }
+
+    fn message(&mut self, text: &str) {
+        eprintln!("{}", text);
+    }
+}
+
+/// Collect the output and messages in a string, for scripts and tests
+pub struct StringOutput {
+    output: Rc<RefCell<String>>,
+}
+
+impl StringOutput {
+    /// Returns the output and a handle on what is written to it
+    pub fn new() -> (StringOutput, Rc<RefCell<String>>) {
+        let output = Rc::new(RefCell::new(String::new()));
+        (StringOutput { output: Rc::clone(&output) }, output)
+    }
+}
+
+impl Output for StringOutput {
+    fn write(&mut self, text: &str) {
+        self.output.borrow_mut().push_str(text);
+    }
+
+    fn message(&mut self, text: &str) {
+        let mut output = self.output.borrow_mut();
+        output.push_str(text);
+        output.push('\n');
+    }
+}
+
+/// Conses, vectors and hash tables can only be printed nested this deeply,
+/// the same as Emacs
+pub const PRINT_MAX_DEPTH: usize = 200;
+
+/// The state of printing an object, see Lsp::print_with
+///
+/// The conses, vectors and hash tables being printed are kept so that an
+/// object which contains itself is printed as a reference back to where it
+/// started, rather than looping forever.
+pub struct Printer<'a, O: Write + 'a> {
+    stream: &'a mut O,
+    /// Print so that the text can be read back, as prin1 does
+    pub escape: bool,
+    /// Count each object printed as a step and the text as allocated, see
+    /// Lsp::print_checked
+    pub checked: bool,
+    /// The objects which the one being printed is inside, outermost first
+    being_printed: Vec<LispObj>,
+    /// Bytes of text written so far
+    written: usize,
+}
+
+impl<'a, O: Write> Printer<'a, O> {
+    pub fn new(stream: &'a mut O, escape: bool) -> Printer<'a, O> {
+        Printer {
+            stream,
+            escape,
+            checked: false,
+            being_printed: Vec::new(),
+            written: 0,
+        }
+    }
+
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// How many levels out obj is already being printed, if it is
+    pub fn level_of(&self, obj: &LispObj) -> Option<usize> {
+        self.being_printed.iter().position( |outer| eq(outer, obj) )
+    }
+
+    /// Start printing obj inside the objects already being printed
+    pub fn enter(&mut self, obj: &LispObj) -> Result<(), LispError> {
+        if self.being_printed.len() >= PRINT_MAX_DEPTH {
+            return Err(LispError::error("Apparently circular structure being printed"));
+        }
+        self.being_printed.push(obj.clone());
+        Ok(())
+    }
+
+    pub fn leave(&mut self) {
+        self.being_printed.pop();
+    }
+}
+
+impl<'a, O: Write> Write for Printer<'a, O> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.written += s.len();
+        self.stream.write_str(s)
+    }
+}
+
+impl Lsp {
+    pub fn set_output(&mut self, output: Box<dyn Output>) {
+        self.output = output;
+    }
+
+    /// Send text to a printcharfun, or standard-output if it is missing or nil
+    pub fn print_to(&mut self, printcharfun: Option<&LispObj>, text: &str)
+                    -> Result<(), LispError> {
+        let dest = match printcharfun {
+            None | Some(&LispObj::Atm(symbols::NIL)) => self.eval_atm_val(symbols::STANDARD_OUTPUT)?,
+            Some(dest) => dest.clone(),
+        };
+
+        match dest {
+            LispObj::Atm(symbols::NIL) | LispObj::Atm(symbols::T) => {
+                self.output.write(text);
+                Ok(())
+            },
+            LispObj::Ext(ext) => ext.borrow_mut().insert_text(text),
+            fun => {
+                for c in text.chars() {
+                    self.funcall(&fun, &[LispObj::Int(c as i64)])?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// The flags, width and precision of a format specification
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alt: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+fn mismatch() -> LispError {
+    LispError::error("Format specifier doesn’t match argument type")
+}
+
+/// Read a decimal number from the format string, if there is one
+fn spec_number(fmt: &[char], i: &mut usize) -> Option<usize> {
+    let mut n = None;
+    while let Some(d) = fmt.get(*i).and_then( |c| c.to_digit(10) ) {
+        n = Some(n.unwrap_or(0) * 10 + d as usize);
+        *i += 1;
+    }
+    n
+}
+
+/// Put the sign, a prefix such as 0x and the padding around digits
+fn pad_number(spec: &Spec, negative: bool, prefix: &str, digits: &str, zero_ok: bool) -> String {
+    let sign = if negative {
+        "-"
+    } else if spec.plus {
+        "+"
+    } else if spec.space {
+        " "
+    } else {
+        ""
+    };
+
+    let len = sign.len() + prefix.len() + digits.chars().count();
+    let fill = spec.width.saturating_sub(len);
+    if spec.zero && zero_ok && !spec.left {
+        format!("{}{}{}{}", sign, prefix, "0".repeat(fill), digits)
+    } else {
+        pad(spec, &format!("{}{}{}", sign, prefix, digits))
+    }
+}
+
+/// Pad text with spaces to the field width
+fn pad(spec: &Spec, text: &str) -> String {
+    let fill = " ".repeat(spec.width.saturating_sub(text.chars().count()));
+    if spec.left {
+        format!("{}{}", text, fill)
+    } else {
+        format!("{}{}", fill, text)
+    }
+}
+
+fn format_integer(spec: &Spec, conv: char, arg: &LispObj) -> Result<String, LispError> {
+    let i = match arg {
+        &LispObj::Int(i) => BigInt::from_i64(i),
+        LispObj::Big(i) => (**i).clone(),
+        &LispObj::Float(f) => BigInt::from_f64(f.trunc()).ok_or_else(mismatch)?,
+        _ => return Err(mismatch()),
+    };
+    let radix = match conv {
+        'o' => 8,
+        'x' | 'X' => 16,
+        _ => 10,
+    };
+
+    let mut digits = i.abs().to_string_radix(radix);
+    if conv == 'X' {
+        digits = digits.to_uppercase();
+    }
+    if let Some(precision) = spec.precision {
+        if digits.len() < precision {
+            digits = "0".repeat(precision - digits.len()) + &digits;
+        }
+    }
+    let prefix = match conv {
+        'o' if spec.alt && !i.is_zero() => "0",
+        'x' if spec.alt && !i.is_zero() => "0x",
+        'X' if spec.alt && !i.is_zero() => "0X",
+        _ => "",
+    };
+
+    Ok(pad_number(spec, i.is_negative(), prefix, &digits, spec.precision.is_none()))
+}
+
+/// Exponential notation with a sign and at least two exponent digits, as C
+fn exponential(f: f64, precision: usize) -> String {
+    let sci = format!("{:.*e}", precision, f);
+    let (mant, exp) = sci.split_at(sci.find('e').unwrap());
+    let exp: i32 = exp[1..].parse().unwrap();
+    format!("{}e{}{:02}", mant, if exp < 0 { '-' } else { '+' }, exp.abs())
+}
+
+/// Remove trailing zeros after the decimal point, for %g
+fn strip_zeros(s: &str) -> String {
+    let (mant, exp) = s.split_at(s.find('e').unwrap_or(s.len()));
+    if !mant.contains('.') {
+        return s.to_owned();
+    }
+    format!("{}{}", mant.trim_end_matches('0').trim_end_matches('.'), exp)
+}
+
+fn format_float(spec: &Spec, conv: char, arg: &LispObj) -> Result<String, LispError> {
+    let f = match arg {
+        &LispObj::Int(i) => i as f64,
+        LispObj::Big(i) => i.to_f64(),
+        &LispObj::Float(f) => f,
+        _ => return Err(mismatch()),
+    };
+    let negative = f.is_sign_negative() && !f.is_nan();
+    let a = f.abs();
+    if !f.is_finite() {
+        return Ok(pad_number(spec, negative, "", if f.is_nan() { "nan" } else { "inf" }, false));
+    }
+
+    let precision = spec.precision.unwrap_or(6);
+    let digits = match conv {
+        'f' => {
+            let fixed = format!("{:.*}", precision, a);
+            if spec.alt && precision == 0 { fixed + "." } else { fixed }
+        },
+        'e' => exponential(a, precision),
+        _ => {
+            let precision = cmp::max(precision, 1);
+            let exp = if a == 0.0 {
+                0
+            } else {
+                let sci = exponential(a, precision - 1);
+                sci[sci.find('e').unwrap() + 1..].parse::<i32>().unwrap()
+            };
+
+            let g = if exp < -4 || exp >= precision as i32 {
+                exponential(a, precision - 1)
+            } else {
+                format!("{:.*}", (precision as i32 - 1 - exp) as usize, a)
+            };
+            if spec.alt { g } else { strip_zeros(&g) }
+        },
+    };
+
+    Ok(pad_number(spec, negative, "", &digits, true))
+}
+
+/// Substitute the arguments into a format string, as `format` does
+///
+/// A specification is `%[field$][flags][width][.precision]conversion`, where
+/// the flags are `-+ #0` and the conversions are `s S d o x X c f e g` and
+/// `%`.
+pub fn format(lsp: &Lsp, fmt: &str, args: &[LispObj]) -> Result<String, LispError> {
+    let fmt: Vec<char> = fmt.chars().collect();
+    let mut out = String::new();
+    let mut next_arg = 0;
+    let mut i = 0;
+
+    while i < fmt.len() {
+        let c = fmt[i];
+        i += 1;
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let start = i;
+        match spec_number(&fmt, &mut i) {
+            Some(field) if fmt.get(i) == Some(&'$') => {
+                next_arg = field.saturating_sub(1);
+                i += 1;
+            },
+            _ => i = start,
+        }
+
+        let mut spec = Spec::default();
+        while let Some(&flag) = fmt.get(i) {
+            match flag {
+                '-' => spec.left = true,
+                '+' => spec.plus = true,
+                ' ' => spec.space = true,
+                '#' => spec.alt = true,
+                '0' => spec.zero = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        spec.width = spec_number(&fmt, &mut i).unwrap_or(0);
+        if fmt.get(i) == Some(&'.') {
+            i += 1;
+            spec.precision = Some(spec_number(&fmt, &mut i).unwrap_or(0));
+        }
+        // Both can pad the text out to any length
+        lsp.check_alloc(cmp::max(spec.width, spec.precision.unwrap_or(0)))?;
+
+        let conv = match fmt.get(i) {
+            Some(&conv) => conv,
+            None => return Err(LispError::error("Format string ends in middle of format specifier")),
+        };
+        i += 1;
+        if conv == '%' {
+            out.push('%');
+            continue;
+        }
+
+        let arg = match args.get(next_arg) {
+            Some(arg) => arg,
+            None => return Err(LispError::error("Not enough arguments for format string")),
+        };
+        next_arg += 1;
+
+        let text = match conv {
+            's' | 'S' => {
+                let mut text = String::new();
+                lsp.print_checked(&mut text, arg, conv == 'S')?;
+                if let Some(precision) = spec.precision {
+                    text = text.chars().take(precision).collect();
+                }
+                pad(&spec, &text)
+            },
+            'd' | 'o' | 'x' | 'X' => format_integer(&spec, conv, arg)?,
+            'c' => pad(&spec, &char_arg(arg).map_err( |_| mismatch() )?.to_string()),
+            'f' | 'e' | 'g' => format_float(&spec, conv, arg)?,
+            conv => return Err(LispError::error(format!("Invalid format operation %{}", conv))),
+        };
+        out.push_str(&text);
+    }
+
+    gc::count_string(out.len());
+    Ok(out)
+}
+
+/// Curve the grave accents and apostrophes in a format string, as
+/// `format-message` does
+pub fn curve_quotes(fmt: &str) -> String {
+    fmt.chars()
+        .map( |c| match c {
+            '`' => '‘',
+            '\'' => '’',
+            c => c,
+        })
+        .collect()
+}
+
+/// Apply a format string and arguments taken from args
+fn format_args(lsp: &mut Lsp, name: &str, args: &mut Iter<LispObj>, message: bool)
+               -> Result<String, LispError> {
+    let fmt = match args.next() {
+        Some(LispObj::Str(fmt)) if message => curve_quotes(fmt),
+        Some(LispObj::Str(fmt)) => fmt.to_string(),
+        Some(obj) => return Err(LispError::wrong_type(symbols::STRINGP, obj)),
+        None => return Err(wrong_args(lsp, name, 0)),
+    };
+    format(lsp, &fmt, args.as_slice())
+}
+
+def_builtin! { "format", FormatBuiltin, Evaluated, 1, many, lsp, args; {
+    Ok(LispObj::string(format_args(lsp, "format", args, false)?))
+}}
+
+def_builtin! { "format-message", FormatMessageBuiltin, Evaluated, 1, many, lsp, args; {
+    Ok(LispObj::string(format_args(lsp, "format-message", args, true)?))
+}}
+
+def_builtin! { "message", MessageBuiltin, Evaluated, 1, many, lsp, args; {
+    if args.as_slice().first().is_some_and(LispObj::is_nil) {
+        lsp.output.message("");
+        return Ok(LispObj::nil());
+    }
+
+    let text = format_args(lsp, "message", args, true)?;
+    lsp.output.message(&text);
+    Ok(LispObj::string(text))
+}}
+
+/// Print an object to the printcharfun in args, returning the object
+fn print_builtin(lsp: &mut Lsp, args: &mut Iter<LispObj>, escape: bool, newlines: bool)
+                 -> Result<LispObj, LispError> {
+    let (obj, printcharfun) = take2!(args);
+    let obj = obj.unwrap();
+
+    let mut text = String::new();
+    if newlines {
+        text.push('\n');
+    }
+    lsp.print_checked(&mut text, obj, escape)?;
+    if newlines {
+        text.push('\n');
+    }
+
+    lsp.print_to(printcharfun, &text)?;
+    Ok(obj.clone())
+}
+
+def_builtin! { "prin1", Prin1Builtin, Evaluated, 1, 3, lsp, args; {
+    print_builtin(lsp, args, true, false)
+}}
+
+def_builtin! { "princ", PrincBuiltin, Evaluated, 1, 2, lsp, args; {
+    print_builtin(lsp, args, false, false)
+}}
+
+def_builtin! { "print", PrintBuiltin, Evaluated, 1, 2, lsp, args; {
+    print_builtin(lsp, args, true, true)
+}}
+
+def_builtin! { "terpri", TerpriBuiltin, Evaluated, 0, 2, lsp, args; {
+    lsp.print_to(args.next(), "\n")?;
+    Ok(LispObj::t())
+}}
+
+def_builtin! { "prin1-to-string", Prin1ToStringBuiltin, Evaluated, 1, 3, lsp, args; {
+    let (obj, noescape) = take2!(args);
+    let escape = noescape.is_none_or(LispObj::is_nil);
+
+    let mut text = String::new();
+    lsp.print_checked(&mut text, obj.unwrap(), escape)?;
+    Ok(LispObj::string(text))
+}}
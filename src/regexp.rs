@@ -0,0 +1,606 @@
+// Copyright (C) 2017 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A subset of Emacs regular expressions
+//!
+//! This is enough for the string functions which take a regexp, such as
+//! `split-string` and `string-trim`. It understands `.`, bracket expressions
+//! (including classes like `[:space:]`), the postfix operators `*`, `+`, `?`
+//! and `\{m,n\}` and their non-greedy forms, the anchors `^`, `$`, `` \` ``
+//! and `\'`, word boundaries, `\w`, `\s-` and their negations, and grouping
+//! with `\(...\)`, `\(?:...\)` and `\|`. Groups do not record match data and
+//! back references are not supported.
+//!
+//! Matching is done by backtracking over a slice of characters, so positions
+//! are character indices rather than byte offsets. When case is folded, as
+//! `case-fold-search` asks for, characters match regardless of their case.
+//! Backtracking can take exponential time, so the matcher calls back to the
+//! interpreter at each step to let it stop a match which runs for too long.
+
+use std::slice;
+use std::cell::RefCell;
+
+use super::*;
+use strings::{downcase_char, upcase_char};
+
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    /// Any character except newline
+    Any,
+    Set(CharSet),
+    Class(Class, bool),
+    LineStart,
+    LineEnd,
+    TextStart,
+    TextEnd,
+    WordBoundary(bool),
+    Group(Vec<Vec<Node>>),
+    Repeat(Repeat),
+}
+
+/// A node repeated between min and max times, greedily or not
+#[derive(Debug, Clone)]
+struct Repeat {
+    node: Box<Node>,
+    min: usize,
+    max: Option<usize>,
+    greedy: bool,
+}
+
+/// A bracket expression such as `[^a-z[:digit:]]`
+#[derive(Debug, Clone)]
+struct CharSet {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+    classes: Vec<Class>,
+}
+
+impl CharSet {
+    fn matches(&self, c: char) -> bool {
+        let found = self.ranges.iter().any( |&(lo, hi)| lo <= c && c <= hi ) ||
+            self.classes.iter().any( |class| class.matches(c) );
+        found != self.negated
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Class {
+    Alpha,
+    Alnum,
+    Digit,
+    XDigit,
+    Space,
+    Blank,
+    Upper,
+    Lower,
+    Punct,
+    Cntrl,
+    Graph,
+    Print,
+    Ascii,
+    NonAscii,
+    Word,
+}
+
+impl Class {
+    fn from_name(name: &str) -> Option<Class> {
+        Some(match name {
+            "alpha" => Class::Alpha,
+            "alnum" => Class::Alnum,
+            "digit" => Class::Digit,
+            "xdigit" => Class::XDigit,
+            "space" => Class::Space,
+            "blank" => Class::Blank,
+            "upper" => Class::Upper,
+            "lower" => Class::Lower,
+            "punct" => Class::Punct,
+            "cntrl" => Class::Cntrl,
+            "graph" => Class::Graph,
+            "print" => Class::Print,
+            "ascii" => Class::Ascii,
+            "nonascii" => Class::NonAscii,
+            "word" => Class::Word,
+            _ => return None,
+        })
+    }
+
+    fn matches(self, c: char) -> bool {
+        match self {
+            Class::Alpha => c.is_alphabetic(),
+            Class::Alnum | Class::Word => c.is_alphanumeric(),
+            Class::Digit => c.is_ascii_digit(),
+            Class::XDigit => c.is_ascii_hexdigit(),
+            Class::Space => c.is_whitespace(),
+            Class::Blank => c == ' ' || c == '\t' || (c.is_whitespace() && !c.is_control()),
+            Class::Upper => c.is_uppercase(),
+            Class::Lower => c.is_lowercase(),
+            Class::Punct => if c.is_ascii() {
+                c.is_ascii_punctuation()
+            } else {
+                !c.is_alphanumeric() && !c.is_whitespace() && !c.is_control()
+            },
+            Class::Cntrl => c.is_control(),
+            Class::Graph => !c.is_whitespace() && !c.is_control(),
+            Class::Print => !c.is_control(),
+            Class::Ascii => c.is_ascii(),
+            Class::NonAscii => !c.is_ascii(),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> LispError {
+    LispError::new(symbols::INVALID_REGEXP, LispObj::list_from(&[LispObj::str(msg)]))
+}
+
+struct Parser {
+    pat: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self, n: usize) -> Option<char> {
+        self.pat.get(self.pos + n).cloned()
+    }
+
+    /// Is the next thing `\c`
+    fn at_escape(&self, c: char) -> bool {
+        self.peek(0) == Some('\\') && self.peek(1) == Some(c)
+    }
+
+    fn alternatives(&mut self, depth: usize) -> Result<Vec<Vec<Node>>, LispError> {
+        let mut alts = vec![self.sequence()?];
+        while self.at_escape('|') {
+            self.pos += 2;
+            alts.push(self.sequence()?);
+        }
+
+        if self.at_escape(')') {
+            if depth == 0 {
+                return Err(invalid("Unmatched ) or \\)"));
+            }
+            self.pos += 2;
+        } else if depth > 0 {
+            return Err(invalid("Unmatched ( or \\("));
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, LispError> {
+        let mut seq = Vec::new();
+
+        while let Some(c) = self.peek(0) {
+            if self.at_escape('|') || self.at_escape(')') {
+                break;
+            }
+            self.pos += 1;
+
+            let node = match c {
+                '^' if seq.is_empty() => Node::LineStart,
+                '$' if self.peek(0).is_none() || self.at_escape('|') || self.at_escape(')') =>
+                    Node::LineEnd,
+                // Postfix operators are literal when there is nothing to repeat
+                '*' | '+' | '?' if seq.is_empty() => Node::Char(c),
+                '*' | '+' | '?' => {
+                    let (min, max) = match c {
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        _ => (0, Some(1)),
+                    };
+                    let node = self.repeat(&mut seq, min, max)?;
+                    seq.push(node);
+                    continue;
+                },
+                '.' => Node::Any,
+                '[' => Node::Set(self.set()?),
+                '\\' if self.peek(0) == Some('{') => {
+                    self.pos += 1;
+                    let (min, max) = self.interval()?;
+                    let node = self.repeat(&mut seq, min, max)?;
+                    seq.push(node);
+                    continue;
+                },
+                '\\' => self.escape()?,
+                c => Node::Char(c),
+            };
+            seq.push(node);
+        }
+
+        Ok(seq)
+    }
+
+    /// Wrap the last node in a repetition
+    fn repeat(&mut self, seq: &mut Vec<Node>, min: usize, max: Option<usize>)
+              -> Result<Node, LispError> {
+        let greedy = if self.peek(0) == Some('?') {
+            self.pos += 1;
+            false
+        } else {
+            true
+        };
+
+        match seq.pop() {
+            Some(node) => Ok(Node::Repeat(Repeat {
+                node: Box::new(node),
+                min,
+                max,
+                greedy,
+            })),
+            None => Err(invalid("Invalid preceding regular expression")),
+        }
+    }
+
+    /// Parse what follows a backslash
+    fn escape(&mut self) -> Result<Node, LispError> {
+        let c = match self.peek(0) {
+            Some(c) => c,
+            None => return Err(invalid("Trailing backslash")),
+        };
+        self.pos += 1;
+
+        Ok(match c {
+            '(' => {
+                if self.peek(0) == Some('?') && self.peek(1) == Some(':') {
+                    self.pos += 2;
+                }
+                Node::Group(self.alternatives(1)?)
+            },
+            'w' => Node::Class(Class::Word, false),
+            'W' => Node::Class(Class::Word, true),
+            's' | 'S' => {
+                let class = match self.peek(0) {
+                    Some('-') | Some(' ') => Class::Space,
+                    Some('w') => Class::Word,
+                    Some('.') => Class::Punct,
+                    _ => return Err(invalid("Invalid syntax designator")),
+                };
+                self.pos += 1;
+                Node::Class(class, c == 'S')
+            },
+            '`' => Node::TextStart,
+            '\'' => Node::TextEnd,
+            'b' => Node::WordBoundary(true),
+            'B' => Node::WordBoundary(false),
+            '1'..='9' => return Err(invalid("Back references are not supported")),
+            c => Node::Char(c),
+        })
+    }
+
+    /// Parse the body of `\{m,n\}`, `\{m\}` or `\{,n\}`
+    fn interval(&mut self) -> Result<(usize, Option<usize>), LispError> {
+        let min = self.number().unwrap_or(0);
+        let max = if self.peek(0) == Some(',') {
+            self.pos += 1;
+            self.number()
+        } else {
+            Some(min)
+        };
+
+        if !self.at_escape('}') || max.is_some_and(|max| max < min) {
+            return Err(invalid("Invalid content of \\{\\}"));
+        }
+        self.pos += 2;
+        Ok((min, max))
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let mut n = None;
+        while let Some(d) = self.peek(0).and_then( |c| c.to_digit(10) ) {
+            n = Some(n.unwrap_or(0) * 10 + d as usize);
+            self.pos += 1;
+        }
+        n
+    }
+
+    /// Parse a bracket expression, after the opening `[`
+    fn set(&mut self) -> Result<CharSet, LispError> {
+        let mut set = CharSet {
+            negated: false,
+            ranges: Vec::new(),
+            classes: Vec::new(),
+        };
+        if self.peek(0) == Some('^') {
+            set.negated = true;
+            self.pos += 1;
+        }
+
+        let mut first = true;
+        loop {
+            let c = match self.peek(0) {
+                Some(c) => c,
+                None => return Err(invalid("Unmatched [ or [^")),
+            };
+            self.pos += 1;
+
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+
+            if c == '[' && self.peek(0) == Some(':') {
+                let rest: String = self.pat[self.pos + 1..].iter().collect();
+                if let Some(end) = rest.find(":]") {
+                    let class = Class::from_name(&rest[..end])
+                        .ok_or_else( || invalid("Invalid character class name") )?;
+                    set.classes.push(class);
+                    self.pos += 1 + rest[..end].chars().count() + 2;
+                    continue;
+                }
+            }
+
+            if self.peek(0) == Some('-') && self.peek(1).is_some_and(|e| e != ']') {
+                let end = self.peek(1).unwrap();
+                self.pos += 2;
+                set.ranges.push((c, end));
+            } else {
+                set.ranges.push((c, c));
+            }
+        }
+
+        Ok(set)
+    }
+}
+
+fn is_word(c: Option<&char>) -> bool {
+    c.is_some_and(|c| c.is_alphanumeric())
+}
+
+/// Called at each step of matching, an error stops the match
+pub type Poll<'a> = &'a mut dyn FnMut() -> Result<(), LispError>;
+
+/// Backtracking matcher over some text
+///
+/// Each node is matched at a position and then the continuation is tried with
+/// where the match ended; if that fails the node tries its next alternative.
+/// Once poll gives an error every alternative fails, so the match unwinds.
+struct Matcher<'a, 'p> {
+    text: &'a [char],
+    fold: bool,
+    poll: RefCell<Poll<'p>>,
+    error: RefCell<Option<LispError>>,
+}
+
+impl<'a, 'p> Matcher<'a, 'p> {
+    /// Poll before taking a step, false if matching must stop
+    fn step(&self) -> bool {
+        if self.error.borrow().is_some() {
+            return false;
+        }
+        match (*self.poll.borrow_mut())() {
+            Ok(()) => true,
+            Err(err) => {
+                *self.error.borrow_mut() = Some(err);
+                false
+            },
+        }
+    }
+
+    fn same_char(&self, a: char, b: char) -> bool {
+        a == b || (self.fold && downcase_char(a) == downcase_char(b))
+    }
+
+    fn in_set(&self, set: &CharSet, c: char) -> bool {
+        set.matches(c) ||
+            (self.fold && (set.matches(downcase_char(c)) || set.matches(upcase_char(c))))
+    }
+
+    /// Match a node which is not a group or repetition, giving where it ends
+    fn single(&self, node: &Node, i: usize) -> Option<usize> {
+        let text = self.text;
+        let cur = text.get(i);
+        let matched = match node {
+            &Node::Char(c) =>
+                return cur.and_then( |&t| if self.same_char(t, c) { Some(i + 1) } else { None } ),
+            &Node::Any => return cur.and_then( |&c| if c != '\n' { Some(i + 1) } else { None } ),
+            Node::Set(set) =>
+                return cur.and_then( |&c| if self.in_set(set, c) { Some(i + 1) } else { None } ),
+            &Node::Class(class, negated) =>
+                return cur.and_then( |&c| if class.matches(c) != negated { Some(i + 1) } else { None } ),
+            &Node::LineStart => i == 0 || text[i - 1] == '\n',
+            &Node::LineEnd => i == text.len() || text[i] == '\n',
+            &Node::TextStart => i == 0,
+            &Node::TextEnd => i == text.len(),
+            &Node::WordBoundary(at) => {
+                let before = if i > 0 { text.get(i - 1) } else { None };
+                (is_word(before) != is_word(cur)) == at
+            },
+            &Node::Group(_) | &Node::Repeat(..) => unreachable!(),
+        };
+
+        if matched { Some(i) } else { None }
+    }
+
+    fn seq(&self, nodes: &[Node], i: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+        if !self.step() {
+            return false;
+        }
+        let (node, rest) = match nodes.split_first() {
+            Some(split) => split,
+            None => return k(i),
+        };
+
+        match node {
+            Node::Group(alts) =>
+                alts.iter().any( |alt| self.seq(alt, i, &mut |j| self.seq(rest, j, k)) ),
+            Node::Repeat(rep) => match *rep.node {
+                Node::Char(_) | Node::Any | Node::Set(_) | Node::Class(..) =>
+                    self.repeat_single(rep, i, rest, k),
+                _ => self.repeat(rep, 0, i, rest, k),
+            },
+            node => match self.single(node, i) {
+                Some(j) => self.seq(rest, j, k),
+                None => false,
+            },
+        }
+    }
+
+    /// Repeat a node which always matches one character
+    ///
+    /// This is the common case, it is done with a loop instead of recursion
+    /// so that long runs don't use much stack.
+    fn repeat_single(&self, rep: &Repeat, i: usize, rest: &[Node],
+                     k: &mut dyn FnMut(usize) -> bool) -> bool {
+        let Repeat { ref node, min, max, greedy } = *rep;
+        let mut end = i;
+        while max.is_none_or(|max| end - i < max) {
+            match self.single(node, end) {
+                Some(j) => end = j,
+                None => break,
+            }
+        }
+
+        if end - i < min {
+            return false;
+        }
+        if greedy {
+            (i + min..end + 1).rev().any( |j| self.seq(rest, j, k) )
+        } else {
+            (i + min..end + 1).any( |j| self.seq(rest, j, k) )
+        }
+    }
+
+    /// Repeat any node, having matched it n times already
+    fn repeat(&self, rep: &Repeat, n: usize, i: usize, rest: &[Node],
+              k: &mut dyn FnMut(usize) -> bool) -> bool {
+        let can_stop = n >= rep.min;
+        let can_continue = rep.max.is_none_or(|max| n < max);
+        let more = |k: &mut dyn FnMut(usize) -> bool| {
+            // Stop matching empty strings once the minimum is reached
+            can_continue && self.seq(slice::from_ref(&*rep.node), i, &mut |j| {
+                (j != i || n < rep.min) && self.repeat(rep, n + 1, j, rest, k)
+            })
+        };
+        let stop = |k: &mut dyn FnMut(usize) -> bool| can_stop && self.seq(rest, i, k);
+
+        // A greedy repetition tries another match before the rest
+        (rep.greedy && more(k)) || stop(k) || (!rep.greedy && more(k))
+    }
+}
+
+/// A compiled regular expression
+#[derive(Debug, Clone)]
+pub struct Regexp {
+    nodes: Vec<Node>,
+    fold: bool,
+}
+
+impl Regexp {
+    pub fn new(pattern: &str) -> Result<Regexp, LispError> {
+        Regexp::with_case_fold(pattern, false)
+    }
+
+    /// Compile a regexp which ignores case if fold is true
+    pub fn with_case_fold(pattern: &str, fold: bool) -> Result<Regexp, LispError> {
+        let mut parser = Parser {
+            pat: pattern.chars().collect(),
+            pos: 0,
+        };
+
+        Ok(Regexp {
+            nodes: vec![Node::Group(parser.alternatives(0)?)],
+            fold,
+        })
+    }
+
+    /// Match starting exactly at start, giving the end of the match
+    pub fn match_at(&self, text: &[char], start: usize, poll: Poll)
+                    -> Result<Option<usize>, LispError> {
+        let matcher = Matcher {
+            text,
+            fold: self.fold,
+            poll: RefCell::new(poll),
+            error: RefCell::new(None),
+        };
+        let mut end = None;
+        matcher.seq(&self.nodes, start, &mut |j| {
+            end = Some(j);
+            true
+        });
+        match matcher.error.into_inner() {
+            Some(err) => Err(err),
+            None => Ok(end),
+        }
+    }
+
+    /// Find the first match at or after start, giving its start and end
+    pub fn search(&self, text: &[char], start: usize, poll: Poll)
+                  -> Result<Option<(usize, usize)>, LispError> {
+        for i in start..text.len() + 1 {
+            if let Some(end) = self.match_at(text, i, poll)? {
+                return Ok(Some((i, end)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(pattern: &str, text: &str) -> Option<(usize, usize)> {
+        let text: Vec<char> = text.chars().collect();
+        Regexp::new(pattern).unwrap().search(&text, 0, &mut || Ok(())).unwrap()
+    }
+
+    #[test]
+    fn matching() {
+        assert_eq!(search("b+", "abbbc"), Some((1, 4)));
+        assert_eq!(search("b+?", "abbbc"), Some((1, 2)));
+        assert_eq!(search("a.*c", "xabcabc"), Some((1, 7)));
+        assert_eq!(search("[^a-c]", "abcd"), Some((3, 4)));
+        assert_eq!(search("[[:space:]]+", "é  x"), Some((1, 3)));
+        assert_eq!(search("\\(ab\\|c\\)\\{2\\}", "abxcab"), Some((3, 6)));
+        assert_eq!(search("^b", "ab\nb"), Some((3, 4)));
+        assert_eq!(search("b\\'", "ab\nb"), Some((3, 4)));
+        assert_eq!(search("\\bfoo\\b", "foobar foo"), Some((7, 10)));
+        assert_eq!(search("\\(?:a*\\)*b", "aab"), Some((0, 3)));
+        assert_eq!(search("*a", "x*a"), Some((1, 3)));
+        assert_eq!(search("x", "abc"), None);
+        assert_eq!(search("", "abc"), Some((0, 0)));
+    }
+
+    #[test]
+    fn case_fold() {
+        let text: Vec<char> = "xAbC".chars().collect();
+        for &(pattern, fold, expected) in [("abc", false, None), ("abc", true, Some((1, 4))),
+                                           ("[a-b]+", true, Some((1, 3))),
+                                           ("[[:upper:]]+", true, Some((0, 4)))].iter() {
+            let re = Regexp::with_case_fold(pattern, fold).unwrap();
+            assert_eq!((pattern, re.search(&text, 0, &mut || Ok(())).unwrap()), (pattern, expected));
+        }
+    }
+
+    #[test]
+    fn poll() {
+        let text: Vec<char> = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaab".chars().collect();
+        let re = Regexp::new("\\(a*\\)*c").unwrap();
+        let mut steps = 0;
+        let err = re.search(&text, 0, &mut || {
+            steps += 1;
+            if steps > 1000 { Err(LispError::error("Too many steps")) } else { Ok(()) }
+        }).unwrap_err();
+        assert_eq!(err.symbol, symbols::ERROR);
+        assert_eq!(steps, 1001);
+    }
+
+    #[test]
+    fn invalid() {
+        for pattern in ["\\(a", "a\\)", "[a", "a\\", "a\\{2,1\\}", "[[:foo:]]"].iter() {
+            let err = Regexp::new(pattern).unwrap_err();
+            assert_eq!(err.symbol, symbols::INVALID_REGEXP, "{}", pattern);
+        }
+    }
+}
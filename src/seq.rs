@@ -0,0 +1,605 @@
+// Copyright (C) 2017 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Lists, vectors and sequences
+//!
+//! A sequence is a list, a vector or a string. Vectors are shared and can be
+//! changed in place with aset, but strings are stored by value so the
+//! functions which would modify a string return a new one instead.
+
+use std::mem::size_of;
+use super::*;
+
+/// The elements of a sequence, the characters if it is a string
+pub fn seq_items(lsp: &Lsp, seq: &LispObj) -> Result<Vec<LispObj>, LispError> {
+    match seq {
+        &LispObj::Atm(symbols::NIL) => Ok(Vec::new()),
+        &LispObj::Cons(_) => list_items(lsp, seq),
+        LispObj::Vector(v) => Ok(v.borrow().to_vec()),
+        LispObj::Str(s) => Ok(s.chars().map( |c| LispObj::Int(c as i64) ).collect()),
+        obj => Err(LispError::wrong_type(symbols::SEQUENCEP, obj)),
+    }
+}
+
+/// The elements of a list, counting a step for each, see Lsp::check_limits
+pub fn list_items(lsp: &Lsp, list: &LispObj) -> Result<Vec<LispObj>, LispError> {
+    let mut items = Vec::new();
+    let mut itr = list.iter();
+
+    for item in itr.by_ref() {
+        lsp.check_limits()?;
+        items.push(item);
+    }
+
+    itr.finish(list)?;
+    Ok(items)
+}
+
+/// A sequence of the same type as seq, containing items
+fn seq_like(seq: &LispObj, items: &[LispObj]) -> Result<LispObj, LispError> {
+    match *seq {
+        LispObj::Vector(_) => Ok(LispObj::vec_from(items)),
+        LispObj::Str(_) => Ok(LispObj::string(items.iter().map(char_arg).collect::<Result<_, _>>()?)),
+        _ => Ok(LispObj::list_from(items)),
+    }
+}
+
+fn int_arg(obj: &LispObj) -> Result<i64, LispError> {
+    match obj {
+        &LispObj::Int(i) => Ok(i),
+        obj => Err(LispError::wrong_type(symbols::INTEGERP, obj)),
+    }
+}
+
+pub fn natnum_arg(obj: &LispObj) -> Result<usize, LispError> {
+    match obj {
+        &LispObj::Int(i) if i >= 0 => Ok(i as usize),
+        obj => Err(LispError::wrong_type(symbols::NATNUMP, obj)),
+    }
+}
+
+/// The list after skipping n conses, nil if the list is shorter than that
+///
+/// As in Emacs, once a circular list has been followed round its loop the
+/// rest of the count is taken modulo the length of the loop.
+fn nthcdr(lsp: &Lsp, n: i64, list: &LispObj) -> Result<LispObj, LispError> {
+    let mut tail = list.clone();
+    let mut cycle = CycleCheck::new(list);
+    let mut i = 0;
+
+    while i < n {
+        lsp.check_limits()?;
+        tail = match tail {
+            LispObj::Cons(ref cons) => cons.cdr(),
+            ref obj if obj.is_nil() => break,
+            ref obj => return Err(LispError::wrong_type(symbols::LISTP, obj)),
+        };
+        i += 1;
+
+        if cycle.step(&tail) {
+            for _ in 0..((n - i) as u64 % cycle.cycle_len() as u64) {
+                lsp.check_limits()?;
+                tail = tail.cdr()?;
+            }
+            break;
+        }
+    }
+    Ok(tail)
+}
+
+/// The first tail of list whose car satisfies pred, or nil
+fn find_tail<F>(lsp: &Lsp, list: &LispObj, mut pred: F) -> Result<LispObj, LispError>
+    where F: FnMut(&LispObj) -> Result<bool, LispError>
+{
+    let mut tail = list.clone();
+    let mut cycle = CycleCheck::new(list);
+
+    while let LispObj::Cons(cons) = tail {
+        lsp.check_limits()?;
+        if pred(&cons.car())? {
+            return Ok(LispObj::Cons(cons));
+        }
+        tail = cons.cdr();
+        if cycle.step(&tail) {
+            return Err(LispError::circular_list(list));
+        }
+    }
+
+    if tail.is_nil() {
+        Ok(tail)
+    } else {
+        Err(LispError::wrong_type(symbols::LISTP, list))
+    }
+}
+
+/// The first element of an alist which is a cons whose car satisfies pred
+fn find_assoc<F>(lsp: &mut Lsp, alist: &LispObj, mut pred: F) -> Result<LispObj, LispError>
+    where F: FnMut(&mut Lsp, &LispObj) -> Result<bool, LispError>
+{
+    let mut itr = alist.iter();
+
+    for elt in itr.by_ref() {
+        lsp.check_limits()?;
+        if let LispObj::Cons(ref cons) = elt {
+            if pred(lsp, &cons.car())? {
+                return Ok(elt.clone());
+            }
+        }
+    }
+
+    itr.finish(alist)?;
+    Ok(LispObj::nil())
+}
+
+/// A stable merge sort where the comparison is a Lisp function
+fn sort_items(lsp: &mut Lsp, pred: &LispObj, mut items: Vec<LispObj>)
+              -> Result<Vec<LispObj>, LispError> {
+    if items.len() < 2 {
+        return Ok(items);
+    }
+
+    let right = items.split_off(items.len() / 2);
+    let mut left = sort_items(lsp, pred, items)?.into_iter().peekable();
+    let mut right = sort_items(lsp, pred, right)?.into_iter().peekable();
+    let mut sorted = Vec::new();
+
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        let right_first = !lsp.funcall(pred, &[r.clone(), l.clone()])?.is_nil();
+
+        if right_first {
+            sorted.extend(right.next());
+        } else {
+            sorted.extend(left.next());
+        }
+    }
+
+    sorted.extend(left);
+    sorted.extend(right);
+    Ok(sorted)
+}
+
+/// The element of a vector or string at idx
+fn aref(array: &LispObj, idx: &LispObj) -> Result<LispObj, LispError> {
+    let i = int_arg(idx)?;
+    let item = match array {
+        LispObj::Vector(v) if i >= 0 => v.borrow().get(i as usize).cloned(),
+        LispObj::Str(s) if i >= 0 => s.chars().nth(i as usize).map( |c| LispObj::Int(c as i64) ),
+        &LispObj::Vector(_) | &LispObj::Str(_) => None,
+        obj => return Err(LispError::wrong_type(symbols::ARRAYP, obj)),
+    };
+
+    item.ok_or_else( || LispError::args_out_of_range(&[array.clone(), idx.clone()]) )
+}
+
+/// Call fun on each element of seq, collecting the results
+fn map_seq(lsp: &mut Lsp, fun: &LispObj, seq: &LispObj) -> Result<Vec<LispObj>, LispError> {
+    seq_items(lsp, seq)?.into_iter()
+        .map( |item| lsp.funcall(fun, &[item]) )
+        .collect()
+}
+
+/// Bind var to val, lexically if possible, while running f
+fn with_loop_var<F>(lsp: &mut Lsp, var: Atom, val: LispObj, f: F) -> Result<LispObj, LispError>
+    where F: FnOnce(&mut Lsp) -> Result<LispObj, LispError>
+{
+    let mut ns = Namespace::new();
+    let mut env = lsp.lexenv.clone();
+
+    if lsp.binds_lexically(&env, var) {
+        env = LispObj::cons(LispObj::cons(LispObj::Atm(var), val), env);
+    } else {
+        ns.intern(Symbol::with_val(var, val));
+    }
+    lsp.with_scope(ns, env, f)
+}
+
+/// Split the `(VAR FORM [RESULT])` spec of dolist and dotimes
+fn loop_spec(lsp: &mut Lsp, name: &str, args: &mut Iter<LispObj>)
+             -> Result<(Atom, LispObj, Option<LispObj>), LispError> {
+    let spec = match args.next() {
+        Some(spec) => spec.list_to_vec()?,
+        None => return Err(wrong_args(lsp, name, 0)),
+    };
+
+    let var = match spec.len() {
+        2 | 3 => symbol_name(&spec[0])?,
+        _ => return Err(LispError::wrong_type(symbols::LISTP, &LispObj::list_from(&spec))),
+    };
+    if lsp.is_constant(var) {
+        return Err(LispError::setting_constant(var));
+    }
+    Ok((var, spec[1].clone(), spec.get(2).cloned()))
+}
+
+/// Signal memory-full if a list of len items would go over the limit
+fn check_list_alloc(lsp: &Lsp, len: usize) -> Result<(), LispError> {
+    lsp.check_alloc(len.saturating_mul(size_of::<ConsCell>() + size_of::<LispObj>()))
+}
+
+def_builtin! { "make-vector", MakeVectorBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (len, init) = two_args(lsp, "make-vector", args)?;
+    let len = natnum_arg(len)?;
+    lsp.check_alloc(len.saturating_mul(size_of::<LispObj>()))?;
+    Ok(LispObj::vec_from(&vec![init.clone(); len]))
+}}
+
+def_builtin! { "vector", VectorBuiltin, Evaluated, 0, many, _lsp, args; {
+    Ok(LispObj::vec_from(args.as_slice()))
+}}
+
+def_builtin! { "aref", ArefBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (array, idx) = two_args(lsp, "aref", args)?;
+    aref(array, idx)
+}}
+
+def_builtin! { "aset", AsetBuiltin, Evaluated, 3, 3, _lsp, args; {
+    let (array, idx, val) = take3!(args);
+    let (array, idx, val) = (array.unwrap(), idx.unwrap(), val.unwrap());
+    let i = int_arg(idx)?;
+
+    match array {
+        LispObj::Vector(v) if i >= 0 => match v.borrow_mut().get_mut(i as usize) {
+            Some(item) => *item = val.clone(),
+            _ => return Err(LispError::args_out_of_range(&[array.clone(), idx.clone()])),
+        },
+        &LispObj::Vector(_) => return Err(LispError::args_out_of_range(&[array.clone(), idx.clone()])),
+        &LispObj::Str(_) => return Err(LispError::error("Strings can not be modified in place")),
+        obj => return Err(LispError::wrong_type(symbols::ARRAYP, obj)),
+    }
+    Ok(val.clone())
+}}
+
+def_builtin! { "vconcat", VconcatBuiltin, Evaluated, 0, many, lsp, args; {
+    let mut items = Vec::new();
+    for seq in args {
+        items.extend(seq_items(lsp, seq)?);
+    }
+    Ok(LispObj::vec_from(&items))
+}}
+
+def_builtin! { "elt", EltBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (seq, n) = two_args(lsp, "elt", args)?;
+
+    if seq.is_list() {
+        nthcdr(lsp, int_arg(n)?, seq)?.car()
+    } else {
+        aref(seq, n)
+    }
+}}
+
+def_builtin! { "nth", NthBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (n, list) = two_args(lsp, "nth", args)?;
+    nthcdr(lsp, int_arg(n)?, list)?.car()
+}}
+
+def_builtin! { "nthcdr", NthcdrBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (n, list) = two_args(lsp, "nthcdr", args)?;
+    nthcdr(lsp, int_arg(n)?, list)
+}}
+
+// The last argument is not copied but becomes the tail of the new list
+def_builtin! { "append", AppendBuiltin, Evaluated, 0, many, lsp, args; {
+    let (tail, seqs) = match args.as_slice().split_last() {
+        Some((tail, seqs)) => (tail.clone(), seqs),
+        None => return Ok(LispObj::nil()),
+    };
+
+    let mut items = Vec::new();
+    for seq in seqs {
+        items.extend(seq_items(lsp, seq)?);
+    }
+    check_list_alloc(lsp, items.len())?;
+    Ok(LispObj::list_with_tail(&items, tail))
+}}
+
+def_builtin! { "reverse", ReverseBuiltin, Evaluated, 1, 1, lsp, args; {
+    let seq = one_arg(lsp, "reverse", args)?;
+    let mut items = seq_items(lsp, seq)?;
+    items.reverse();
+    seq_like(seq, &items)
+}}
+
+// Lists are reversed by relinking their cells and vectors in place
+def_builtin! { "nreverse", NreverseBuiltin, Evaluated, 1, 1, lsp, args; {
+    let seq = one_arg(lsp, "nreverse", args)?;
+
+    match seq {
+        &LispObj::Cons(_) => {
+            seq_items(lsp, seq)?;
+            let (mut prev, mut next) = (LispObj::nil(), seq.clone());
+            while let LispObj::Cons(cons) = next {
+                next = cons.cdr();
+                cons.set_cdr(prev);
+                prev = LispObj::Cons(cons);
+            }
+            Ok(prev)
+        },
+        LispObj::Vector(v) => {
+            v.borrow_mut().reverse();
+            Ok(seq.clone())
+        },
+        _ => {
+            let mut items = seq_items(lsp, seq)?;
+            items.reverse();
+            seq_like(seq, &items)
+        },
+    }
+}}
+
+// (sort SEQ PREDICATE)
+//
+// The sort is stable and done in place; a list keeps its cells but they are
+// given new cars.
+def_builtin! { "sort", SortBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (seq, pred) = two_args(lsp, "sort", args)?;
+
+    match seq {
+        &LispObj::Atm(symbols::NIL) => Ok(seq.clone()),
+        LispObj::Cons(cons) => {
+            let sorted = {
+                let items = seq_items(lsp, seq)?;
+                sort_items(lsp, pred, items)?
+            };
+            let mut tail = LispObj::Cons(cons.clone());
+            for item in sorted {
+                if let LispObj::Cons(cell) = tail {
+                    cell.set_car(item);
+                    tail = cell.cdr();
+                }
+            }
+            Ok(seq.clone())
+        },
+        LispObj::Vector(v) => {
+            let items = v.borrow().to_vec();
+            *v.borrow_mut() = VectorCell(sort_items(lsp, pred, items)?);
+            Ok(seq.clone())
+        },
+        obj => Err(LispError::wrong_type(symbols::SEQUENCEP, obj)),
+    }
+}}
+
+def_builtin! { "mapcar", MapcarBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (fun, seq) = two_args(lsp, "mapcar", args)?;
+    Ok(LispObj::list_from(&map_seq(lsp, fun, seq)?))
+}}
+
+def_builtin! { "mapc", MapcBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (fun, seq) = two_args(lsp, "mapc", args)?;
+    map_seq(lsp, fun, seq)?;
+    Ok(seq.clone())
+}}
+
+// (mapconcat FUNCTION SEQUENCE &optional SEPARATOR)
+def_builtin! { "mapconcat", MapconcatBuiltin, Evaluated, 2, 3, lsp, args; {
+    let (fun, seq, sep) = take3!(args);
+    let mut out = String::new();
+
+    for (i, part) in map_seq(lsp, fun.unwrap(), seq.unwrap())?.iter().enumerate() {
+        if i > 0 {
+            if let Some(sep) = sep {
+                push_chars(lsp, &mut out, sep)?;
+            }
+        }
+        push_chars(lsp, &mut out, part)?;
+    }
+    Ok(LispObj::string(out))
+}}
+
+// (dolist (VAR LIST [RESULT]) BODY...)
+//
+// Each element gets a new binding of VAR, so closures made in BODY see the
+// element they were made with. RESULT is evaluated with VAR bound to nil.
+def_builtin! { "dolist", DolistBuiltin, Unevaluated, 1, many, lsp, args; {
+    let (var, list, result) = loop_spec(lsp, "dolist", args)?;
+    let body = args.as_slice();
+    let list = lsp.eval(&list)?;
+    let mut itr = list.iter();
+
+    for item in itr.by_ref() {
+        lsp.check_limits()?;
+        with_loop_var(lsp, var, item, |lsp| lsp.eval_progn(body))?;
+    }
+    itr.finish(&list)?;
+
+    match result {
+        Some(result) => with_loop_var(lsp, var, LispObj::nil(), |lsp| lsp.eval(&result)),
+        None => Ok(LispObj::nil()),
+    }
+}}
+
+// (dotimes (VAR COUNT [RESULT]) BODY...)
+//
+// RESULT is evaluated with VAR bound to COUNT.
+def_builtin! { "dotimes", DotimesBuiltin, Unevaluated, 1, many, lsp, args; {
+    let (var, count, result) = loop_spec(lsp, "dotimes", args)?;
+    let body = args.as_slice();
+    let count = int_arg(&lsp.eval(&count)?)?;
+
+    for i in 0..count {
+        lsp.check_limits()?;
+        with_loop_var(lsp, var, LispObj::Int(i), |lsp| lsp.eval_progn(body))?;
+    }
+
+    match result {
+        Some(result) => with_loop_var(lsp, var, LispObj::Int(count), |lsp| lsp.eval(&result)),
+        None => Ok(LispObj::nil()),
+    }
+}}
+
+def_builtin! { "member", MemberBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (elt, list) = two_args(lsp, "member", args)?;
+    find_tail(lsp, list, |item| equal(lsp, item, elt))
+}}
+
+def_builtin! { "memq", MemqBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (elt, list) = two_args(lsp, "memq", args)?;
+    find_tail(lsp, list, |item| Ok(eq(item, elt)))
+}}
+
+def_builtin! { "assq", AssqBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (key, alist) = two_args(lsp, "assq", args)?;
+    find_assoc(lsp, alist, |_, car| Ok(eq(car, key)))
+}}
+
+// (assoc KEY ALIST &optional TESTFN)
+//
+// TESTFN is called with the car of an element and KEY.
+def_builtin! { "assoc", AssocBuiltin, Evaluated, 2, 3, lsp, args; {
+    let (key, alist, testfn) = take3!(args);
+    let key = key.unwrap();
+
+    match testfn {
+        None | Some(&LispObj::Atm(symbols::NIL)) => find_assoc(lsp, alist.unwrap(), |lsp, car| equal(lsp, car, key)),
+        Some(testfn) => find_assoc(lsp, alist.unwrap(), |lsp, car| {
+            Ok(!lsp.funcall(testfn, &[car.clone(), key.clone()])?.is_nil())
+        }),
+    }
+}}
+
+// Removes the elements by changing the cdrs of the list
+def_builtin! { "delq", DelqBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (elt, list) = two_args(lsp, "delq", args)?;
+    let mut head = list.clone();
+    let mut prev: Option<Cons> = None;
+    let mut tail = list.clone();
+    let mut cycle = CycleCheck::new(list);
+
+    while let LispObj::Cons(cons) = tail {
+        lsp.check_limits()?;
+        tail = cons.cdr();
+        if cycle.step(&tail) {
+            return Err(LispError::circular_list(list));
+        }
+        if eq(&cons.car(), elt) {
+            match prev {
+                Some(ref prev) => prev.set_cdr(tail.clone()),
+                None => head = tail.clone(),
+            }
+        } else {
+            prev = Some(cons);
+        }
+    }
+    Ok(head)
+}}
+
+def_builtin! { "remove", RemoveBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (elt, seq) = two_args(lsp, "remove", args)?;
+    let mut items = Vec::new();
+    for item in seq_items(lsp, seq)? {
+        if !equal(lsp, &item, elt)? {
+            items.push(item);
+        }
+    }
+    seq_like(seq, &items)
+}}
+
+// (last LIST &optional N)
+def_builtin! { "last", LastBuiltin, Evaluated, 1, 2, lsp, args; {
+    let (list, n) = take2!(args);
+    let list = list.unwrap();
+    let n = match n {
+        None | Some(&LispObj::Atm(symbols::NIL)) => 1,
+        Some(n) => int_arg(n)?,
+    };
+
+    let mut cells = Vec::new();
+    let mut tail = list.clone();
+    let mut cycle = CycleCheck::new(list);
+    while let LispObj::Cons(cons) = tail {
+        lsp.check_limits()?;
+        tail = cons.cdr();
+        if cycle.step(&tail) {
+            return Err(LispError::circular_list(list));
+        }
+        cells.push(LispObj::Cons(cons));
+    }
+
+    if n <= 0 {
+        Ok(tail)
+    } else if n as usize >= cells.len() {
+        Ok(list.clone())
+    } else {
+        Ok(cells.swap_remove(cells.len() - n as usize))
+    }
+}}
+
+// (butlast LIST &optional N)
+def_builtin! { "butlast", ButlastBuiltin, Evaluated, 1, 2, lsp, args; {
+    let (list, n) = take2!(args);
+    let list = list.unwrap();
+    let n = match n {
+        None | Some(&LispObj::Atm(symbols::NIL)) => 1,
+        Some(n) => int_arg(n)?,
+    };
+
+    if n <= 0 {
+        return Ok(list.clone());
+    }
+    let items = list_items(lsp, list)?;
+    Ok(LispObj::list_from(&items[..items.len().saturating_sub(n as usize)]))
+}}
+
+// (number-sequence FROM &optional TO SEP)
+def_builtin! { "number-sequence", NumberSequenceBuiltin, Evaluated, 1, 3, lsp, args; {
+    let (from, to, sep) = take3!(args);
+    let from = from.unwrap();
+    let one = LispObj::Int(1);
+    let sep = match sep {
+        None | Some(&LispObj::Atm(symbols::NIL)) => &one,
+        Some(sep) => sep,
+    };
+
+    let to = match to {
+        None | Some(&LispObj::Atm(symbols::NIL)) => return Ok(LispObj::list_from(std::slice::from_ref(from))),
+        Some(to) => to,
+    };
+
+    let mut items = Vec::new();
+    match (from, to, sep) {
+        (&LispObj::Int(_), &LispObj::Int(_), &LispObj::Int(0)) =>
+            return Err(LispError::error("The increment can not be zero")),
+        (&LispObj::Int(from), &LispObj::Int(to), &LispObj::Int(sep)) => {
+            let len = (to as i128 - from as i128) / sep as i128 + 1;
+            check_list_alloc(lsp, if len > 0 { len as usize } else { 0 })?;
+            let mut n = from;
+            while (sep > 0 && n <= to) || (sep < 0 && n >= to) {
+                items.push(LispObj::Int(n));
+                n += sep;
+            }
+        },
+        _ => {
+            let float = |obj: &LispObj| match obj {
+                &LispObj::Int(i) => Ok(i as f64),
+                &LispObj::Float(f) => Ok(f),
+                obj => Err(LispError::wrong_type(symbols::NUMBER_OR_MARKER_P, obj)),
+            };
+            let (from, to, sep) = (float(from)?, float(to)?, float(sep)?);
+            if sep == 0.0 {
+                return Err(LispError::error("The increment can not be zero"));
+            }
+            check_list_alloc(lsp, ((to - from) / sep + 1.0).max(0.0) as usize)?;
+
+            // Multiply rather than add so that rounding errors do not build up
+            let mut i = 0.0;
+            let mut n = from;
+            while (sep > 0.0 && n <= to) || (sep < 0.0 && n >= to) {
+                items.push(LispObj::Float(n));
+                i += 1.0;
+                n = from + i * sep;
+            }
+        },
+    }
+    Ok(LispObj::list_from(&items))
+}}
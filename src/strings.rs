@@ -0,0 +1,512 @@
+// Copyright (C) 2017 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Strings and characters
+//!
+//! As in Emacs, characters are just integers and strings are sequences of
+//! them. Strings are stored as UTF-8, so indices and lengths count
+//! characters, not bytes. Characters above the Unicode range are accepted by
+//! `characterp` but can not be put into a string.
+
+use super::*;
+use regexp::Regexp;
+
+/// The largest character code, Emacs' `(max-char)`
+pub const MAX_CHAR: i64 = 0x3FFFFF;
+
+/// What split-string splits on by default
+const SPLIT_SEPARATORS: &str = "[ \x0c\t\n\r\x0b]+";
+
+/// What string-trim removes by default
+const TRIM_DEFAULT: &str = "[ \t\n\r]+";
+
+pub fn is_character(obj: &LispObj) -> bool {
+    match obj {
+        &LispObj::Int(c) => (0..=MAX_CHAR).contains(&c),
+        _ => false,
+    }
+}
+
+/// A character argument which can be used in a string
+pub fn char_arg(obj: &LispObj) -> Result<char, LispError> {
+    match obj {
+        &LispObj::Int(c) if is_character(obj) => ::std::char::from_u32(c as u32)
+            .ok_or_else( || LispError::error(format!("Character {} is not Unicode", c)) ),
+        obj => Err(LispError::wrong_type(symbols::CHARACTERP, obj)),
+    }
+}
+
+fn string_arg(obj: &LispObj) -> Result<&str, LispError> {
+    match obj {
+        LispObj::Str(s) => Ok(s),
+        obj => Err(LispError::wrong_type(symbols::STRINGP, obj)),
+    }
+}
+
+/// A string, or the name of a symbol, for the comparison functions
+fn string_or_symbol(lsp: &Lsp, obj: &LispObj) -> Result<String, LispError> {
+    match obj {
+        LispObj::Str(s) => Ok(s.to_string()),
+        &LispObj::Atm(_) | &LispObj::Sym(_) => Ok(lsp.stringify(symbol_name(obj)?).to_owned()),
+        obj => Err(LispError::wrong_type(symbols::STRINGP, obj)),
+    }
+}
+
+/// Whether case-fold-search says to ignore case
+fn case_fold(lsp: &Lsp) -> Result<bool, LispError> {
+    Ok(!lsp.eval_atm_val(symbols::CASE_FOLD_SEARCH)?.is_nil())
+}
+
+/// Compile a regexp, ignoring case if case-fold-search is set
+fn regexp(lsp: &Lsp, pattern: &str) -> Result<Regexp, LispError> {
+    Regexp::with_case_fold(pattern, case_fold(lsp)?)
+}
+
+/// An optional regexp argument, or the default when it is missing or nil
+fn regexp_arg(lsp: &Lsp, arg: Option<&LispObj>, default: &str) -> Result<Regexp, LispError> {
+    match arg {
+        None | Some(&LispObj::Atm(symbols::NIL)) => regexp(lsp, default),
+        Some(obj) => regexp(lsp, string_arg(obj)?),
+    }
+}
+
+/// Append the characters of a sequence to out
+pub fn push_chars(lsp: &Lsp, out: &mut String, seq: &LispObj) -> Result<(), LispError> {
+    match seq {
+        LispObj::Str(s) => {
+            gc::count_string(s.len());
+            out.push_str(s);
+        },
+        &LispObj::Atm(symbols::NIL) => (),
+        &LispObj::Cons(_) => for c in list_items(lsp, seq)? {
+            out.push(char_arg(&c)?);
+        },
+        LispObj::Vector(v) => for c in v.borrow().iter() {
+            out.push(char_arg(c)?);
+        },
+        obj => return Err(LispError::wrong_type(symbols::SEQUENCEP, obj)),
+    }
+    Ok(())
+}
+
+/// Resolve the from and to arguments of substring to a range of indices
+///
+/// Either may be negative to count from the end, a missing or nil to means
+/// the end.
+fn index_range(seq: &LispObj, from: Option<&LispObj>, to: Option<&LispObj>, len: usize)
+               -> Result<(usize, usize), LispError> {
+    let index = |arg: Option<&LispObj>, default: usize| match arg {
+        None | Some(&LispObj::Atm(symbols::NIL)) => Ok(default as i64),
+        Some(&LispObj::Int(i)) if i < 0 => Ok(i + len as i64),
+        Some(&LispObj::Int(i)) => Ok(i),
+        Some(obj) => Err(LispError::wrong_type(symbols::INTEGERP, obj)),
+    };
+    let (start, end) = (index(from, 0)?, index(to, len)?);
+
+    if 0 <= start && start <= end && end <= len as i64 {
+        Ok((start as usize, end as usize))
+    } else {
+        let nil = LispObj::nil();
+        Err(LispError::args_out_of_range(&[seq.clone(),
+                                           from.unwrap_or(&nil).clone(),
+                                           to.unwrap_or(&nil).clone()]))
+    }
+}
+
+/// Change the case of a character, if it maps to a single character
+fn map_char<I: Iterator<Item=char>>(c: char, mut mapped: I) -> char {
+    match (mapped.next(), mapped.next()) {
+        (Some(m), None) => m,
+        _ => c,
+    }
+}
+
+pub fn upcase_char(c: char) -> char {
+    map_char(c, c.to_uppercase())
+}
+
+pub fn downcase_char(c: char) -> char {
+    map_char(c, c.to_lowercase())
+}
+
+/// Upcase the first letter of each word, and downcase the rest if rest_lower
+fn capitalize(s: &str, rest_lower: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut in_word = false;
+
+    for c in s.chars() {
+        if !c.is_alphanumeric() {
+            out.push(c);
+        } else if in_word && rest_lower {
+            out.extend(c.to_lowercase());
+        } else if in_word {
+            out.push(c);
+        } else {
+            out.extend(c.to_uppercase());
+        }
+        in_word = c.is_alphanumeric();
+    }
+    out
+}
+
+/// Apply a case conversion to a character or string
+fn change_case<F, G>(obj: &LispObj, char_fn: F, str_fn: G) -> Result<LispObj, LispError>
+    where F: Fn(char) -> char, G: Fn(&str) -> String
+{
+    match obj {
+        LispObj::Str(s) => Ok(LispObj::string(str_fn(s))),
+        &LispObj::Int(c) if is_character(obj) => Ok(LispObj::Int(
+            ::std::char::from_u32(c as u32).map_or(c, |c| char_fn(c) as i64)
+        )),
+        obj => Err(LispError::wrong_type(symbols::CHAR_OR_STRING_P, obj)),
+    }
+}
+
+/// Parse the number at the start of s, as string-to-number does
+///
+/// Leading spaces and tabs are skipped and anything after the number is
+/// ignored. If there is no number then the result is zero.
+fn parse_number(s: &str, base: u32) -> LispObj {
+    let s = s.trim_start_matches( [' ', '\t'] );
+    let bytes = s.as_bytes();
+    let mut end = 0;
+    if end < bytes.len() && (bytes[end] == b'-' || bytes[end] == b'+') {
+        end += 1;
+    }
+
+    let digits = |from: usize| s[from..].chars()
+        .take_while( |c| c.is_digit(base) )
+        .count() + from;
+    let int_end = digits(end);
+
+    if base != 10 {
+        return BigInt::parse(&s[..int_end], base)
+            .map_or(LispObj::Int(0), LispObj::integer);
+    }
+
+    let mut float_end = int_end;
+    let mut is_float = false;
+    if float_end < bytes.len() && bytes[float_end] == b'.' {
+        let frac_end = digits(float_end + 1);
+        if frac_end > float_end + 1 {
+            is_float = true;
+            float_end = frac_end;
+        } else if int_end > end {
+            float_end += 1;
+        }
+    }
+
+    let has_digits = float_end > end + 1 || (float_end > end && bytes[end].is_ascii_digit());
+    if has_digits && float_end < bytes.len() && (bytes[float_end] == b'e' || bytes[float_end] == b'E') {
+        let exp = &s[float_end + 1..];
+        if exp.starts_with("+INF") || exp.starts_with("+NaN") {
+            let mantissa: f64 = s[..float_end].trim_end_matches('.').parse().unwrap_or(0.0);
+            let special = if exp.starts_with("+INF") { f64::INFINITY } else { f64::NAN };
+            return LispObj::Float(if mantissa.is_sign_negative() { -special } else { special });
+        }
+
+        let sign = if exp.starts_with('-') || exp.starts_with('+') { 1 } else { 0 };
+        let exp_end = digits(float_end + 1 + sign);
+        if exp_end > float_end + 1 + sign {
+            is_float = true;
+            float_end = exp_end;
+        }
+    }
+
+    if is_float {
+        LispObj::Float(s[..float_end].parse().unwrap_or(0.0))
+    } else {
+        BigInt::parse(&s[..int_end], 10).map_or(LispObj::Int(0), LispObj::integer)
+    }
+}
+
+def_builtin! { "stringp", StringpBuiltin, Evaluated, 1, 1, lsp, args; {
+    Ok(LispObj::from_bool(one_arg(lsp, "stringp", args)?.is_str()))
+}}
+
+def_builtin! { "characterp", CharacterpBuiltin, Evaluated, 1, 2, _lsp, args; {
+    Ok(LispObj::from_bool(is_character(args.next().unwrap())))
+}}
+
+def_builtin! { "char-equal", CharEqualBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (a, b) = two_args(lsp, "char-equal", args)?;
+    let (a, b) = (char_arg(a)?, char_arg(b)?);
+
+    Ok(LispObj::from_bool(a == b || (case_fold(lsp)? && downcase_char(a) == downcase_char(b))))
+}}
+
+def_builtin! { "length", LengthBuiltin, Evaluated, 1, 1, lsp, args; {
+    let len = match one_arg(lsp, "length", args)? {
+        LispObj::Str(s) => s.chars().count(),
+        LispObj::Vector(v) => v.borrow().len(),
+        &LispObj::Atm(symbols::NIL) => 0,
+        list @ &LispObj::Cons(_) => {
+            let mut len = 0;
+            let mut tail = list.clone();
+            let mut cycle = CycleCheck::new(list);
+            while let LispObj::Cons(cons) = tail {
+                lsp.check_limits()?;
+                len += 1;
+                tail = cons.cdr();
+                if cycle.step(&tail) {
+                    return Err(LispError::circular_list(list));
+                }
+            }
+            if !tail.is_nil() {
+                return Err(LispError::wrong_type(symbols::LISTP, list));
+            }
+            len
+        },
+        obj => return Err(LispError::wrong_type(symbols::SEQUENCEP, obj)),
+    };
+    Ok(LispObj::Int(len as i64))
+}}
+
+def_builtin! { "concat", ConcatBuiltin, Evaluated, 0, many, lsp, args; {
+    let mut out = String::new();
+    for seq in args {
+        push_chars(lsp, &mut out, seq)?;
+    }
+    Ok(LispObj::string(out))
+}}
+
+def_builtin! { "string", StringBuiltin, Evaluated, 0, many, _lsp, args; {
+    Ok(LispObj::string(args.map(char_arg).collect::<Result<String, LispError>>()?))
+}}
+
+def_builtin! { "make-string", MakeStringBuiltin, Evaluated, 2, 3, lsp, args; {
+    let (len, init) = take2!(args);
+    let len = match len.unwrap() {
+        &LispObj::Int(len) if len >= 0 => len as usize,
+        obj => return Err(LispError::wrong_type(symbols::NATNUMP, obj)),
+    };
+    let c = char_arg(init.unwrap())?;
+
+    let size = len.saturating_mul(c.len_utf8());
+    lsp.check_alloc(size)?;
+    gc::count_string(size);
+    Ok(LispObj::string(std::iter::repeat_n(c, len).collect()))
+}}
+
+def_builtin! { "substring", SubstringBuiltin, Evaluated, 1, 3, _lsp, args; {
+    let (seq, from, to) = take3!(args);
+    let seq = seq.unwrap();
+
+    match seq {
+        LispObj::Str(s) => {
+            let (start, end) = index_range(seq, from, to, s.chars().count())?;
+            Ok(LispObj::string(s.chars().skip(start).take(end - start).collect()))
+        },
+        LispObj::Vector(v) => {
+            let v = v.borrow();
+            let (start, end) = index_range(seq, from, to, v.len())?;
+            Ok(LispObj::vec_from(&v[start..end]))
+        },
+        obj => Err(LispError::wrong_type(symbols::STRINGP, obj)),
+    }
+}}
+
+def_builtin! { "string=", StringEqualBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (a, b) = two_args(lsp, "string=", args)?;
+    Ok(LispObj::from_bool(string_or_symbol(lsp, a)? == string_or_symbol(lsp, b)?))
+}}
+
+def_builtin! { "string<", StringLessBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (a, b) = two_args(lsp, "string<", args)?;
+    Ok(LispObj::from_bool(string_or_symbol(lsp, a)? < string_or_symbol(lsp, b)?))
+}}
+
+def_builtin! { "string>", StringGreaterBuiltin, Evaluated, 2, 2, lsp, args; {
+    let (a, b) = two_args(lsp, "string>", args)?;
+    Ok(LispObj::from_bool(string_or_symbol(lsp, a)? > string_or_symbol(lsp, b)?))
+}}
+
+def_builtin! { "string-prefix-p", StringPrefixPBuiltin, Evaluated, 2, 3, _lsp, args; {
+    let (prefix, s, fold) = take3!(args);
+    let (prefix, s) = (string_arg(prefix.unwrap())?, string_arg(s.unwrap())?);
+
+    Ok(LispObj::from_bool(if fold.is_some_and(|f| !f.is_nil()) {
+        s.to_lowercase().starts_with(&prefix.to_lowercase())
+    } else {
+        s.starts_with(prefix)
+    }))
+}}
+
+def_builtin! { "string-suffix-p", StringSuffixPBuiltin, Evaluated, 2, 3, _lsp, args; {
+    let (suffix, s, fold) = take3!(args);
+    let (suffix, s) = (string_arg(suffix.unwrap())?, string_arg(s.unwrap())?);
+
+    Ok(LispObj::from_bool(if fold.is_some_and(|f| !f.is_nil()) {
+        s.to_lowercase().ends_with(&suffix.to_lowercase())
+    } else {
+        s.ends_with(suffix)
+    }))
+}}
+
+// Follows split-string in subr.el, including its handling of empty matches
+def_builtin! { "split-string", SplitStringBuiltin, Evaluated, 1, 4, lsp, args; {
+    let text: Vec<char> = string_arg(args.next().unwrap())?.chars().collect();
+    let (separators, omit_nulls, trim) = take3!(args);
+    let keep_nulls = match separators {
+        None | Some(&LispObj::Atm(symbols::NIL)) => false,
+        Some(_) => omit_nulls.is_none_or(|o| o.is_nil()),
+    };
+    let sep = regexp_arg(lsp, separators, SPLIT_SEPARATORS)?;
+    let trim = match trim {
+        None | Some(&LispObj::Atm(symbols::NIL)) => None,
+        Some(obj) => {
+            let trim = string_arg(obj)?;
+            Some((regexp(lsp, trim)?, regexp(lsp, &format!("\\(?:{}\\)\\'", trim))?))
+        },
+    };
+
+    let mut spans = Vec::new();
+    let mut start = 0;
+    let mut last_match = None;
+    loop {
+        // Don't find the same empty match again
+        let from = if last_match == Some(start) && start < text.len() { start + 1 } else { start };
+        match sep.search(&text, from, &mut || lsp.check_limits())? {
+            Some((match_start, match_end)) if start < text.len() => {
+                spans.push((start, match_start));
+                last_match = Some(match_start);
+                start = match_end;
+            },
+            _ => break,
+        }
+    }
+    spans.push((start, text.len()));
+
+    let mut parts = Vec::new();
+    for (mut start, mut end) in spans {
+        if let Some((ref leading, ref trailing)) = trim {
+            if let Some(trimmed) = leading.match_at(&text[..end], start, &mut || lsp.check_limits())? {
+                start = trimmed;
+            }
+            if let Some((trimmed, _)) = trailing.search(&text[start..end], 0,
+                                                        &mut || lsp.check_limits())? {
+                end = start + trimmed;
+            }
+        }
+        if keep_nulls || start < end {
+            parts.push(LispObj::string(text[start..end].iter().collect()));
+        }
+    }
+
+    Ok(LispObj::list_from(&parts))
+}}
+
+def_builtin! { "string-join", StringJoinBuiltin, Evaluated, 1, 2, lsp, args; {
+    let (strings, sep) = take2!(args);
+    let sep = match sep {
+        None | Some(&LispObj::Atm(symbols::NIL)) => "",
+        Some(obj) => string_arg(obj)?,
+    };
+
+    let mut out = String::new();
+    for (i, s) in list_items(lsp, strings.unwrap())?.iter().enumerate() {
+        if i > 0 {
+            out.push_str(sep);
+        }
+        push_chars(lsp, &mut out, s)?;
+    }
+    Ok(LispObj::string(out))
+}}
+
+def_builtin! { "string-trim-left", StringTrimLeftBuiltin, Evaluated, 1, 2, lsp, args; {
+    let text: Vec<char> = string_arg(args.next().unwrap())?.chars().collect();
+    let leading = regexp_arg(lsp, args.next(), TRIM_DEFAULT)?;
+    let start = leading.match_at(&text, 0, &mut || lsp.check_limits())?.unwrap_or(0);
+
+    Ok(LispObj::string(text[start..].iter().collect()))
+}}
+
+def_builtin! { "string-trim-right", StringTrimRightBuiltin, Evaluated, 1, 2, lsp, args; {
+    let text: Vec<char> = string_arg(args.next().unwrap())?.chars().collect();
+    let end = trim_right(lsp, &text, args.next())?;
+
+    Ok(LispObj::string(text[..end].iter().collect()))
+}}
+
+def_builtin! { "string-trim", StringTrimBuiltin, Evaluated, 1, 3, lsp, args; {
+    let text: Vec<char> = string_arg(args.next().unwrap())?.chars().collect();
+    let (left, right) = take2!(args);
+    let leading = regexp_arg(lsp, left, TRIM_DEFAULT)?;
+    let start = leading.match_at(&text, 0, &mut || lsp.check_limits())?.unwrap_or(0);
+    let end = start + trim_right(lsp, &text[start..], right)?;
+
+    Ok(LispObj::string(text[start..end].iter().collect()))
+}}
+
+/// Where the text ends without the trailing match of regexp
+fn trim_right(lsp: &mut Lsp, text: &[char], trailing: Option<&LispObj>) -> Result<usize, LispError> {
+    let pattern = match trailing {
+        None | Some(&LispObj::Atm(symbols::NIL)) => TRIM_DEFAULT,
+        Some(obj) => string_arg(obj)?,
+    };
+    let trailing = regexp(lsp, &format!("\\(?:{}\\)\\'", pattern))?;
+
+    Ok(trailing.search(text, 0, &mut || lsp.check_limits())?.map_or(text.len(), |(start, _)| start))
+}
+
+def_builtin! { "upcase", UpcaseBuiltin, Evaluated, 1, 1, lsp, args; {
+    change_case(one_arg(lsp, "upcase", args)?, upcase_char, str::to_uppercase)
+}}
+
+def_builtin! { "downcase", DowncaseBuiltin, Evaluated, 1, 1, lsp, args; {
+    change_case(one_arg(lsp, "downcase", args)?, downcase_char, str::to_lowercase)
+}}
+
+def_builtin! { "capitalize", CapitalizeBuiltin, Evaluated, 1, 1, lsp, args; {
+    change_case(one_arg(lsp, "capitalize", args)?, upcase_char, |s| capitalize(s, true))
+}}
+
+def_builtin! { "upcase-initials", UpcaseInitialsBuiltin, Evaluated, 1, 1, lsp, args; {
+    change_case(one_arg(lsp, "upcase-initials", args)?, upcase_char, |s| capitalize(s, false))
+}}
+
+def_builtin! { "string-to-number", StringToNumberBuiltin, Evaluated, 1, 2, _lsp, args; {
+    let (s, base) = take2!(args);
+    let base = match base {
+        None | Some(&LispObj::Atm(symbols::NIL)) => 10,
+        Some(&LispObj::Int(base)) if (2..=16).contains(&base) => base as u32,
+        Some(obj @ &LispObj::Int(_)) => return Err(LispError::args_out_of_range(std::slice::from_ref(obj))),
+        Some(obj) => return Err(LispError::wrong_type(symbols::INTEGERP, obj)),
+    };
+
+    Ok(parse_number(string_arg(s.unwrap())?, base))
+}}
+
+def_builtin! { "number-to-string", NumberToStringBuiltin, Evaluated, 1, 1, lsp, args; {
+    let num = one_arg(lsp, "number-to-string", args)?;
+    match num {
+        &LispObj::Int(_) | &LispObj::Big(_) | &LispObj::Float(_) => {
+            let mut s = String::new();
+            lsp.print(&mut s, num).unwrap();
+            Ok(LispObj::string(s))
+        },
+        obj => Err(LispError::wrong_type(symbols::NUMBERP, obj)),
+    }
+}}
+
+def_builtin! { "string-to-char", StringToCharBuiltin, Evaluated, 1, 1, lsp, args; {
+    let s = string_arg(one_arg(lsp, "string-to-char", args)?)?;
+    Ok(LispObj::Int(s.chars().next().map_or(0, |c| c as i64)))
+}}
+
+def_builtin! { "char-to-string", CharToStringBuiltin, Evaluated, 1, 1, lsp, args; {
+    let c = char_arg(one_arg(lsp, "char-to-string", args)?)?;
+    Ok(LispObj::string(c.to_string()))
+}}
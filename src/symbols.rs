@@ -1,7 +1,7 @@
 use fnv::FnvHashMap;
-use std::usize;
 use std::fmt;
 use std::cmp;
+use std::collections::hash_map::Values;
 
 use super::*;
 
@@ -18,7 +18,7 @@ macro_rules! gen_const_atoms {
     };
     ($cur:ident; $last_index:expr ; $($var:ident = $index:expr)+) => {
         $( pub const $var: Atom = Atom { indx: $index }; )+
-           pub const $cur: Atom = Atom { indx: $last_index };
+           pub const $cur: Atom = Atom { indx: $last_index + 1 };
     };
 }
 
@@ -26,7 +26,49 @@ macro_rules! gen_const_atoms {
 gen_const_atoms! {
     NIL, T, LAMBDA, MACRO, ANONYMOUS, QUOTE, EXIT, LOAD_PATH,
 
-    KEYMAP, CURRENT_BUFFER, CURRENT_CURSOR, CURRENT_FRAME, A
+    KEYMAP, CURRENT_BUFFER, CURRENT_CURSOR, CURRENT_FRAME, A,
+
+    ERROR, QUIT, USER_ERROR, ERROR_CONDITIONS, ERROR_MESSAGE, VOID_VARIABLE,
+    VOID_FUNCTION, INVALID_FUNCTION, WRONG_TYPE_ARGUMENT, WRONG_NUMBER_OF_ARGUMENTS,
+    INTEGERP, STRINGP, SYMBOLP, LISTP, FUNCTIONP, FILE_ERROR, FILE_MISSING,
+
+    CONSP, VECTORP,
+
+    GARBAGE_COLLECT, GC_CONS_THRESHOLD, GCS_DONE, GC_CONS_PERCENTAGE,
+
+    INTERACTIVE, VARIABLE_DOCUMENTATION,
+
+    CLOSURE, FUNCTION, LEXICAL_BINDING,
+
+    AND_OPTIONAL, AND_REST, MANY, UNEVALLED,
+
+    BACKQUOTE, COMMA, COMMA_AT,
+
+    INVALID_READ_SYNTAX, END_OF_FILE,
+
+    DEBUG_ON_ERROR,
+
+    MAX_LISP_EVAL_DEPTH, RECURSION_ERROR, EXCESSIVE_LISP_NESTING,
+
+    NUMBERP, NUMBER_OR_MARKER_P, INTEGER_OR_MARKER_P, ARITH_ERROR, RANGE_ERROR,
+    OVERFLOW_ERROR,
+
+    CHARACTERP, SEQUENCEP, ARGS_OUT_OF_RANGE, INVALID_REGEXP, CASE_FOLD_SEARCH,
+    NATNUMP, CHAR_OR_STRING_P,
+
+    STANDARD_OUTPUT, STANDARD_INPUT, BUFFERP,
+
+    EQ, EQL, EQUAL, HASH_TABLE, HASH_TABLE_P, HASH_TABLE_TEST, TEST, DATA,
+
+    ARRAYP, CIRCULAR_LIST,
+
+    THROW, NO_CATCH,
+
+    GENSYM_COUNTER, OBARRAY, OBARRAYP,
+
+    KEYWORDP, SETTING_CONSTANT,
+
+    INHIBIT_QUIT, STEP_LIMIT_EXCEEDED, MEMORY_FULL
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
@@ -47,7 +89,7 @@ pub struct AtomRegistry {
 
 impl AtomRegistry {
     pub fn with_capacity(capacity: usize) -> AtomRegistry {
-        let cap = capacity + 12;
+        let cap = capacity + 32;
         let mut me = AtomRegistry {
             table: Vec::with_capacity(cap),
             rev_table: FnvHashMap::with_capacity_and_hasher(cap, Default::default()),
@@ -63,57 +105,149 @@ impl AtomRegistry {
         atomize_const_atoms!(
             "nil", "t", "lambda", "macro", "#<anonymous>", "quote", "exit", "load-path",
 
-            "keymap", "current-buffer", "current-cursor", "current-frame", "a"
+            "keymap", "current-buffer", "current-cursor", "current-frame", "a",
+
+            "error", "quit", "user-error", "error-conditions", "error-message", "void-variable",
+            "void-function", "invalid-function", "wrong-type-argument", "wrong-number-of-arguments",
+            "integerp", "stringp", "symbolp", "listp", "functionp", "file-error", "file-missing",
+
+            "consp", "vectorp",
+
+            "garbage-collect", "gc-cons-threshold", "gcs-done", "gc-cons-percentage",
+
+            "interactive", "variable-documentation",
+
+            "closure", "function", "lexical-binding",
+
+            "&optional", "&rest", "many", "unevalled",
+
+            "`", ",", ",@",
+
+            "invalid-read-syntax", "end-of-file",
+
+            "debug-on-error",
+
+            "max-lisp-eval-depth", "recursion-error", "excessive-lisp-nesting",
+
+            "numberp", "number-or-marker-p", "integer-or-marker-p", "arith-error", "range-error",
+            "overflow-error",
+
+            "characterp", "sequencep", "args-out-of-range", "invalid-regexp", "case-fold-search",
+            "natnump", "char-or-string-p",
+
+            "standard-output", "standard-input", "bufferp",
+
+            "eq", "eql", "equal", "hash-table", "hash-table-p", "hash-table-test", "test", "data",
+
+            "arrayp", "circular-list",
+
+            "#<throw>", "no-catch",
+
+            "gensym-counter", "obarray", "obarrayp",
+
+            "keywordp", "setting-constant",
+
+            "inhibit-quit", "step-limit-exceeded", "memory-full"
         );
         me
     }
 
     pub fn atomize(&mut self, name: &str) -> Atom {
-        if let Some(atom) = self.rev_table.get(name).and_then(|atm| Some(atm.clone())) {
+        if let Some(atom) = self.rev_table.get(name).copied() {
             atom
         } else {
             self.table.push(name.into());
             let atom = Atom {
                 indx: self.table.len() - 1,
             };
-            self.rev_table.insert(name.into(), atom.clone());
+            self.rev_table.insert(name.into(), atom);
             atom
         }
     }
 
     pub fn atomize_mv(&mut self, name: String) -> Atom {
-        if let Some(atom) = self.rev_table.get(&name).and_then(|atm| Some(atm.clone())) {
+        if let Some(atom) = self.rev_table.get(&name).copied() {
             atom
         } else {
             self.table.push(name.clone());
             let atom = Atom {
                 indx: self.table.len() - 1,
             };
-            self.rev_table.insert(name, atom.clone());
+            self.rev_table.insert(name, atom);
             atom
         }
     }
 
+    /// The atom for a name, without creating one if it does not exist
+    pub fn get(&self, name: &str) -> Option<Atom> {
+        self.rev_table.get(name).cloned()
+    }
+
+    /// A new atom for the name of a symbol which is not interned
+    ///
+    /// The name is not entered in the table, so the atom is distinct from
+    /// the one the name is read as.
+    pub fn uninterned(&mut self, name: String) -> Atom {
+        self.table.push(name);
+        Atom {
+            indx: self.table.len() - 1,
+        }
+    }
+
+    /// Is the atom the one its name is read as
+    pub fn is_interned(&self, atom: Atom) -> bool {
+        self.get(self.stringify(atom)) == Some(atom)
+    }
+
+    /// Remove the atom's name from the table, returning whether it was there
+    ///
+    /// The atom keeps its name, but the next time the name is read or
+    /// interned a new atom is created for it.
+    pub fn unintern(&mut self, atom: Atom) -> bool {
+        if self.is_interned(atom) {
+            self.rev_table.remove(&self.table[atom.indx]);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Every atom which is in the table
+    pub fn interned(&self) -> Vec<Atom> {
+        self.rev_table.values().cloned().collect()
+    }
+
     pub fn stringify(&self, atom: Atom) -> &str {
         &self.table[atom.indx]
     }
+
+    /// Does the name start with a colon, e.g. `:test`
+    pub fn is_keyword(&self, atom: Atom) -> bool {
+        self.table[atom.indx].starts_with(':')
+    }
 }
 
 pub struct SymbolData {
     pub value: Option<LispObj>,
     pub function: Option<LispObj>,
-    pub properties: Option<FnvHashMap<Atom, LispObj>>,
+    /// The property list, in the order symbol-plist returns it
+    pub properties: Option<Vec<(Atom, LispObj)>>,
+    /// Declared with defvar or defconst, so always dynamically bound
+    pub special: bool,
 }
 
 impl SymbolData {
-    fn new(val: Option<LispObj>, fun: Option<LispObj>, props: Option<FnvHashMap<Atom, LispObj>>)
+    fn new(val: Option<LispObj>, fun: Option<LispObj>, props: Option<Vec<(Atom, LispObj)>>)
            -> Rc<RefCell<SymbolData>>
     {
-        Rc::new(RefCell::new(SymbolData {
+        let data = Rc::new(RefCell::new(SymbolData {
             value: val,
             function: fun,
             properties: props,
-        }))
+            special: false,
+        }));
+        gc::track_symbol(&data);
+        data
     }
 }
 
@@ -124,16 +258,21 @@ pub struct Symbol {
 }
 
 impl Symbol {
+    /// Identifies the symbol's data on the heap, see gc::addr
+    pub fn addr(&self) -> usize {
+        gc::addr(&self.data)
+    }
+
     pub fn new(name: Atom) -> Symbol {
         Symbol {
-            name: name,
+            name,
             data: SymbolData::new(None, None, None),
         }
     }
 
     pub fn with_val(name: Atom, val: LispObj) -> Symbol {
         Symbol {
-            name: name,
+            name,
             data: SymbolData::new(Some(val), None, None)
         }
     }
@@ -144,33 +283,75 @@ impl Symbol {
 
     pub fn with_fun(name: Atom, fun: LispObj) -> Symbol {
         Symbol {
-            name: name,
+            name,
             data: SymbolData::new(None, Some(fun), None)
         }
     }
 
     pub fn get_val(&self) -> Option<LispObj> {
         let data = self.data.borrow();
-        if let Some(ref val) = data.value {
-            Some(val.clone())
-        } else {
-            None
-        }
+        data.value.clone()
     }
 
     pub fn get_fun(&self) -> Option<LispObj> {
         let data = self.data.borrow();
-        if let Some(ref val) = data.function {
-            Some(val.clone())
-        } else {
-            None
-        }
+        data.function.clone()
+    }
+
+    pub fn set_val(&self, val: LispObj) {
+        let mut data = self.data.borrow_mut();
+        data.value = Some(val);
     }
 
     pub fn set_fun(&self, fun: LispObj) {
         let mut data = self.data.borrow_mut();
         data.function = Some(fun);
     }
+
+    /// Make the value void
+    pub fn unset_val(&self) {
+        self.data.borrow_mut().value = None;
+    }
+
+    /// Make the function definition void
+    pub fn unset_fun(&self) {
+        self.data.borrow_mut().function = None;
+    }
+
+    pub fn is_special(&self) -> bool {
+        self.data.borrow().special
+    }
+
+    pub fn set_special(&self) {
+        self.data.borrow_mut().special = true;
+    }
+
+    pub fn get_prop(&self, prop: Atom) -> Option<LispObj> {
+        let data = self.data.borrow();
+        data.properties.as_ref()
+            .and_then( |props| props.iter().find( |&&(p, _)| p == prop ) )
+            .map( |(_, val)| val.clone() )
+    }
+
+    /// Set a property, a new one goes on the front of the list as in Emacs
+    pub fn put_prop(&self, prop: Atom, val: LispObj) {
+        let mut data = self.data.borrow_mut();
+        let props = data.properties.get_or_insert_with(Vec::new);
+
+        match props.iter_mut().find( |&&mut (p, _)| p == prop ) {
+            Some(&mut (_, ref mut old)) => *old = val,
+            None => props.insert(0, (prop, val)),
+        }
+    }
+
+    /// The properties and their values in order
+    pub fn plist(&self) -> Vec<(Atom, LispObj)> {
+        self.data.borrow().properties.clone().unwrap_or_default()
+    }
+
+    pub fn set_plist(&self, props: Vec<(Atom, LispObj)>) {
+        self.data.borrow_mut().properties = Some(props);
+    }
 }
 
 impl fmt::Debug for Symbol {
@@ -181,19 +362,31 @@ impl fmt::Debug for Symbol {
     }
 }
 
+/// Symbols are the same if they share their data
+///
+/// Two uninterned symbols with the same name are different symbols.
 impl cmp::PartialEq for Symbol {
     fn eq(&self, other: &Symbol) -> bool {
-        self.name == other.name
+        Rc::ptr_eq(&self.data, &other.data)
     }
 }
 
 /// A collection of named functions and variables
 ///
-/// This is probably fairly close to an obarray in Emacs.
+/// The global namespace holds the value, function and properties of each
+/// atom which has any. The global obarray is the table of names in the
+/// AtomRegistry, a name is interned once it has been read or passed to
+/// intern. Symbols made by make-symbol are never added to a namespace.
 pub struct Namespace {
     syms: FnvHashMap<Atom, Symbol>,
 }
 
+impl Default for Namespace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Namespace {
     pub fn new() -> Namespace {
         Namespace {
@@ -209,6 +402,16 @@ impl Namespace {
         self.syms.get(&name)
     }
 
+    /// Get the symbol with this name, creating an empty one if necessary
+    pub fn get_or_intern(&mut self, name: Atom) -> &Symbol {
+        self.syms.entry(name).or_insert_with( || Symbol::new(name) )
+    }
+
+    /// Remove the symbol with this name, returning it if there was one
+    pub fn remove(&mut self, name: Atom) -> Option<Symbol> {
+        self.syms.remove(&name)
+    }
+
     pub fn get_val(&self, name: Atom) -> Option<LispObj> {
         self.get(name).and_then( |sym| sym.get_val() )
     }
@@ -216,6 +419,10 @@ impl Namespace {
     pub fn get_fun(&self, name: Atom) -> Option<LispObj> {
         self.get(name).and_then( |sym| sym.get_fun() )
     }
+
+    pub fn iter(&self) -> Values<'_, Atom, Symbol> {
+        self.syms.values()
+    }
 }
 
 #[cfg(test)]
@@ -278,4 +485,13 @@ mod tests {
         assert_eq!(u, CURRENT_FRAME);
         assert_eq!(v, CURRENT_CURSOR);
     }
+
+    #[test]
+    fn last_const_atom() {
+        let mut reg = AtomRegistry::with_capacity(1);
+
+        assert_eq!(reg.atomize("gcs-done"), GCS_DONE);
+        assert_eq!(reg.atomize("gc-cons-threshold"), GC_CONS_THRESHOLD);
+        assert!(GCS_DONE != GC_CONS_THRESHOLD);
+    }
 }
@@ -15,32 +15,194 @@
 
 use std::str::Chars;
 use std::iter::Peekable;
+use std::rc::Rc;
+use std::cell::Cell;
 use symbols::{Atom, AtomRegistry};
+use error::LispError;
+use bignum::BigInt;
+use {CHAR_ALT, CHAR_SUPER, CHAR_HYPER, CHAR_SHIFT, CHAR_CTL, CHAR_META, CHAR_MODIFIER_MASK};
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Number {
-    pub significand: i32,
+    pub significand: i64,
     //base: u8,
     //exponent: u8,
 }
 
 impl Number {
-    fn default() -> Number {
+    fn new(significand: i64) -> Number {
         Number {
-            significand: 0,
+            significand,
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq)]
 pub enum Token {
     Spc,
     Qot,
+    /// Backquote
+    Bqt,
+    /// Comma, unquote inside a backquote
+    Cma,
+    /// Comma-at, unquote and splice inside a backquote
+    Spl,
+    /// `#'`, quote a function
+    Fun,
+    /// A lone `.` separating the tail of a dotted list
+    Dot,
+    /// Opening bracket, `s` for the `#s(` of a record literal
     Lbr(char),
     Rbr(char),
     Atm(Atom),
+    /// An uninterned symbol, `#:NAME`
+    Sym(Atom),
     Str(String),
-    Num(Number)
+    Num(Number),
+    /// An integer which does not fit in a Number
+    Big(BigInt),
+    /// A floating point number
+    Flt(f64),
+}
+
+/// The characters of some input, counting lines and characters as they are
+/// consumed
+pub struct LineChars<'a> {
+    chars: Chars<'a>,
+    line: Rc<Cell<usize>>,
+    count: Rc<Cell<usize>>,
+}
+
+impl<'a> Iterator for LineChars<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        match c {
+            Some('\n') => {
+                self.line.set(self.line.get() + 1);
+                self.count.set(self.count.get() + 1);
+            },
+            Some(_) => self.count.set(self.count.get() + 1),
+            None => (),
+        }
+        c
+    }
+}
+
+/// Input which is tokenized one token at a time
+pub struct Source<'a> {
+    chars: Peekable<LineChars<'a>>,
+    line: Rc<Cell<usize>>,
+    count: Rc<Cell<usize>>,
+}
+
+impl<'a> Source<'a> {
+    pub fn new(input: &'a str) -> Source<'a> {
+        let line = Rc::new(Cell::new(1));
+        let count = Rc::new(Cell::new(0));
+        let chars = LineChars {
+            chars: input.chars(),
+            line: Rc::clone(&line),
+            count: Rc::clone(&count),
+        };
+
+        Source {
+            chars: chars.peekable(),
+            line,
+            count,
+        }
+    }
+
+    /// Skip over n characters without tokenizing them
+    pub fn skip(&mut self, n: usize) {
+        for _ in 0..n {
+            self.chars.next();
+        }
+    }
+
+    /// The index of the first character which has not been tokenized
+    pub fn pos(&mut self) -> usize {
+        // The tokenizer may have peeked at the next character
+        let peeked = self.chars.peek().is_some();
+        self.count.get() - if peeked { 1 } else { 0 }
+    }
+}
+
+/// Apply the control modifier to a character as `?\C-` does
+fn ctrl(c: i32) -> i32 {
+    let base = c & !CHAR_MODIFIER_MASK;
+
+    if base == '?' as i32 {
+        0o177 | (c & CHAR_MODIFIER_MASK)
+    } else if base >= 0o200 {
+        c | CHAR_CTL
+    } else if (base & 0o137) >= 0o101 && (base & 0o137) <= 0o132 {
+        // Letters of either case
+        c & (0o37 | !0o177)
+    } else if (base & 0o177) >= 0o100 && (base & 0o177) <= 0o137 {
+        c & (0o37 | !0o177)
+    } else {
+        c | CHAR_CTL
+    }
+}
+
+/// Parse a decimal integer such as `-12` or `12.`
+fn parse_integer(s: &str) -> Option<Token> {
+    let digits = s.strip_suffix('.').unwrap_or(s);
+    let unsigned = digits.trim_start_matches( ['+', '-'] );
+
+    if unsigned.is_empty() || digits.len() - unsigned.len() > 1 ||
+        !unsigned.chars().all( |c| c.is_ascii_digit() )
+    {
+        return None;
+    }
+
+    match digits.trim_start_matches('+').parse::<i64>() {
+        Ok(i) => Some(Token::Num(Number::new(i))),
+        Err(_) => BigInt::parse(digits, 10).map(Token::Big),
+    }
+}
+
+/// Parse a float such as `1.5`, `.5e-3`, `1e3` or `1.0e+INF`
+fn parse_float(s: &str) -> Option<f64> {
+    let (sign, rest) = match s.chars().next() {
+        Some(c @ '+') | Some(c @ '-') => (c, &s[1..]),
+        _ => ('+', s),
+    };
+    let (mant, exp) = match rest.find( ['e', 'E'] ) {
+        Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+        None => (rest, None),
+    };
+
+    let dots = mant.chars().filter( |&c| c == '.' ).count();
+    if dots > 1 || !mant.chars().any( |c| c.is_ascii_digit() ) ||
+        !mant.chars().all( |c| c.is_ascii_digit() || c == '.' )
+    {
+        return None;
+    }
+
+    let neg = if sign == '-' { -1.0 } else { 1.0 };
+    match exp {
+        None if mant.ends_with('.') || dots == 0 => None,
+        None => format!("{}{}", sign, mant).parse().ok(),
+        Some("+INF") => Some(neg * f64::INFINITY),
+        Some("+NaN") => Some(neg * f64::NAN),
+        Some(exp) => {
+            let digits = exp.trim_start_matches( ['+', '-'] );
+            if digits.is_empty() || exp.len() - digits.len() > 1 ||
+                !digits.chars().all( |c| c.is_ascii_digit() )
+            {
+                return None;
+            }
+            format!("{}{}e{}", sign, mant.trim_end_matches('.'), exp).parse().ok()
+        },
+    }
+}
+
+/// Would the reader take this text as a number rather than a symbol
+pub fn looks_like_number(s: &str) -> bool {
+    parse_integer(s).is_some() || parse_float(s).is_some()
 }
 
 pub trait Tokenizer {
@@ -58,11 +220,17 @@ pub trait Tokenizer {
         }
     }
 
-    fn tok_spc(&self, itr: &mut Peekable<Chars>) -> Result<Token, &'static str> {
+    /// Characters which end a symbol or number
+    fn is_delim(c: char) -> bool {
+        matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0c' | '(' | '{' | '[' | ']' | '}' | ')' |
+                    '"' | '\'' | ';' | ',' | '`')
+    }
+
+    fn tok_spc(&self, itr: &mut Peekable<LineChars>) -> Result<Token, LispError> {
         loop {
             if let Some(&c) = itr.peek() {
                 match c {
-                    ' ' | '\t' | '\n' | '\r' => itr.next(),
+                    ' ' | '\t' | '\n' | '\r' | '\x0c' => itr.next(),
                     _ => break Ok(Token::Spc),
                 };
             } else {
@@ -71,20 +239,149 @@ pub trait Tokenizer {
         }
     }
 
-    fn tok_str(&self, q: char, itr: &mut Peekable<Chars>) -> Result<Token, &'static str> {
+    /// Skip a comment up to the end of the line
+    fn tok_comment(&self, itr: &mut Peekable<LineChars>) -> Result<Token, LispError> {
+        for c in itr.by_ref() {
+            if c == '\n' {
+                break;
+            }
+        }
+        Ok(Token::Spc)
+    }
+
+    /// Read a fixed or maximum number of digits for an escape such as `\x41`
+    fn tok_escape_digits(&self, itr: &mut Peekable<LineChars>, radix: u32, max: usize, exact: bool)
+                         -> Result<i32, LispError>
+    {
+        let mut code: i32 = 0;
+        let mut count = 0;
+
+        while count < max {
+            match itr.peek().and_then( |c| c.to_digit(radix) ) {
+                Some(d) => {
+                    code = code.checked_mul(radix as i32)
+                        .and_then( |c| c.checked_add(d as i32) )
+                        .ok_or_else( || LispError::invalid_read_syntax("Invalid escape") )?;
+                    itr.next();
+                    count += 1;
+                },
+                None => break,
+            }
+        }
+
+        if count == 0 || (exact && count < max) {
+            Err(LispError::invalid_read_syntax("Invalid escape"))
+        } else {
+            Ok(code)
+        }
+    }
+
+    /// The character after a modifier prefix such as `\C-`
+    fn tok_modified(&self, itr: &mut Peekable<LineChars>) -> Result<i32, LispError> {
+        match itr.next() {
+            Some('\\') => self.tok_escape(itr),
+            Some(c) => Ok(c as i32),
+            None => Err(LispError::end_of_file()),
+        }
+    }
+
+    /// Read the escape sequence following a backslash in a character literal
+    /// or string, returning the character code with any modifier bits
+    fn tok_escape(&self, itr: &mut Peekable<LineChars>) -> Result<i32, LispError> {
+        let c = match itr.next() {
+            Some(c) => c,
+            None => return Err(LispError::end_of_file()),
+        };
+        let modifier = match c {
+            'C' | 'M' | 'S' | 'H' | 'A' | 's' if itr.peek() == Some(&'-') => {
+                itr.next();
+                Some(c)
+            },
+            _ => None,
+        };
+
+        Ok(match (c, modifier) {
+            (_, Some('C')) | ('^', _) => ctrl(self.tok_modified(itr)?),
+            (_, Some('M')) => self.tok_modified(itr)? | CHAR_META,
+            (_, Some('S')) => self.tok_modified(itr)? | CHAR_SHIFT,
+            (_, Some('H')) => self.tok_modified(itr)? | CHAR_HYPER,
+            (_, Some('A')) => self.tok_modified(itr)? | CHAR_ALT,
+            (_, Some(_)) => self.tok_modified(itr)? | CHAR_SUPER,
+            ('a', _) => 7,
+            ('b', _) => 8,
+            ('d', _) => 127,
+            ('e', _) => 27,
+            ('f', _) => 12,
+            ('n', _) => '\n' as i32,
+            ('r', _) => '\r' as i32,
+            ('t', _) => '\t' as i32,
+            ('v', _) => 11,
+            ('s', _) => ' ' as i32,
+            ('x', _) => self.tok_escape_digits(itr, 16, 8, false)?,
+            ('u', _) => self.tok_escape_digits(itr, 16, 4, true)?,
+            ('U', _) => self.tok_escape_digits(itr, 16, 8, true)?,
+            ('N', _) => {
+                // Only the numeric form, \N{U+X}, character names are unknown
+                if itr.next() != Some('{') || itr.next() != Some('U') || itr.next() != Some('+') {
+                    return Err(LispError::invalid_read_syntax("\\N"));
+                }
+                let code = self.tok_escape_digits(itr, 16, 8, false)?;
+                if itr.next() != Some('}') {
+                    return Err(LispError::invalid_read_syntax("\\N"));
+                }
+                code
+            },
+            (c, _) if c.is_digit(8) => {
+                let mut code = c.to_digit(8).unwrap() as i32;
+                for _ in 0..2 {
+                    match itr.peek().and_then( |c| c.to_digit(8) ) {
+                        Some(d) => code = code * 8 + d as i32,
+                        None => break,
+                    }
+                    itr.next();
+                }
+                code
+            },
+            (c, _) => c as i32,
+        })
+    }
+
+    /// A character literal such as `?a`, `?\n` or `?\C-x`
+    fn tok_char(&self, itr: &mut Peekable<LineChars>) -> Result<Token, LispError> {
+        let code = match itr.next() {
+            Some('\\') => self.tok_escape(itr)?,
+            Some(c) => c as i32,
+            None => return Err(LispError::end_of_file()),
+        };
+
+        match itr.peek() {
+            Some(&c) if !Self::is_delim(c) => Err(LispError::invalid_read_syntax("?")),
+            _ => Ok(Token::Num(Number::new(code as i64))),
+        }
+    }
+
+    fn tok_str(&self, q: char, itr: &mut Peekable<LineChars>) -> Result<Token, LispError> {
         let mut s = String::new();
 
         while let Some(c) = itr.next() {
             match c {
-                '\\' => match itr.next() {
-                    Some(c) if c == q => s.push(c),
-                    Some('n') => s.push('\n'),
-                    Some('t') => s.push('\t'),
-                    Some('r') => s.push('\r'),
-                    Some('\\') => s.push('\\'),
-                    Some(l) => {
-                        s.push(c);
-                        s.push(l);
+                '\\' => match itr.peek() {
+                    // An escaped newline or space is ignored
+                    Some(&'\n') | Some(&' ') => {
+                        itr.next();
+                    },
+                    Some(_) => {
+                        let mut code = self.tok_escape(itr)?;
+                        // Meta ASCII characters set the 8th bit, as in a
+                        // unibyte Emacs string
+                        if code & CHAR_MODIFIER_MASK == CHAR_META && code & !CHAR_META < 0x80 {
+                            code = (code & !CHAR_META) | 0x80;
+                        }
+                        match ::std::char::from_u32(code as u32) {
+                            Some(c) if code & CHAR_MODIFIER_MASK == 0 => s.push(c),
+                            _ => return Err(LispError::invalid_read_syntax(
+                                "Invalid modifier in string")),
+                        }
                     },
                     None => break,
                 },
@@ -93,97 +390,172 @@ pub trait Tokenizer {
             }
         }
 
-        Err("EOF while tokenizing string")
+        Err(LispError::end_of_file())
     }
 
-    fn tok_atom_or_num(&mut self, l: char, itr: &mut Peekable<Chars>)
-                -> Result<Token, &'static str> {
+    /// Read the digits of an integer in some radix, as in `#x1F`
+    fn tok_radix(&self, radix: u32, itr: &mut Peekable<LineChars>) -> Result<Token, LispError> {
         let mut s = String::new();
-        let mut num = Number::default();
-        let mut seen_digit = false;
-        // let mut seen_e = false;
-        // let mut seen_dot = false;
-        let mut seen_sign = false;
-        s.push(l);
-
-        // Try to parse token as number
-        match l {
-            '0' | '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' => {
-                seen_digit = true;
-            },
-            '-' => seen_sign = true,
-            _ => (),
+
+        while let Some(&c) = itr.peek() {
+            if Self::is_delim(c) {
+                break;
+            }
+            s.push(c);
+            itr.next();
         }
 
-        if seen_digit || seen_sign {
-            while let Some(&c) = itr.peek() {
-                match c {
-                    ' ' | '\t' | '\n' | '\r' | '(' | '{' | '[' | ']' | '}' | ')' => {
-                        if !seen_digit {
-                            break;
-                        }
-                        return match s.parse::<i32>() {
-                            Ok(i) => {
-                                num.significand = i;
-                                Ok(Token::Num(num))
-                            },
-                            Err(e) => {
-                                panic!("Rust can not parse '{}' into an integer: {}",
-                                       s, e)
-                            },
-                        };
-                    },
-                    '0' | '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' => {
-                        seen_digit = true;
-                        s.push(c);
-                        itr.next()
-                    },
-                    _ => {
-                        s.push(c);
-                        itr.next();
+        let digits = s.trim_start_matches( ['+', '-'] );
+        if digits.is_empty() || s.len() - digits.len() > 1 {
+            return Err(LispError::invalid_read_syntax(format!("integer, radix {}", radix)));
+        }
+
+        match i64::from_str_radix(s.trim_start_matches('+'), radix) {
+            Ok(i) => Ok(Token::Num(Number::new(i))),
+            Err(_) => BigInt::parse(&s, radix).map(Token::Big).ok_or_else( || {
+                LispError::invalid_read_syntax(format!("integer, radix {}", radix))
+            }),
+        }
+    }
+
+    /// Read the syntax introduced by `#`
+    fn tok_hash(&mut self, itr: &mut Peekable<LineChars>) -> Result<Token, LispError> {
+        match itr.next() {
+            Some('\'') => Ok(Token::Fun),
+            Some('x') | Some('X') => self.tok_radix(16, itr),
+            Some('o') | Some('O') => self.tok_radix(8, itr),
+            Some('b') | Some('B') => self.tok_radix(2, itr),
+            Some(c) if c.is_ascii_digit() => {
+                let mut radix = c.to_digit(10).unwrap();
+                while let Some(d) = itr.peek().and_then( |c| c.to_digit(10) ) {
+                    radix = radix * 10 + d;
+                    itr.next();
+                    if radix > 36 {
                         break;
+                    }
+                }
+                match itr.next() {
+                    Some('r') if (2..=36).contains(&radix) => self.tok_radix(radix, itr),
+                    _ => Err(LispError::invalid_read_syntax("#")),
+                }
+            },
+            // There are no byte-code objects, so these are read as vectors
+            Some('[') => Ok(Token::Lbr('[')),
+            Some('s') if itr.peek() == Some(&'(') => {
+                itr.next();
+                Ok(Token::Lbr('s'))
+            },
+            Some('#') => Ok(Token::Atm(self.atoms().atomize(""))),
+            // A new uninterned symbol each time it is read
+            Some(':') => {
+                let name = match itr.peek() {
+                    Some(&c) if !Self::is_delim(c) => {
+                        itr.next();
+                        self.tok_name(c, itr)?.0
                     },
+                    _ => String::new(),
                 };
-            }
+                Ok(Token::Sym(self.atoms().uninterned(name)))
+            },
+            Some(_) => Err(LispError::invalid_read_syntax("#")),
+            None => Err(LispError::end_of_file()),
         }
+    }
 
+    /// Read the name of a symbol, or a number, and whether it had any escapes
+    fn tok_name(&self, l: char, itr: &mut Peekable<LineChars>) -> Result<(String, bool), LispError> {
+        let mut s = String::new();
+        let mut escaped = false;
+        let mut c = l;
 
-        // If parsing token a number fails parse it as an atom
-        while let Some(&c) = itr.peek() {
-            match c {
-                ' ' | '\t' | '\n' | '\r' | '(' | '{' | '[' | ']' | '}' | ')' => {
-                    return Ok(Token::Atm(self.atoms().atomize_mv(s)))
-                },
-                _ => {
-                    s.push(c);
-                    itr.next();
-                },
+        loop {
+            if c == '\\' {
+                escaped = true;
+                match itr.next() {
+                    Some(e) => s.push(e),
+                    None => return Err(LispError::end_of_file()),
+                }
+            } else {
+                s.push(c);
+            }
+
+            match itr.peek() {
+                Some(&n) if !Self::is_delim(n) => c = n,
+                _ => break,
             }
+            itr.next();
+        }
+
+        Ok((s, escaped))
+    }
+
+    fn tok_atom_or_num(&mut self, l: char, itr: &mut Peekable<LineChars>)
+                -> Result<Token, LispError> {
+        let (s, escaped) = self.tok_name(l, itr)?;
+
+        // A symbol containing an escape is never a number, e.g. \1
+        if escaped {
+            return Ok(Token::Atm(self.atoms().atomize_mv(s)));
         }
 
-        Err("EOF while tokenizing atom")
+        if s == "." {
+            Ok(Token::Dot)
+        } else if let Some(tok) = parse_integer(&s) {
+            Ok(tok)
+        } else if let Some(f) = parse_float(&s) {
+            Ok(Token::Flt(f))
+        } else {
+            Ok(Token::Atm(self.atoms().atomize_mv(s)))
+        }
     }
 
-    fn tokenize(&mut self, input: &String) -> Result<Vec<Token>, &'static str> {
-        let mut toks = Vec::<Token>::new();
-        let mut itr = input.chars().peekable();
+    #[allow(dead_code)]
+    fn tokenize(&mut self, input: &String) -> Result<Vec<Token>, LispError> {
+        Ok(self.tokenize_lines(input)?.into_iter().map( |(tok, _)| tok ).collect())
+    }
+
+    /// The next token and the line it starts on, None at the end of the input
+    fn next_token(&mut self, src: &mut Source) -> Result<Option<(Token, usize)>, LispError> {
+        let itr = &mut src.chars;
 
         while let Some(c) = itr.next() {
-            let res = match c {
-                ' ' | '\t' | '\n' | '\r' => self.tok_spc(&mut itr),
+            let start = src.line.get();
+            let tok = match c {
+                ' ' | '\t' | '\n' | '\r' | '\x0c' => self.tok_spc(itr),
                 '(' | '{' | '[' => Ok(Token::Lbr(c)),
                 ')' | '}' | ']' => Ok(Token::Rbr(c)),
-                '"' => self.tok_str('"', &mut itr),
+                '"' => self.tok_str('"', itr),
                 '\'' => Ok(Token::Qot),
-                _ => self.tok_atom_or_num(c, &mut itr)
-            };
-            match res {
-                Ok(Token::Spc) => (),
-                Ok(tok) => toks.push(tok),
-                Err(e) => return Err(e),
+                '`' => Ok(Token::Bqt),
+                ',' => if itr.peek() == Some(&'@') {
+                    itr.next();
+                    Ok(Token::Spl)
+                } else {
+                    Ok(Token::Cma)
+                },
+                ';' => self.tok_comment(itr),
+                '?' => self.tok_char(itr),
+                '#' => self.tok_hash(itr),
+                _ => self.tok_atom_or_num(c, itr)
+            }?;
+
+            if tok != Token::Spc {
+                return Ok(Some((tok, start)));
             }
         }
 
+        Ok(None)
+    }
+
+    /// Tokenize the input, pairing each token with the line it starts on
+    #[allow(dead_code)]
+    fn tokenize_lines(&mut self, input: &str) -> Result<Vec<(Token, usize)>, LispError> {
+        let mut src = Source::new(input);
+        let mut toks = Vec::new();
+
+        while let Some(tok) = self.next_token(&mut src)? {
+            toks.push(tok);
+        }
         Ok(toks)
     }
 }
@@ -239,4 +611,51 @@ mod tests {
         let res = nizer.tokenize(&lisp.into()).unwrap();
         assert_eq!(res[1], Token::Num(Number { significand: -1 } ));
     }
+
+    #[test]
+    fn comment() {
+        let mut nizer = TestTokenizer::new();
+        let lisp = ";; -*- lexical-binding: t -*-\n(nil ; comment (\n 1)";
+
+        let res = nizer.tokenize(&lisp.into()).unwrap();
+        assert_eq!(res, vec![Token::Lbr('('), Token::Atm(symbols::NIL),
+                             Token::Num(Number { significand: 1 }), Token::Rbr(')')]);
+    }
+
+    #[test]
+    fn backquote() {
+        let mut nizer = TestTokenizer::new();
+        let lisp = "`(a,b ,@c)";
+
+        let res = nizer.tokenize(&lisp.into()).unwrap();
+        let reg = nizer.atoms();
+        assert_eq!(res, vec![Token::Bqt, Token::Lbr('('), Token::Atm(reg.atomize("a")),
+                             Token::Cma, Token::Atm(reg.atomize("b")),
+                             Token::Spl, Token::Atm(reg.atomize("c")), Token::Rbr(')')]);
+    }
+
+    #[test]
+    fn float_and_overflow() {
+        let mut nizer = TestTokenizer::new();
+        let lisp = "(1.5 -2e2 99999999999999999999 ?\\C-\\M-a . 1+)";
+
+        let res = nizer.tokenize(&lisp.into()).unwrap();
+        let reg = nizer.atoms();
+        assert_eq!(res[1], Token::Flt(1.5));
+        assert_eq!(res[2], Token::Flt(-200.0));
+        assert_eq!(res[3], Token::Big(BigInt::parse("99999999999999999999", 10).unwrap()));
+        assert_eq!(res[4], Token::Num(Number { significand: (1 | CHAR_META) as i64 }));
+        assert_eq!(res[5], Token::Dot);
+        assert_eq!(res[6], Token::Atm(reg.atomize("1+")));
+    }
+
+    #[test]
+    fn lines() {
+        let mut nizer = TestTokenizer::new();
+        let lisp = "(a\n \"b\nc\" ; d\n\n e)";
+
+        let res = nizer.tokenize_lines(lisp).unwrap();
+        let lines: Vec<usize> = res.iter().map( |&(_, line)| line ).collect();
+        assert_eq!(lines, vec![1, 1, 2, 5, 5]);
+    }
 }
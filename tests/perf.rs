@@ -46,3 +46,92 @@ fn cons(b: &mut Bencher) {
 
     b.iter(|| lsp.eval(&ast));
 }
+
+#[bench]
+fn fib_compiled(b: &mut Bencher) {
+    let mut lsp = Lsp::new();
+    let src = r#"
+(fset 'fib
+  '(lambda (a)
+    (if (eq a 1)
+	1
+      (if (eq a 2)
+	  2
+	(+ (fib (- a 1)) (fib (- a 2)))))))
+
+(byte-compile 'fib)
+"#.to_owned();
+    let ast = lsp.read(&src).unwrap();
+    lsp.eval(&ast).unwrap();
+    let ast = lsp.read(&"(fib 20)".to_owned()).unwrap();
+
+    b.iter(|| assert_eq!(Ok(LispObj::Int(10946)), lsp.eval(&ast)));
+}
+
+#[bench]
+fn fib_lexical(b: &mut Bencher) {
+    let mut lsp = Lsp::new();
+    lsp.set_lexical_binding(true);
+    let src = r#"
+(defun fib (a)
+  (let ((n a))
+    (if (eq n 1)
+        1
+      (if (eq n 2)
+          2
+        (+ (fib (- n 1)) (fib (- n 2)))))))
+
+(fib 20)
+"#.to_owned();
+    let ast = lsp.read(&src).unwrap();
+
+    b.iter(|| assert_eq!(Ok(LispObj::Int(10946)), lsp.eval(&ast)));
+}
+
+#[bench]
+fn fib_lexical_compiled(b: &mut Bencher) {
+    let mut lsp = Lsp::new();
+    lsp.set_lexical_binding(true);
+    let src = r#"
+(defun fib (a)
+  (let ((n a))
+    (if (eq n 1)
+        1
+      (if (eq n 2)
+          2
+        (+ (fib (- n 1)) (fib (- n 2)))))))
+
+(byte-compile 'fib)
+"#.to_owned();
+    let ast = lsp.read(&src).unwrap();
+    lsp.eval(&ast).unwrap();
+    let ast = lsp.read(&"(fib 20)".to_owned()).unwrap();
+
+    b.iter(|| assert_eq!(Ok(LispObj::Int(10946)), lsp.eval(&ast)));
+}
+
+#[bench]
+fn cons_compiled(b: &mut Bencher) {
+    let mut lsp = Lsp::new();
+    let src = r#"
+(fset 'repeat
+  '(lambda (a c)
+    (if (eq c 0)
+	a
+      (cons a (repeat a (- c 1))))))
+
+(fset 'add1
+  '(lambda (l)
+    (if (listp l)
+	(cons (+ 1 (car l)) (add1 (cdr l)))
+      l)))
+
+(byte-compile 'repeat)
+(byte-compile 'add1)
+"#.to_owned();
+    let ast = lsp.read(&src).unwrap();
+    lsp.eval(&ast).unwrap();
+    let ast = lsp.read(&"(add1 (repeat 1 100))".to_owned()).unwrap();
+
+    b.iter(|| lsp.eval(&ast));
+}